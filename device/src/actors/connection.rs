@@ -0,0 +1,340 @@
+//! Actor keeping a Wi-Fi network connection up
+//!
+//! `ConnectionManager` owns a network driver and, like `NetworkActor`, performs network operations
+//! on behalf of other actors through its `Address`. On each `Check`, it verifies that the
+//! driver is still joined to the access point and rejoins with exponential backoff when it is
+//! not. Once the link is back up, TCP connections made through the manager are re-established,
+//! as are connections closed while the link stays up. A connection is re-established on a fresh
+//! socket of the driver, which may have reset the previous one, while its owner keeps using the
+//! handle it connected with.
+
+use crate::actors::net::{perform, NetworkHandler, NetworkRequest, NetworkResponse};
+use crate::fmt::*;
+use crate::kernel::actor::{Actor, Address};
+use crate::traits::{
+    ip::{IpProtocol, SocketAddress},
+    tcp::{Readiness, TcpError, TcpStack},
+    wifi::{Join, WifiSupplicant},
+};
+use core::future::Future;
+use core::pin::Pin;
+use embassy::time::{with_timeout, Duration, Instant};
+use heapless::{consts::U4, Vec};
+
+const MIN_BACKOFF: u64 = 1000;
+const MAX_BACKOFF: u64 = 60_000;
+/// Time to wait for a free socket when re-establishing a connection.
+const OPEN_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub trait FromLinkEvent<M> {
+    fn from(event: LinkEvent) -> Option<M>
+    where
+        Self: Sized;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkEvent {
+    Up,
+    Down,
+}
+
+pub enum ConnectionRequest<'m, S: TcpStack + 'm> {
+    Network(NetworkRequest<'m, S>),
+    /// Check the link, rejoining if it is down. Typically sent periodically using a `Ticker`.
+    Check,
+}
+
+struct Connection<H> {
+    /// Handle used by the owner of the connection. It stays allocated in the driver until the
+    /// owner closes it, so that it is not given out again.
+    handle: H,
+    /// Socket of the driver carrying the connection.
+    socket: H,
+    proto: IpProtocol,
+    dst: SocketAddress,
+    timeout: Option<Duration>,
+}
+
+pub struct ConnectionManager<'a, S, A>
+where
+    S: WifiSupplicant + TcpStack + 'a,
+    A: Actor + FromLinkEvent<A::Message<'a>> + 'static,
+{
+    join: Join<'a>,
+    stack: Option<S>,
+    handler: Option<Address<'a, A>>,
+    connections: Vec<Connection<S::SocketHandle>, U4>,
+    up: bool,
+    backoff: u64,
+    next_join: Instant,
+}
+
+impl<'a, S, A> ConnectionManager<'a, S, A>
+where
+    S: WifiSupplicant + TcpStack + 'a,
+    S::SocketHandle: PartialEq,
+    A: Actor + FromLinkEvent<A::Message<'a>> + 'a,
+{
+    pub fn new(join: Join<'a>) -> Self {
+        Self {
+            join,
+            stack: None,
+            handler: None,
+            connections: Vec::new(),
+            up: false,
+            backoff: MIN_BACKOFF,
+            next_join: Instant::from_ticks(0),
+        }
+    }
+
+    async fn request(&mut self, request: NetworkRequest<'_, S>) -> NetworkResponse<S> {
+        let request = match request {
+            NetworkRequest::Connect(handle, proto, dst) => {
+                let socket = self.socket(handle);
+                let stack = self.stack.as_mut().unwrap();
+                let result = stack.connect(socket, proto, dst).await;
+                if result.is_ok() {
+                    match self.connections.iter_mut().find(|c| c.handle == handle) {
+                        Some(c) => {
+                            c.proto = proto;
+                            c.dst = dst;
+                        }
+                        None => {
+                            let connection = Connection {
+                                handle,
+                                socket,
+                                proto,
+                                dst,
+                                timeout: None,
+                            };
+                            if self.connections.push(connection).is_err() {
+                                warn!(
+                                    "Too many connections, connection will not be re-established"
+                                );
+                            }
+                        }
+                    }
+                }
+                return NetworkResponse::Connected(result);
+            }
+            NetworkRequest::Close(handle) => {
+                if let Some(index) = self.connections.iter().position(|c| c.handle == handle) {
+                    let c = self.connections.swap_remove(index);
+                    if c.socket != handle {
+                        self.stack.as_mut().unwrap().close(c.socket).await;
+                    }
+                }
+                NetworkRequest::Close(handle)
+            }
+            NetworkRequest::SetTimeout(handle, timeout) => {
+                if let Some(c) = self.connections.iter_mut().find(|c| c.handle == handle) {
+                    c.timeout = timeout;
+                }
+                NetworkRequest::SetTimeout(self.socket(handle), timeout)
+            }
+            NetworkRequest::Write(handle, buf) => NetworkRequest::Write(self.socket(handle), buf),
+            NetworkRequest::Read(handle, buf) => NetworkRequest::Read(self.socket(handle), buf),
+            NetworkRequest::PollReadable(handle) => {
+                NetworkRequest::PollReadable(self.socket(handle))
+            }
+            NetworkRequest::Available(handle) => NetworkRequest::Available(self.socket(handle)),
+            NetworkRequest::Open => NetworkRequest::Open,
+        };
+        perform(self.stack.as_mut().unwrap(), request).await
+    }
+
+    /// The socket of the driver currently used for a handle of an owner.
+    fn socket(&self, handle: S::SocketHandle) -> S::SocketHandle {
+        self.connections
+            .iter()
+            .find(|c| c.handle == handle)
+            .map(|c| c.socket)
+            .unwrap_or(handle)
+    }
+
+    async fn check(&mut self) {
+        let stack = self.stack.as_mut().unwrap();
+        let up = match stack.status().await {
+            Ok(status) => status.is_some(),
+            Err(e) => {
+                warn!("Error checking link status: {:?}", e);
+                false
+            }
+        };
+
+        if self.up && !up {
+            info!("Link down");
+            self.up = false;
+            self.notify(LinkEvent::Down);
+        }
+
+        if !up && Instant::now() >= self.next_join {
+            self.rejoin().await;
+        } else if up && !self.up {
+            // The driver rejoined by itself
+            self.link_up().await;
+        } else if up {
+            self.check_connections().await;
+        }
+    }
+
+    /// Re-establish connections closed while the link is up, by the remote end or by a reset of
+    /// the driver.
+    async fn check_connections(&mut self) {
+        for index in 0..self.connections.len() {
+            let socket = self.connections[index].socket;
+            let stack = self.stack.as_mut().unwrap();
+            match stack.poll_readable(socket).await {
+                Ok(Readiness::HalfClosed) | Err(TcpError::SocketClosed) => {
+                    info!("Connection to {:?} closed", self.connections[index].dst);
+                    self.reconnect(index).await;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Re-establish a connection on a fresh socket. The socket it replaces is closed, unless it
+    /// is the handle of the owner.
+    async fn reconnect(&mut self, index: usize) {
+        let stack = self.stack.as_mut().unwrap();
+        let c = &mut self.connections[index];
+        if c.socket != c.handle {
+            stack.close(c.socket).await;
+            c.socket = c.handle;
+        }
+        let socket = match with_timeout(OPEN_TIMEOUT, stack.open()).await {
+            Ok(socket) => socket,
+            Err(_) => {
+                warn!("No socket to re-establish connection to {:?}", c.dst);
+                return;
+            }
+        };
+        if let Some(timeout) = c.timeout {
+            if let Err(e) = stack.set_timeout(socket, Some(timeout)).await {
+                warn!("Error setting timeout of socket: {:?}", e);
+            }
+        }
+        match stack.connect(socket, c.proto, c.dst).await {
+            Ok(()) => c.socket = socket,
+            Err(e) => {
+                // Retried on the next check, which finds the handle of the owner closed
+                warn!("Error re-establishing connection to {:?}: {:?}", c.dst, e);
+                stack.close(socket).await;
+            }
+        }
+    }
+
+    async fn rejoin(&mut self) {
+        info!("Joining access point");
+        let join = self.join;
+        match self.stack.as_mut().unwrap().join(join).await {
+            Ok(ip) => {
+                info!("Joined access point with address {:?}", ip);
+                self.link_up().await;
+            }
+            Err(e) => {
+                warn!(
+                    "Error joining access point, retrying in {} ms: {:?}",
+                    self.backoff, e
+                );
+                self.next_join = Instant::now() + Duration::from_millis(self.backoff);
+                self.backoff = core::cmp::min(self.backoff * 2, MAX_BACKOFF);
+            }
+        }
+    }
+
+    async fn link_up(&mut self) {
+        self.up = true;
+        self.backoff = MIN_BACKOFF;
+        // Sockets are closed when the link goes down
+        for index in 0..self.connections.len() {
+            self.reconnect(index).await;
+        }
+        self.notify(LinkEvent::Up);
+    }
+
+    fn notify(&self, event: LinkEvent) {
+        if let Some(handler) = self.handler {
+            if let Some(m) = A::from(event) {
+                let _ = handler.notify(m);
+            }
+        }
+    }
+}
+
+impl<'a, S, A> Unpin for ConnectionManager<'a, S, A>
+where
+    S: WifiSupplicant + TcpStack + 'a,
+    A: Actor + FromLinkEvent<A::Message<'a>> + 'a,
+{
+}
+
+impl<'a, S, A> Actor for ConnectionManager<'a, S, A>
+where
+    S: WifiSupplicant + TcpStack + 'a,
+    S::SocketHandle: Send + PartialEq,
+    A: Actor + FromLinkEvent<A::Message<'a>> + 'a,
+{
+    // Room for requests from several actors
+    #[rustfmt::skip]
+    type MessageQueueSize<'m> where 'a: 'm = U4;
+    type Configuration = (S, Address<'a, A>);
+    #[rustfmt::skip]
+    type Message<'m> where 'a: 'm = ConnectionRequest<'m, S>;
+    #[rustfmt::skip]
+    type Response<'m> where 'a: 'm = Option<NetworkResponse<S>>;
+    #[rustfmt::skip]
+    type OnStartFuture<'m> where 'a: 'm = impl Future<Output = ()> + 'm;
+    #[rustfmt::skip]
+    type OnMessageFuture<'m> where 'a: 'm = impl Future<Output = Option<NetworkResponse<S>>> + 'm;
+
+    fn on_mount(&mut self, config: Self::Configuration) {
+        let (stack, handler) = config;
+        self.stack.replace(stack);
+        self.handler.replace(handler);
+    }
+
+    fn on_start(mut self: Pin<&mut Self>) -> Self::OnStartFuture<'_> {
+        async move { self.rejoin().await }
+    }
+
+    fn on_message<'m>(
+        mut self: Pin<&'m mut Self>,
+        message: Self::Message<'m>,
+    ) -> Self::OnMessageFuture<'m> {
+        async move {
+            match message {
+                ConnectionRequest::Network(request) => Some(self.request(request).await),
+                ConnectionRequest::Check => {
+                    self.check().await;
+                    None
+                }
+            }
+        }
+    }
+}
+
+impl<'a, S, A> NetworkHandler for ConnectionManager<'a, S, A>
+where
+    S: WifiSupplicant + TcpStack + 'a,
+    S::SocketHandle: Send + PartialEq,
+    A: Actor + FromLinkEvent<A::Message<'a>> + 'a,
+{
+    type Stack = S;
+
+    fn from_request<'m>(request: NetworkRequest<'m, S>) -> Self::Message<'m>
+    where
+        Self: 'm,
+    {
+        ConnectionRequest::Network(request)
+    }
+
+    fn into_response<'m>(response: Self::Response<'m>) -> NetworkResponse<S>
+    where
+        Self: 'm,
+    {
+        // Network requests always have a response
+        response.unwrap()
+    }
+}
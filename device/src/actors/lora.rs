@@ -0,0 +1,313 @@
+//! Actors sharing a LoRa driver
+//!
+//! `LoraActor` owns a `LoraDriver` and performs operations on behalf of other actors. Its
+//! `Address` implements `LoraDriver` by forwarding each operation to the actor, so that a
+//! `DownlinkListener` can wait for Class C downlinks while the application keeps sending uplinks
+//! through another copy of the address.
+
+use crate::fmt::*;
+use crate::kernel::{
+    actor::{Actor, Address},
+    util::ImmediateFuture,
+};
+use crate::traits::lora::*;
+use core::future::Future;
+use core::pin::Pin;
+use embassy::time::{with_timeout, Duration, Instant, Timer};
+use heapless::consts::U4;
+
+/// Delay before retrying while the message queue of the actor is full.
+const QUEUE_BACKOFF: Duration = Duration::from_millis(10);
+
+/// How long the actor waits for a downlink before serving other requests.
+const RECEIVE_INTERVAL: Duration = Duration::from_millis(500);
+
+pub enum LoraRequest<'m> {
+    Configure(&'m LoraConfig),
+    Reset(ResetMode),
+    Join(ConnectMode),
+    Send(QoS, Port, &'m [u8]),
+    SendRecv(QoS, Port, &'m [u8], &'m mut [u8]),
+    Receive,
+    LinkStatus,
+    NextSendAllowed,
+}
+
+pub enum LoraResponse {
+    Done(Result<(), LoraError>),
+    Received(Result<(Port, usize), LoraError>),
+    Downlink(Result<Downlink, LoraError>),
+    LinkStatus(Result<LinkStatus, LoraError>),
+    NextSendAllowed(Instant),
+}
+
+/// An actor performing LoRa operations on behalf of other actors. The `Address` of the actor
+/// implements `LoraDriver`.
+///
+/// Waiting for a downlink is split into short receive attempts, so that uplinks requested in the
+/// meantime are sent without waiting for a downlink to arrive. Receiving must therefore be
+/// cancel safe in the driver.
+pub struct LoraActor<D: LoraDriver> {
+    driver: Option<D>,
+}
+
+impl<D: LoraDriver> LoraActor<D> {
+    pub fn new() -> Self {
+        Self { driver: None }
+    }
+}
+
+impl<D: LoraDriver> Default for LoraActor<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D: LoraDriver> Unpin for LoraActor<D> {}
+
+impl<D: LoraDriver + 'static> Actor for LoraActor<D> {
+    // Room for requests from the listener and the application
+    type MessageQueueSize<'m> = U4;
+    type Configuration = D;
+
+    #[rustfmt::skip]
+    type Message<'m> = LoraRequest<'m>;
+    #[rustfmt::skip]
+    type Response<'m> = LoraResponse;
+    #[rustfmt::skip]
+    type OnStartFuture<'m> = ImmediateFuture;
+    #[rustfmt::skip]
+    type OnMessageFuture<'m> = impl Future<Output = LoraResponse> + 'm;
+
+    fn on_mount(&mut self, driver: Self::Configuration) {
+        self.driver.replace(driver);
+    }
+
+    fn on_start(self: Pin<&'_ mut Self>) -> Self::OnStartFuture<'_> {
+        ImmediateFuture::new()
+    }
+
+    fn on_message<'m>(
+        mut self: Pin<&'m mut Self>,
+        message: Self::Message<'m>,
+    ) -> Self::OnMessageFuture<'m> {
+        async move {
+            let driver = self.driver.as_mut().unwrap();
+            match message {
+                LoraRequest::Configure(config) => {
+                    LoraResponse::Done(driver.configure(config).await)
+                }
+                LoraRequest::Reset(mode) => LoraResponse::Done(driver.reset(mode).await),
+                LoraRequest::Join(mode) => LoraResponse::Done(driver.join(mode).await),
+                LoraRequest::Send(qos, port, data) => {
+                    LoraResponse::Done(driver.send(qos, port, data).await)
+                }
+                LoraRequest::SendRecv(qos, port, data, rx) => {
+                    LoraResponse::Received(driver.send_recv(qos, port, data, rx).await)
+                }
+                LoraRequest::Receive => {
+                    match with_timeout(RECEIVE_INTERVAL, driver.receive()).await {
+                        Ok(result) => LoraResponse::Downlink(result),
+                        Err(_) => LoraResponse::Downlink(Err(LoraError::RecvTimeout)),
+                    }
+                }
+                LoraRequest::LinkStatus => LoraResponse::LinkStatus(driver.link_status().await),
+                LoraRequest::NextSendAllowed => {
+                    LoraResponse::NextSendAllowed(driver.next_send_allowed().await)
+                }
+            }
+        }
+    }
+}
+
+/// Perform a request, waiting for room in the message queue of the actor.
+macro_rules! forward {
+    ($address:expr, $request:expr) => {
+        loop {
+            match $address.request($request) {
+                Ok(response) => break response.await,
+                Err(_) => Timer::after(QUEUE_BACKOFF).await,
+            }
+        }
+    };
+}
+
+impl<'a, D> LoraDriver for Address<'a, LoraActor<D>>
+where
+    D: LoraDriver + 'static,
+{
+    #[rustfmt::skip]
+    type ConfigureFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn configure<'m>(&'m mut self, config: &'m LoraConfig) -> Self::ConfigureFuture<'m> {
+        async move {
+            match forward!(self, LoraRequest::Configure(config)) {
+                LoraResponse::Done(result) => result,
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type ResetFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn reset<'m>(&'m mut self, mode: ResetMode) -> Self::ResetFuture<'m> {
+        async move {
+            match forward!(self, LoraRequest::Reset(mode)) {
+                LoraResponse::Done(result) => result,
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type JoinFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn join<'m>(&'m mut self, mode: ConnectMode) -> Self::JoinFuture<'m> {
+        async move {
+            match forward!(self, LoraRequest::Join(mode)) {
+                LoraResponse::Done(result) => result,
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type SendFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn send<'m>(&'m mut self, qos: QoS, port: Port, data: &'m [u8]) -> Self::SendFuture<'m> {
+        async move {
+            match forward!(self, LoraRequest::Send(qos, port, data)) {
+                LoraResponse::Done(result) => result,
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type SendRecvFuture<'m> where 'a: 'm = impl Future<Output = Result<(Port, usize), LoraError>> + 'm;
+    fn send_recv<'m>(
+        &'m mut self,
+        qos: QoS,
+        port: Port,
+        data: &'m [u8],
+        rx: &'m mut [u8],
+    ) -> Self::SendRecvFuture<'m> {
+        async move {
+            match forward!(self, LoraRequest::SendRecv(qos, port, data, &mut *rx)) {
+                LoraResponse::Received(result) => result,
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type ReceiveFuture<'m> where 'a: 'm = impl Future<Output = Result<Downlink, LoraError>> + 'm;
+    fn receive<'m>(&'m mut self) -> Self::ReceiveFuture<'m> {
+        async move {
+            loop {
+                match forward!(self, LoraRequest::Receive) {
+                    // Nothing received yet, other requests were given a turn
+                    LoraResponse::Downlink(Err(LoraError::RecvTimeout)) => {}
+                    LoraResponse::Downlink(result) => return result,
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type LinkStatusFuture<'m> where 'a: 'm = impl Future<Output = Result<LinkStatus, LoraError>> + 'm;
+    fn link_status<'m>(&'m mut self) -> Self::LinkStatusFuture<'m> {
+        async move {
+            match forward!(self, LoraRequest::LinkStatus) {
+                LoraResponse::LinkStatus(result) => result,
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type NextSendAllowedFuture<'m> where 'a: 'm = impl Future<Output = Instant> + 'm;
+    fn next_send_allowed<'m>(&'m mut self) -> Self::NextSendAllowedFuture<'m> {
+        async move {
+            match forward!(self, LoraRequest::NextSendAllowed) {
+                LoraResponse::NextSendAllowed(instant) => instant,
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+pub trait FromDownlink<M> {
+    fn from(downlink: Downlink) -> Option<M>
+    where
+        Self: Sized;
+}
+
+/// Listens for downlinks sent by the network between uplinks, and forwards them to an actor.
+///
+/// The listener is given the `Address` of a `LoraActor`, configured for Class C and joined
+/// before mounting the listener. Uplinks are sent through other copies of the address while the
+/// listener waits.
+pub struct DownlinkListener<
+    'a,
+    D: LoraDriver + 'a,
+    A: Actor + FromDownlink<A::Message<'a>> + 'static,
+> {
+    driver: D,
+    handler: Option<Address<'a, A>>,
+}
+
+impl<'a, D: LoraDriver + 'a, A: Actor + FromDownlink<A::Message<'a>> + 'a>
+    DownlinkListener<'a, D, A>
+{
+    pub fn new(driver: D) -> Self {
+        Self {
+            driver,
+            handler: None,
+        }
+    }
+}
+
+impl<'a, D: LoraDriver + 'a, A: Actor + FromDownlink<A::Message<'a>> + 'a> Unpin
+    for DownlinkListener<'a, D, A>
+{
+}
+
+impl<'a, D: LoraDriver + 'a, A: Actor + FromDownlink<A::Message<'a>> + 'a> Actor
+    for DownlinkListener<'a, D, A>
+{
+    type Configuration = Address<'a, A>;
+    #[rustfmt::skip]
+    type OnStartFuture<'m> where 'a: 'm = impl Future<Output = ()> + 'm;
+    #[rustfmt::skip]
+    type OnMessageFuture<'m> where 'a: 'm = impl Future<Output = ()> + 'm;
+
+    fn on_mount(&mut self, config: Self::Configuration) {
+        self.handler.replace(config);
+    }
+
+    fn on_start(mut self: Pin<&mut Self>) -> Self::OnStartFuture<'_> {
+        async move {
+            loop {
+                match self.driver.receive().await {
+                    Ok(downlink) => {
+                        if let Some(handler) = self.handler {
+                            if let Some(m) = A::from(downlink) {
+                                let _ = handler.notify(m);
+                            }
+                        }
+                    }
+                    Err(LoraError::NotReady) => {
+                        warn!("Driver not ready to receive, stopping listener");
+                        return;
+                    }
+                    Err(e) => {
+                        warn!("Error receiving downlink: {:?}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn on_message<'m>(self: Pin<&'m mut Self>, _: Self::Message<'m>) -> Self::OnMessageFuture<'m> {
+        async move {}
+    }
+}
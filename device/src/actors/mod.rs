@@ -1,4 +1,8 @@
 pub mod button;
+pub mod connection;
 pub mod led;
+pub mod lora;
+pub mod mqtt;
+pub mod net;
 pub mod ticker;
 pub mod timer;
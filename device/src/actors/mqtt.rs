@@ -0,0 +1,128 @@
+use crate::clients::mqtt::{MqttClient, MqttConfig, MqttError, Publication, QoS};
+use crate::fmt::*;
+use crate::kernel::actor::{Actor, Address};
+use crate::traits::tcp::TcpStack;
+use core::future::Future;
+use core::pin::Pin;
+
+pub trait FromMqttPublication<M> {
+    fn from(publication: Publication) -> Option<M>
+    where
+        Self: Sized;
+}
+
+#[derive(Clone, Copy)]
+pub enum MqttRequest<'m> {
+    Publish {
+        topic: &'m str,
+        payload: &'m [u8],
+        qos: QoS,
+    },
+    Subscribe {
+        topic: &'m str,
+        qos: QoS,
+    },
+    /// Process incoming publications and keep the connection alive. Typically sent periodically
+    /// using a `Ticker`.
+    Poll,
+}
+
+/// An actor owning an MQTT connection, delivering incoming publications to the handler
+/// configured when mounted.
+pub struct Mqtt<'a, S, A>
+where
+    S: TcpStack + 'a,
+    A: Actor + FromMqttPublication<A::Message<'a>> + 'static,
+{
+    config: MqttConfig<'a>,
+    client: Option<MqttClient<'a, S>>,
+    handler: Option<Address<'a, A>>,
+}
+
+impl<'a, S, A> Mqtt<'a, S, A>
+where
+    S: TcpStack + 'a,
+    A: Actor + FromMqttPublication<A::Message<'a>> + 'static,
+{
+    pub fn new(config: MqttConfig<'a>) -> Self {
+        Self {
+            config,
+            client: None,
+            handler: None,
+        }
+    }
+}
+
+impl<'a, S, A> Unpin for Mqtt<'a, S, A>
+where
+    S: TcpStack + 'a,
+    A: Actor + FromMqttPublication<A::Message<'a>> + 'static,
+{
+}
+
+impl<'a, S, A> Actor for Mqtt<'a, S, A>
+where
+    S: TcpStack + 'a,
+    A: Actor + FromMqttPublication<A::Message<'a>> + 'static,
+{
+    type Configuration = (S, Address<'a, A>);
+    #[rustfmt::skip]
+    type Message<'m> where 'a: 'm = MqttRequest<'m>;
+    #[rustfmt::skip]
+    type Response<'m> where 'a: 'm = Result<(), MqttError>;
+    #[rustfmt::skip]
+    type OnStartFuture<'m> where 'a: 'm = impl Future<Output = ()> + 'm;
+    #[rustfmt::skip]
+    type OnMessageFuture<'m> where 'a: 'm = impl Future<Output = Result<(), MqttError>> + 'm;
+
+    fn on_mount(&mut self, config: Self::Configuration) {
+        let (stack, handler) = config;
+        self.client.replace(MqttClient::new(stack, self.config));
+        self.handler.replace(handler);
+    }
+
+    fn on_start(mut self: Pin<&mut Self>) -> Self::OnStartFuture<'_> {
+        async move {
+            if let Err(e) = self.client.as_mut().unwrap().connect().await {
+                warn!("Error connecting to MQTT broker: {:?}", e);
+            }
+        }
+    }
+
+    fn on_message<'m>(
+        mut self: Pin<&'m mut Self>,
+        message: Self::Message<'m>,
+    ) -> Self::OnMessageFuture<'m> {
+        async move {
+            match message {
+                MqttRequest::Publish {
+                    topic,
+                    payload,
+                    qos,
+                } => {
+                    self.client
+                        .as_mut()
+                        .unwrap()
+                        .publish(topic, payload, qos)
+                        .await
+                }
+                MqttRequest::Subscribe { topic, qos } => {
+                    self.client.as_mut().unwrap().subscribe(topic, qos).await
+                }
+                MqttRequest::Poll => loop {
+                    let publication = self.client.as_mut().unwrap().poll().await?;
+                    match publication {
+                        Some(publication) => {
+                            if let Some(handler) = self.handler {
+                                if let Some(m) = A::from(publication) {
+                                    let _ = handler.notify(m);
+                                }
+                            }
+                        }
+                        None => return Ok(()),
+                    }
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,286 @@
+//! Actor sharing a network stack
+//!
+//! `NetworkActor` owns a `TcpStack` and performs operations on behalf of other actors. The
+//! `Address` of any `NetworkHandler` implements `TcpStack` by forwarding each operation to the
+//! actor, so the address can be handed to several actors that each hold sockets on the same stack.
+
+use crate::fmt::*;
+use crate::kernel::{
+    actor::{Actor, Address},
+    util::ImmediateFuture,
+};
+use crate::traits::{
+    ip::{IpProtocol, SocketAddress},
+    tcp::{Readiness, TcpError, TcpStack},
+};
+use core::future::Future;
+use core::pin::Pin;
+use embassy::time::{Duration, Timer};
+use heapless::consts::U4;
+
+/// Delay before retrying while the message queue of the actor is full.
+const QUEUE_BACKOFF: Duration = Duration::from_millis(10);
+
+pub enum NetworkRequest<'m, S: TcpStack + 'm> {
+    Open,
+    Connect(S::SocketHandle, IpProtocol, SocketAddress),
+    Write(S::SocketHandle, &'m [u8]),
+    Read(S::SocketHandle, &'m mut [u8]),
+    Close(S::SocketHandle),
+    PollReadable(S::SocketHandle),
+    Available(S::SocketHandle),
+    SetTimeout(S::SocketHandle, Option<Duration>),
+}
+
+pub enum NetworkResponse<S: TcpStack> {
+    Opened(S::SocketHandle),
+    Connected(Result<(), TcpError>),
+    Transferred(Result<usize, TcpError>),
+    Closed,
+    Readiness(Result<Readiness, TcpError>),
+    Available(Result<usize, TcpError>),
+    TimeoutSet(Result<(), TcpError>),
+}
+
+/// An actor performing network requests on behalf of other actors. The `Address` of such an
+/// actor implements `TcpStack`.
+pub trait NetworkHandler: Actor {
+    type Stack: TcpStack;
+
+    fn from_request<'m>(request: NetworkRequest<'m, Self::Stack>) -> Self::Message<'m>
+    where
+        Self: 'm;
+
+    fn into_response<'m>(response: Self::Response<'m>) -> NetworkResponse<Self::Stack>
+    where
+        Self: 'm;
+}
+
+pub struct NetworkActor<S: TcpStack> {
+    stack: Option<S>,
+}
+
+impl<S: TcpStack> NetworkActor<S> {
+    pub fn new() -> Self {
+        Self { stack: None }
+    }
+}
+
+impl<S: TcpStack> Default for NetworkActor<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: TcpStack> Unpin for NetworkActor<S> {}
+
+impl<S> Actor for NetworkActor<S>
+where
+    S: TcpStack + 'static,
+    S::SocketHandle: Send,
+{
+    // Room for requests from several actors
+    type MessageQueueSize<'m> = U4;
+    type Configuration = S;
+
+    #[rustfmt::skip]
+    type Message<'m> = NetworkRequest<'m, S>;
+    #[rustfmt::skip]
+    type Response<'m> = NetworkResponse<S>;
+    #[rustfmt::skip]
+    type OnStartFuture<'m> = ImmediateFuture;
+    #[rustfmt::skip]
+    type OnMessageFuture<'m> = impl Future<Output = NetworkResponse<S>> + 'm;
+
+    fn on_mount(&mut self, stack: Self::Configuration) {
+        self.stack.replace(stack);
+    }
+
+    fn on_start(self: Pin<&'_ mut Self>) -> Self::OnStartFuture<'_> {
+        ImmediateFuture::new()
+    }
+
+    fn on_message<'m>(
+        mut self: Pin<&'m mut Self>,
+        message: Self::Message<'m>,
+    ) -> Self::OnMessageFuture<'m> {
+        async move { perform(self.stack.as_mut().unwrap(), message).await }
+    }
+}
+
+impl<S> NetworkHandler for NetworkActor<S>
+where
+    S: TcpStack + 'static,
+    S::SocketHandle: Send,
+{
+    type Stack = S;
+
+    fn from_request<'m>(request: NetworkRequest<'m, S>) -> Self::Message<'m> {
+        request
+    }
+
+    fn into_response<'m>(response: Self::Response<'m>) -> NetworkResponse<S> {
+        response
+    }
+}
+
+/// Perform a request on a stack.
+pub(crate) async fn perform<'m, S: TcpStack>(
+    stack: &'m mut S,
+    request: NetworkRequest<'m, S>,
+) -> NetworkResponse<S> {
+    match request {
+        NetworkRequest::Open => NetworkResponse::Opened(stack.open().await),
+        NetworkRequest::Connect(handle, proto, dst) => {
+            NetworkResponse::Connected(stack.connect(handle, proto, dst).await)
+        }
+        NetworkRequest::Write(handle, buf) => {
+            NetworkResponse::Transferred(stack.write(handle, buf).await)
+        }
+        NetworkRequest::Read(handle, buf) => {
+            NetworkResponse::Transferred(stack.read(handle, buf).await)
+        }
+        NetworkRequest::Close(handle) => {
+            stack.close(handle).await;
+            NetworkResponse::Closed
+        }
+        NetworkRequest::PollReadable(handle) => {
+            NetworkResponse::Readiness(stack.poll_readable(handle).await)
+        }
+        NetworkRequest::Available(handle) => {
+            NetworkResponse::Available(stack.available(handle).await)
+        }
+        NetworkRequest::SetTimeout(handle, timeout) => {
+            NetworkResponse::TimeoutSet(stack.set_timeout(handle, timeout).await)
+        }
+    }
+}
+
+/// Perform a request, waiting for room in the message queue of the actor.
+macro_rules! forward {
+    ($address:expr, $request:expr) => {
+        loop {
+            match $address.request(A::from_request($request)) {
+                Ok(response) => break A::into_response(response.await),
+                Err(_) => Timer::after(QUEUE_BACKOFF).await,
+            }
+        }
+    };
+}
+
+impl<'a, A> TcpStack for Address<'a, A>
+where
+    A: NetworkHandler + 'static,
+{
+    type SocketHandle = <A::Stack as TcpStack>::SocketHandle;
+
+    #[rustfmt::skip]
+    type OpenFuture<'m> where 'a: 'm = impl Future<Output = Self::SocketHandle> + 'm;
+    fn open<'m>(&'m mut self) -> Self::OpenFuture<'m> {
+        async move {
+            match forward!(self, NetworkRequest::Open) {
+                NetworkResponse::Opened(handle) => handle,
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type ConnectFuture<'m> where 'a: 'm = impl Future<Output = Result<(), TcpError>> + 'm;
+    fn connect<'m>(
+        &'m mut self,
+        handle: Self::SocketHandle,
+        proto: IpProtocol,
+        dst: SocketAddress,
+    ) -> Self::ConnectFuture<'m> {
+        async move {
+            match forward!(self, NetworkRequest::Connect(handle, proto, dst)) {
+                NetworkResponse::Connected(result) => result,
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type WriteFuture<'m> where 'a: 'm = impl Future<Output = Result<usize, TcpError>> + 'm;
+    fn write<'m>(&'m mut self, handle: Self::SocketHandle, buf: &'m [u8]) -> Self::WriteFuture<'m> {
+        async move {
+            match forward!(self, NetworkRequest::Write(handle, buf)) {
+                NetworkResponse::Transferred(result) => result,
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type ReadFuture<'m> where 'a: 'm = impl Future<Output = Result<usize, TcpError>> + 'm;
+    fn read<'m>(
+        &'m mut self,
+        handle: Self::SocketHandle,
+        buf: &'m mut [u8],
+    ) -> Self::ReadFuture<'m> {
+        async move {
+            match forward!(self, NetworkRequest::Read(handle, &mut *buf)) {
+                NetworkResponse::Transferred(result) => result,
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type CloseFuture<'m> where 'a: 'm = impl Future<Output = ()> + 'm;
+    fn close<'m>(&'m mut self, handle: Self::SocketHandle) -> Self::CloseFuture<'m> {
+        async move {
+            match forward!(self, NetworkRequest::Close(handle)) {
+                NetworkResponse::Closed => {}
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    fn close_detached(&mut self, handle: Self::SocketHandle) {
+        if self
+            .notify(A::from_request(NetworkRequest::Close(handle)))
+            .is_err()
+        {
+            warn!("Network request queue full, unable to close socket");
+        }
+    }
+
+    #[rustfmt::skip]
+    type PollReadableFuture<'m> where 'a: 'm = impl Future<Output = Result<Readiness, TcpError>> + 'm;
+    fn poll_readable<'m>(&'m mut self, handle: Self::SocketHandle) -> Self::PollReadableFuture<'m> {
+        async move {
+            match forward!(self, NetworkRequest::PollReadable(handle)) {
+                NetworkResponse::Readiness(result) => result,
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type AvailableFuture<'m> where 'a: 'm = impl Future<Output = Result<usize, TcpError>> + 'm;
+    fn available<'m>(&'m mut self, handle: Self::SocketHandle) -> Self::AvailableFuture<'m> {
+        async move {
+            match forward!(self, NetworkRequest::Available(handle)) {
+                NetworkResponse::Available(result) => result,
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type SetTimeoutFuture<'m> where 'a: 'm = impl Future<Output = Result<(), TcpError>> + 'm;
+    fn set_timeout<'m>(
+        &'m mut self,
+        handle: Self::SocketHandle,
+        timeout: Option<Duration>,
+    ) -> Self::SetTimeoutFuture<'m> {
+        async move {
+            match forward!(self, NetworkRequest::SetTimeout(handle, timeout)) {
+                NetworkResponse::TimeoutSet(result) => result,
+                _ => unreachable!(),
+            }
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use super::CoapError;
+
+pub const URI_PATH: u16 = 11;
+pub const CONTENT_FORMAT: u16 = 12;
+pub const BLOCK2: u16 = 23;
+pub const BLOCK1: u16 = 27;
+pub const SIZE1: u16 = 60;
+
+const VERSION: u8 = 1;
+const PAYLOAD_MARKER: u8 = 0xFF;
+
+/// Response codes, encoded as `class << 5 | detail`.
+pub const CREATED: u8 = 0x41;
+pub const CHANGED: u8 = 0x44;
+pub const CONTENT: u8 = 0x45;
+pub const CONTINUE: u8 = 0x5F;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum MessageType {
+    Confirmable = 0,
+    NonConfirmable = 1,
+    Acknowledgement = 2,
+    Reset = 3,
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Self {
+        match value & 0x03 {
+            0 => MessageType::Confirmable,
+            1 => MessageType::NonConfirmable,
+            2 => MessageType::Acknowledgement,
+            _ => MessageType::Reset,
+        }
+    }
+}
+
+/// The value of a Block1 or Block2 option.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Block {
+    pub num: u32,
+    pub more: bool,
+    pub szx: u8,
+}
+
+impl Block {
+    pub fn new(num: u32, more: bool, szx: u8) -> Self {
+        Self { num, more, szx }
+    }
+
+    /// Block size in bytes.
+    pub fn size(&self) -> usize {
+        1 << (self.szx as usize + 4)
+    }
+
+    pub fn value(&self) -> u32 {
+        self.num << 4 | (self.more as u32) << 3 | (self.szx as u32 & 0x07)
+    }
+
+    pub fn from_value(value: u32) -> Self {
+        Self {
+            num: value >> 4,
+            more: value & 0x08 != 0,
+            szx: (value & 0x07) as u8,
+        }
+    }
+}
+
+/// Writes a message into a caller provided buffer. Options must be added in ascending order.
+pub struct MessageBuilder<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+    last_option: u16,
+}
+
+impl<'b> MessageBuilder<'b> {
+    pub fn new(
+        buf: &'b mut [u8],
+        mtype: MessageType,
+        code: u8,
+        message_id: u16,
+        token: &[u8],
+    ) -> Result<Self, CoapError> {
+        if token.len() > 8 {
+            return Err(CoapError::MalformedMessage);
+        }
+        let mut builder = Self {
+            buf,
+            pos: 0,
+            last_option: 0,
+        };
+        builder.bytes(&[
+            VERSION << 6 | (mtype as u8) << 4 | token.len() as u8,
+            code,
+            (message_id >> 8) as u8,
+            message_id as u8,
+        ])?;
+        builder.bytes(token)?;
+        Ok(builder)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pos
+    }
+
+    fn bytes(&mut self, data: &[u8]) -> Result<(), CoapError> {
+        if self.pos + data.len() > self.buf.len() {
+            return Err(CoapError::BufferTooSmall);
+        }
+        self.buf[self.pos..self.pos + data.len()].copy_from_slice(data);
+        self.pos += data.len();
+        Ok(())
+    }
+
+    pub fn option(&mut self, number: u16, value: &[u8]) -> Result<(), CoapError> {
+        if number < self.last_option {
+            return Err(CoapError::MalformedMessage);
+        }
+        let delta = number - self.last_option;
+        self.last_option = number;
+
+        let (delta_nibble, delta_ext, delta_ext_len) = encode_nibble(delta);
+        let (len_nibble, len_ext, len_ext_len) = encode_nibble(value.len() as u16);
+        self.bytes(&[delta_nibble << 4 | len_nibble])?;
+        self.bytes(&delta_ext[..delta_ext_len])?;
+        self.bytes(&len_ext[..len_ext_len])?;
+        self.bytes(value)
+    }
+
+    /// Add an option holding an unsigned integer, using the shortest encoding.
+    pub fn uint_option(&mut self, number: u16, value: u32) -> Result<(), CoapError> {
+        let bytes = value.to_be_bytes();
+        let skip = (value.leading_zeros() / 8) as usize;
+        self.option(number, &bytes[skip..])
+    }
+
+    pub fn payload(&mut self, data: &[u8]) -> Result<(), CoapError> {
+        if !data.is_empty() {
+            self.bytes(&[PAYLOAD_MARKER])?;
+            self.bytes(data)?;
+        }
+        Ok(())
+    }
+}
+
+fn encode_nibble(value: u16) -> (u8, [u8; 2], usize) {
+    if value < 13 {
+        (value as u8, [0; 2], 0)
+    } else if value < 269 {
+        (13, [(value - 13) as u8, 0], 1)
+    } else {
+        (14, (value - 269).to_be_bytes(), 2)
+    }
+}
+
+fn decode_nibble(nibble: u8, data: &[u8]) -> Result<(u16, usize), CoapError> {
+    match nibble {
+        13 if !data.is_empty() => Ok((data[0] as u16 + 13, 1)),
+        14 if data.len() >= 2 => Ok((((data[0] as u16) << 8 | data[1] as u16) + 269, 2)),
+        n if n < 13 => Ok((n as u16, 0)),
+        _ => Err(CoapError::MalformedMessage),
+    }
+}
+
+/// Decode a single option, returning the option delta, value and the number of bytes consumed.
+fn next_option(data: &[u8]) -> Result<(u16, &[u8], usize), CoapError> {
+    let header = data[0];
+    let mut pos = 1;
+    let (delta, n) = decode_nibble(header >> 4, &data[pos..])?;
+    pos += n;
+    let (len, n) = decode_nibble(header & 0x0F, &data[pos..])?;
+    pos += n;
+    let end = pos + len as usize;
+    if end > data.len() {
+        return Err(CoapError::MalformedMessage);
+    }
+    Ok((delta, &data[pos..end], end))
+}
+
+/// Decode an unsigned integer option value.
+pub fn uint_value(value: &[u8]) -> u32 {
+    value.iter().take(4).fold(0, |acc, b| acc << 8 | *b as u32)
+}
+
+/// A message decoded from a datagram, borrowing from the receive buffer.
+#[derive(Debug)]
+pub struct Message<'b> {
+    pub mtype: MessageType,
+    pub code: u8,
+    pub message_id: u16,
+    pub token: &'b [u8],
+    options: &'b [u8],
+    pub payload: &'b [u8],
+}
+
+impl<'b> Message<'b> {
+    pub fn parse(buf: &'b [u8]) -> Result<Self, CoapError> {
+        if buf.len() < 4 || buf[0] >> 6 != VERSION {
+            return Err(CoapError::MalformedMessage);
+        }
+        let tkl = (buf[0] & 0x0F) as usize;
+        if tkl > 8 || buf.len() < 4 + tkl {
+            return Err(CoapError::MalformedMessage);
+        }
+        let start = 4 + tkl;
+        let mut pos = start;
+        while pos < buf.len() && buf[pos] != PAYLOAD_MARKER {
+            let (_, _, len) = next_option(&buf[pos..])?;
+            pos += len;
+        }
+        let payload = if pos < buf.len() {
+            &buf[pos + 1..]
+        } else {
+            &buf[pos..]
+        };
+        Ok(Self {
+            mtype: MessageType::from_u8(buf[0] >> 4),
+            code: buf[1],
+            message_id: (buf[2] as u16) << 8 | buf[3] as u16,
+            token: &buf[4..start],
+            options: &buf[start..pos],
+            payload,
+        })
+    }
+
+    pub fn options(&self) -> Options<'b> {
+        Options {
+            data: self.options,
+            number: 0,
+        }
+    }
+
+    /// The value of the first occurrence of an option.
+    pub fn option(&self, number: u16) -> Option<&'b [u8]> {
+        self.options()
+            .find(|(n, _)| *n == number)
+            .map(|(_, value)| value)
+    }
+
+    pub fn block1(&self) -> Option<Block> {
+        self.option(BLOCK1)
+            .map(|value| Block::from_value(uint_value(value)))
+    }
+
+    pub fn block2(&self) -> Option<Block> {
+        self.option(BLOCK2)
+            .map(|value| Block::from_value(uint_value(value)))
+    }
+}
+
+/// Iterator over the options of a message, yielding the option number and value.
+pub struct Options<'b> {
+    data: &'b [u8],
+    number: u16,
+}
+
+impl<'b> Iterator for Options<'b> {
+    type Item = (u16, &'b [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        // Options were validated when the message was parsed
+        let (delta, value, len) = next_option(self.data).ok()?;
+        self.data = &self.data[len..];
+        self.number += delta;
+        Some((self.number, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_get() {
+        let mut buf = [0; 32];
+        let mut builder =
+            MessageBuilder::new(&mut buf, MessageType::Confirmable, 1, 0x1234, &[0xAB])
+                .expect("Can't encode");
+        builder.option(URI_PATH, b"a").expect("Can't encode");
+        builder.option(URI_PATH, b"bc").expect("Can't encode");
+        builder
+            .uint_option(BLOCK2, Block::new(1, false, 3).value())
+            .expect("Can't encode");
+        let len = builder.len();
+        assert_eq!(&buf[..len], b"\x41\x01\x12\x34\xab\xb1a\x02bc\xc1\x13");
+    }
+
+    #[test]
+    fn encode_extended_delta() {
+        let mut buf = [0; 16];
+        let mut builder = MessageBuilder::new(&mut buf, MessageType::NonConfirmable, 2, 1, &[])
+            .expect("Can't encode");
+        builder.uint_option(SIZE1, 300).expect("Can't encode");
+        builder.payload(b"x").expect("Can't encode");
+        let len = builder.len();
+        assert_eq!(&buf[..len], b"\x50\x02\x00\x01\xd2\x2f\x01\x2c\xffx");
+    }
+
+    #[test]
+    fn encode_buffer_too_small() {
+        let mut buf = [0; 6];
+        let mut builder = MessageBuilder::new(&mut buf, MessageType::Confirmable, 1, 1, &[])
+            .expect("Can't encode");
+        assert!(matches!(
+            builder.payload(b"abc"),
+            Err(CoapError::BufferTooSmall)
+        ));
+    }
+
+    #[test]
+    fn decode_response() {
+        let message =
+            Message::parse(b"\x61\x45\x12\x34\xab\xd1\x0a\x1b\xffhello").expect("Can't decode");
+        assert_eq!(MessageType::Acknowledgement, message.mtype);
+        assert_eq!(CONTENT, message.code);
+        assert_eq!(0x1234, message.message_id);
+        assert_eq!(&[0xAB], message.token);
+        assert_eq!(b"hello", message.payload);
+        assert_eq!(Some(Block::new(1, true, 3)), message.block2());
+        assert_eq!(None, message.block1());
+    }
+
+    #[test]
+    fn decode_malformed() {
+        assert!(Message::parse(b"\x41\x01").is_err());
+        assert!(Message::parse(b"\x41\x01\x00\x01").is_err());
+        assert!(Message::parse(b"\x40\x01\x00\x01\xd1").is_err());
+    }
+
+    #[test]
+    fn block_value() {
+        let block = Block::new(5, true, 3);
+        assert_eq!(128, block.size());
+        assert_eq!(block, Block::from_value(block.value()));
+    }
+}
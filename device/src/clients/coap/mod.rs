@@ -0,0 +1,365 @@
+//! CoAP client
+//!
+//! A minimal CoAP (RFC 7252) client that runs on top of any `UdpStack`. Confirmable requests are
+//! retransmitted until acknowledged, separate responses are supported, and payloads larger than
+//! a single datagram are transferred using block-wise transfers (RFC 7959).
+
+pub mod message;
+
+use crate::fmt::*;
+use crate::traits::{
+    ip::SocketAddress,
+    udp::{UdpError, UdpStack},
+};
+use embassy::time::{with_timeout, Duration};
+use message::{Block, Message, MessageBuilder, MessageType};
+
+const BUFFER_LEN: usize = 256;
+/// Block size exponent used for block-wise transfers, giving 128 byte blocks so that a block
+/// and its headers fit in a single datagram.
+const BLOCK_SZX: u8 = 3;
+const ACK_TIMEOUT: u64 = 2000;
+const MAX_RETRANSMIT: usize = 4;
+const SEPARATE_RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Request methods.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Method {
+    Get = 1,
+    Post = 2,
+    Put = 3,
+    Delete = 4,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CoapError {
+    Network(UdpError),
+    Timeout,
+    Reset,
+    BufferTooSmall,
+    MalformedMessage,
+    UnexpectedResponse(u8),
+}
+
+impl From<UdpError> for CoapError {
+    fn from(e: UdpError) -> Self {
+        CoapError::Network(e)
+    }
+}
+
+/// The outcome of a request. The payload has been written into the buffer passed with the request.
+#[derive(Debug, Clone, Copy)]
+pub struct CoapResponse {
+    /// Response code, encoded as `class << 5 | detail`.
+    pub code: u8,
+    /// Length of the payload.
+    pub len: usize,
+}
+
+impl CoapResponse {
+    /// Response code class, i.e. 2 for 2.xx success responses.
+    pub fn class(&self) -> u8 {
+        self.code >> 5
+    }
+
+    pub fn detail(&self) -> u8 {
+        self.code & 0x1F
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.class() == 2
+    }
+}
+
+/// A response received for a single exchange. The payload is located in the receive buffer.
+struct Reply {
+    code: u8,
+    block2: Option<Block>,
+    payload: core::ops::Range<usize>,
+}
+
+pub struct CoapClient<S>
+where
+    S: UdpStack,
+{
+    stack: S,
+    server: SocketAddress,
+    socket: Option<S::SocketHandle>,
+    message_id: u16,
+    token: u16,
+    rx_buf: [u8; BUFFER_LEN],
+}
+
+impl<S> CoapClient<S>
+where
+    S: UdpStack,
+{
+    /// Create a client for `server`. Message IDs and tokens start from a value returned by
+    /// `get_random`, so that they are not reused after a reboot (RFC 7252 sections 4.4 and 5.3.1).
+    pub fn new(stack: S, server: SocketAddress, get_random: fn() -> u32) -> Self {
+        let seed = get_random();
+        Self {
+            stack,
+            server,
+            socket: None,
+            message_id: seed as u16,
+            token: (seed >> 16) as u16,
+            rx_buf: [0; BUFFER_LEN],
+        }
+    }
+
+    pub async fn get(&mut self, path: &str, rx: &mut [u8]) -> Result<CoapResponse, CoapError> {
+        self.request(Method::Get, true, path, &[], rx).await
+    }
+
+    pub async fn post(
+        &mut self,
+        path: &str,
+        payload: &[u8],
+        rx: &mut [u8],
+    ) -> Result<CoapResponse, CoapError> {
+        self.request(Method::Post, true, path, payload, rx).await
+    }
+
+    pub async fn put(
+        &mut self,
+        path: &str,
+        payload: &[u8],
+        rx: &mut [u8],
+    ) -> Result<CoapResponse, CoapError> {
+        self.request(Method::Put, true, path, payload, rx).await
+    }
+
+    /// Perform a request, writing the response payload into `rx`.
+    ///
+    /// Payloads larger than a block are sent using Block1. If the server responds using Block2,
+    /// the remaining blocks are requested using the same method without a payload.
+    pub async fn request(
+        &mut self,
+        method: Method,
+        confirmable: bool,
+        path: &str,
+        payload: &[u8],
+        rx: &mut [u8],
+    ) -> Result<CoapResponse, CoapError> {
+        let block_size = Block::new(0, false, BLOCK_SZX).size();
+        let mut reply = if payload.len() > block_size {
+            let mut num = 0;
+            loop {
+                let start = num as usize * block_size;
+                let end = core::cmp::min(start + block_size, payload.len());
+                let more = end < payload.len();
+                let block1 = Block::new(num, more, BLOCK_SZX);
+                let reply = self
+                    .exchange(
+                        method,
+                        confirmable,
+                        path,
+                        &payload[start..end],
+                        Some(block1),
+                        None,
+                    )
+                    .await?;
+                if !more {
+                    break reply;
+                }
+                if reply.code != message::CONTINUE {
+                    return Err(CoapError::UnexpectedResponse(reply.code));
+                }
+                num += 1;
+            }
+        } else {
+            self.exchange(method, confirmable, path, payload, None, None)
+                .await?
+        };
+
+        let mut len = 0;
+        loop {
+            let data = &self.rx_buf[reply.payload.clone()];
+            if len + data.len() > rx.len() {
+                return Err(CoapError::BufferTooSmall);
+            }
+            rx[len..len + data.len()].copy_from_slice(data);
+            len += data.len();
+
+            match reply.block2 {
+                Some(block) if block.more => {
+                    let next = Block::new(block.num + 1, false, block.szx);
+                    let code = reply.code;
+                    reply = self
+                        .exchange(method, confirmable, path, &[], None, Some(next))
+                        .await?;
+                    if reply.code != code {
+                        return Err(CoapError::UnexpectedResponse(reply.code));
+                    }
+                }
+                _ => {
+                    return Ok(CoapResponse {
+                        code: reply.code,
+                        len,
+                    })
+                }
+            }
+        }
+    }
+
+    async fn ensure_open(&mut self) -> Result<S::SocketHandle, CoapError> {
+        match self.socket {
+            Some(socket) => Ok(socket),
+            None => {
+                let socket = self.stack.open().await;
+                if let Err(e) = self.stack.connect(socket, self.server).await {
+                    self.stack.close(socket).await;
+                    return Err(e.into());
+                }
+                self.socket.replace(socket);
+                Ok(socket)
+            }
+        }
+    }
+
+    async fn send(&mut self, data: &[u8]) -> Result<(), CoapError> {
+        let socket = self.ensure_open().await?;
+        if let Err(e) = self.stack.send(socket, data).await {
+            self.reset().await;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<usize, CoapError> {
+        let socket = self.ensure_open().await?;
+        match self.stack.recv(socket, &mut self.rx_buf).await {
+            Ok(len) => Ok(len),
+            Err(e) => {
+                self.reset().await;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Close the socket after a network error, so that the next request opens a new one.
+    async fn reset(&mut self) {
+        if let Some(socket) = self.socket.take() {
+            self.stack.close(socket).await;
+        }
+    }
+
+    fn next_message_id(&mut self) -> u16 {
+        self.message_id = self.message_id.wrapping_add(1);
+        self.message_id
+    }
+
+    fn next_token(&mut self) -> [u8; 2] {
+        self.token = self.token.wrapping_add(1);
+        self.token.to_be_bytes()
+    }
+
+    async fn send_empty(&mut self, mtype: MessageType, message_id: u16) -> Result<(), CoapError> {
+        let mut tx = [0; 4];
+        MessageBuilder::new(&mut tx, mtype, 0, message_id, &[])?;
+        self.send(&tx).await
+    }
+
+    /// Send a single request and wait for its response, retransmitting confirmable requests
+    /// until they are acknowledged.
+    async fn exchange(
+        &mut self,
+        method: Method,
+        confirmable: bool,
+        path: &str,
+        payload: &[u8],
+        block1: Option<Block>,
+        block2: Option<Block>,
+    ) -> Result<Reply, CoapError> {
+        let message_id = self.next_message_id();
+        let token = self.next_token();
+        let mtype = if confirmable {
+            MessageType::Confirmable
+        } else {
+            MessageType::NonConfirmable
+        };
+
+        let mut tx = [0; BUFFER_LEN];
+        let mut builder = MessageBuilder::new(&mut tx, mtype, method as u8, message_id, &token)?;
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            builder.option(message::URI_PATH, segment.as_bytes())?;
+        }
+        if let Some(block) = block2 {
+            builder.uint_option(message::BLOCK2, block.value())?;
+        }
+        if let Some(block) = block1 {
+            builder.uint_option(message::BLOCK1, block.value())?;
+        }
+        builder.payload(payload)?;
+        let tx_len = builder.len();
+
+        self.send(&tx[..tx_len]).await?;
+        let mut acknowledged = !confirmable;
+        let mut retransmissions = 0;
+        let mut timeout = ACK_TIMEOUT;
+        loop {
+            let wait = if acknowledged {
+                SEPARATE_RESPONSE_TIMEOUT
+            } else {
+                Duration::from_millis(timeout)
+            };
+            let len = match with_timeout(wait, self.recv()).await {
+                Ok(result) => result?,
+                Err(_) => {
+                    if acknowledged || retransmissions == MAX_RETRANSMIT {
+                        return Err(CoapError::Timeout);
+                    }
+                    retransmissions += 1;
+                    timeout *= 2;
+                    trace!("Retransmitting message {}", message_id);
+                    self.send(&tx[..tx_len]).await?;
+                    continue;
+                }
+            };
+
+            let message = match Message::parse(&self.rx_buf[..len]) {
+                Ok(message) => message,
+                Err(_) => {
+                    warn!("Ignoring malformed message");
+                    continue;
+                }
+            };
+            let reply = Reply {
+                code: message.code,
+                block2: message.block2(),
+                payload: len - message.payload.len()..len,
+            };
+            let matches_token = message.token == &token[..];
+            match message.mtype {
+                MessageType::Reset if message.message_id == message_id => {
+                    return Err(CoapError::Reset);
+                }
+                MessageType::Acknowledgement if message.message_id == message_id => {
+                    if reply.code == 0 {
+                        // Empty acknowledgement, the response will follow separately
+                        acknowledged = true;
+                    } else if matches_token {
+                        return Ok(reply);
+                    }
+                }
+                MessageType::Confirmable if matches_token => {
+                    let id = message.message_id;
+                    self.send_empty(MessageType::Acknowledgement, id).await?;
+                    return Ok(reply);
+                }
+                MessageType::NonConfirmable if matches_token => {
+                    return Ok(reply);
+                }
+                MessageType::Confirmable => {
+                    let id = message.message_id;
+                    self.send_empty(MessageType::Reset, id).await?;
+                }
+                _ => {
+                    trace!("Ignoring unrelated message");
+                }
+            }
+        }
+    }
+}
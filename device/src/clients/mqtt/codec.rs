@@ -0,0 +1,312 @@
+use super::{MqttError, Publication, QoS};
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+/// Control packets received from the broker.
+#[derive(Debug)]
+pub enum Packet {
+    ConnAck { session_present: bool, code: u8 },
+    Publish(Publication, Option<u16>),
+    PubAck(u16),
+    SubAck(u16, u8),
+    PingResp,
+    Other(u8),
+}
+
+/// Writes packets into a caller provided buffer.
+pub struct Encoder<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl<'b> Encoder<'b> {
+    pub fn new(buf: &'b mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pos
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), MqttError> {
+        if self.pos >= self.buf.len() {
+            return Err(MqttError::BufferTooSmall);
+        }
+        self.buf[self.pos] = value;
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn u16(&mut self, value: u16) -> Result<(), MqttError> {
+        self.u8((value >> 8) as u8)?;
+        self.u8(value as u8)
+    }
+
+    fn bytes(&mut self, data: &[u8]) -> Result<(), MqttError> {
+        if self.pos + data.len() > self.buf.len() {
+            return Err(MqttError::BufferTooSmall);
+        }
+        self.buf[self.pos..self.pos + data.len()].copy_from_slice(data);
+        self.pos += data.len();
+        Ok(())
+    }
+
+    fn string(&mut self, value: &str) -> Result<(), MqttError> {
+        self.u16(value.len() as u16)?;
+        self.bytes(value.as_bytes())
+    }
+
+    fn header(&mut self, kind: u8, flags: u8, mut remaining: usize) -> Result<(), MqttError> {
+        self.u8(kind << 4 | flags)?;
+        loop {
+            let mut byte = (remaining % 128) as u8;
+            remaining /= 128;
+            if remaining > 0 {
+                byte |= 0x80;
+            }
+            self.u8(byte)?;
+            if remaining == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    pub fn connect(
+        &mut self,
+        client_id: &str,
+        credentials: Option<(&str, &str)>,
+        keep_alive: u16,
+    ) -> Result<(), MqttError> {
+        let mut remaining = 10 + 2 + client_id.len();
+        let mut flags = 0x02; // clean session
+        if let Some((username, password)) = credentials {
+            remaining += 2 + username.len() + 2 + password.len();
+            flags |= 0xC0;
+        }
+        self.header(CONNECT, 0, remaining)?;
+        self.string("MQTT")?;
+        self.u8(4)?;
+        self.u8(flags)?;
+        self.u16(keep_alive)?;
+        self.string(client_id)?;
+        if let Some((username, password)) = credentials {
+            self.string(username)?;
+            self.string(password)?;
+        }
+        Ok(())
+    }
+
+    pub fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        packet_id: u16,
+    ) -> Result<(), MqttError> {
+        let mut remaining = 2 + topic.len() + payload.len();
+        if let QoS::AtLeastOnce = qos {
+            remaining += 2;
+        }
+        self.header(PUBLISH, (qos as u8) << 1, remaining)?;
+        self.string(topic)?;
+        if let QoS::AtLeastOnce = qos {
+            self.u16(packet_id)?;
+        }
+        self.bytes(payload)
+    }
+
+    pub fn puback(&mut self, packet_id: u16) -> Result<(), MqttError> {
+        self.header(PUBACK, 0, 2)?;
+        self.u16(packet_id)
+    }
+
+    pub fn subscribe(&mut self, topic: &str, qos: QoS, packet_id: u16) -> Result<(), MqttError> {
+        self.header(SUBSCRIBE, 0x02, 2 + 2 + topic.len() + 1)?;
+        self.u16(packet_id)?;
+        self.string(topic)?;
+        self.u8(qos as u8)
+    }
+
+    pub fn pingreq(&mut self) -> Result<(), MqttError> {
+        self.header(PINGREQ, 0, 0)
+    }
+
+    pub fn disconnect(&mut self) -> Result<(), MqttError> {
+        self.header(DISCONNECT, 0, 0)
+    }
+}
+
+fn read_u16(data: &[u8]) -> Result<u16, MqttError> {
+    if data.len() < 2 {
+        return Err(MqttError::MalformedPacket);
+    }
+    Ok((data[0] as u16) << 8 | data[1] as u16)
+}
+
+/// Length of the packet at the start of `buf`, once its fixed header has been received. Returns
+/// the offset of the packet body and the total length of the packet.
+fn fixed_header(buf: &[u8]) -> Result<Option<(usize, usize)>, MqttError> {
+    let mut remaining: usize = 0;
+    let mut multiplier = 1;
+    let mut pos = 1;
+    loop {
+        if pos >= buf.len() {
+            return Ok(None);
+        }
+        if pos > 4 {
+            return Err(MqttError::MalformedPacket);
+        }
+        let byte = buf[pos];
+        remaining += (byte & 0x7F) as usize * multiplier;
+        multiplier *= 128;
+        pos += 1;
+        if byte & 0x80 == 0 {
+            return Ok(Some((pos, pos + remaining)));
+        }
+    }
+}
+
+/// Total length of the packet at the start of `buf`, which may not have been received in full
+/// yet. Returns `None` if the fixed header is incomplete.
+pub fn packet_len(buf: &[u8]) -> Result<Option<usize>, MqttError> {
+    Ok(fixed_header(buf)?.map(|(_, end)| end))
+}
+
+/// Decode a single packet from the start of `buf`. Returns `None` if the buffer does not yet hold
+/// a complete packet, otherwise the packet and the number of bytes it occupied.
+pub fn decode(buf: &[u8]) -> Result<Option<(Packet, usize)>, MqttError> {
+    let (pos, end) = match fixed_header(buf)? {
+        Some((pos, end)) if buf.len() >= end => (pos, end),
+        _ => return Ok(None),
+    };
+    let body = &buf[pos..end];
+
+    let packet = match buf[0] >> 4 {
+        CONNACK => {
+            if body.len() < 2 {
+                return Err(MqttError::MalformedPacket);
+            }
+            Packet::ConnAck {
+                session_present: body[0] & 0x01 != 0,
+                code: body[1],
+            }
+        }
+        PUBLISH => {
+            let qos = (buf[0] >> 1) & 0x03;
+            let topic_len = read_u16(body)? as usize;
+            if body.len() < 2 + topic_len {
+                return Err(MqttError::MalformedPacket);
+            }
+            let topic = core::str::from_utf8(&body[2..2 + topic_len])
+                .map_err(|_| MqttError::MalformedPacket)?;
+            let mut offset = 2 + topic_len;
+            let packet_id = if qos > 0 {
+                let id = read_u16(&body[offset..])?;
+                offset += 2;
+                Some(id)
+            } else {
+                None
+            };
+            Packet::Publish(Publication::new(topic, &body[offset..])?, packet_id)
+        }
+        PUBACK => Packet::PubAck(read_u16(body)?),
+        SUBACK => {
+            if body.len() < 3 {
+                return Err(MqttError::MalformedPacket);
+            }
+            Packet::SubAck(read_u16(body)?, body[2])
+        }
+        PINGRESP => Packet::PingResp,
+        other => Packet::Other(other),
+    };
+    Ok(Some((packet, end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_connect() {
+        let mut buf = [0; 64];
+        let mut encoder = Encoder::new(&mut buf);
+        encoder
+            .connect("dev", Some(("user", "pw")), 60)
+            .expect("Can't encode");
+        let len = encoder.len();
+        assert_eq!(
+            &buf[..len],
+            b"\x10\x19\x00\x04MQTT\x04\xc2\x00\x3c\x00\x03dev\x00\x04user\x00\x02pw"
+        );
+    }
+
+    #[test]
+    fn encode_publish_qos1() {
+        let mut buf = [0; 32];
+        let mut encoder = Encoder::new(&mut buf);
+        encoder
+            .publish("a/b", b"hi", QoS::AtLeastOnce, 7)
+            .expect("Can't encode");
+        let len = encoder.len();
+        assert_eq!(&buf[..len], b"\x32\x09\x00\x03a/b\x00\x07hi");
+    }
+
+    #[test]
+    fn encode_remaining_length() {
+        let payload = [0; 200];
+        let mut buf = [0; 256];
+        let mut encoder = Encoder::new(&mut buf);
+        encoder
+            .publish("t", &payload, QoS::AtMostOnce, 0)
+            .expect("Can't encode");
+        assert_eq!(&buf[..3], &[0x30, 0xCB, 0x01]);
+    }
+
+    #[test]
+    fn decode_partial() {
+        assert!(matches!(decode(b"\x20"), Ok(None)));
+        assert!(matches!(decode(b"\x20\x02\x00"), Ok(None)));
+    }
+
+    #[test]
+    fn decode_packet_len() {
+        assert!(matches!(packet_len(b"\x30"), Ok(None)));
+        assert!(matches!(packet_len(b"\x30\xCB"), Ok(None)));
+        assert!(matches!(packet_len(b"\x30\xCB\x01"), Ok(Some(206))));
+        assert!(matches!(
+            packet_len(b"\x30\xFF\xFF\xFF\xFF\x01"),
+            Err(MqttError::MalformedPacket)
+        ));
+    }
+
+    #[test]
+    fn decode_connack() {
+        match decode(b"\x20\x02\x00\x00\xd0") {
+            Ok(Some((Packet::ConnAck { code, .. }, len))) => {
+                assert_eq!(0, code);
+                assert_eq!(4, len);
+            }
+            _ => panic!("Unexpected packet"),
+        }
+    }
+
+    #[test]
+    fn decode_publish() {
+        match decode(b"\x32\x09\x00\x03a/b\x00\x07hi") {
+            Ok(Some((Packet::Publish(publication, packet_id), _))) => {
+                assert_eq!("a/b", publication.topic());
+                assert_eq!(b"hi", publication.payload());
+                assert_eq!(Some(7), packet_id);
+            }
+            _ => panic!("Unexpected packet"),
+        }
+    }
+}
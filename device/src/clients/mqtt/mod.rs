@@ -0,0 +1,421 @@
+//! MQTT 3.1.1 client
+//!
+//! A minimal MQTT client that runs on top of any `TcpStack`. It supports connecting with
+//! credentials and keep-alive, publishing with QoS 0 and 1, subscribing, and will
+//! transparently reconnect to the broker after a network error.
+
+mod codec;
+
+use crate::fmt::*;
+use crate::traits::{
+    ip::{IpProtocol, SocketAddress},
+    tcp::{TcpError, TcpStack},
+};
+use codec::{Encoder, Packet};
+use embassy::time::{with_timeout, Duration, Instant, Timer};
+use heapless::{
+    consts::{U2, U256, U4, U64},
+    spsc::Queue,
+    String, Vec,
+};
+
+const BUFFER_LEN: usize = 512;
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Quality of service for publications and subscriptions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum QoS {
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum MqttError {
+    Network(TcpError),
+    ConnectionRefused(u8),
+    SubscriptionRefused,
+    BufferTooSmall,
+    MalformedPacket,
+    Timeout,
+}
+
+impl From<TcpError> for MqttError {
+    fn from(e: TcpError) -> Self {
+        MqttError::Network(e)
+    }
+}
+
+/// Settings used when connecting to the broker.
+#[derive(Debug, Clone, Copy)]
+pub struct MqttConfig<'a> {
+    pub broker: SocketAddress,
+    pub client_id: &'a str,
+    pub credentials: Option<(&'a str, &'a str)>,
+    pub keep_alive: u16,
+}
+
+impl<'a> MqttConfig<'a> {
+    pub fn new(client_id: &'a str, broker: SocketAddress) -> Self {
+        Self {
+            broker,
+            client_id,
+            credentials: None,
+            keep_alive: 60,
+        }
+    }
+
+    pub fn credentials(mut self, username: &'a str, password: &'a str) -> Self {
+        self.credentials.replace((username, password));
+        self
+    }
+
+    /// Keep alive interval in seconds, 0 to disable keep alive.
+    pub fn keep_alive(mut self, keep_alive: u16) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+}
+
+/// A message published by the broker on one of the subscribed topics.
+#[derive(Debug)]
+pub struct Publication {
+    topic: String<U64>,
+    payload: Vec<u8, U256>,
+}
+
+impl Publication {
+    pub(crate) fn new(topic: &str, payload: &[u8]) -> Result<Self, MqttError> {
+        let mut t = String::new();
+        t.push_str(topic).map_err(|_| MqttError::BufferTooSmall)?;
+        let mut p = Vec::new();
+        p.extend_from_slice(payload)
+            .map_err(|_| MqttError::BufferTooSmall)?;
+        Ok(Self {
+            topic: t,
+            payload: p,
+        })
+    }
+
+    pub fn topic(&self) -> &str {
+        self.topic.as_str()
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload[..]
+    }
+}
+
+pub struct MqttClient<'a, S>
+where
+    S: TcpStack,
+{
+    config: MqttConfig<'a>,
+    stack: S,
+    socket: Option<S::SocketHandle>,
+    connected: bool,
+    packet_id: u16,
+    last_sent: Instant,
+    rx_buf: [u8; BUFFER_LEN],
+    rx_len: usize,
+    incoming: Queue<Publication, U2>,
+    subscriptions: Vec<(String<U64>, QoS), U4>,
+}
+
+impl<'a, S> MqttClient<'a, S>
+where
+    S: TcpStack,
+{
+    pub fn new(stack: S, config: MqttConfig<'a>) -> Self {
+        Self {
+            config,
+            stack,
+            socket: None,
+            connected: false,
+            packet_id: 0,
+            last_sent: Instant::now(),
+            rx_buf: [0; BUFFER_LEN],
+            rx_len: 0,
+            incoming: Queue::new(),
+            subscriptions: Vec::new(),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Connect to the broker, closing any previous connection first. Subscriptions made
+    /// on a previous connection are renewed.
+    pub async fn connect(&mut self) -> Result<(), MqttError> {
+        if let Some(socket) = self.socket.take() {
+            self.stack.close(socket).await;
+        }
+        self.connected = false;
+        self.rx_len = 0;
+
+        let socket = self.stack.open().await;
+        self.socket.replace(socket);
+        self.stack
+            .connect(socket, IpProtocol::Tcp, self.config.broker)
+            .await?;
+
+        let mut tx = [0; BUFFER_LEN];
+        let mut encoder = Encoder::new(&mut tx);
+        encoder.connect(
+            self.config.client_id,
+            self.config.credentials,
+            self.config.keep_alive,
+        )?;
+        let len = encoder.len();
+        self.write_all(&tx[..len]).await?;
+
+        match self
+            .wait_for(|p| matches!(p, Packet::ConnAck { .. }))
+            .await?
+        {
+            Packet::ConnAck { code: 0, .. } => {
+                self.connected = true;
+                info!("Connected to MQTT broker");
+            }
+            Packet::ConnAck { code, .. } => return Err(MqttError::ConnectionRefused(code)),
+            _ => return Err(MqttError::MalformedPacket),
+        }
+
+        for i in 0..self.subscriptions.len() {
+            let (topic, qos) = self.subscriptions[i].clone();
+            self.send_subscribe(topic.as_str(), qos).await?;
+        }
+        Ok(())
+    }
+
+    /// Gracefully disconnect from the broker.
+    pub async fn disconnect(&mut self) -> Result<(), MqttError> {
+        if self.connected {
+            let mut tx = [0; 2];
+            let mut encoder = Encoder::new(&mut tx);
+            encoder.disconnect()?;
+            let _ = self.write_all(&tx).await;
+            self.connected = false;
+        }
+        if let Some(socket) = self.socket.take() {
+            self.stack.close(socket).await;
+        }
+        Ok(())
+    }
+
+    /// Publish a message, waiting for the broker acknowledgement when using QoS 1.
+    pub async fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+    ) -> Result<(), MqttError> {
+        self.ensure_connected().await?;
+        let packet_id = self.next_packet_id();
+        let mut tx = [0; BUFFER_LEN];
+        let mut encoder = Encoder::new(&mut tx);
+        encoder.publish(topic, payload, qos, packet_id)?;
+        let len = encoder.len();
+        self.write_all(&tx[..len]).await?;
+
+        if let QoS::AtLeastOnce = qos {
+            self.wait_for(|p| matches!(p, Packet::PubAck(id) if *id == packet_id))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Subscribe to a topic filter. The subscription is renewed whenever the client reconnects.
+    pub async fn subscribe(&mut self, topic: &str, qos: QoS) -> Result<(), MqttError> {
+        self.ensure_connected().await?;
+        self.send_subscribe(topic, qos).await?;
+        if !self.subscriptions.iter().any(|(t, _)| t.as_str() == topic) {
+            let mut t = String::new();
+            t.push_str(topic).map_err(|_| MqttError::BufferTooSmall)?;
+            self.subscriptions
+                .push((t, qos))
+                .map_err(|_| MqttError::BufferTooSmall)?;
+        }
+        Ok(())
+    }
+
+    /// Send a PINGREQ and wait for the broker response.
+    pub async fn ping(&mut self) -> Result<(), MqttError> {
+        self.ensure_connected().await?;
+        let mut tx = [0; 2];
+        let mut encoder = Encoder::new(&mut tx);
+        encoder.pingreq()?;
+        self.write_all(&tx).await?;
+        self.wait_for(|p| matches!(p, Packet::PingResp)).await?;
+        Ok(())
+    }
+
+    /// Process any data received from the broker and send keep-alive pings when needed.
+    /// Returns a publication if one has been received.
+    pub async fn poll(&mut self) -> Result<Option<Publication>, MqttError> {
+        if let Some(publication) = self.incoming.dequeue() {
+            return Ok(Some(publication));
+        }
+        self.ensure_connected().await?;
+
+        // A keep alive interval of 0 disables keep alive
+        if self.config.keep_alive > 0 {
+            let keep_alive = Duration::from_secs(self.config.keep_alive as u64 / 2);
+            if Instant::now() >= self.last_sent + keep_alive {
+                self.ping().await?;
+            }
+        }
+
+        if self.rx_len < BUFFER_LEN {
+            self.read_some().await?;
+        }
+        while let Some(packet) = self.next_packet()? {
+            self.handle_packet(packet).await?;
+        }
+        Ok(self.incoming.dequeue())
+    }
+
+    async fn ensure_connected(&mut self) -> Result<(), MqttError> {
+        if !self.connected {
+            self.connect().await?;
+        }
+        Ok(())
+    }
+
+    async fn send_subscribe(&mut self, topic: &str, qos: QoS) -> Result<(), MqttError> {
+        let packet_id = self.next_packet_id();
+        let mut tx = [0; BUFFER_LEN];
+        let mut encoder = Encoder::new(&mut tx);
+        encoder.subscribe(topic, qos, packet_id)?;
+        let len = encoder.len();
+        self.write_all(&tx[..len]).await?;
+
+        match self
+            .wait_for(|p| matches!(p, Packet::SubAck(id, _) if *id == packet_id))
+            .await?
+        {
+            Packet::SubAck(_, 0x80) => Err(MqttError::SubscriptionRefused),
+            _ => Ok(()),
+        }
+    }
+
+    fn next_packet_id(&mut self) -> u16 {
+        self.packet_id = self.packet_id.wrapping_add(1);
+        if self.packet_id == 0 {
+            self.packet_id = 1;
+        }
+        self.packet_id
+    }
+
+    async fn write_all(&mut self, data: &[u8]) -> Result<(), MqttError> {
+        let socket = self
+            .socket
+            .ok_or(MqttError::Network(TcpError::SocketClosed))?;
+        let mut pos = 0;
+        while pos < data.len() {
+            match self.stack.write(socket, &data[pos..]).await {
+                Ok(len) => pos += len,
+                Err(e) => {
+                    self.connected = false;
+                    return Err(e.into());
+                }
+            }
+        }
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    async fn read_some(&mut self) -> Result<usize, MqttError> {
+        let socket = self
+            .socket
+            .ok_or(MqttError::Network(TcpError::SocketClosed))?;
+        let rx_len = self.rx_len;
+        match self.stack.read(socket, &mut self.rx_buf[rx_len..]).await {
+            Ok(len) => {
+                self.rx_len += len;
+                Ok(len)
+            }
+            Err(e) => {
+                self.connected = false;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Take the next complete packet from the receive buffer. A packet that fails to decode is
+    /// dropped, so that the packets after it can still be processed. When the end of the packet
+    /// can't be found, the received data is discarded and the client reconnects on next use.
+    fn next_packet(&mut self) -> Result<Option<Packet>, MqttError> {
+        let len = match codec::packet_len(&self.rx_buf[..self.rx_len]) {
+            Ok(Some(len)) if len <= self.rx_len => len,
+            Ok(Some(len)) if len <= BUFFER_LEN => return Ok(None),
+            Ok(None) => return Ok(None),
+            Ok(Some(_)) => return Err(self.discard_received(MqttError::BufferTooSmall)),
+            Err(e) => return Err(self.discard_received(e)),
+        };
+        let result = codec::decode(&self.rx_buf[..len]);
+        self.rx_buf.copy_within(len..self.rx_len, 0);
+        self.rx_len -= len;
+        match result? {
+            Some((packet, _)) => Ok(Some(packet)),
+            None => Err(MqttError::MalformedPacket),
+        }
+    }
+
+    fn discard_received(&mut self, e: MqttError) -> MqttError {
+        warn!("Unable to process data received from the broker: {:?}", e);
+        self.rx_len = 0;
+        self.connected = false;
+        e
+    }
+
+    /// Handle a packet that is not a response to an outstanding request.
+    async fn handle_packet(&mut self, packet: Packet) -> Result<(), MqttError> {
+        match packet {
+            Packet::Publish(publication, packet_id) => {
+                if let Some(packet_id) = packet_id {
+                    let mut tx = [0; 4];
+                    let mut encoder = Encoder::new(&mut tx);
+                    encoder.puback(packet_id)?;
+                    self.write_all(&tx).await?;
+                }
+                if self.incoming.enqueue(publication).is_err() {
+                    warn!("Incoming publication queue full, dropping message");
+                }
+            }
+            other => {
+                trace!("Ignoring packet {:?}", other);
+            }
+        }
+        Ok(())
+    }
+
+    async fn wait_for<F: Fn(&Packet) -> bool>(&mut self, expected: F) -> Result<Packet, MqttError> {
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+        loop {
+            while let Some(packet) = self.next_packet()? {
+                if expected(&packet) {
+                    return Ok(packet);
+                }
+                self.handle_packet(packet).await?;
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                self.connected = false;
+                return Err(MqttError::Timeout);
+            }
+            match with_timeout(deadline - now, self.read_some()).await {
+                Ok(Ok(0)) => Timer::after(READ_BACKOFF).await,
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => return Err(e),
+                Err(_) => {
+                    self.connected = false;
+                    return Err(MqttError::Timeout);
+                }
+            }
+        }
+    }
+}
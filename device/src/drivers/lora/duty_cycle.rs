@@ -0,0 +1,237 @@
+use crate::time::{Duration, Instant};
+use crate::traits::lora::{DataRate, LoraRegion};
+
+/// Size of the LoRaWAN headers and MIC added to the application payload of an uplink.
+pub const UPLINK_OVERHEAD: usize = 13;
+/// Size of a join request.
+pub const JOIN_REQUEST_LEN: usize = 23;
+
+/// Modulation parameters deciding the time on air of a LoRa packet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Modulation {
+    pub spreading_factor: u8,
+    /// Bandwidth in Hz.
+    pub bandwidth: u32,
+    /// Denominator of the coding rate, from 5 to 8 for 4/5 to 4/8.
+    pub coding_rate: u8,
+    pub preamble_len: u16,
+    pub explicit_header: bool,
+    pub crc: bool,
+}
+
+impl Modulation {
+    pub fn new(spreading_factor: u8, bandwidth: u32) -> Self {
+        Self {
+            spreading_factor,
+            bandwidth,
+            coding_rate: 5,
+            preamble_len: 8,
+            explicit_header: true,
+            crc: true,
+        }
+    }
+
+    /// Modulation of LoRaWAN uplinks at the given data rate. Data rates that are not LoRa
+    /// modulated in the region are treated as the slowest one, overestimating the time on air.
+    pub fn uplink(region: LoraRegion, data_rate: DataRate) -> Self {
+        let dr = data_rate as u8;
+        match region {
+            LoraRegion::US915 => match dr {
+                0..=3 => Self::new(10 - dr, 125_000),
+                4 => Self::new(8, 500_000),
+                _ => Self::new(10, 125_000),
+            },
+            LoraRegion::AU915 => match dr {
+                0..=5 => Self::new(12 - dr, 125_000),
+                6 => Self::new(8, 500_000),
+                _ => Self::new(12, 125_000),
+            },
+            _ => match dr {
+                0..=5 => Self::new(12 - dr, 125_000),
+                6 if region != LoraRegion::CN470 => Self::new(7, 250_000),
+                _ => Self::new(12, 125_000),
+            },
+        }
+    }
+
+    /// Time on air of a packet with the given payload length, following the Semtech SX127x
+    /// datasheet.
+    pub fn time_on_air(&self, payload_len: usize) -> Duration {
+        let sf = self.spreading_factor as i64;
+        let symbol_us = (1_000_000i64 << sf) / self.bandwidth as i64;
+        // Low data rate optimization is required for symbols longer than 16 ms
+        let de = if symbol_us > 16_000 { 1 } else { 0 };
+        let h = if self.explicit_header { 0 } else { 1 };
+        let crc = if self.crc { 1 } else { 0 };
+
+        let preamble_us = (self.preamble_len as i64 * 4 + 17) * symbol_us / 4;
+        let numerator = 8 * payload_len as i64 - 4 * sf + 28 + 16 * crc - 20 * h;
+        let denominator = 4 * (sf - 2 * de);
+        let payload_symbols = if numerator > 0 {
+            8 + (numerator + denominator - 1) / denominator * self.coding_rate as i64
+        } else {
+            8
+        };
+        let total_us = preamble_us + payload_symbols * symbol_us;
+        Duration::from_millis(((total_us + 999) / 1000) as u64)
+    }
+}
+
+/// A frequency band with a duty-cycle limit.
+#[derive(Debug, Clone, Copy)]
+struct SubBand {
+    /// Frequency range in Hz, end exclusive.
+    start: u32,
+    end: u32,
+    /// Inverse of the duty cycle, 100 for 1%.
+    divisor: u32,
+    available_at: Instant,
+}
+
+impl SubBand {
+    fn new(start: u32, end: u32, divisor: u32) -> Self {
+        Self {
+            start,
+            end,
+            divisor,
+            available_at: Instant::from_ticks(0),
+        }
+    }
+}
+
+const MAX_SUB_BANDS: usize = 6;
+
+/// Tracks transmissions per sub-band, to keep within the duty-cycle limits of the region. After
+/// transmitting, a sub-band with a duty cycle of 1% is unavailable for 99 times the time on air.
+pub struct DutyCycle {
+    bands: [Option<SubBand>; MAX_SUB_BANDS],
+}
+
+impl DutyCycle {
+    /// Duty-cycle limits of the region. Only EU868 limits are known, other regions are not
+    /// limited.
+    pub fn new(region: LoraRegion) -> Self {
+        let bands = match region {
+            LoraRegion::EU868 => [
+                Some(SubBand::new(863_000_000, 865_000_000, 1000)),
+                Some(SubBand::new(865_000_000, 868_000_000, 100)),
+                Some(SubBand::new(868_000_000, 868_600_000, 100)),
+                Some(SubBand::new(868_700_000, 869_200_000, 1000)),
+                Some(SubBand::new(869_400_000, 869_650_000, 10)),
+                Some(SubBand::new(869_700_000, 870_000_000, 100)),
+            ],
+            _ => [None; MAX_SUB_BANDS],
+        };
+        Self { bands }
+    }
+
+    /// Record a transmission on the given frequency, that ended at `end`.
+    pub fn record(&mut self, frequency: u32, end: Instant, time_on_air: Duration) {
+        for band in self.bands.iter_mut().flatten() {
+            if (band.start..band.end).contains(&frequency) {
+                let available_at = end + time_on_air * (band.divisor - 1);
+                if available_at > band.available_at {
+                    band.available_at = available_at;
+                }
+            }
+        }
+    }
+
+    /// When the sub-band of the given frequency may be used again.
+    pub fn available_at(&self, frequency: u32) -> Instant {
+        self.bands
+            .iter()
+            .flatten()
+            .filter(|band| (band.start..band.end).contains(&frequency))
+            .map(|band| band.available_at)
+            .max()
+            .unwrap_or(Instant::from_ticks(0))
+    }
+
+    /// When any sub-band may be used again. The LoRaWAN stack picks the uplink channel, so
+    /// sending is only allowed once every sub-band used so far is available.
+    pub fn next_allowed(&self) -> Instant {
+        self.bands
+            .iter()
+            .flatten()
+            .map(|band| band.available_at)
+            .max()
+            .unwrap_or(Instant::from_ticks(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_on_air() {
+        // Join request at SF7
+        assert_eq!(
+            Duration::from_millis(62),
+            Modulation::new(7, 125_000).time_on_air(JOIN_REQUEST_LEN)
+        );
+        // Largest EU868 payload at SF12, with low data rate optimization
+        assert_eq!(
+            Duration::from_millis(2466),
+            Modulation::new(12, 125_000).time_on_air(51)
+        );
+        assert_eq!(
+            Duration::from_millis(31),
+            Modulation::new(7, 250_000).time_on_air(JOIN_REQUEST_LEN)
+        );
+    }
+
+    #[test]
+    fn test_uplink_modulation() {
+        assert_eq!(
+            Modulation::new(9, 125_000),
+            Modulation::uplink(LoraRegion::EU868, DataRate::DR3)
+        );
+        assert_eq!(
+            Modulation::new(7, 250_000),
+            Modulation::uplink(LoraRegion::EU868, DataRate::DR6)
+        );
+        assert_eq!(
+            Modulation::new(8, 500_000),
+            Modulation::uplink(LoraRegion::US915, DataRate::DR4)
+        );
+        // FSK is treated as the slowest data rate
+        assert_eq!(
+            Modulation::new(12, 125_000),
+            Modulation::uplink(LoraRegion::EU868, DataRate::DR7)
+        );
+    }
+
+    #[test]
+    fn test_duty_cycle() {
+        let start = Instant::from_ticks(0);
+        let mut duty_cycle = DutyCycle::new(LoraRegion::EU868);
+        assert_eq!(start, duty_cycle.next_allowed());
+
+        let end = start + Duration::from_secs(10);
+        duty_cycle.record(868_100_000, end, Duration::from_millis(100));
+        assert_eq!(end + Duration::from_millis(9900), duty_cycle.next_allowed());
+        assert_eq!(
+            end + Duration::from_millis(9900),
+            duty_cycle.available_at(868_500_000)
+        );
+        assert_eq!(start, duty_cycle.available_at(869_525_000));
+
+        // 10% band
+        duty_cycle.record(869_525_000, end, Duration::from_millis(100));
+        assert_eq!(
+            end + Duration::from_millis(900),
+            duty_cycle.available_at(869_525_000)
+        );
+        assert_eq!(end + Duration::from_millis(9900), duty_cycle.next_allowed());
+    }
+
+    #[test]
+    fn test_no_duty_cycle() {
+        let mut duty_cycle = DutyCycle::new(LoraRegion::US915);
+        let end = Instant::from_ticks(0) + Duration::from_secs(10);
+        duty_cycle.record(902_300_000, end, Duration::from_millis(100));
+        assert_eq!(Instant::from_ticks(0), duty_cycle.next_allowed());
+    }
+}
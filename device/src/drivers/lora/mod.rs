@@ -1,4 +1,6 @@
 #[cfg(feature = "lora+rak811")]
 pub mod rak811;
+pub mod duty_cycle;
+pub mod session;
 #[cfg(feature = "lora+sx127x")]
 pub mod sx127x;
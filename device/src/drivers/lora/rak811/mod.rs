@@ -1,13 +1,18 @@
 ///A network driver for a RAK811 attached via a UART.
 ///
-///Currently requires the RAK811 to be flashed with a 2.x version of the AT firmware.
+///Currently requires the RAK811 to be flashed with a 2.x version of the AT firmware, other
+///versions are refused when initializing the modem.
 ///
 mod buffer;
 mod parser;
 mod protocol;
+use super::duty_cycle::{DutyCycle, Modulation, UPLINK_OVERHEAD};
 use crate::{
     kernel::{actor::Actor, channel::*},
-    traits::lora::*,
+    traits::{
+        lora::*,
+        modem::{ModemDetails, ModemError, ModemInfo, Version},
+    },
 };
 
 pub use buffer::*;
@@ -18,19 +23,28 @@ use core::{
 };
 use embassy::{
     io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt},
+    time::{with_timeout, Duration, Instant, Timer},
     util::Signal,
 };
 use embedded_hal::digital::v2::OutputPin;
 use futures::future::{select, Either};
 use futures::pin_mut;
-use heapless::{consts::U2, spsc::Queue};
+use heapless::{consts::U2, spsc::Queue, Vec};
 pub use protocol::*;
 
 const RECV_BUFFER_LEN: usize = 256;
+/// How long the module has to report its firmware version when initializing.
+const VERSION_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long to wait for a downlink reported after the TX event.
+const DOWNLINK_TIMEOUT: Duration = Duration::from_secs(3);
+/// The module does not report the channel of an uplink, so uplinks are accounted against the
+/// sub-band of the default EU868 channels.
+const EU868_DEFAULT_FREQUENCY: u32 = 868_100_000;
 
 pub struct Initialized {
     signal: Signal<Result<LoraRegion, LoraError>>,
     initialized: AtomicBool,
+    reset: Signal<()>,
 }
 
 impl Initialized {
@@ -38,9 +52,17 @@ impl Initialized {
         Self {
             signal: Signal::new(),
             initialized: AtomicBool::new(false),
+            reset: Signal::new(),
         }
     }
 
+    /// Ask the modem to reset the module using the reset pin. The next `wait` returns the
+    /// region reported once the module is initialized again.
+    fn request_reset(&self) {
+        self.initialized.store(false, Ordering::SeqCst);
+        self.reset.signal(());
+    }
+
     async fn wait(&self) -> Result<Option<LoraRegion>, LoraError> {
         if self.initialized.swap(true, Ordering::SeqCst) == false {
             let region = self.signal.wait().await?;
@@ -62,6 +84,11 @@ pub struct Rak811Driver {
 
 pub struct Rak811Controller<'a> {
     config: LoraConfig,
+    joined: bool,
+    duty_cycle: Option<DutyCycle>,
+    downlinks: Queue<Downlink, U2>,
+    /// A response received while waiting for a downlink, handled as the next response.
+    pending: Option<Response>,
     initialized: &'a Initialized,
     command_producer: ChannelSender<'a, CommandBuffer, U2>,
     response_consumer: ChannelReceiver<'a, Response, U2>,
@@ -131,6 +158,7 @@ where
     }
 
     async fn initialize(&mut self) -> Result<LoraRegion, LoraError> {
+        self.parse_buffer = Buffer::new();
         self.reset.set_high().ok();
         self.reset.set_low().ok();
         loop {
@@ -140,6 +168,7 @@ where
                 match response {
                     Response::Initialized(region) => {
                         log::info!("Got initialize response with region {:?}", region);
+                        self.check_firmware().await?;
                         return Ok(region);
                     }
                     e => {
@@ -151,6 +180,40 @@ where
         }
     }
 
+    /// Refuse firmware other than 2.x, whose commands differ. Firmware whose version cannot be
+    /// parsed is refused once the version timeout expires.
+    async fn check_firmware(&mut self) -> Result<(), LoraError> {
+        let mut uart = unsafe { Pin::new_unchecked(&mut self.uart) };
+        uart.write_all(b"at+version\r\n")
+            .await
+            .map_err(|_| LoraError::SendError)?;
+        let response = with_timeout(VERSION_TIMEOUT, async {
+            loop {
+                self.process().await?;
+                if let Some(response) = self.parse() {
+                    return Ok::<_, LoraError>(response);
+                }
+            }
+        })
+        .await;
+        match response {
+            Ok(Ok(Response::FirmwareInfo(info))) if info.major == 2 => Ok(()),
+            Ok(Ok(Response::FirmwareInfo(info))) => {
+                log::error!("Unsupported firmware: {:?}", info);
+                Err(LoraError::UnsupportedFirmware)
+            }
+            Ok(Ok(e)) => {
+                log::error!("Got unexpected response: {:?}", e);
+                Err(LoraError::NotInitialized)
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => {
+                log::error!("No firmware version reported");
+                Err(LoraError::UnsupportedFirmware)
+            }
+        }
+    }
+
     async fn process(&mut self) -> Result<(), LoraError> {
         let mut buf = [0; 1];
         let mut uart = unsafe { Pin::new_unchecked(&mut self.uart) };
@@ -188,17 +251,30 @@ where
         self.initialized.signal(result);
         loop {
             let mut buf = [0; 1];
-            let (cmd, input) = {
+            let (cmd, input, reset) = {
+                let initialized = self.initialized;
                 let command_fut = self.command_consumer.receive();
                 let mut uart = unsafe { Pin::new_unchecked(&mut self.uart) };
                 let uart_fut = uart.read(&mut buf[..]);
+                let reset_fut = initialized.reset.wait();
                 pin_mut!(uart_fut);
+                pin_mut!(reset_fut);
 
-                match select(command_fut, uart_fut).await {
-                    Either::Left((s, _)) => (Some(s), None),
-                    Either::Right((r, _)) => (None, Some(r)),
+                match select(select(command_fut, uart_fut), reset_fut).await {
+                    Either::Left((Either::Left((s, _)), _)) => (Some(s), None, false),
+                    Either::Left((Either::Right((r, _)), _)) => (None, Some(r), false),
+                    Either::Right(_) => (None, None, true),
                 }
             };
+
+            // Reset requested by the controller, start over
+            if reset {
+                log::info!("Resetting module");
+                let result = self.initialize().await;
+                self.initialized.signal(result);
+                continue;
+            }
+
             // We got command to write, write it
             if let Some(s) = cmd {
                 let mut uart = unsafe { Pin::new_unchecked(&mut self.uart) };
@@ -225,26 +301,68 @@ where
     }
 }
 
-/*
-    /// Send reset command to lora module. Depending on the mode, this will restart
-    /// the module or reload its configuration from EEPROM.
-    pub fn reset(&mut self, mode: ResetMode) -> Result<(), DriverError> {
-        let response = self.send_command(Command::Reset(mode))?;
-        match response {
-            Response::Ok => {
-                let response = self.recv_response()?;
-                match response {
-                    Response::Initialized(band) => {
-                        self.lora_band = band;
-                        Ok(())
-                    }
-                    _ => Err(DriverError::NotInitialized),
-                }
-            }
-            r => log_unexpected(r),
-        }
+/// The module selects TX power by level, from the highest power down. Only the EU868 levels are
+/// known.
+fn power_level(region: Option<LoraRegion>, tx_power: i8) -> Result<u8, LoraError> {
+    const EU868_LEVELS: [i8; 6] = [20, 14, 11, 8, 5, 2];
+    match region {
+        Some(LoraRegion::EU868) => EU868_LEVELS
+            .iter()
+            .position(|p| *p == tx_power)
+            .map(|l| l as u8)
+            .ok_or(LoraError::UnsupportedOption),
+        _ => Err(LoraError::UnsupportedOption),
     }
-*/
+}
+
+/// Channel masks enabling only the given sub-band. Each mask covers 16 of the 125 kHz channels,
+/// and the last one the 500 kHz channels, one per sub-band.
+fn channel_masks(region: Option<LoraRegion>, sub_band: u8) -> Result<[(u8, u16); 5], LoraError> {
+    match region {
+        Some(LoraRegion::US915) | Some(LoraRegion::AU915) => {}
+        _ => return Err(LoraError::UnsupportedOption),
+    }
+    if !(1..=8).contains(&sub_band) {
+        return Err(LoraError::ConfigError);
+    }
+    let index = sub_band - 1;
+    let mut masks = [(0, 0), (1, 0), (2, 0), (3, 0), (4, 1 << index)];
+    masks[(index / 2) as usize].1 = if index % 2 == 0 { 0x00FF } else { 0xFF00 };
+    Ok(masks)
+}
+
+fn to_data_rate(dr: u32) -> Option<DataRate> {
+    match dr {
+        0 => Some(DataRate::DR0),
+        1 => Some(DataRate::DR1),
+        2 => Some(DataRate::DR2),
+        3 => Some(DataRate::DR3),
+        4 => Some(DataRate::DR4),
+        5 => Some(DataRate::DR5),
+        6 => Some(DataRate::DR6),
+        7 => Some(DataRate::DR7),
+        8 => Some(DataRate::DR8),
+        9 => Some(DataRate::DR9),
+        10 => Some(DataRate::DR10),
+        11 => Some(DataRate::DR11),
+        12 => Some(DataRate::DR12),
+        13 => Some(DataRate::DR13),
+        14 => Some(DataRate::DR14),
+        15 => Some(DataRate::DR15),
+        _ => None,
+    }
+}
+
+fn to_downlink(port: Port, len: usize, data: &[u8]) -> Downlink {
+    let mut downlink = Downlink {
+        port,
+        data: Vec::new(),
+        rssi: None,
+        snr: None,
+    };
+    downlink.data.extend_from_slice(&data[..len]).ok();
+    downlink
+}
 
 fn log_unexpected(r: Response) -> Result<(), LoraError> {
     log::error!("Unexpected response: {:?}", r);
@@ -259,37 +377,51 @@ impl<'a> LoraDriver for Rak811Controller<'a> {
     }
 
     #[rustfmt::skip]
-    type JoinFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
-    fn join<'m>(&'m mut self, mode: ConnectMode) -> Self::JoinFuture<'m> {
+    type ResetFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn reset<'m>(&'m mut self, mode: ResetMode) -> Self::ResetFuture<'m> {
         async move {
-            let response = self.send_command(Command::Join(mode)).await?;
-            match response {
-                Response::Ok => {
-                    let response = self.response_consumer.receive().await;
+            self.joined = false;
+            match mode {
+                ResetMode::Hardware => {
+                    self.initialized.request_reset();
+                    self.wait_initialized().await
+                }
+                mode => {
+                    let response = self.send_command(Command::Reset(mode)).await?;
                     match response {
-                        Response::Recv(EventCode::JoinedSuccess, _, _, _) => Ok(()),
+                        Response::Ok => {
+                            let response = self.next_response().await;
+                            match response {
+                                Response::Initialized(region) => {
+                                    self.set_region(region);
+                                    Ok(())
+                                }
+                                r => {
+                                    log::error!("Unexpected response: {:?}", r);
+                                    Err(LoraError::NotInitialized)
+                                }
+                            }
+                        }
                         r => log_unexpected(r),
                     }
                 }
-                r => log_unexpected(r),
             }
         }
     }
 
     #[rustfmt::skip]
-    type SendFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
-    fn send<'m>(&'m mut self, qos: QoS, port: Port, data: &'m [u8]) -> Self::SendFuture<'m> {
+    type JoinFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn join<'m>(&'m mut self, mode: ConnectMode) -> Self::JoinFuture<'m> {
         async move {
-            let response = self.send_command(Command::Send(qos, port, data)).await?;
+            let response = self.send_command(Command::Join(mode)).await?;
             match response {
                 Response::Ok => {
-                    let response = self.response_consumer.receive().await;
-                    let expected_code = match qos {
-                        QoS::Unconfirmed => EventCode::TxUnconfirmed,
-                        QoS::Confirmed => EventCode::TxConfirmed,
-                    };
+                    let response = self.next_response().await;
                     match response {
-                        Response::Recv(c, 0, _, _) if expected_code == c => Ok(()),
+                        Response::Recv(EventCode::JoinedSuccess, _, _, _) => {
+                            self.joined = true;
+                            Ok(())
+                        }
                         r => log_unexpected(r),
                     }
                 }
@@ -299,7 +431,13 @@ impl<'a> LoraDriver for Rak811Controller<'a> {
     }
 
     #[rustfmt::skip]
-    type SendRecvFuture<'m> where 'a: 'm = impl Future<Output = Result<usize, LoraError>> + 'm;
+    type SendFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn send<'m>(&'m mut self, qos: QoS, port: Port, data: &'m [u8]) -> Self::SendFuture<'m> {
+        async move { self.transmit(qos, port, data, None).await.map(|_| ()) }
+    }
+
+    #[rustfmt::skip]
+    type SendRecvFuture<'m> where 'a: 'm = impl Future<Output = Result<(Port, usize), LoraError>> + 'm;
     fn send_recv<'m>(
         &'m mut self,
         qos: QoS,
@@ -307,7 +445,194 @@ impl<'a> LoraDriver for Rak811Controller<'a> {
         data: &'m [u8],
         rx: &'m mut [u8],
     ) -> Self::SendRecvFuture<'m> {
-        async move { Ok(0) }
+        async move { self.transmit(qos, port, data, Some(rx)).await }
+    }
+
+    #[rustfmt::skip]
+    type ReceiveFuture<'m> where 'a: 'm = impl Future<Output = Result<Downlink, LoraError>> + 'm;
+    fn receive<'m>(&'m mut self) -> Self::ReceiveFuture<'m> {
+        async move {
+            if let Some(downlink) = self.downlinks.dequeue() {
+                return Ok(downlink);
+            }
+            if self.config.device_class != Some(DeviceClass::C) {
+                return Err(LoraError::NotReady);
+            }
+            loop {
+                match self.next_response().await {
+                    Response::Recv(EventCode::RecvData, port, len, Some(data)) => {
+                        return Ok(to_downlink(port, len, &data));
+                    }
+                    r => log::warn!("Ignoring unexpected response: {:?}", r),
+                }
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type LinkStatusFuture<'m> where 'a: 'm = impl Future<Output = Result<LinkStatus, LoraError>> + 'm;
+    fn link_status<'m>(&'m mut self) -> Self::LinkStatusFuture<'m> {
+        async move {
+            match self.send_command(Command::GetStatus).await? {
+                Response::Status {
+                    tx_ok,
+                    tx_err,
+                    rx_ok,
+                    rx_timeout,
+                    rx_err,
+                    rssi,
+                    snr,
+                } => Ok(LinkStatus {
+                    joined: self.joined,
+                    // The network may change the data rate with ADR
+                    data_rate: self.data_rate().await.ok(),
+                    // The module reports the signal of the last downlink, if any
+                    rssi: if rx_ok > 0 { Some(rssi) } else { None },
+                    snr: if rx_ok > 0 { Some(snr) } else { None },
+                    tx_ok,
+                    tx_err,
+                    rx_ok,
+                    rx_timeout,
+                    rx_err,
+                }),
+                r => {
+                    log::error!("Unexpected response: {:?}", r);
+                    Err(LoraError::OtherError)
+                }
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type NextSendAllowedFuture<'m> where 'a: 'm = impl Future<Output = Instant> + 'm;
+    fn next_send_allowed<'m>(&'m mut self) -> Self::NextSendAllowedFuture<'m> {
+        async move {
+            self.duty_cycle
+                .as_ref()
+                .map(|duty_cycle| duty_cycle.next_allowed())
+                .unwrap_or_else(|| Instant::from_ticks(0))
+        }
+    }
+}
+
+/// Point-to-point links, using the module in P2P mode. Configuring the radio switches the module
+/// to P2P mode, and LoRaWAN can only be used again after configuring LoRaWAN mode.
+impl<'a> LoraRadio for Rak811Controller<'a> {
+    #[rustfmt::skip]
+    type ConfigureRadioFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn configure_radio<'m>(
+        &'m mut self,
+        config: &'m RadioConfig,
+    ) -> Self::ConfigureRadioFuture<'m> {
+        async move {
+            if !(5..=20).contains(&config.tx_power) {
+                return Err(LoraError::UnsupportedOption);
+            }
+            if self.config.lora_mode != Some(LoraMode::P2P) {
+                self.send_command_ok(Command::SetMode(LoraMode::P2P))
+                    .await?;
+                self.config.lora_mode.replace(LoraMode::P2P);
+            }
+            self.send_command_ok(Command::SetRfConfig(*config)).await
+        }
+    }
+
+    #[rustfmt::skip]
+    type TransmitFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn transmit<'m>(&'m mut self, data: &'m [u8]) -> Self::TransmitFuture<'m> {
+        async move {
+            if data.len() > P2P_MAX_LEN {
+                return Err(LoraError::SendError);
+            }
+            match self.send_command(Command::P2pSend(data)).await? {
+                Response::Ok => {}
+                r => {
+                    log::error!("Unexpected response: {:?}", r);
+                    return Err(LoraError::SendError);
+                }
+            }
+            match self.next_response().await {
+                Response::Recv(EventCode::P2PTxComplete, _, _, _) => Ok(()),
+                r => {
+                    log::error!("Unexpected response: {:?}", r);
+                    Err(LoraError::SendError)
+                }
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type ReceivePacketFuture<'m> where 'a: 'm = impl Future<Output = Result<RadioPacket, LoraError>> + 'm;
+    fn receive_packet<'m>(
+        &'m mut self,
+        rx: &'m mut [u8],
+        timeout: Duration,
+    ) -> Self::ReceivePacketFuture<'m> {
+        async move {
+            match self.send_command(Command::P2pReceive).await? {
+                Response::Ok => {}
+                r => {
+                    log::error!("Unexpected response: {:?}", r);
+                    return Err(LoraError::RecvError);
+                }
+            }
+            let consumer = &mut self.response_consumer;
+            let received = with_timeout(timeout, async {
+                loop {
+                    match consumer.receive().await {
+                        Response::P2pRecv(rssi, snr, len, data) => break (rssi, snr, len, data),
+                        r => log::warn!("Ignoring unexpected response: {:?}", r),
+                    }
+                }
+            })
+            .await;
+
+            // A packet received before the module stops listening precedes the response to the
+            // stop command
+            let mut received = received.ok();
+            let mut response = self.send_command(Command::P2pStopReceive).await?;
+            while let Response::P2pRecv(rssi, snr, len, data) = response {
+                if received.is_none() {
+                    received.replace((rssi, snr, len, data));
+                } else {
+                    log::warn!("Discarding packet of {} bytes received while stopping", len);
+                }
+                response = self.next_response().await;
+            }
+            if !matches!(response, Response::Ok) {
+                log::error!("Unexpected response: {:?}", response);
+                return Err(LoraError::RecvError);
+            }
+
+            let (rssi, snr, len, data) = received.ok_or(LoraError::RecvTimeout)?;
+            if len > rx.len() {
+                return Err(LoraError::RecvBufferTooSmall);
+            }
+            rx[..len].copy_from_slice(&data[..len]);
+            Ok(RadioPacket { len, rssi, snr })
+        }
+    }
+}
+
+impl<'a> ModemInfo for Rak811Controller<'a> {
+    #[rustfmt::skip]
+    type InfoFuture<'m> where 'a: 'm = impl Future<Output = Result<ModemDetails, ModemError>> + 'm;
+    fn info<'m>(&'m mut self) -> Self::InfoFuture<'m> {
+        async move {
+            match self.send_command(Command::QueryFirmwareInfo).await {
+                Ok(Response::FirmwareInfo(info)) => Ok(ModemDetails {
+                    at_version: Version::new(info.major, info.minor, info.patch, info.build),
+                    sdk_version: None,
+                    module: None,
+                    mac: None,
+                }),
+                Ok(r) => {
+                    log::error!("Unexpected response: {:?}", r);
+                    Err(ModemError::UnexpectedResponse)
+                }
+                Err(_) => Err(ModemError::CommandFailed),
+            }
+        }
     }
 }
 
@@ -319,24 +644,84 @@ impl<'a> Rak811Controller<'a> {
     ) -> Self {
         Self {
             config: LoraConfig::new(),
+            joined: false,
+            duty_cycle: None,
+            downlinks: Queue::new(),
+            pending: None,
             initialized,
             command_producer,
             response_consumer,
         }
     }
 
-    async fn send_command<'m>(&mut self, command: Command<'m>) -> Result<Response, LoraError> {
+    /// Wait for the module to be initialized, taking the region it reports after a reset.
+    async fn wait_initialized(&mut self) -> Result<(), LoraError> {
         if let Some(region) = self.initialized.wait().await? {
-            self.config.region.replace(region);
+            self.set_region(region);
         }
+        Ok(())
+    }
+
+    /// Use a region, starting the duty-cycle limits over when it changes.
+    fn set_region(&mut self, region: LoraRegion) {
+        if self.config.region != Some(region) {
+            self.duty_cycle.take();
+        }
+        self.config.region.replace(region);
+    }
+
+    async fn send_command<'m>(&mut self, command: Command<'m>) -> Result<Response, LoraError> {
+        self.wait_initialized().await?;
         let mut s = Command::buffer();
         command.encode(&mut s);
         log::debug!("Sending command {}", s.as_str());
         s.push_str("\r\n").unwrap();
         self.command_producer.send(s).await;
 
-        let response = self.response_consumer.receive().await;
-        Ok(response)
+        loop {
+            match self.next_response().await {
+                Response::Recv(EventCode::RecvData, port, len, Some(data)) => {
+                    self.unsolicited(port, len, data)
+                }
+                response => return Ok(response),
+            }
+        }
+    }
+
+    /// The next response of the module, starting with one put back while waiting for a
+    /// downlink.
+    async fn next_response(&mut self) -> Response {
+        match self.pending.take() {
+            Some(response) => response,
+            None => self.response_consumer.receive().await,
+        }
+    }
+
+    /// Keep a downlink received while waiting for a command response, to be returned by
+    /// `receive`.
+    fn unsolicited(&mut self, port: Port, len: usize, data: [u8; RECV_BUFFER_LEN]) {
+        if self
+            .downlinks
+            .enqueue(to_downlink(port, len, &data))
+            .is_err()
+        {
+            log::warn!(
+                "Downlink queue full, discarding {} bytes on port {}",
+                len,
+                port
+            );
+        }
+    }
+
+    /// The data rate currently used by the module for uplinks.
+    async fn data_rate(&mut self) -> Result<DataRate, LoraError> {
+        match self.send_command(Command::GetConfig(ConfigKey::Dr)).await? {
+            Response::ConfigValue(dr) => to_data_rate(dr).ok_or(LoraError::OtherError),
+            r => {
+                log::error!("Unexpected response: {:?}", r);
+                Err(LoraError::OtherError)
+            }
+        }
     }
 
     async fn send_command_ok<'m>(&mut self, command: Command<'m>) -> Result<(), LoraError> {
@@ -346,15 +731,141 @@ impl<'a> Rak811Controller<'a> {
         }
     }
 
-    async fn apply_config(&mut self, config: &LoraConfig) -> Result<(), LoraError> {
-        if let Some(region) = self.initialized.wait().await? {
-            self.config.region.replace(region);
+    /// Send data and wait for the TX event. A downlink received in the RX windows is reported
+    /// as a separate event, which may arrive on either side of the TX event. If a downlink is
+    /// expected and none arrived before the TX event, wait a short while for one to follow.
+    async fn transmit(
+        &mut self,
+        qos: QoS,
+        port: Port,
+        data: &[u8],
+        mut rx: Option<&mut [u8]>,
+    ) -> Result<(Port, usize), LoraError> {
+        self.check_duty_cycle().await?;
+        let data_rate = self.uplink_data_rate().await;
+        let response = self.send_command(Command::Send(qos, port, data)).await?;
+        match response {
+            Response::Ok => {}
+            r => {
+                log::error!("Unexpected response: {:?}", r);
+                return Err(LoraError::SendError);
+            }
+        }
+
+        let expected_code = match qos {
+            QoS::Unconfirmed => EventCode::TxUnconfirmed,
+            QoS::Confirmed => EventCode::TxConfirmed,
+        };
+        let mut received = None;
+        loop {
+            match self.next_response().await {
+                Response::Recv(EventCode::RecvData, p, len, buf) => {
+                    received.replace(self.downlink(p, len, buf, rx.as_deref_mut()));
+                }
+                Response::Recv(c, 0, _, _) if expected_code == c => break,
+                r => {
+                    log::error!("Unexpected response: {:?}", r);
+                    return Err(LoraError::SendError);
+                }
+            }
+        }
+        self.record_transmission(data.len(), data_rate);
+
+        if let Some(result) = received {
+            return result;
+        }
+        if rx.is_none() {
+            // A later downlink is kept for `receive`
+            return Ok((0, 0));
+        }
+
+        match with_timeout(DOWNLINK_TIMEOUT, self.response_consumer.receive()).await {
+            Ok(Response::Recv(EventCode::RecvData, p, len, buf)) => self.downlink(p, len, buf, rx),
+            Ok(r) => {
+                // The uplink was sent, leave the response to whoever expects it
+                self.pending.replace(r);
+                Ok((0, 0))
+            }
+            Err(_) => Ok((0, 0)),
+        }
+    }
+
+    fn duty_cycle(&mut self) -> &mut DutyCycle {
+        let region = self.config.region.unwrap_or(LoraRegion::UNKNOWN);
+        self.duty_cycle
+            .get_or_insert_with(|| DutyCycle::new(region))
+    }
+
+    /// Wait until the duty-cycle limits allow transmitting, or fail if the policy is to reject.
+    async fn check_duty_cycle(&mut self) -> Result<(), LoraError> {
+        self.wait_initialized().await?;
+        let next = self.duty_cycle().next_allowed();
+        if next <= Instant::now() {
+            return Ok(());
+        }
+        match self.config.duty_cycle.unwrap_or(DutyCyclePolicy::Reject) {
+            DutyCyclePolicy::Wait => {
+                log::debug!("Waiting for duty cycle");
+                Timer::at(next).await;
+                Ok(())
+            }
+            DutyCyclePolicy::Reject => Err(LoraError::DutyCycleExceeded),
+        }
+    }
+
+    /// The data rate of the next uplink. Unless a data rate is configured with ADR off, it is
+    /// queried from the module, falling back to the slowest one.
+    async fn uplink_data_rate(&mut self) -> DataRate {
+        match (self.config.adr, self.config.data_rate) {
+            (Some(false), Some(data_rate)) => data_rate,
+            _ => self.data_rate().await.unwrap_or(DataRate::DR0),
+        }
+    }
+
+    /// Account an uplink against the duty-cycle limits. Every transmission the module may
+    /// repeat is counted.
+    fn record_transmission(&mut self, len: usize, data_rate: DataRate) {
+        let region = self.config.region.unwrap_or(LoraRegion::UNKNOWN);
+        let transmissions = self.config.retries.map(|r| r as u32 + 1).unwrap_or(1);
+        let time_on_air = Modulation::uplink(region, data_rate).time_on_air(len + UPLINK_OVERHEAD)
+            * transmissions;
+        self.duty_cycle()
+            .record(EU868_DEFAULT_FREQUENCY, Instant::now(), time_on_air);
+    }
+
+    /// Copy a received downlink into the receive buffer, returning its port and length. Without
+    /// a buffer, the downlink is kept to be returned by `receive`.
+    fn downlink(
+        &mut self,
+        port: Port,
+        len: usize,
+        buf: Option<[u8; RECV_BUFFER_LEN]>,
+        rx: Option<&mut [u8]>,
+    ) -> Result<(Port, usize), LoraError> {
+        log::debug!("Received {} bytes on port {}", len, port);
+        match (rx, buf) {
+            (Some(rx), Some(buf)) => {
+                if len > rx.len() {
+                    return Err(LoraError::RecvBufferTooSmall);
+                }
+                rx[0..len].copy_from_slice(&buf[0..len]);
+                Ok((port, len))
+            }
+            (None, Some(buf)) => {
+                self.unsolicited(port, len, buf);
+                Ok((0, 0))
+            }
+            (_, None) => Ok((port, 0)),
         }
+    }
+
+    async fn apply_config(&mut self, config: &LoraConfig) -> Result<(), LoraError> {
+        self.wait_initialized().await?;
         log::info!("Applying config: {:?}", config);
         if let Some(region) = config.region {
             if self.config.region != config.region {
                 self.send_command_ok(Command::SetBand(region)).await?;
-                self.config.region.replace(region);
+                self.set_region(region);
             }
         }
         if let Some(lora_mode) = config.lora_mode {
@@ -388,6 +899,94 @@ impl<'a> Rak811Controller<'a> {
             self.config.app_key.replace(*app_key);
         }
 
+        if let Some(ref nwks_key) = config.nwks_key {
+            self.send_command_ok(Command::SetConfig(ConfigOption::NwksKey(nwks_key)))
+                .await?;
+            self.config.nwks_key.replace(*nwks_key);
+        }
+
+        if let Some(ref apps_key) = config.apps_key {
+            self.send_command_ok(Command::SetConfig(ConfigOption::AppsKey(apps_key)))
+                .await?;
+            self.config.apps_key.replace(*apps_key);
+        }
+
+        if let Some(device_class) = config.device_class {
+            if self.config.device_class != config.device_class {
+                self.send_command_ok(Command::SetConfig(ConfigOption::Class(device_class)))
+                    .await?;
+                self.config.device_class.replace(device_class);
+            }
+        }
+
+        if let Some(data_rate) = config.data_rate {
+            if self.config.data_rate != config.data_rate {
+                self.send_command_ok(Command::SetConfig(ConfigOption::Dr(data_rate as u8)))
+                    .await?;
+                self.config.data_rate.replace(data_rate);
+            }
+        }
+
+        if let Some(adr) = config.adr {
+            if self.config.adr != config.adr {
+                self.send_command_ok(Command::SetConfig(ConfigOption::Adr(adr)))
+                    .await?;
+                self.config.adr.replace(adr);
+            }
+        }
+
+        if let Some(tx_power) = config.tx_power {
+            if self.config.tx_power != config.tx_power {
+                let level = power_level(self.config.region, tx_power)?;
+                self.send_command_ok(Command::SetConfig(ConfigOption::PwrLevel(level)))
+                    .await?;
+                self.config.tx_power.replace(tx_power);
+            }
+        }
+
+        if let Some(retries) = config.retries {
+            if self.config.retries != config.retries {
+                // The module counts transmissions rather than retransmissions
+                if retries > 14 {
+                    return Err(LoraError::UnsupportedOption);
+                }
+                self.send_command_ok(Command::SetConfig(ConfigOption::Nbtrans(retries + 1)))
+                    .await?;
+                self.config.retries.replace(retries);
+            }
+        }
+
+        if let Some(rx_delay1) = config.rx_delay1 {
+            if self.config.rx_delay1 != config.rx_delay1 {
+                // The module only supports whole seconds
+                if rx_delay1 % 1000 != 0 || rx_delay1 / 1000 > 15 {
+                    return Err(LoraError::UnsupportedOption);
+                }
+                let delay = (rx_delay1 / 1000) as u8;
+                self.send_command_ok(Command::SetConfig(ConfigOption::RxDelay1(delay)))
+                    .await?;
+                self.config.rx_delay1.replace(rx_delay1);
+            }
+        }
+
+        if let Some(sub_band) = config.sub_band {
+            if self.config.sub_band != config.sub_band {
+                for (id, mask) in channel_masks(self.config.region, sub_band)?.iter() {
+                    self.send_command_ok(Command::SetConfig(ConfigOption::ChMask(*id, *mask)))
+                        .await?;
+                }
+                self.config.sub_band.replace(sub_band);
+            }
+        }
+
+        if let Some(duty_cycle) = config.duty_cycle {
+            self.config.duty_cycle.replace(duty_cycle);
+        }
+
+        if config.fcnt_up.is_some() || config.fcnt_down.is_some() {
+            log::warn!("Frame counters cannot be set on the RAK811, ignoring");
+        }
+
         log::debug!("Config applied");
         Ok(())
     }
@@ -554,3 +1153,76 @@ where
         async move {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_power_level() {
+        assert_eq!(0, power_level(Some(LoraRegion::EU868), 20).unwrap());
+        assert_eq!(1, power_level(Some(LoraRegion::EU868), 14).unwrap());
+        assert_eq!(5, power_level(Some(LoraRegion::EU868), 2).unwrap());
+        assert!(matches!(
+            power_level(Some(LoraRegion::EU868), 13),
+            Err(LoraError::UnsupportedOption)
+        ));
+        assert!(matches!(
+            power_level(Some(LoraRegion::US915), 20),
+            Err(LoraError::UnsupportedOption)
+        ));
+        assert!(matches!(
+            power_level(None, 14),
+            Err(LoraError::UnsupportedOption)
+        ));
+    }
+
+    #[test]
+    fn test_channel_masks() {
+        assert_eq!(
+            [(0, 0x00FF), (1, 0), (2, 0), (3, 0), (4, 0x0001)],
+            channel_masks(Some(LoraRegion::US915), 1).unwrap()
+        );
+        assert_eq!(
+            [(0, 0xFF00), (1, 0), (2, 0), (3, 0), (4, 0x0002)],
+            channel_masks(Some(LoraRegion::US915), 2).unwrap()
+        );
+        assert_eq!(
+            [(0, 0), (1, 0), (2, 0), (3, 0xFF00), (4, 0x0080)],
+            channel_masks(Some(LoraRegion::AU915), 8).unwrap()
+        );
+        assert!(matches!(
+            channel_masks(Some(LoraRegion::US915), 0),
+            Err(LoraError::ConfigError)
+        ));
+        assert!(matches!(
+            channel_masks(Some(LoraRegion::AU915), 9),
+            Err(LoraError::ConfigError)
+        ));
+        assert!(matches!(
+            channel_masks(Some(LoraRegion::EU868), 1),
+            Err(LoraError::UnsupportedOption)
+        ));
+        assert!(matches!(
+            channel_masks(None, 1),
+            Err(LoraError::UnsupportedOption)
+        ));
+    }
+
+    #[test]
+    fn test_region_change_restarts_duty_cycle() {
+        let initialized = Initialized::new();
+        let mut commands: Channel<CommandBuffer, U2> = Channel::new();
+        let mut responses: Channel<Response, U2> = Channel::new();
+        let (cp, _) = commands.split();
+        let (_, rc) = responses.split();
+        let mut controller = Rak811Controller::new(&initialized, cp, rc);
+
+        controller.set_region(LoraRegion::EU868);
+        controller.duty_cycle();
+        controller.set_region(LoraRegion::EU868);
+        assert!(controller.duty_cycle.is_some());
+        controller.set_region(LoraRegion::US915);
+        assert!(controller.duty_cycle.is_none());
+    }
+}
@@ -64,6 +64,20 @@ fn parse_u32(input: &[u8]) -> IResult<&[u8], u32> {
     IResult::Ok((input, atoi_u32(digits).unwrap()))
 }
 
+/// Parse a number with an optional minus sign, saturating at the bounds of `i16`.
+fn parse_i16(input: &[u8]) -> IResult<&[u8], i16> {
+    let (input, sign) = nom::combinator::opt(nom::character::streaming::char('-'))(input)?;
+    let (input, value) = parse_u32(input)?;
+    let value = value.min(i16::MAX as u32) as i16;
+    IResult::Ok((input, if sign.is_some() { -value } else { value }))
+}
+
+/// Parse a number with an optional minus sign, saturating at the bounds of `i8`.
+fn parse_i8(input: &[u8]) -> IResult<&[u8], i8> {
+    let (input, value) = parse_i16(input)?;
+    IResult::Ok((input, value.max(i8::MIN as i16).min(i8::MAX as i16) as i8))
+}
+
 #[rustfmt::skip]
 named!(
     crlf,
@@ -182,20 +196,19 @@ named!(
     pub status<Response>,
     do_parse!(
         tag!("OK") >>
-        tx_ok: parse_u8 >>
+        tx_ok: parse_u32 >>
         char!(',') >>
-        tx_err: parse_u8 >>
+        tx_err: parse_u32 >>
         char!(',') >>
-        rx_ok: parse_u8 >>
+        rx_ok: parse_u32 >>
         char!(',') >>
-        rx_timeout: parse_u8 >>
+        rx_timeout: parse_u32 >>
         char!(',') >>
-        rx_err: parse_u8 >>
+        rx_err: parse_u32 >>
         char!(',') >>
-        rssi_sign: opt!(char!('-')) >>
-        rssi: parse_u8 >>
+        rssi: parse_i16 >>
         char!(',') >>
-        snr: parse_u32 >>
+        snr: parse_i8 >>
         crlf >>
         ( {
             Response::Status {
@@ -204,7 +217,7 @@ named!(
                 rx_ok,
                 rx_timeout,
                 rx_err,
-                rssi: rssi_sign.map(|s| if s == '-' { - (rssi as i8) } else {rssi as i8}).unwrap_or(rssi as i8),
+                rssi,
                 snr,
             }
           }
@@ -212,6 +225,19 @@ named!(
     )
 );
 
+#[rustfmt::skip]
+named!(
+    pub config_value<Response>,
+    do_parse!(
+        tag!("OK") >>
+        value: parse_u32 >>
+        crlf >>
+        (
+            Response::ConfigValue(value)
+        )
+    )
+);
+
 #[rustfmt::skip]
 named!(
     pub welcome<Response>,
@@ -264,6 +290,28 @@ named!(
     )
 );
 
+#[rustfmt::skip]
+named!(
+    pub p2p_recv<Response>,
+    do_parse!(
+        tag!("at+recv=") >>
+        rssi: parse_i16 >>
+        char!(',') >>
+        snr: parse_i8 >>
+        char!(',') >>
+        len: parse_u8 >>
+        char!(':') >>
+        data: take!(len) >>
+        crlf >>
+        ( {
+            let mut buf: [u8; super::RECV_BUFFER_LEN] = [0; super::RECV_BUFFER_LEN];
+            buf[..data.len()].copy_from_slice(data);
+            Response::P2pRecv(rssi, snr, len as usize, buf)
+          }
+        )
+    )
+);
+
 named!(
     pub parse<Response>,
     alt!(
@@ -273,15 +321,78 @@ named!(
         | lora_band
         | mode_info
         | recv
+        | p2p_recv
         | status
+        | config_value
         | welcome
     )
 );
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn test_status() {
+        match parse(b"OK5,1,3,0,0,-123,-7\r\n") {
+            Ok((
+                _,
+                Response::Status {
+                    tx_ok,
+                    tx_err,
+                    rx_ok,
+                    rx_timeout,
+                    rx_err,
+                    rssi,
+                    snr,
+                },
+            )) => {
+                assert_eq!((5, 1, 3, 0, 0), (tx_ok, tx_err, rx_ok, rx_timeout, rx_err));
+                assert_eq!(-123, rssi);
+                assert_eq!(-7, snr);
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        match parse(b"OK1,0,1,0,0,-135,12\r\n") {
+            Ok((_, Response::Status { rssi, snr, .. })) => {
+                assert_eq!(-135, rssi);
+                assert_eq!(12, snr);
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_config_value() {
+        match parse(b"OK5\r\n") {
+            Ok((_, Response::ConfigValue(5))) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_p2p_recv() {
+        match parse(b"at+recv=-62,7,4:ping\r\n") {
+            Ok((_, Response::P2pRecv(rssi, snr, len, data))) => {
+                assert_eq!(-62, rssi);
+                assert_eq!(7, snr);
+                assert_eq!(4, len);
+                assert_eq!(b"ping", &data[..len]);
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        match parse(b"at+recv=-140,-12,2:hi\r\n") {
+            Ok((_, Response::P2pRecv(rssi, snr, len, _))) => {
+                assert_eq!(-140, rssi);
+                assert_eq!(-12, snr);
+                assert_eq!(2, len);
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
 }
@@ -12,6 +12,7 @@ pub enum ConfigKey {
     AppsKey,
     ChMask,
     ChList,
+    Dr,
 }
 
 #[derive(Debug)]
@@ -26,6 +27,10 @@ pub enum Command<'a> {
     GetConfig(ConfigKey),
     Send(QoS, Port, &'a [u8]),
     GetStatus,
+    SetRfConfig(RadioConfig),
+    P2pSend(&'a [u8]),
+    P2pReceive,
+    P2pStopReceive,
 }
 
 #[derive(Debug)]
@@ -37,19 +42,20 @@ pub enum ConfigOption<'a> {
     NwksKey(&'a NwksKey),
     AppsKey(&'a AppsKey),
     ChMask(u8, u16),
+    Class(DeviceClass),
+    PwrLevel(u8),
+    Adr(bool),
+    Dr(u8),
+    /// RX1 delay in seconds.
+    RxDelay1(u8),
+    Nbtrans(u8),
     /*
-    PwrLevel,
-    Adr,
-    Dr,
     PublicNet,
-    RxDelay1,
     Rx2,
     ChList,
     ChMask,
     MaxChs,
     JoinCnt,
-    Nbtrans,
-    Class,
     Duty,*/
 }
 
@@ -62,15 +68,20 @@ pub enum Response {
     FirmwareInfo(FirmwareInfo),
     LoraBand(LoraRegion),
     Recv(EventCode, Port, usize, Option<[u8; super::RECV_BUFFER_LEN]>),
+    /// A packet received in P2P mode, with its RSSI and SNR.
+    P2pRecv(i16, i8, usize, [u8; super::RECV_BUFFER_LEN]),
     Status {
-        tx_ok: u8,
-        tx_err: u8,
-        rx_ok: u8,
-        rx_timeout: u8,
-        rx_err: u8,
-        rssi: i8,
-        snr: u32,
+        tx_ok: u32,
+        tx_err: u32,
+        rx_ok: u32,
+        rx_timeout: u32,
+        rx_err: u32,
+        /// RSSI in dBm and SNR in dB of the last received downlink.
+        rssi: i16,
+        snr: i8,
     },
+    /// A numeric configuration value.
+    ConfigValue(u32),
     Initialized(LoraRegion),
 }
 
@@ -101,6 +112,10 @@ pub struct FirmwareInfo {
 
 pub type CommandBuffer = String<U128>;
 
+/// Largest P2P packet that fits in a command, as two hex digits per byte.
+pub const P2P_MAX_LEN: usize = (128 - "at+txc=1,0,".len() - "\r\n".len()) / 2;
+const P2P_PREAMBLE_LEN: u16 = 8;
+
 impl<'a> Command<'a> {
     pub fn buffer() -> CommandBuffer {
         String::new()
@@ -139,7 +154,8 @@ impl<'a> Command<'a> {
                     s,
                     "at+reset={}",
                     match mode {
-                        ResetMode::Restart => 0,
+                        // The reset pin is driven by the modem, a restart is the closest command.
+                        ResetMode::Restart | ResetMode::Hardware => 0,
                         ResetMode::Reload => 1,
                     }
                 )
@@ -161,6 +177,37 @@ impl<'a> Command<'a> {
             Command::GetStatus => {
                 write!(s, "at+status").unwrap();
             }
+            Command::SetRfConfig(config) => {
+                write!(
+                    s,
+                    "at+rf_config={},{},{},{},{},{}",
+                    config.frequency,
+                    config.spreading_factor as u8,
+                    match config.bandwidth {
+                        Bandwidth::KHz125 => 0,
+                        Bandwidth::KHz250 => 1,
+                        Bandwidth::KHz500 => 2,
+                    },
+                    match config.coding_rate {
+                        CodingRate::CR4_5 => 1,
+                        CodingRate::CR4_6 => 2,
+                        CodingRate::CR4_7 => 3,
+                        CodingRate::CR4_8 => 4,
+                    },
+                    P2P_PREAMBLE_LEN,
+                    config.tx_power,
+                )
+                .unwrap();
+            }
+            Command::P2pSend(data) => {
+                write!(s, "at+txc=1,0,{}", HexSlice(data)).unwrap();
+            }
+            Command::P2pReceive => {
+                write!(s, "at+rxc=1").unwrap();
+            }
+            Command::P2pStopReceive => {
+                write!(s, "at+rx_stop").unwrap();
+            }
         }
     }
 }
@@ -170,7 +217,7 @@ struct HexSlice<'a>(&'a [u8]);
 impl<'a> core::fmt::Display for HexSlice<'a> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::result::Result<(), core::fmt::Error> {
         for b in self.0.iter() {
-            write!(f, "{:x}", b)?;
+            write!(f, "{:02x}", b)?;
         }
         Ok(())
     }
@@ -203,6 +250,9 @@ impl ConfigKey {
             ConfigKey::ChList => {
                 s.push_str("ch_list").unwrap();
             }
+            ConfigKey::Dr => {
+                s.push_str("dr").unwrap();
+            }
         }
     }
 }
@@ -231,6 +281,32 @@ impl<'a> ConfigOption<'a> {
             ConfigOption::ChMask(id, mask) => {
                 write!(s, "ch_mask:{},{:04x}", id, mask).unwrap();
             }
+            ConfigOption::PwrLevel(level) => {
+                write!(s, "pwr_level:{}", level).unwrap();
+            }
+            ConfigOption::Adr(adr) => {
+                write!(s, "adr:{}", if *adr { "on" } else { "off" }).unwrap();
+            }
+            ConfigOption::Dr(dr) => {
+                write!(s, "dr:{}", dr).unwrap();
+            }
+            ConfigOption::RxDelay1(delay) => {
+                write!(s, "rx_delay1:{}", delay).unwrap();
+            }
+            ConfigOption::Nbtrans(n) => {
+                write!(s, "nbtrans:{}", n).unwrap();
+            }
+            ConfigOption::Class(class) => {
+                write!(
+                    s,
+                    "class:{}",
+                    match class {
+                        DeviceClass::A => 0,
+                        DeviceClass::C => 2,
+                    }
+                )
+                .unwrap();
+            }
         }
     }
 }
@@ -343,8 +419,41 @@ impl EventCode {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    fn assert_encoded(expected: &str, option: ConfigOption) {
+        let mut s = Command::buffer();
+        Command::SetConfig(option).encode(&mut s);
+        assert_eq!(expected, s.as_str());
+    }
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn test_encode_config_options() {
+        assert_encoded("at+set_config=pwr_level:1", ConfigOption::PwrLevel(1));
+        assert_encoded("at+set_config=adr:on", ConfigOption::Adr(true));
+        assert_encoded("at+set_config=adr:off", ConfigOption::Adr(false));
+        assert_encoded("at+set_config=dr:5", ConfigOption::Dr(5));
+        assert_encoded("at+set_config=rx_delay1:1", ConfigOption::RxDelay1(1));
+        assert_encoded("at+set_config=nbtrans:3", ConfigOption::Nbtrans(3));
+        assert_encoded(
+            "at+set_config=ch_mask:0,00ff",
+            ConfigOption::ChMask(0, 0x00FF),
+        );
+    }
+
+    #[test]
+    fn test_encode_payload_zero_padded() {
+        let mut s = Command::buffer();
+        Command::Send(QoS::Unconfirmed, 1, &[0x01, 0x0a, 0xf0]).encode(&mut s);
+        assert_eq!("at+send=0,1,010af0", s.as_str());
+
+        let mut s = Command::buffer();
+        Command::P2pSend(&[0x00, 0x0f, 0xff]).encode(&mut s);
+        assert_eq!("at+txc=1,0,000fff", s.as_str());
+    }
 }
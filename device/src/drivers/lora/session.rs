@@ -0,0 +1,230 @@
+use crate::traits::{
+    lora::{AppsKey, DevAddr, NwksKey},
+    storage::{Storage, StorageError},
+};
+use core::future::Future;
+
+const MAGIC: [u8; 4] = *b"LWS2";
+
+/// Length of an encoded session.
+pub const SESSION_LEN: usize = 4 + 4 + 16 + 16 + 4 + 4 + 1 + 1;
+
+/// Number of uplinks between saves of the frame counters. A restored session skips this many
+/// uplink frame counters, so that a counter is never reused after an unsaved uplink.
+pub const SAVE_INTERVAL: u32 = 16;
+
+/// A LoRaWAN session, enough to resume sending without joining again.
+#[derive(Debug, Clone, Copy)]
+pub struct Session {
+    pub dev_addr: DevAddr,
+    pub nwks_key: NwksKey,
+    pub apps_key: AppsKey,
+    pub fcnt_up: u32,
+    /// Frame counter of the last downlink, `None` until the first downlink of the session.
+    pub fcnt_down: Option<u32>,
+}
+
+impl Session {
+    pub fn encode(&self) -> [u8; SESSION_LEN] {
+        let mut data = [0; SESSION_LEN];
+        let dev_addr: [u8; 4] = self.dev_addr.into();
+        let nwks_key: [u8; 16] = self.nwks_key.into();
+        let apps_key: [u8; 16] = self.apps_key.into();
+        data[0..4].copy_from_slice(&MAGIC);
+        data[4..8].copy_from_slice(&dev_addr);
+        data[8..24].copy_from_slice(&nwks_key);
+        data[24..40].copy_from_slice(&apps_key);
+        data[40..44].copy_from_slice(&self.fcnt_up.to_le_bytes());
+        if let Some(fcnt_down) = self.fcnt_down {
+            data[44..48].copy_from_slice(&fcnt_down.to_le_bytes());
+            data[48] = 1;
+        }
+        data[49] = checksum(&data[..49]);
+        data
+    }
+
+    /// Decode a session, returning `None` if no valid session is stored in `data`.
+    pub fn decode(data: &[u8; SESSION_LEN]) -> Option<Self> {
+        if data[0..4] != MAGIC || data[49] != checksum(&data[..49]) {
+            return None;
+        }
+        let mut dev_addr = [0; 4];
+        let mut nwks_key = [0; 16];
+        let mut apps_key = [0; 16];
+        let mut fcnt_up = [0; 4];
+        let mut fcnt_down = [0; 4];
+        dev_addr.copy_from_slice(&data[4..8]);
+        nwks_key.copy_from_slice(&data[8..24]);
+        apps_key.copy_from_slice(&data[24..40]);
+        fcnt_up.copy_from_slice(&data[40..44]);
+        fcnt_down.copy_from_slice(&data[44..48]);
+        Some(Self {
+            dev_addr: dev_addr.into(),
+            nwks_key: nwks_key.into(),
+            apps_key: apps_key.into(),
+            fcnt_up: u32::from_le_bytes(fcnt_up),
+            fcnt_down: match data[48] {
+                0 => None,
+                _ => Some(u32::from_le_bytes(fcnt_down)),
+            },
+        })
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, b| sum.wrapping_add(*b)) ^ 0xFF
+}
+
+/// Keeps a LoRaWAN session in storage, at a fixed offset.
+pub struct SessionStore<S: Storage> {
+    storage: S,
+    offset: u32,
+}
+
+impl<S: Storage> SessionStore<S> {
+    pub fn new(storage: S, offset: u32) -> Self {
+        Self { storage, offset }
+    }
+
+    /// Load the stored session, if any. The uplink frame counter is advanced past any uplinks
+    /// sent after the session was last saved.
+    pub async fn load(&mut self) -> Result<Option<Session>, StorageError> {
+        let mut data = [0; SESSION_LEN];
+        self.storage.read(self.offset, &mut data).await?;
+        Ok(Session::decode(&data).map(|mut session| {
+            session.fcnt_up = session.fcnt_up.saturating_add(SAVE_INTERVAL);
+            session
+        }))
+    }
+
+    pub async fn save(&mut self, session: &Session) -> Result<(), StorageError> {
+        let data = session.encode();
+        self.storage.write(self.offset, &data).await
+    }
+
+    pub async fn clear(&mut self) -> Result<(), StorageError> {
+        self.storage.write(self.offset, &[0; SESSION_LEN]).await
+    }
+}
+
+/// Storage for drivers without a session store, holding no session.
+pub struct NoStorage;
+
+impl Storage for NoStorage {
+    #[rustfmt::skip]
+    type ReadFuture<'m> = impl Future<Output = Result<(), StorageError>> + 'm;
+    fn read<'m>(&'m mut self, _: u32, _: &'m mut [u8]) -> Self::ReadFuture<'m> {
+        async move { Err(StorageError::OutOfBounds) }
+    }
+
+    #[rustfmt::skip]
+    type WriteFuture<'m> = impl Future<Output = Result<(), StorageError>> + 'm;
+    fn write<'m>(&'m mut self, _: u32, _: &'m [u8]) -> Self::WriteFuture<'m> {
+        async move { Err(StorageError::OutOfBounds) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use futures::executor::block_on;
+    use std::vec::Vec;
+
+    struct MemoryStorage {
+        data: Vec<u8>,
+    }
+
+    impl MemoryStorage {
+        fn new(size: usize) -> Self {
+            Self {
+                data: std::vec![0xFF; size],
+            }
+        }
+    }
+
+    impl Storage for MemoryStorage {
+        type ReadFuture<'m> = impl Future<Output = Result<(), StorageError>> + 'm;
+        fn read<'m>(&'m mut self, offset: u32, buf: &'m mut [u8]) -> Self::ReadFuture<'m> {
+            async move {
+                let start = offset as usize;
+                let end = start + buf.len();
+                if end > self.data.len() {
+                    return Err(StorageError::OutOfBounds);
+                }
+                buf.copy_from_slice(&self.data[start..end]);
+                Ok(())
+            }
+        }
+
+        type WriteFuture<'m> = impl Future<Output = Result<(), StorageError>> + 'm;
+        fn write<'m>(&'m mut self, offset: u32, data: &'m [u8]) -> Self::WriteFuture<'m> {
+            async move {
+                let start = offset as usize;
+                let end = start + data.len();
+                if end > self.data.len() {
+                    return Err(StorageError::OutOfBounds);
+                }
+                self.data[start..end].copy_from_slice(data);
+                Ok(())
+            }
+        }
+    }
+
+    fn session() -> Session {
+        Session {
+            dev_addr: "26011F2A".into(),
+            nwks_key: "000102030405060708090A0B0C0D0E0F".into(),
+            apps_key: "F0E0D0C0B0A090807060504030201000".into(),
+            fcnt_up: 42,
+            fcnt_down: Some(7),
+        }
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let session = session();
+        let decoded = Session::decode(&session.encode()).unwrap();
+        assert_eq!(session.encode(), decoded.encode());
+
+        let fresh = Session {
+            fcnt_down: None,
+            ..session
+        };
+        assert_eq!(None, Session::decode(&fresh.encode()).unwrap().fcnt_down);
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(Session::decode(&[0xFF; SESSION_LEN]).is_none());
+
+        let mut data = session().encode();
+        data[10] ^= 0x01;
+        assert!(Session::decode(&data).is_none());
+    }
+
+    #[test]
+    fn test_store() {
+        let mut store = SessionStore::new(MemoryStorage::new(128), 16);
+        assert!(block_on(store.load()).unwrap().is_none());
+
+        block_on(store.save(&session())).unwrap();
+        let loaded = block_on(store.load()).unwrap().unwrap();
+        assert_eq!(42 + SAVE_INTERVAL, loaded.fcnt_up);
+        assert_eq!(Some(7), loaded.fcnt_down);
+        let dev_addr: [u8; 4] = loaded.dev_addr.into();
+        assert_eq!([0x26, 0x01, 0x1F, 0x2A], dev_addr);
+
+        block_on(store.clear()).unwrap();
+        assert!(block_on(store.load()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_store_out_of_bounds() {
+        let mut store = SessionStore::new(MemoryStorage::new(32), 0);
+        assert_eq!(
+            Err(StorageError::OutOfBounds),
+            block_on(store.save(&session()))
+        );
+    }
+}
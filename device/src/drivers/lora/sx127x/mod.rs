@@ -11,16 +11,29 @@ use embedded_hal::{
     digital::v2::OutputPin,
 };
 
+use heapless::{consts::U256, Vec};
 use lorawan_device::{
-    radio, region, Device as LorawanDevice, Error as LorawanError, Event as LorawanEvent,
+    radio::{self, Bandwidth, SpreadingFactor},
+    region, Device as LorawanDevice, Error as LorawanError, Event as LorawanEvent,
     Response as LorawanResponse,
 };
 use lorawan_encoding::default_crypto::DefaultFactory as Crypto;
 
+mod p2p;
 mod sx127x_lora;
 mod sx127x_radio;
 
-use sx127x_radio::{RadioPhyEvent, Sx127xRadio as Radio};
+use super::duty_cycle::DutyCycle;
+use super::session::{NoStorage, Session, SessionStore, SAVE_INTERVAL};
+use crate::traits::storage::Storage;
+use sx127x_radio::{RadioPhyEvent, Sx127xRadio as Radio, MAX_TX_POWER};
+
+/// The radio register driver, implementing `LoraRadio` for point-to-point links.
+pub use sx127x_lora::LoRa;
+
+// Use lower datarate that seems more stable
+const DEFAULT_DATA_RATE: DataRate = DataRate::DR3;
+const DEFAULT_RX_DELAY1: u32 = 5000;
 
 enum DriverState<SPI, CS, RESET, E>
 where
@@ -33,27 +46,45 @@ where
     Configured(LorawanDevice<Radio<SPI, CS, RESET, E>, Crypto>),
 }
 
-pub struct Sx127xDriver<'a, P, SPI, CS, RESET, E>
+pub struct Sx127xDriver<'a, P, SPI, CS, RESET, E, S = NoStorage>
 where
     P: WaitForRisingEdge,
     SPI: Transfer<u8, Error = E> + Write<u8, Error = E> + 'static,
     CS: OutputPin + 'static,
     RESET: OutputPin + 'static,
     E: 'static,
+    S: Storage,
 {
     irq: P,
     state: Option<DriverState<SPI, CS, RESET, E>>,
+    config: Option<LoraConfig>,
+    sessions: Option<SessionStore<S>>,
+    /// Set when the session was restored from storage, making the next join a no-op.
+    restored: bool,
+    /// Uplink frame counter of the last saved session.
+    saved_fcnt_up: u32,
+    /// Frame counter of the last downlink, `None` until the first downlink of the session.
+    fcnt_down: Option<u32>,
+    /// Uplink and downlink counters, the rest of the status is filled in when requested.
+    status: LinkStatus,
+    duty_cycle: Option<DutyCycle>,
     get_random: fn() -> u32,
     _phantom: core::marker::PhantomData<&'a SPI>,
 }
 
+/// How a LoRaWAN device gets its session.
+enum Activation {
+    Otaa(EUI, EUI, AppKey),
+    Session(Session),
+}
+
 pub enum DriverEvent {
     ProcessAfter(u32),
     JoinSuccess,
     JoinFailed,
     SessionExpired,
     Ack,
-    AckWithData(usize, [u8; 255]),
+    AckWithData(Port, usize, [u8; 255]),
     AckTimeout,
     None,
 }
@@ -77,11 +108,243 @@ where
         Ok(Self {
             irq,
             state: Some(DriverState::Initialized(radio)),
+            config: None,
+            sessions: None,
+            restored: false,
+            saved_fcnt_up: 0,
+            fcnt_down: None,
+            status: LinkStatus::default(),
+            duty_cycle: None,
             _phantom: core::marker::PhantomData,
             get_random,
         })
     }
 
+    /// Keep the LoRaWAN session in `storage` at `offset`, so that it survives reboots. A stored
+    /// session is restored when configuring the driver, and joining is skipped.
+    pub fn with_storage<S: Storage>(
+        self,
+        storage: S,
+        offset: u32,
+    ) -> Sx127xDriver<'a, P, SPI, CS, RESET, E, S> {
+        Sx127xDriver {
+            irq: self.irq,
+            state: self.state,
+            config: self.config,
+            sessions: Some(SessionStore::new(storage, offset)),
+            restored: false,
+            saved_fcnt_up: 0,
+            fcnt_down: None,
+            status: self.status,
+            duty_cycle: self.duty_cycle,
+            _phantom: core::marker::PhantomData,
+            get_random: self.get_random,
+        }
+    }
+}
+
+impl<'a, P, SPI, CS, RESET, E, S> Sx127xDriver<'a, P, SPI, CS, RESET, E, S>
+where
+    P: WaitForRisingEdge,
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E> + 'a,
+    CS: OutputPin + 'a,
+    RESET: OutputPin + 'a,
+    S: Storage,
+{
+    /// Create a LoRaWAN device from the configuration, discarding any previous session. A
+    /// device activated with a session can send right away.
+    fn create_device(&mut self, activation: Activation) -> Result<(), LoraError> {
+        let config = self.config.ok_or(LoraError::NotInitialized)?;
+        let mut region = to_region(&config)?;
+        region.set_receive_delay1(config.rx_delay1.unwrap_or(DEFAULT_RX_DELAY1));
+        let mut radio = match self.state.take().unwrap() {
+            DriverState::Initialized(radio) => radio,
+            DriverState::Configured(mut lorawan) => lorawan.get_radio().take(),
+        };
+        if let Some(tx_power) = config.tx_power {
+            if let Err(e) = radio.set_tx_power(tx_power) {
+                self.state.replace(DriverState::Initialized(radio));
+                return Err(e);
+            }
+        }
+        self.fcnt_down = None;
+        let mut lorawan: LorawanDevice<Radio<SPI, CS, RESET, E>, Crypto> = match activation {
+            Activation::Otaa(dev_eui, app_eui, app_key) => LorawanDevice::new(
+                region,
+                radio,
+                dev_eui.reverse().into(),
+                app_eui.reverse().into(),
+                app_key.into(),
+                self.get_random,
+            ),
+            Activation::Session(session) => {
+                let mut lorawan = LorawanDevice::new_abp(
+                    region,
+                    radio,
+                    session.dev_addr.reverse().into(),
+                    session.nwks_key.into(),
+                    session.apps_key.into(),
+                    self.get_random,
+                );
+                lorawan.set_fcnt_up(session.fcnt_up);
+                lorawan.set_fcnt_down(session.fcnt_down.unwrap_or(0));
+                self.fcnt_down = session.fcnt_down;
+                lorawan
+            }
+        };
+        lorawan.set_datarate(to_datarate(config.data_rate.unwrap_or(DEFAULT_DATA_RATE)));
+        self.state.replace(DriverState::Configured(lorawan));
+        self.restored = false;
+        self.saved_fcnt_up = 0;
+        Ok(())
+    }
+
+    /// The session of the current LoRaWAN device, if joined.
+    fn session(&mut self) -> Option<Session> {
+        match self.state.as_mut() {
+            Some(DriverState::Configured(lorawan)) => {
+                let keys = lorawan.get_session_keys()?;
+                let mut dev_addr = [0; 4];
+                dev_addr.copy_from_slice(keys.devaddr().as_ref());
+                Some(Session {
+                    dev_addr: DevAddr::from(dev_addr).reverse(),
+                    nwks_key: keys.newskey().inner().0.into(),
+                    apps_key: keys.appskey().inner().0.into(),
+                    fcnt_up: lorawan.get_fcnt_up(),
+                    fcnt_down: self.fcnt_down,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Save the current session, if a session store is used.
+    async fn save_session(&mut self) {
+        if self.sessions.is_none() {
+            return;
+        }
+        if let Some(session) = self.session() {
+            if let Some(sessions) = self.sessions.as_mut() {
+                if sessions.save(&session).await.is_err() {
+                    warn!("Unable to save LoRaWAN session");
+                } else {
+                    self.saved_fcnt_up = session.fcnt_up;
+                }
+            }
+        }
+    }
+
+    fn is_class_c(&self) -> bool {
+        matches!(
+            self.config.and_then(|c| c.device_class),
+            Some(DeviceClass::C)
+        )
+    }
+
+    /// Keep receiving on the RX2 parameters between uplinks when configured for Class C.
+    fn listen(&mut self) {
+        if !self.is_class_c() {
+            return;
+        }
+        let region = self
+            .config
+            .and_then(|c| c.region)
+            .unwrap_or(LoraRegion::EU868);
+        if let (
+            Ok((frequency, spreading_factor, bandwidth)),
+            Some(DriverState::Configured(lorawan)),
+        ) = (rx2(region), self.state.as_mut())
+        {
+            if lorawan
+                .get_radio()
+                .listen(frequency, spreading_factor, bandwidth)
+                .is_err()
+            {
+                error!("Unable to listen for downlinks");
+            }
+        }
+    }
+
+    async fn receive(&mut self) -> Result<Downlink, LoraError> {
+        if !self.is_class_c() {
+            return Err(LoraError::NotReady);
+        }
+        loop {
+            let quality = match self.state.as_mut() {
+                Some(DriverState::Configured(lorawan)) => lorawan.get_radio().take_packet(),
+                _ => return Err(LoraError::NotReady),
+            };
+            match quality {
+                Some((rssi, snr)) => {
+                    if let Some(downlink) = self.decode_downlink(rssi, snr) {
+                        self.status.rx_ok += 1;
+                        return Ok(downlink);
+                    }
+                }
+                None => self.irq.wait_for_rising_edge().await,
+            }
+        }
+    }
+
+    /// Decode a packet received while listening, dropping it unless it is a data downlink for
+    /// the current session.
+    fn decode_downlink(&mut self, rssi: i16, snr: i8) -> Option<Downlink> {
+        use lorawan_encoding::parser::{
+            parse_with_factory, DataHeader, DataPayload, FRMPayload, PhyPayload,
+        };
+
+        let lorawan = match self.state.as_mut() {
+            Some(DriverState::Configured(lorawan)) => lorawan,
+            _ => return None,
+        };
+        let keys = lorawan.get_session_keys()?;
+        let mut packet: Vec<u8, U256> =
+            Vec::from_slice(lorawan.get_radio().get_received_packet().as_ref()).ok()?;
+        let encrypted = match parse_with_factory(&mut packet[..], Crypto::default()) {
+            Ok(PhyPayload::Data(DataPayload::Encrypted(encrypted))) => encrypted,
+            _ => {
+                trace!("Ignoring packet that is not a data downlink");
+                return None;
+            }
+        };
+        if encrypted.fhdr().dev_addr().as_ref() != keys.devaddr().as_ref() {
+            trace!("Ignoring downlink for another device");
+            return None;
+        }
+        let fcnt = match extend_fcnt(self.fcnt_down, encrypted.fhdr().fcnt()) {
+            Some(fcnt) => fcnt,
+            None => {
+                warn!("Ignoring replayed downlink");
+                self.status.rx_err += 1;
+                return None;
+            }
+        };
+        if !encrypted.validate_mic(keys.newskey(), fcnt) {
+            warn!("Ignoring downlink with invalid MIC");
+            self.status.rx_err += 1;
+            return None;
+        }
+        let decrypted = encrypted
+            .decrypt(Some(keys.newskey()), Some(keys.appskey()), fcnt)
+            .ok()?;
+        lorawan.set_fcnt_down(fcnt);
+        self.fcnt_down = Some(fcnt);
+
+        let port = decrypted.f_port()?;
+        match decrypted.frm_payload() {
+            Ok(FRMPayload::Data(data)) => {
+                trace!("Downlink received \t\t(FCntDown={}\tFRM: {:?})", fcnt, data);
+                Some(Downlink {
+                    port,
+                    data: Vec::from_slice(data).ok()?,
+                    rssi: Some(rssi),
+                    snr: Some(snr),
+                })
+            }
+            _ => None,
+        }
+    }
+
     fn process_event(&mut self, event: LorawanEvent<'a, Radio<SPI, CS, RESET, E>>) -> DriverEvent {
         // crate::log_stack!();
         match self.state.take().unwrap() {
@@ -118,7 +381,7 @@ where
     }
 
     fn process_response(
-        &self,
+        &mut self,
         lorawan: &mut LorawanDevice<Radio<SPI, CS, RESET, E>, Crypto>,
         response: Result<LorawanResponse, LorawanError<Radio<SPI, CS, RESET, E>>>,
     ) -> DriverEvent {
@@ -136,6 +399,7 @@ where
                     trace!("RxWindow expired but no ACK expected. Ready to Send");
                 }
                 LorawanResponse::DownlinkReceived(fcnt_down) => {
+                    self.fcnt_down = Some(fcnt_down);
                     if let Some(downlink) = lorawan.take_data_downlink() {
                         use lorawan_encoding::parser::FRMPayload;
 
@@ -145,9 +409,10 @@ where
                                 fcnt_down,
                                 data,
                             );
+                            let port = downlink.f_port().unwrap_or(0);
                             let mut buf = [0; 255];
                             buf[0..data.len()].copy_from_slice(&data[0..data.len()]);
-                            return DriverEvent::AckWithData(data.len(), buf);
+                            return DriverEvent::AckWithData(port, data.len(), buf);
                         } else {
                             trace!("Downlink received \t\t(FcntDown={})", fcnt_down);
                             return DriverEvent::Ack;
@@ -232,6 +497,82 @@ where
         }
     }
 
+    /// The radio has no soft reset, so every reset mode pulls the reset pin. Any LoRaWAN
+    /// session is discarded, including a stored one, and the network must be joined again.
+    async fn reset(&mut self) -> Result<(), LoraError> {
+        let mut radio = match self.state.take().unwrap() {
+            DriverState::Initialized(radio) => radio,
+            DriverState::Configured(mut lorawan) => lorawan.get_radio().take(),
+        };
+        let result = radio.reset().await;
+        self.state.replace(DriverState::Initialized(radio));
+        self.restored = false;
+        if let Some(sessions) = self.sessions.as_mut() {
+            if sessions.clear().await.is_err() {
+                warn!("Unable to clear stored LoRaWAN session");
+            }
+        }
+        result
+    }
+
+    fn link_status(&mut self) -> LinkStatus {
+        let mut status = self.status;
+        let radio = match self.state.as_mut() {
+            Some(DriverState::Configured(lorawan)) => {
+                status.joined = lorawan.get_session_keys().is_some();
+                status.data_rate = Some(
+                    self.config
+                        .and_then(|c| c.data_rate)
+                        .unwrap_or(DEFAULT_DATA_RATE),
+                );
+                lorawan.get_radio()
+            }
+            Some(DriverState::Initialized(radio)) => radio,
+            None => return status,
+        };
+        if let Some((rssi, snr)) = radio.last_rx_quality() {
+            status.rssi.replace(rssi);
+            status.snr.replace(snr);
+        }
+        status
+    }
+
+    /// Account the last transmission of the radio against the duty-cycle limits.
+    fn record_transmission(&mut self) {
+        if let (Some(DriverState::Configured(lorawan)), Some(duty_cycle)) =
+            (self.state.as_mut(), self.duty_cycle.as_mut())
+        {
+            if let Some((frequency, time_on_air)) = lorawan.get_radio().take_last_tx() {
+                duty_cycle.record(frequency, Instant::now(), time_on_air);
+            }
+        }
+    }
+
+    /// Wait until the duty-cycle limits allow transmitting, or fail if the policy is to reject.
+    async fn check_duty_cycle(&mut self, policy: DutyCyclePolicy) -> Result<(), LoraError> {
+        let next = match self.duty_cycle.as_ref() {
+            Some(duty_cycle) => duty_cycle.next_allowed(),
+            None => return Ok(()),
+        };
+        if next <= Instant::now() {
+            return Ok(());
+        }
+        match policy {
+            DutyCyclePolicy::Wait => {
+                trace!("Waiting for duty cycle");
+                Timer::at(next).await;
+                Ok(())
+            }
+            DutyCyclePolicy::Reject => Err(LoraError::DutyCycleExceeded),
+        }
+    }
+
+    fn duty_cycle_policy(&self) -> DutyCyclePolicy {
+        self.config
+            .and_then(|c| c.duty_cycle)
+            .unwrap_or(DutyCyclePolicy::Reject)
+    }
+
     async fn send_data(
         &mut self,
         qos: QoS,
@@ -266,13 +607,42 @@ where
         }
     }
 
+    /// Send an uplink.
+    async fn uplink(
+        &mut self,
+        qos: QoS,
+        port: Port,
+        data: &[u8],
+        rx: Option<&mut [u8]>,
+    ) -> Result<(Port, usize), LoraError> {
+        self.check_duty_cycle(self.duty_cycle_policy()).await?;
+        let result = self.send_recv(qos, port, data, rx).await;
+        self.record_transmission();
+        match result {
+            Ok(_) => self.status.tx_ok += 1,
+            Err(_) => self.status.tx_err += 1,
+        }
+        // Save before the counters skipped on restore are used up
+        let used = match self.state.as_mut() {
+            Some(DriverState::Configured(lorawan)) => {
+                lorawan.get_fcnt_up().wrapping_sub(self.saved_fcnt_up)
+            }
+            _ => 0,
+        };
+        if used >= SAVE_INTERVAL {
+            self.save_session().await;
+        }
+        self.listen();
+        result
+    }
+
     async fn send_recv(
         &mut self,
         qos: QoS,
         port: Port,
         data: &[u8],
         rx: Option<&mut [u8]>,
-    ) -> Result<usize, LoraError> {
+    ) -> Result<(Port, usize), LoraError> {
         // Await response
         let mut event = self.send_data(qos, port, data).await?;
         loop {
@@ -290,20 +660,23 @@ where
                         }
                     }
                 }
-                DriverEvent::AckWithData(len, buf) => {
+                DriverEvent::AckWithData(port, len, buf) => {
                     trace!("Received {} bytes of data", len);
+                    self.status.rx_ok += 1;
                     if let Some(rx) = rx {
                         rx[0..len].copy_from_slice(&buf[0..len]);
                     }
-                    return Ok(len);
+                    return Ok((port, len));
                 }
                 DriverEvent::AckTimeout => {
                     trace!("Ack timed out!");
+                    self.status.rx_timeout += 1;
                     return Err(LoraError::AckTimeout);
                 }
                 DriverEvent::Ack => {
                     trace!("Ack received!");
-                    return Ok(0);
+                    self.status.rx_ok += 1;
+                    return Ok((0, 0));
                 }
                 _ => {
                     // Wait for interrupt
@@ -317,71 +690,123 @@ where
     }
 }
 
-impl<'a, P, SPI, CS, RESET, E> LoraDriver for Sx127xDriver<'a, P, SPI, CS, RESET, E>
+impl<'a, P, SPI, CS, RESET, E, S> LoraDriver for Sx127xDriver<'a, P, SPI, CS, RESET, E, S>
 where
     P: WaitForRisingEdge + 'a,
     SPI: Transfer<u8, Error = E> + Write<u8, Error = E> + 'a,
     E: 'a,
     CS: OutputPin + 'a,
     RESET: OutputPin + 'a,
+    S: Storage + 'a,
 {
     #[rustfmt::skip]
     type ConfigureFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
     fn configure<'m>(&'m mut self, config: &'m LoraConfig) -> Self::ConfigureFuture<'m> {
         async move {
-            match self.state.take().unwrap() {
-                DriverState::Initialized(radio) => {
-                    //info!("Configuring radio");
-                    let dev_eui = config.device_eui.as_ref().expect("device EUI must be set");
-                    let app_eui = config.app_eui.as_ref().expect("app EUI must be set");
-                    let app_key = config.app_key.as_ref().expect("app KEY must be set");
-                    //info!("Creating device");
-                    let region = to_region(config.region.unwrap_or(LoraRegion::EU868));
-                    if let Err(e) = region {
-                        return Err(e);
-                    }
-                    let mut region = region.unwrap();
-                    region.set_receive_delay1(5000);
-                    let mut lorawan: LorawanDevice<Radio<SPI, CS, RESET, E>, Crypto> =
-                        LorawanDevice::new(
-                            region,
-                            radio,
-                            dev_eui.reverse().into(),
-                            app_eui.reverse().into(),
-                            app_key.clone().into(),
-                            self.get_random,
-                        );
-                    lorawan.set_datarate(region::DR::_3); // Use lower datarate that seems more stable
-                    self.state.replace(DriverState::Configured(lorawan));
-                    Ok(())
-                }
-                other => {
-                    //info!("Driver not yet initialized, ignoring configuration");
-                    self.state.replace(other);
-                    Err(LoraError::OtherError)
+            to_region(config)?;
+            if config.adr == Some(true) {
+                error!("Adaptive data rate is not supported");
+                return Err(LoraError::UnsupportedOption);
+            }
+            if config.retries.unwrap_or(0) > 0 {
+                // Retransmitting with the same frame counter is not supported by the stack
+                error!("Retransmissions are not supported");
+                return Err(LoraError::UnsupportedOption);
+            }
+            if let Some(tx_power) = config.tx_power {
+                if !(0..=MAX_TX_POWER).contains(&tx_power) {
+                    error!("TX power must be between 0 and {} dBm", MAX_TX_POWER);
+                    return Err(LoraError::UnsupportedOption);
                 }
             }
+            if otaa_credentials(config).is_none() && abp_credentials(config).is_none() {
+                error!("Neither OTAA nor ABP credentials are set");
+                return Err(LoraError::ConfigError);
+            }
+            let region = config.region.unwrap_or(LoraRegion::EU868);
+            if self.duty_cycle.is_none()
+                || self
+                    .config
+                    .and_then(|c| c.region)
+                    .unwrap_or(LoraRegion::EU868)
+                    != region
+            {
+                self.duty_cycle.replace(DutyCycle::new(region));
+            }
+            self.config.replace(*config);
+
+            let restored = match self.sessions.as_mut() {
+                Some(sessions) => sessions.load().await.unwrap_or_else(|_| {
+                    warn!("Unable to load stored LoRaWAN session");
+                    None
+                }),
+                None => None,
+            };
+            if let Some(session) = restored {
+                info!("Restoring stored LoRaWAN session");
+                self.create_device(Activation::Session(session))?;
+                self.restored = true;
+                // Make sure the skipped frame counters are not reused after another reboot
+                self.save_session().await;
+            }
+            Ok(())
         }
     }
 
     #[rustfmt::skip]
     type JoinFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
-    fn join<'m>(&'m mut self, _: ConnectMode) -> Self::JoinFuture<'m> {
-        async move { self.join().await }
+    fn join<'m>(&'m mut self, mode: ConnectMode) -> Self::JoinFuture<'m> {
+        async move {
+            if self.restored {
+                info!("Using restored session, skipping join");
+                self.restored = false;
+                self.listen();
+                return Ok(());
+            }
+            let config = self.config.ok_or(LoraError::NotInitialized)?;
+            match mode {
+                ConnectMode::OTAA => {
+                    let (dev_eui, app_eui, app_key) =
+                        otaa_credentials(&config).ok_or(LoraError::ConfigError)?;
+                    self.check_duty_cycle(self.duty_cycle_policy()).await?;
+                    self.create_device(Activation::Otaa(dev_eui, app_eui, app_key))?;
+                    let result = self.join().await;
+                    self.record_transmission();
+                    result?;
+                }
+                ConnectMode::ABP => {
+                    let (dev_addr, nwks_key, apps_key) =
+                        abp_credentials(&config).ok_or(LoraError::ConfigError)?;
+                    // The session is created with the device
+                    self.create_device(Activation::Session(Session {
+                        dev_addr,
+                        nwks_key,
+                        apps_key,
+                        fcnt_up: config.fcnt_up.unwrap_or(0),
+                        fcnt_down: config.fcnt_down,
+                    }))?;
+                }
+            }
+            self.save_session().await;
+            self.listen();
+            Ok(())
+        }
     }
-    /*
-    fn reset(self, message: Reset) -> Response<Self, Result<(), LoraError>> {
-        Response::immediate(self, Err(LoraError::OtherError))
+
+    #[rustfmt::skip]
+    type ResetFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn reset<'m>(&'m mut self, _: ResetMode) -> Self::ResetFuture<'m> {
+        async move { self.reset().await }
     }
-    */
+
     #[rustfmt::skip]
     type SendFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
     fn send<'m>(&'m mut self, qos: QoS, port: Port, data: &'m [u8]) -> Self::SendFuture<'m> {
-        async move { self.send_recv(qos, port, data, None).await.map(|_| ()) }
+        async move { self.uplink(qos, port, data, None).await.map(|_| ()) }
     }
 
     #[rustfmt::skip]
-    type SendRecvFuture<'m> where 'a: 'm = impl Future<Output = Result<usize, LoraError>> + 'm;
+    type SendRecvFuture<'m> where 'a: 'm = impl Future<Output = Result<(Port, usize), LoraError>> + 'm;
     fn send_recv<'m>(
         &'m mut self,
         qos: QoS,
@@ -389,15 +814,344 @@ where
         data: &'m [u8],
         rx: &'m mut [u8],
     ) -> Self::SendRecvFuture<'m> {
-        async move { self.send_recv(qos, port, data, Some(rx)).await }
+        async move { self.uplink(qos, port, data, Some(rx)).await }
+    }
+
+    #[rustfmt::skip]
+    type ReceiveFuture<'m> where 'a: 'm = impl Future<Output = Result<Downlink, LoraError>> + 'm;
+    fn receive<'m>(&'m mut self) -> Self::ReceiveFuture<'m> {
+        async move { self.receive().await }
+    }
+
+    #[rustfmt::skip]
+    type LinkStatusFuture<'m> where 'a: 'm = impl Future<Output = Result<LinkStatus, LoraError>> + 'm;
+    fn link_status<'m>(&'m mut self) -> Self::LinkStatusFuture<'m> {
+        async move { Ok(self.link_status()) }
+    }
+
+    #[rustfmt::skip]
+    type NextSendAllowedFuture<'m> where 'a: 'm = impl Future<Output = Instant> + 'm;
+    fn next_send_allowed<'m>(&'m mut self) -> Self::NextSendAllowedFuture<'m> {
+        async move {
+            self.duty_cycle
+                .as_ref()
+                .map(|duty_cycle| duty_cycle.next_allowed())
+                .unwrap_or_else(|| Instant::from_ticks(0))
+        }
     }
 }
 
-fn to_region(region: LoraRegion) -> Result<region::Configuration, LoraError> {
+fn otaa_credentials(config: &LoraConfig) -> Option<(EUI, EUI, AppKey)> {
+    Some((config.device_eui?, config.app_eui?, config.app_key?))
+}
+
+fn abp_credentials(config: &LoraConfig) -> Option<(DevAddr, NwksKey, AppsKey)> {
+    Some((config.device_address?, config.nwks_key?, config.apps_key?))
+}
+
+/// Frequency, spreading factor and bandwidth of the RX2 window, used for Class C reception.
+fn rx2(region: LoraRegion) -> Result<(u32, SpreadingFactor, Bandwidth), LoraError> {
+    match region {
+        LoraRegion::EU868 => Ok((869_525_000, SpreadingFactor::_12, Bandwidth::_125KHz)),
+        LoraRegion::US915 => Ok((923_300_000, SpreadingFactor::_12, Bandwidth::_500KHz)),
+        LoraRegion::AU915 => Ok((923_300_000, SpreadingFactor::_12, Bandwidth::_500KHz)),
+        LoraRegion::KR920 => Ok((921_900_000, SpreadingFactor::_12, Bandwidth::_125KHz)),
+        LoraRegion::AS923 => Ok((923_200_000, SpreadingFactor::_10, Bandwidth::_125KHz)),
+        LoraRegion::IN865 => Ok((866_550_000, SpreadingFactor::_10, Bandwidth::_125KHz)),
+        LoraRegion::CN470 => Ok((505_300_000, SpreadingFactor::_12, Bandwidth::_125KHz)),
+        LoraRegion::UNKNOWN => Err(LoraError::UnsupportedRegion),
+    }
+}
+
+/// Extend the 16-bit frame counter of a downlink to 32 bits, using the last counter received.
+/// Fails for a counter that was already used; any counter is accepted while `last` is `None`,
+/// before the first downlink of a session. An older downlink replayed after its counter is taken
+/// as rolled over, and fails the MIC check.
+fn extend_fcnt(last: Option<u32>, fcnt: u16) -> Option<u32> {
+    let last = match last {
+        Some(last) => last,
+        None => return Some(fcnt as u32),
+    };
+    let mut extended = (last & 0xFFFF_0000) | fcnt as u32;
+    if extended < last {
+        extended = extended.checked_add(0x1_0000)?;
+    }
+    if extended > last {
+        Some(extended)
+    } else {
+        None
+    }
+}
+
+fn to_datarate(data_rate: DataRate) -> region::DR {
+    match data_rate {
+        DataRate::DR0 => region::DR::_0,
+        DataRate::DR1 => region::DR::_1,
+        DataRate::DR2 => region::DR::_2,
+        DataRate::DR3 => region::DR::_3,
+        DataRate::DR4 => region::DR::_4,
+        DataRate::DR5 => region::DR::_5,
+        DataRate::DR6 => region::DR::_6,
+        DataRate::DR7 => region::DR::_7,
+        DataRate::DR8 => region::DR::_8,
+        DataRate::DR9 => region::DR::_9,
+        DataRate::DR10 => region::DR::_10,
+        DataRate::DR11 => region::DR::_11,
+        DataRate::DR12 => region::DR::_12,
+        DataRate::DR13 => region::DR::_13,
+        DataRate::DR14 => region::DR::_14,
+        DataRate::DR15 => region::DR::_15,
+    }
+}
+
+/// Channel plan of the configured region. A sub-band is only supported by the US915 and AU915
+/// plans, which have 64 uplink channels.
+fn to_region(config: &LoraConfig) -> Result<region::Configuration, LoraError> {
+    let region = config.region.unwrap_or(LoraRegion::EU868);
+    match (region, config.sub_band) {
+        (LoraRegion::US915, Some(sub_band)) | (LoraRegion::AU915, Some(sub_band))
+            if !(1..=8).contains(&sub_band) =>
+        {
+            return Err(LoraError::ConfigError)
+        }
+        (LoraRegion::US915, _) | (LoraRegion::AU915, _) | (_, None) => {}
+        (_, Some(_)) => return Err(LoraError::UnsupportedOption),
+    }
     match region {
         LoraRegion::EU868 => Ok(region::EU868::default().into()),
-        LoraRegion::US915 => Ok(region::US915::default().into()),
+        LoraRegion::US915 => {
+            let mut us915 = region::US915::default();
+            if let Some(sub_band) = config.sub_band {
+                us915.set_subband(sub_band);
+            }
+            Ok(us915.into())
+        }
+        LoraRegion::AU915 => {
+            let mut au915 = region::AU915::default();
+            if let Some(sub_band) = config.sub_band {
+                au915.set_subband(sub_band);
+            }
+            Ok(au915.into())
+        }
+        LoraRegion::KR920 => Ok(region::KR920::default().into()),
+        LoraRegion::AS923 => Ok(region::AS923::default().into()),
+        LoraRegion::IN865 => Ok(region::IN865::default().into()),
         LoraRegion::CN470 => Ok(region::CN470::default().into()),
-        _ => Err(LoraError::UnsupportedRegion),
+        LoraRegion::UNKNOWN => Err(LoraError::UnsupportedRegion),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::super::session::SESSION_LEN;
+    use super::*;
+    use crate::traits::storage::StorageError;
+    use core::cell::Cell;
+    use core::convert::Infallible;
+    use futures::executor::block_on;
+    use std::rc::Rc;
+    use std::vec::Vec as StdVec;
+
+    #[test]
+    fn test_to_region() {
+        for region in [
+            LoraRegion::EU868,
+            LoraRegion::US915,
+            LoraRegion::AU915,
+            LoraRegion::KR920,
+            LoraRegion::AS923,
+            LoraRegion::IN865,
+            LoraRegion::CN470,
+        ]
+        .iter()
+        {
+            assert!(to_region(&LoraConfig::new().region(*region)).is_ok());
+            assert!(rx2(*region).is_ok());
+        }
+        assert!(to_region(&LoraConfig::new()).is_ok());
+        assert!(matches!(
+            to_region(&LoraConfig::new().region(LoraRegion::UNKNOWN)),
+            Err(LoraError::UnsupportedRegion)
+        ));
+    }
+
+    #[test]
+    fn test_extend_fcnt() {
+        assert_eq!(Some(0), extend_fcnt(None, 0));
+        assert_eq!(Some(7), extend_fcnt(None, 7));
+        assert_eq!(Some(1), extend_fcnt(Some(0), 1));
+        assert_eq!(Some(6), extend_fcnt(Some(5), 6));
+        assert_eq!(Some(0x1_0002), extend_fcnt(Some(0xFFFE), 2));
+        assert_eq!(Some(0x2_0010), extend_fcnt(Some(0x2_0001), 0x10));
+        // Replays
+        assert_eq!(None, extend_fcnt(Some(0), 0));
+        assert_eq!(None, extend_fcnt(Some(5), 5));
+        assert_eq!(Some(0x1_0004), extend_fcnt(Some(5), 4));
+        assert_eq!(None, extend_fcnt(Some(0xFFFF_FFF0), 2));
+    }
+
+    #[test]
+    fn test_to_region_sub_band() {
+        for region in [LoraRegion::US915, LoraRegion::AU915].iter() {
+            let config = LoraConfig::new().region(*region);
+            assert!(to_region(&config.sub_band(1)).is_ok());
+            assert!(to_region(&config.sub_band(8)).is_ok());
+            assert!(matches!(
+                to_region(&config.sub_band(0)),
+                Err(LoraError::ConfigError)
+            ));
+            assert!(matches!(
+                to_region(&config.sub_band(9)),
+                Err(LoraError::ConfigError)
+            ));
+        }
+        assert!(matches!(
+            to_region(&LoraConfig::new().region(LoraRegion::EU868).sub_band(2)),
+            Err(LoraError::UnsupportedOption)
+        ));
+        assert!(matches!(
+            to_region(&LoraConfig::new().region(LoraRegion::AS923).sub_band(2)),
+            Err(LoraError::UnsupportedOption)
+        ));
+    }
+
+    /// A radio that accepts every register write, and reads the expected version. Counts the
+    /// SPI operations performed, in a counter shared with the test.
+    #[derive(Default)]
+    struct MockSpi {
+        operations: Rc<Cell<usize>>,
+    }
+
+    impl Transfer<u8> for MockSpi {
+        type Error = Infallible;
+        fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+            self.operations.set(self.operations.get() + 1);
+            for word in words.iter_mut().skip(1) {
+                *word = 0x12;
+            }
+            Ok(words)
+        }
+    }
+
+    impl Write<u8> for MockSpi {
+        type Error = Infallible;
+        fn write(&mut self, _: &[u8]) -> Result<(), Infallible> {
+            self.operations.set(self.operations.get() + 1);
+            Ok(())
+        }
+    }
+
+    struct MockPin;
+
+    impl OutputPin for MockPin {
+        type Error = Infallible;
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    struct MockDelay;
+
+    impl DelayMs<u8> for MockDelay {
+        fn delay_ms(&mut self, _: u8) {}
+    }
+
+    /// An interrupt that never fires.
+    struct MockIrq;
+
+    impl WaitForRisingEdge for MockIrq {
+        type Future<'m> = core::future::Pending<()>;
+        fn wait_for_rising_edge<'m>(&'m mut self) -> Self::Future<'m> {
+            core::future::pending()
+        }
+    }
+
+    struct MemoryStorage {
+        data: StdVec<u8>,
+    }
+
+    impl Storage for MemoryStorage {
+        type ReadFuture<'m> = impl Future<Output = Result<(), StorageError>> + 'm;
+        fn read<'m>(&'m mut self, offset: u32, buf: &'m mut [u8]) -> Self::ReadFuture<'m> {
+            async move {
+                let start = offset as usize;
+                buf.copy_from_slice(&self.data[start..start + buf.len()]);
+                Ok(())
+            }
+        }
+
+        type WriteFuture<'m> = impl Future<Output = Result<(), StorageError>> + 'm;
+        fn write<'m>(&'m mut self, offset: u32, data: &'m [u8]) -> Self::WriteFuture<'m> {
+            async move {
+                let start = offset as usize;
+                self.data[start..start + data.len()].copy_from_slice(data);
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn test_restored_session_skips_join() {
+        let session = Session {
+            dev_addr: "26011F2A".into(),
+            nwks_key: "000102030405060708090A0B0C0D0E0F".into(),
+            apps_key: "F0E0D0C0B0A090807060504030201000".into(),
+            fcnt_up: 42,
+            fcnt_down: Some(7),
+        };
+        let mut storage = MemoryStorage {
+            data: std::vec![0xFF; 64],
+        };
+        storage.data[..SESSION_LEN].copy_from_slice(&session.encode());
+
+        let spi = MockSpi::default();
+        let operations = spi.operations.clone();
+        let mut driver = Sx127xDriver::new(MockIrq, spi, MockPin, MockPin, &mut MockDelay, || 4)
+            .unwrap()
+            .with_storage(storage, 0);
+        let config = LoraConfig::new()
+            .region(LoraRegion::EU868)
+            .device_eui(&"0102030405060708".into())
+            .app_eui(&"0807060504030201".into())
+            .app_key(&"000102030405060708090A0B0C0D0E0F".into());
+        assert!(block_on(driver.configure(&config)).is_ok());
+
+        // Joining neither touches the radio nor waits for a join accept
+        let configured = operations.get();
+        assert!(configured > 0);
+        assert!(block_on(driver.join(ConnectMode::OTAA)).is_ok());
+        assert_eq!(configured, operations.get());
+
+        let restored = driver.session().unwrap();
+        assert_eq!(42 + SAVE_INTERVAL, restored.fcnt_up);
+        assert_eq!(Some(7), restored.fcnt_down);
+        assert_eq!(42 + SAVE_INTERVAL, driver.saved_fcnt_up);
+    }
+
+    #[test]
+    fn test_configure_retries() {
+        let mut driver = Sx127xDriver::new(
+            MockIrq,
+            MockSpi::default(),
+            MockPin,
+            MockPin,
+            &mut MockDelay,
+            || 4,
+        )
+        .unwrap();
+        let config = LoraConfig::new()
+            .region(LoraRegion::EU868)
+            .device_eui(&"0102030405060708".into())
+            .app_eui(&"0807060504030201".into())
+            .app_key(&"000102030405060708090A0B0C0D0E0F".into());
+        assert!(matches!(
+            block_on(driver.configure(&config.retries(2))),
+            Err(LoraError::UnsupportedOption)
+        ));
+        assert!(block_on(driver.configure(&config.retries(0))).is_ok());
     }
 }
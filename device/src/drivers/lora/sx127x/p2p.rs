@@ -0,0 +1,134 @@
+use super::sx127x_lora::{LoRa, RadioMode};
+use crate::time::{with_timeout, Duration, Timer};
+use crate::traits::lora::*;
+use core::future::Future;
+use embedded_hal::{
+    blocking::spi::{Transfer, Write},
+    digital::v2::OutputPin,
+};
+
+/// How often the radio is polled while transmitting or receiving.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+const MAX_PACKET_LEN: usize = 255;
+/// Payload CRC error flag in the IRQ flags register.
+const IRQ_CRC_ERROR: u8 = 0x20;
+
+/// Point-to-point links using the radio directly. The radio is polled rather than relying on
+/// the DIO0 interrupt, so any pins will do.
+impl<SPI, CS, RESET, E> LoraRadio for LoRa<SPI, CS, RESET>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    CS: OutputPin,
+    RESET: OutputPin,
+{
+    #[rustfmt::skip]
+    type ConfigureRadioFuture<'m> where Self: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn configure_radio<'m>(
+        &'m mut self,
+        config: &'m RadioConfig,
+    ) -> Self::ConfigureRadioFuture<'m> {
+        async move {
+            // The RFO output supports 0 to 14 dBm
+            if !(0..=14).contains(&config.tx_power) {
+                return Err(LoraError::UnsupportedOption);
+            }
+            let result = (|| {
+                self.set_mode(RadioMode::Stdby)?;
+                self.set_tx_power(config.tx_power.into(), 0)?;
+                self.set_frequency(config.frequency)?;
+                self.set_spreading_factor(config.spreading_factor as u8)?;
+                self.set_signal_bandwidth(match config.bandwidth {
+                    Bandwidth::KHz125 => 125_000,
+                    Bandwidth::KHz250 => 250_000,
+                    Bandwidth::KHz500 => 500_000,
+                })?;
+                self.set_coding_rate_4(match config.coding_rate {
+                    CodingRate::CR4_5 => 5,
+                    CodingRate::CR4_6 => 6,
+                    CodingRate::CR4_7 => 7,
+                    CodingRate::CR4_8 => 8,
+                })?;
+                self.set_preamble_length(8)?;
+                self.set_lora_pa_ramp()?;
+                self.set_lora_sync_word()?;
+                self.set_invert_iq(false)?;
+                self.set_crc(true)
+            })();
+            result.map_err(|_| LoraError::ConfigError)
+        }
+    }
+
+    #[rustfmt::skip]
+    type TransmitFuture<'m> where Self: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn transmit<'m>(&'m mut self, data: &'m [u8]) -> Self::TransmitFuture<'m> {
+        async move {
+            if data.len() > MAX_PACKET_LEN {
+                return Err(LoraError::SendError);
+            }
+            let mut payload = [0; MAX_PACKET_LEN];
+            payload[..data.len()].copy_from_slice(data);
+            self.transmit_payload(payload, data.len())
+                .map_err(|_| LoraError::SendError)?;
+            while self.transmitting().map_err(|_| LoraError::SendError)? {
+                Timer::after(POLL_INTERVAL).await;
+            }
+            Ok(())
+        }
+    }
+
+    #[rustfmt::skip]
+    type ReceivePacketFuture<'m> where Self: 'm = impl Future<Output = Result<RadioPacket, LoraError>> + 'm;
+    fn receive_packet<'m>(
+        &'m mut self,
+        rx: &'m mut [u8],
+        timeout: Duration,
+    ) -> Self::ReceivePacketFuture<'m> {
+        async move {
+            self.set_mode(RadioMode::RxContinuous)
+                .map_err(|_| LoraError::RecvError)?;
+            let ready = with_timeout(timeout, async {
+                while !self.packet_ready().map_err(|_| LoraError::RecvError)? {
+                    Timer::after(POLL_INTERVAL).await;
+                }
+                Ok(())
+            })
+            .await;
+
+            let result = match ready {
+                Ok(Ok(())) => read_packet(self, rx),
+                Ok(Err(e)) => Err(e),
+                Err(_) => Err(LoraError::RecvTimeout),
+            };
+            self.set_mode(RadioMode::Stdby)
+                .map_err(|_| LoraError::RecvError)?;
+            result
+        }
+    }
+}
+
+fn read_packet<SPI, CS, RESET, E>(
+    lora: &mut LoRa<SPI, CS, RESET>,
+    rx: &mut [u8],
+) -> Result<RadioPacket, LoraError>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    CS: OutputPin,
+    RESET: OutputPin,
+{
+    let flags = lora.irq_flags().map_err(|_| LoraError::RecvError)?;
+    let len = lora.read_packet_size().map_err(|_| LoraError::RecvError)?;
+    // Reading the packet clears the IRQ flags
+    let packet = lora.read_packet().map_err(|_| LoraError::RecvError)?;
+    if flags & IRQ_CRC_ERROR != 0 {
+        return Err(LoraError::RecvError);
+    }
+    if len > rx.len() {
+        return Err(LoraError::RecvBufferTooSmall);
+    }
+    rx[..len].copy_from_slice(&packet[..len]);
+    Ok(RadioPacket {
+        len,
+        rssi: lora.get_packet_rssi().unwrap_or(0) as i16,
+        snr: lora.get_packet_snr().unwrap_or(0.0) as i8,
+    })
+}
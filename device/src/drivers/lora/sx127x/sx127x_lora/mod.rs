@@ -74,22 +74,38 @@ where
             explicit_header: true,
             mode: RadioMode::Sleep,
         };
-        sx127x.reset.set_low().map_err(Reset)?;
+        sx127x.assert_reset()?;
         delay.delay_ms(10);
-        sx127x.reset.set_high().map_err(Reset)?;
+        sx127x.release_reset()?;
         delay.delay_ms(10);
-        let version = sx127x.read_register(Register::RegVersion.addr())?;
+        sx127x.init()?;
+        Ok(sx127x)
+    }
+
+    /// Pulls the reset pin low, holding the radio in reset until `release_reset` is called.
+    pub fn assert_reset(&mut self) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        self.reset.set_low().map_err(Reset)
+    }
+
+    /// Releases the reset pin. The radio must be given time to start before calling `init`.
+    pub fn release_reset(&mut self) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        self.reset.set_high().map_err(Reset)
+    }
+
+    /// Checks the radio version and puts a freshly reset radio in standby.
+    pub fn init(&mut self) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        let version = self.read_register(Register::RegVersion.addr())?;
         if version == VERSION_CHECK {
-            sx127x.set_mode(RadioMode::Sleep)?;
-            sx127x.set_frequency(frequency)?;
-            sx127x.write_register(Register::RegFifoTxBaseAddr.addr(), 0)?;
-            sx127x.write_register(Register::RegFifoRxBaseAddr.addr(), 0)?;
-            let lna = sx127x.read_register(Register::RegLna.addr())?;
-            sx127x.write_register(Register::RegLna.addr(), lna | 0x03)?;
-            sx127x.write_register(Register::RegModemConfig3.addr(), 0x04)?;
-            sx127x.set_mode(RadioMode::Stdby)?;
-            sx127x.cs.set_high().map_err(CS)?;
-            Ok(sx127x)
+            self.set_mode(RadioMode::Sleep)?;
+            self.set_frequency(self.frequency)?;
+            self.write_register(Register::RegFifoTxBaseAddr.addr(), 0)?;
+            self.write_register(Register::RegFifoRxBaseAddr.addr(), 0)?;
+            let lna = self.read_register(Register::RegLna.addr())?;
+            self.write_register(Register::RegLna.addr(), lna | 0x03)?;
+            self.write_register(Register::RegModemConfig3.addr(), 0x04)?;
+            self.set_mode(RadioMode::Stdby)?;
+            self.cs.set_high().map_err(CS)?;
+            Ok(())
         } else {
             Err(Error::VersionMismatch(version))
         }
@@ -1,3 +1,4 @@
+use crate::time::{Duration, Timer};
 use crate::traits::lora::LoraError as DriverError;
 use embedded_hal::blocking::delay::DelayMs;
 use embedded_hal::blocking::spi::{Transfer, Write};
@@ -11,17 +12,26 @@ use lorawan_device::{
     Timings,
 };
 
+use super::super::duty_cycle::Modulation;
 use super::sx127x_lora::{LoRa, RadioMode, IRQ};
 
+const RESET_DELAY: Duration = Duration::from_millis(10);
+const DEFAULT_TX_POWER: i8 = 14;
+pub const MAX_TX_POWER: i8 = 14;
+
 pub struct Sx127xRadio<SPI, CS, RESET, E>
 where
     SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
     CS: OutputPin,
     RESET: OutputPin,
 {
-    radio: LoRa<SPI, CS, RESET>,
+    radio: Option<LoRa<SPI, CS, RESET>>,
     radio_state: State,
     buffer: RadioBuffer,
+    tx_power: i8,
+    last_rx: Option<(i16, i8)>,
+    /// Frequency and time on air of the last transmission.
+    last_tx: Option<(u32, Duration)>,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -64,12 +74,138 @@ where
     ) -> Result<Self, DriverError> {
         Ok(Self {
             radio_state: State::Idle,
-            radio: LoRa::new(spi, cs, reset, 867_100_000, delay)
-                .map_err(|_| DriverError::OtherError)?,
+            radio: Some(
+                LoRa::new(spi, cs, reset, 867_100_000, delay)
+                    .map_err(|_| DriverError::OtherError)?,
+            ),
             buffer: RadioBuffer { packet: Vec::new() },
+            tx_power: DEFAULT_TX_POWER,
+            last_rx: None,
+            last_tx: None,
         })
     }
 
+    /// Set the TX power in dBm used for uplinks. The RFO output supports 0 to 14 dBm.
+    pub fn set_tx_power(&mut self, tx_power: i8) -> Result<(), DriverError> {
+        if (0..=MAX_TX_POWER).contains(&tx_power) {
+            self.tx_power = tx_power;
+            Ok(())
+        } else {
+            Err(DriverError::UnsupportedOption)
+        }
+    }
+
+    fn lora(&mut self) -> &mut LoRa<SPI, CS, RESET> {
+        self.radio.as_mut().expect("radio taken")
+    }
+
+    /// Move the radio out of this instance, typically one owned by a LoRaWAN device that is
+    /// about to be discarded. This instance must not be used afterwards.
+    pub fn take(&mut self) -> Self {
+        Self {
+            radio: self.radio.take(),
+            radio_state: State::Idle,
+            buffer: RadioBuffer::default(),
+            tx_power: self.tx_power,
+            last_rx: self.last_rx,
+            last_tx: self.last_tx.take(),
+        }
+    }
+
+    /// Reset the radio using its reset pin and put it back in standby.
+    pub async fn reset(&mut self) -> Result<(), DriverError> {
+        self.lora()
+            .assert_reset()
+            .map_err(|_| DriverError::OtherError)?;
+        Timer::after(RESET_DELAY).await;
+        self.lora()
+            .release_reset()
+            .map_err(|_| DriverError::OtherError)?;
+        Timer::after(RESET_DELAY).await;
+        self.lora().init().map_err(|_| DriverError::OtherError)?;
+        self.radio_state = State::Idle;
+        self.buffer.clear_buf();
+        Ok(())
+    }
+
+    fn start_rx(
+        &mut self,
+        frequency: u32,
+        spreading_factor: SpreadingFactor,
+        bandwidth: Bandwidth,
+    ) -> Result<(), ()> {
+        let result = (move || {
+            self.lora().reset_payload_length()?;
+            self.lora().set_frequency(frequency)?;
+            // TODO: Modify radio to support other coding rates
+            self.lora().set_coding_rate_4(5)?;
+            self.lora()
+                .set_signal_bandwidth(bandwidth_to_i64(bandwidth))?;
+            self.lora()
+                .set_spreading_factor(spreading_factor_to_u8(spreading_factor))?;
+
+            self.lora().set_preamble_length(8)?;
+            self.lora().set_lora_sync_word()?;
+            self.lora().set_invert_iq(true)?;
+            self.lora().set_crc(true)?;
+
+            self.lora().set_dio0_rx_done()?;
+            self.lora().set_mode(RadioMode::RxContinuous)
+
+            /*
+            let irq_flags = self.radio.irq_flags().ok().unwrap();
+            let irq_flags_mask = self.radio.irq_flags_mask().ok().unwrap();
+            log::info!(
+                "RX STARTED, IRQ Flags: 0x{:x}, Mask: 0x{:x}",
+                irq_flags,
+                irq_flags_mask
+            );
+            r*/
+        })();
+        result.map_err(|_| ())
+    }
+
+    /// Receive continuously with the given parameters outside of the LoRaWAN receive windows,
+    /// until the next request from the LoRaWAN stack.
+    pub fn listen(
+        &mut self,
+        frequency: u32,
+        spreading_factor: SpreadingFactor,
+        bandwidth: Bandwidth,
+    ) -> Result<(), DriverError> {
+        self.start_rx(frequency, spreading_factor, bandwidth)
+            .map_err(|_| DriverError::RecvError)?;
+        self.radio_state = State::Idle;
+        Ok(())
+    }
+
+    /// Read a packet received while listening into the packet buffer, returning its signal
+    /// strength and signal to noise ratio. Returns `None` if no packet is available.
+    pub fn take_packet(&mut self) -> Option<(i16, i8)> {
+        if !self.lora().packet_ready().unwrap_or(false) {
+            return None;
+        }
+        self.lora().clear_irq().ok()?;
+        let rssi = self.lora().get_packet_rssi().unwrap_or(0) as i16;
+        let snr = self.lora().get_packet_snr().unwrap_or(0.0) as i8;
+        let size = self.lora().read_packet_size().ok()?;
+        let packet = self.lora().read_packet().ok()?;
+        self.buffer.packet.clear();
+        self.buffer.packet.extend_from_slice(&packet[..size]).ok()?;
+        self.last_rx.replace((rssi, snr));
+        Some((rssi, snr))
+    }
+
+    /// Signal strength and signal to noise ratio of the last received packet.
+    pub fn last_rx_quality(&self) -> Option<(i16, i8)> {
+        self.last_rx
+    }
+
+    /// Frequency and time on air of the last transmission, if not taken already.
+    pub fn take_last_tx(&mut self) -> Option<(u32, Duration)> {
+        self.last_tx.take()
+    }
+
     pub fn handle_event_idle(
         &mut self,
         event: LoraEvent<Self>,
@@ -77,28 +213,37 @@ where
         match event {
             LoraEvent::TxRequest(config, buf) => {
                 //log::trace!("Set config: {:?}", config);
+                let modulation = Modulation::new(
+                    spreading_factor_to_u8(config.rf.spreading_factor),
+                    bandwidth_to_i64(config.rf.bandwidth) as u32,
+                );
+                self.last_tx.replace((
+                    config.rf.frequency,
+                    modulation.time_on_air(buf.packet.len()),
+                ));
+                let tx_power = self.tx_power;
                 let result = (move || {
-                    self.radio.set_tx_power(14, 0)?;
-                    self.radio.set_frequency(config.rf.frequency)?;
+                    self.lora().set_tx_power(tx_power.into(), 0)?;
+                    self.lora().set_frequency(config.rf.frequency)?;
                     // TODO: Modify radio to support other coding rates
-                    self.radio.set_coding_rate_4(5)?;
-                    self.radio
+                    self.lora().set_coding_rate_4(5)?;
+                    self.lora()
                         .set_signal_bandwidth(bandwidth_to_i64(config.rf.bandwidth))?;
-                    self.radio
+                    self.lora()
                         .set_spreading_factor(spreading_factor_to_u8(config.rf.spreading_factor))?;
 
-                    self.radio.set_preamble_length(8)?;
-                    self.radio.set_lora_pa_ramp()?;
-                    self.radio.set_lora_sync_word()?;
-                    self.radio.set_invert_iq(false)?;
-                    self.radio.set_crc(true)?;
+                    self.lora().set_preamble_length(8)?;
+                    self.lora().set_lora_pa_ramp()?;
+                    self.lora().set_lora_sync_word()?;
+                    self.lora().set_invert_iq(false)?;
+                    self.lora().set_crc(true)?;
 
                     let len = buf.packet.len();
                     assert!(len < 255);
                     let mut payload = [0; 255];
                     payload[..len].copy_from_slice(&buf.packet[..len]);
-                    self.radio.set_dio0_tx_done()?;
-                    self.radio.transmit_payload(payload, len)
+                    self.lora().set_dio0_tx_done()?;
+                    self.lora().transmit_payload(payload, len)
                 })();
                 match result {
                     Ok(_) => (State::Txing, Ok(LoraResponse::Txing)),
@@ -107,34 +252,8 @@ where
             }
             LoraEvent::RxRequest(config) => {
                 // log::trace!("Set RX config: {:?}", config);
-                let result = (move || {
-                    self.radio.reset_payload_length()?;
-                    self.radio.set_frequency(config.frequency)?;
-                    // TODO: Modify radio to support other coding rates
-                    self.radio.set_coding_rate_4(5)?;
-                    self.radio
-                        .set_signal_bandwidth(bandwidth_to_i64(config.bandwidth))?;
-                    self.radio
-                        .set_spreading_factor(spreading_factor_to_u8(config.spreading_factor))?;
-
-                    self.radio.set_preamble_length(8)?;
-                    self.radio.set_lora_sync_word()?;
-                    self.radio.set_invert_iq(true)?;
-                    self.radio.set_crc(true)?;
-
-                    self.radio.set_dio0_rx_done()?;
-                    self.radio.set_mode(RadioMode::RxContinuous)
-
-                    /*
-                    let irq_flags = self.radio.irq_flags().ok().unwrap();
-                    let irq_flags_mask = self.radio.irq_flags_mask().ok().unwrap();
-                    log::info!(
-                        "RX STARTED, IRQ Flags: 0x{:x}, Mask: 0x{:x}",
-                        irq_flags,
-                        irq_flags_mask
-                    );
-                    r*/
-                })();
+                let result =
+                    self.start_rx(config.frequency, config.spreading_factor, config.bandwidth);
                 match result {
                     Ok(_) => (State::Rxing, Ok(LoraResponse::Rxing)),
                     Err(_) => (State::Rxing, Err(LoraError::PhyError(()))),
@@ -153,8 +272,8 @@ where
         match event {
             LoraEvent::PhyEvent(phyevent) => match phyevent {
                 RadioPhyEvent::Irq => {
-                    self.radio.set_mode(RadioMode::Stdby).ok().unwrap();
-                    let irq = self.radio.clear_irq().ok().unwrap();
+                    self.lora().set_mode(RadioMode::Stdby).ok().unwrap();
+                    let irq = self.lora().clear_irq().ok().unwrap();
                     if (irq & IRQ::IrqTxDoneMask.addr()) != 0 {
                         (State::Idle, Ok(LoraResponse::TxDone(0)))
                     } else {
@@ -175,13 +294,13 @@ where
         match event {
             LoraEvent::PhyEvent(phyevent) => match phyevent {
                 RadioPhyEvent::Irq => {
-                    self.radio.set_mode(RadioMode::Stdby).ok().unwrap();
-                    let irq = self.radio.clear_irq().ok().unwrap();
+                    self.lora().set_mode(RadioMode::Stdby).ok().unwrap();
+                    let irq = self.lora().clear_irq().ok().unwrap();
                     if (irq & IRQ::IrqRxDoneMask.addr()) != 0 {
-                        let rssi = self.radio.get_packet_rssi().unwrap_or(0) as i16;
-                        let snr = self.radio.get_packet_snr().unwrap_or(0.0) as i8;
-                        if let Ok(size) = self.radio.read_packet_size() {
-                            if let Ok(packet) = self.radio.read_packet() {
+                        let rssi = self.lora().get_packet_rssi().unwrap_or(0) as i16;
+                        let snr = self.lora().get_packet_snr().unwrap_or(0.0) as i8;
+                        if let Ok(size) = self.lora().read_packet_size() {
+                            if let Ok(packet) = self.lora().read_packet() {
                                 self.buffer.packet.clear();
                                 self.buffer
                                     .packet
@@ -190,7 +309,8 @@ where
                                     .unwrap();
                             }
                         }
-                        self.radio.set_mode(RadioMode::Sleep).ok().unwrap();
+                        self.lora().set_mode(RadioMode::Sleep).ok().unwrap();
+                        self.last_rx.replace((rssi, snr));
                         (
                             State::Idle,
                             Ok(LoraResponse::RxDone(RxQuality::new(rssi, snr))),
@@ -201,7 +321,7 @@ where
                 }
             },
             LoraEvent::CancelRx => {
-                self.radio.set_mode(RadioMode::Sleep).ok().unwrap();
+                self.lora().set_mode(RadioMode::Sleep).ok().unwrap();
                 (State::Idle, Ok(LoraResponse::Idle))
             }
             LoraEvent::TxRequest(_, _) => (State::Rxing, Err(LoraError::TxRequestDuringTx)),
@@ -1,3 +1,4 @@
 pub mod led;
 pub mod lora;
+pub mod net;
 pub mod wifi;
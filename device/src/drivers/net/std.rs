@@ -0,0 +1,334 @@
+//! Network stack backed by the host operating system
+//!
+//! Implements `TcpStack`, `UdpStack` and `WifiSupplicant` on top of `std::net` using `async-io`, so
+//! that network applications can run on a development machine. Joining a network always succeeds
+//! immediately.
+//!
+//! Like the ESP8266 driver, reading from a TCP socket returns the data currently available, and
+//! returns 0 when no data has been received yet. Reads on a socket with a timeout wait for data
+//! instead.
+
+use crate::traits::{
+    ip::{IpAddress, IpProtocol, SocketAddress},
+    tcp::{Readiness, TcpError, TcpStack},
+    udp::{self, UdpError},
+    wifi::{AccessPointInfo, Join, JoinError, WifiError, WifiStatus, WifiSupplicant},
+};
+use ::std::io::{ErrorKind, Read, Write};
+use ::std::net::{self, TcpStream, UdpSocket};
+use ::std::vec::Vec;
+use async_io::Async;
+use core::future::Future;
+use embassy::time::{Duration, Instant, Timer};
+use heapless::String;
+
+/// Maximum amount of data reported by `available`.
+const PEEK_LEN: usize = 1024;
+
+/// Interval between attempts while a read or write with a timeout waits for the socket.
+const RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+enum Socket {
+    Unbound,
+    Tcp(Async<TcpStream>),
+    Udp(Async<UdpSocket>),
+}
+
+pub struct StdNetwork {
+    sockets: Vec<Option<Socket>>,
+    timeouts: Vec<Option<Duration>>,
+}
+
+impl StdNetwork {
+    pub fn new() -> Self {
+        Self {
+            sockets: Vec::new(),
+            timeouts: Vec::new(),
+        }
+    }
+
+    fn allocate(&mut self) -> usize {
+        match self.sockets.iter().position(|s| s.is_none()) {
+            Some(index) => {
+                self.sockets[index].replace(Socket::Unbound);
+                self.timeouts[index] = None;
+                index
+            }
+            None => {
+                self.sockets.push(Some(Socket::Unbound));
+                self.timeouts.push(None);
+                self.sockets.len() - 1
+            }
+        }
+    }
+
+    /// Deadline of an operation on a socket starting now.
+    fn deadline(&self, handle: usize) -> Option<Instant> {
+        self.timeouts
+            .get(handle)
+            .copied()
+            .flatten()
+            .map(|timeout| Instant::now() + timeout)
+    }
+
+    fn tcp(&self, handle: usize) -> Option<&Async<TcpStream>> {
+        match self.sockets.get(handle) {
+            Some(Some(Socket::Tcp(stream))) => Some(stream),
+            _ => None,
+        }
+    }
+
+    fn udp(&self, handle: usize) -> Option<&Async<UdpSocket>> {
+        match self.sockets.get(handle) {
+            Some(Some(Socket::Udp(socket))) => Some(socket),
+            _ => None,
+        }
+    }
+
+    fn release(&mut self, handle: usize) {
+        if let Some(socket) = self.sockets.get_mut(handle) {
+            socket.take();
+        }
+    }
+}
+
+impl Default for StdNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn to_std(address: SocketAddress) -> net::SocketAddr {
+    match address.ip() {
+        IpAddress::V4(ip) => net::SocketAddr::from((ip.octets(), address.port())),
+    }
+}
+
+impl WifiSupplicant for StdNetwork {
+    type JoinFuture<'m> = impl Future<Output = Result<IpAddress, JoinError>> + 'm;
+    fn join<'m>(&'m mut self, _: Join<'m>) -> Self::JoinFuture<'m> {
+        async move { Ok(IpAddress::new_v4(127, 0, 0, 1)) }
+    }
+
+    type ScanFuture<'m> = impl Future<Output = Result<usize, WifiError>> + 'm;
+    fn scan<'m>(&'m mut self, _: &'m mut [AccessPointInfo]) -> Self::ScanFuture<'m> {
+        async move { Ok(0) }
+    }
+
+    type DisconnectFuture<'m> = impl Future<Output = Result<(), WifiError>> + 'm;
+    fn disconnect<'m>(&'m mut self) -> Self::DisconnectFuture<'m> {
+        async move { Ok(()) }
+    }
+
+    type StatusFuture<'m> = impl Future<Output = Result<Option<WifiStatus>, WifiError>> + 'm;
+    fn status<'m>(&'m mut self) -> Self::StatusFuture<'m> {
+        async move {
+            Ok(Some(WifiStatus {
+                ssid: String::from("std"),
+                ip: Some(IpAddress::new_v4(127, 0, 0, 1)),
+                rssi: 0,
+                channel: 0,
+            }))
+        }
+    }
+}
+
+impl TcpStack for StdNetwork {
+    type SocketHandle = usize;
+
+    type OpenFuture<'m> = impl Future<Output = Self::SocketHandle> + 'm;
+    fn open<'m>(&'m mut self) -> Self::OpenFuture<'m> {
+        async move { self.allocate() }
+    }
+
+    type ConnectFuture<'m> = impl Future<Output = Result<(), TcpError>> + 'm;
+    fn connect<'m>(
+        &'m mut self,
+        handle: Self::SocketHandle,
+        _: IpProtocol,
+        dst: SocketAddress,
+    ) -> Self::ConnectFuture<'m> {
+        async move {
+            let stream = Async::<TcpStream>::connect(to_std(dst))
+                .await
+                .map_err(|_| TcpError::ConnectError)?;
+            match self.sockets.get_mut(handle) {
+                Some(slot) if slot.is_some() => {
+                    slot.replace(Socket::Tcp(stream));
+                    Ok(())
+                }
+                _ => Err(TcpError::SocketClosed),
+            }
+        }
+    }
+
+    type WriteFuture<'m> = impl Future<Output = Result<usize, TcpError>> + 'm;
+    fn write<'m>(&'m mut self, handle: Self::SocketHandle, buf: &'m [u8]) -> Self::WriteFuture<'m> {
+        async move {
+            let deadline = match self.deadline(handle) {
+                Some(deadline) => deadline,
+                None => {
+                    let stream = self.tcp(handle).ok_or(TcpError::SocketClosed)?;
+                    return stream
+                        .write_with(|s| (&*s).write(buf))
+                        .await
+                        .map_err(|_| TcpError::WriteError);
+                }
+            };
+            loop {
+                let stream = self.tcp(handle).ok_or(TcpError::SocketClosed)?;
+                match stream.get_ref().write(buf) {
+                    Ok(len) => return Ok(len),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(_) => return Err(TcpError::WriteError),
+                }
+                if Instant::now() >= deadline {
+                    return Err(TcpError::Timeout);
+                }
+                Timer::after(RETRY_INTERVAL).await;
+            }
+        }
+    }
+
+    type ReadFuture<'m> = impl Future<Output = Result<usize, TcpError>> + 'm;
+    fn read<'m>(
+        &'m mut self,
+        handle: Self::SocketHandle,
+        buf: &'m mut [u8],
+    ) -> Self::ReadFuture<'m> {
+        async move {
+            let deadline = self.deadline(handle);
+            loop {
+                let stream = self.tcp(handle).ok_or(TcpError::SocketClosed)?;
+                // The socket is non-blocking, so this only returns data already received
+                match stream.get_ref().read(buf) {
+                    Ok(0) if !buf.is_empty() => return Err(TcpError::SocketClosed),
+                    Ok(len) => return Ok(len),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(_) => return Err(TcpError::ReadError),
+                }
+                match deadline {
+                    Some(deadline) if !buf.is_empty() => {
+                        if Instant::now() >= deadline {
+                            return Err(TcpError::Timeout);
+                        }
+                        Timer::after(RETRY_INTERVAL).await;
+                    }
+                    _ => return Ok(0),
+                }
+            }
+        }
+    }
+
+    type CloseFuture<'m> = impl Future<Output = ()> + 'm;
+    fn close<'m>(&'m mut self, handle: Self::SocketHandle) -> Self::CloseFuture<'m> {
+        async move { self.release(handle) }
+    }
+
+    fn close_detached(&mut self, handle: Self::SocketHandle) {
+        self.release(handle)
+    }
+
+    type PollReadableFuture<'m> = impl Future<Output = Result<Readiness, TcpError>> + 'm;
+    fn poll_readable<'m>(&'m mut self, handle: Self::SocketHandle) -> Self::PollReadableFuture<'m> {
+        async move {
+            let stream = self.tcp(handle).ok_or(TcpError::SocketClosed)?;
+            match stream.get_ref().peek(&mut [0; 1]) {
+                Ok(0) => Ok(Readiness::HalfClosed),
+                Ok(_) => Ok(Readiness::Readable),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(Readiness::Pending),
+                Err(_) => Err(TcpError::ReadError),
+            }
+        }
+    }
+
+    type AvailableFuture<'m> = impl Future<Output = Result<usize, TcpError>> + 'm;
+    fn available<'m>(&'m mut self, handle: Self::SocketHandle) -> Self::AvailableFuture<'m> {
+        async move {
+            let stream = self.tcp(handle).ok_or(TcpError::SocketClosed)?;
+            // The operating system does not report the amount of data buffered, so it is peeked at
+            let mut buf = [0; PEEK_LEN];
+            match stream.get_ref().peek(&mut buf) {
+                Ok(0) => Err(TcpError::SocketClosed),
+                Ok(len) => Ok(len),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(0),
+                Err(_) => Err(TcpError::ReadError),
+            }
+        }
+    }
+
+    type SetTimeoutFuture<'m> = impl Future<Output = Result<(), TcpError>> + 'm;
+    fn set_timeout<'m>(
+        &'m mut self,
+        handle: Self::SocketHandle,
+        timeout: Option<Duration>,
+    ) -> Self::SetTimeoutFuture<'m> {
+        async move {
+            match self.sockets.get(handle) {
+                Some(Some(_)) => {
+                    self.timeouts[handle] = timeout;
+                    Ok(())
+                }
+                _ => Err(TcpError::SocketClosed),
+            }
+        }
+    }
+}
+
+// The trait is not imported, as its methods share names with those of `TcpStack`.
+impl udp::UdpStack for StdNetwork {
+    type SocketHandle = usize;
+
+    type OpenFuture<'m> = impl Future<Output = Self::SocketHandle> + 'm;
+    fn open<'m>(&'m mut self) -> Self::OpenFuture<'m> {
+        async move { self.allocate() }
+    }
+
+    type ConnectFuture<'m> = impl Future<Output = Result<(), UdpError>> + 'm;
+    fn connect<'m>(
+        &'m mut self,
+        handle: Self::SocketHandle,
+        dst: SocketAddress,
+    ) -> Self::ConnectFuture<'m> {
+        async move {
+            let socket =
+                Async::<UdpSocket>::bind(([0, 0, 0, 0], 0)).map_err(|_| UdpError::ConnectError)?;
+            socket
+                .get_ref()
+                .connect(to_std(dst))
+                .map_err(|_| UdpError::ConnectError)?;
+            match self.sockets.get_mut(handle) {
+                Some(slot) if slot.is_some() => {
+                    slot.replace(Socket::Udp(socket));
+                    Ok(())
+                }
+                _ => Err(UdpError::SocketClosed),
+            }
+        }
+    }
+
+    type SendFuture<'m> = impl Future<Output = Result<usize, UdpError>> + 'm;
+    fn send<'m>(&'m mut self, handle: Self::SocketHandle, buf: &'m [u8]) -> Self::SendFuture<'m> {
+        async move {
+            let socket = self.udp(handle).ok_or(UdpError::SocketClosed)?;
+            socket.send(buf).await.map_err(|_| UdpError::SendError)
+        }
+    }
+
+    type RecvFuture<'m> = impl Future<Output = Result<usize, UdpError>> + 'm;
+    fn recv<'m>(
+        &'m mut self,
+        handle: Self::SocketHandle,
+        buf: &'m mut [u8],
+    ) -> Self::RecvFuture<'m> {
+        async move {
+            let socket = self.udp(handle).ok_or(UdpError::SocketClosed)?;
+            socket.recv(buf).await.map_err(|_| UdpError::RecvError)
+        }
+    }
+
+    type CloseFuture<'m> = impl Future<Output = ()> + 'm;
+    fn close<'m>(&'m mut self, handle: Self::SocketHandle) -> Self::CloseFuture<'m> {
+        async move { self.release(handle) }
+    }
+}
@@ -29,6 +29,26 @@ impl Buffer {
         }
     }
 
+    /// The link id and length of an active mode `+IPD` frame, once its header has been buffered.
+    pub fn frame_header(&self) -> Option<(usize, usize)> {
+        match parser::frame_header(&self.buffer[0..self.pos]) {
+            Ok((remainder, header)) if remainder.is_empty() => Some(header),
+            _ => None,
+        }
+    }
+
+    /// Whether the buffer holds nothing but line endings.
+    pub fn is_blank(&self) -> bool {
+        self.buffer[0..self.pos]
+            .iter()
+            .all(|b| *b == b'\r' || *b == b'\n')
+    }
+
+    pub fn clear(&mut self) {
+        self.pos = 0;
+        self.needs_parse = false;
+    }
+
     pub fn parse(&mut self) -> Result<Response, ()> {
         if self.pos == 0 {
             return Ok(Response::None);
@@ -1,12 +1,30 @@
 //! Esp8266 Async Driver
 //!
 //! An async driver for the Esp8266 AT-command firmware. The driver implements the drogue-network APIs for
-//! WifiSupplicant and TcpStack.
+//! WifiSupplicant, AccessPoint, TcpStack, TcpServer, UdpStack and ModemInfo.
+//!
+//! By default, received data is kept by the modem until it is read using `AT+CIPRECVDATA`. A
+//! driver created with `Esp8266Driver::active()` instead has data pushed by the modem as it
+//! arrives, and one created with `Esp8266Driver::passthrough()` uses the modem's transparent
+//! passthrough mode for a single connection. Only these reserve memory for received TCP data,
+//! while datagrams are pushed by the modem and buffered in every mode.
+//!
+//! A modem that does not respond to a command in time, or sends data that cannot be parsed, is
+//! reset using its pins and initialized again. Its connections are lost in the process.
+//!
+//! The IP address, hostname, DNS servers and SNTP server of the station can be configured on the
+//! `Esp8266Controller`. The configuration is applied every time a network is joined, so it is kept
+//! when the modem is reset.
+//!
+//! Both the NonOS AT firmware 1.x of ESP8266 modules and the ESP-AT 2.x firmware of ESP32 modules
+//! are supported, selected using the firmware version reported by the modem. AT firmware older
+//! than 1.7 is refused.
 
 mod buffer;
 mod num;
 mod parser;
 mod protocol;
+mod receive;
 mod socket_pool;
 
 use crate::fmt::*;
@@ -16,28 +34,85 @@ use crate::{
     kernel::{actor::Actor, channel::*},
     traits::{
         ip::{IpAddress, IpProtocol, SocketAddress},
-        tcp::{TcpError, TcpStack},
-        wifi::{Join, JoinError, WifiSupplicant},
+        modem::{ModemDetails, ModemError, ModemInfo, Version},
+        tcp::{Readiness, TcpError, TcpServer, TcpStack},
+        udp::{self, UdpError},
+        wifi::{
+            AccessPoint, AccessPointInfo, Join, JoinError, StationInfo, WifiError, WifiStatus,
+            WifiSupplicant,
+        },
     },
 };
 use buffer::Buffer;
 use core::{
+    cell::Cell,
     future::Future,
     pin::Pin,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 use embassy::{
     io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt},
+    time::{with_timeout, Duration, Instant, Timer},
     util::Signal,
 };
 use embedded_hal::digital::v2::OutputPin;
-use futures::future::{select, Either};
+use futures::future::{poll_fn, select, Either};
 use futures::pin_mut;
-use heapless::consts::U2;
-use protocol::{Command, ConnectionType, Response as AtResponse};
+use heapless::{
+    consts::{U2, U32, U4, U64},
+    spsc::Queue,
+    String,
+};
+use protocol::{Command, ConnectionType, Dialect, Response as AtResponse, WiFiMode};
+pub use protocol::{IpAddresses, ResolverAddresses};
+use receive::{ReceiveBuffers, ReceiveStorage};
 
 pub const BUFFER_LEN: usize = 512;
 
+/// Default time to wait for the response to a command.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default time to wait for the response to a command that waits on the network.
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Oldest AT firmware the driver is known to work with.
+const MIN_FIRMWARE_VERSION: Version = Version::new(1, 7, 0, 0);
+
+/// Time to wait for the modem to start after it was powered on or reset.
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the reset pin is held low.
+const RESET_PULSE: Duration = Duration::from_millis(100);
+
+/// Number of times the modem is reset when it fails to initialize.
+const RESET_ATTEMPTS: usize = 3;
+
+/// Maximum length of data sent using a single `AT+CIPSEND`.
+const MAX_SEND_LEN: usize = 2048;
+
+/// Interval between checks for received data while a read waits for data to arrive.
+const READ_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Silence required around the `+++` sequence leaving passthrough mode.
+const ESCAPE_GUARD: Duration = Duration::from_millis(20);
+
+/// Time needed by the modem to accept commands again after leaving passthrough mode.
+const PASSTHROUGH_EXIT: Duration = Duration::from_secs(1);
+
+/// How data is transferred between the modem and the driver.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferMode {
+    /// Received data is kept by the modem until requested, one `AT+CIPSEND` per write.
+    Passive,
+    /// Received data is pushed by the modem in `+IPD` frames and kept in per-socket buffers.
+    /// Data is dropped when a socket is not read fast enough to keep room in its buffer.
+    Active,
+    /// A single TCP connection in transparent transmission mode, where data is exchanged as
+    /// is instead of through AT commands. The connection is not closed by the modem when the
+    /// remote end goes away, and TCP servers and UDP are not available.
+    Passthrough,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum DriverError {
     UnableToInitialize,
@@ -49,13 +124,21 @@ pub enum DriverError {
     ReadError,
     InvalidSocket,
     OperationNotSupported,
+    UnsupportedFirmware,
 }
 
-type CommandBuffer = (usize, [u8; 256]);
+const COMMAND_LEN: usize = 256;
+
+/// Bytes to write to the modem, and whether they are the escape sequence leaving passthrough
+/// mode, which would otherwise be taken as data.
+type CommandBuffer = (usize, [u8; COMMAND_LEN], bool);
 
 pub struct Initialized {
     signal: Signal<Result<(), DriverError>>,
     initialized: AtomicBool,
+    reset: Signal<()>,
+    generation: AtomicUsize,
+    v2: AtomicBool,
 }
 
 impl Initialized {
@@ -63,6 +146,9 @@ impl Initialized {
         Self {
             signal: Signal::new(),
             initialized: AtomicBool::new(false),
+            reset: Signal::new(),
+            generation: AtomicUsize::new(0),
+            v2: AtomicBool::new(false),
         }
     }
 
@@ -76,11 +162,112 @@ impl Initialized {
     pub fn signal(&self, result: Result<(), DriverError>) {
         self.signal.signal(result);
     }
+
+    /// Have the modem reset, as it is not responding.
+    fn request_reset(&self) {
+        self.initialized.store(false, Ordering::SeqCst);
+        self.reset.signal(());
+    }
+
+    /// Mark the start of a reset. Connections made before it are lost.
+    fn reset_started(&self) {
+        self.reset.reset();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn set_dialect(&self, dialect: Dialect) {
+        self.v2.store(dialect == Dialect::V2, Ordering::SeqCst);
+    }
+
+    /// The dialect of the modem firmware, known once it is initialized.
+    fn dialect(&self) -> Dialect {
+        if self.v2.load(Ordering::SeqCst) {
+            Dialect::V2
+        } else {
+            Dialect::V1
+        }
+    }
+
+    /// The number of times the modem was reset.
+    fn generation(&self) -> usize {
+        self.generation.load(Ordering::SeqCst)
+    }
+}
+
+/// An exchange with the modem, which is left incomplete when its caller stops waiting for it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Exchange {
+    /// A command, completed by its final response.
+    Command,
+    /// An `AT+CIPSEND` of data, completed by `SEND OK` or `SEND FAIL`.
+    Send,
+    /// The modem prompted for the data of an `AT+CIPSEND`, which has not been written in full.
+    Data,
+    /// An `AT+CIPSEND` entering passthrough mode, completed by the data prompt.
+    Passthrough,
+}
+
+impl Exchange {
+    fn new(command: &Command) -> Self {
+        match command {
+            Command::Send { .. } => Exchange::Send,
+            Command::StartPassthrough => Exchange::Passthrough,
+            _ => Exchange::Command,
+        }
+    }
+
+    /// The exchange still in progress after a response is received.
+    fn after(self, response: &AtResponse) -> Option<Self> {
+        match (self, response) {
+            (_, AtResponse::Error) => None,
+            (Exchange::Send, AtResponse::ReadyForData) => Some(Exchange::Data),
+            (Exchange::Send, AtResponse::SendOk)
+            | (Exchange::Send, AtResponse::SendFail)
+            | (Exchange::Data, AtResponse::SendOk)
+            | (Exchange::Data, AtResponse::SendFail)
+            | (Exchange::Passthrough, AtResponse::ReadyForData) => None,
+            (Exchange::Command, AtResponse::Connect(..))
+            | (Exchange::Command, AtResponse::AccessPoint(..))
+            | (Exchange::Command, AtResponse::Station(..)) => Some(self),
+            (Exchange::Command, _) => None,
+            _ => Some(self),
+        }
+    }
+}
+
+/// How the station gets its IP address.
+#[derive(Debug, Clone, Copy)]
+pub enum IpConfig {
+    Dhcp,
+    Static(IpAddresses),
 }
 
 pub struct Esp8266Controller<'a> {
     initialized: &'a Initialized,
+    generation: Cell<usize>,
+    command_timeout: Duration,
+    network_timeout: Duration,
+    mode: TransferMode,
+    buffers: ReceiveBuffers<'a>,
     socket_pool: SocketPool,
+    /// Read and write timeouts of the sockets.
+    timeouts: [Option<Duration>; 4],
+    passthrough: Cell<Option<u8>>,
+    ip_config: IpConfig,
+    hostname: Option<String<U32>>,
+    resolvers: Option<ResolverAddresses>,
+    /// Timezone offset in hours and server used for SNTP.
+    sntp: Option<(i8, String<U64>)>,
+    /// SSID, password and channel of the access point, started again when the modem is reset.
+    access_point: Option<(String<U32>, String<U64>, u8)>,
+    /// Whether the modem was reset since the access point was started.
+    access_point_lost: Cell<bool>,
+    incoming: Queue<u8, U4>,
+    disconnected: bool,
+    /// Exchange with the modem whose caller stopped waiting for it.
+    pending: Cell<Option<Exchange>>,
+    /// Sockets dropped without waiting for them to close, one bit per link.
+    closing: Cell<u8>,
     command_producer: ChannelSender<'a, CommandBuffer, U2>,
     response_consumer: ChannelReceiver<'a, AtResponse, U2>,
     notification_consumer: ChannelReceiver<'a, AtResponse, U2>,
@@ -96,29 +283,87 @@ where
     uart: UART,
     enable: ENABLE,
     reset: RESET,
+    command_timeout: Duration,
+    mode: TransferMode,
+    buffers: ReceiveBuffers<'a>,
     parse_buffer: Buffer,
+    /// Link of the connection being opened by `AT+CIPSTART`.
+    connecting: Option<usize>,
+    udp: [bool; 4],
+    /// Link id and remaining length of the `+IPD` frame being received.
+    frame: Option<(usize, usize)>,
+    overflow: bool,
+    entering_passthrough: bool,
+    passthrough: bool,
     command_consumer: ChannelReceiver<'a, CommandBuffer, U2>,
     response_producer: ChannelSender<'a, AtResponse, U2>,
     notification_producer: ChannelSender<'a, AtResponse, U2>,
 }
 
-pub struct Esp8266Driver {
+/// The driver state shared by the controller and the modem. `LINKS` is the number of receive
+/// buffers, which depends on the `TransferMode` and is chosen by the constructor.
+#[rustfmt::skip]
+pub struct Esp8266Driver<const LINKS: usize = 0> {
     initialized: Initialized,
+    command_timeout: Duration,
+    network_timeout: Duration,
+    mode: TransferMode,
+    receive_storage: ReceiveStorage<LINKS>,
     command_channel: Channel<CommandBuffer, U2>,
     response_channel: Channel<AtResponse, U2>,
     notification_channel: Channel<AtResponse, U2>,
 }
 
 impl Esp8266Driver {
+    /// Create a driver using `TransferMode::Passive`.
     pub fn new() -> Self {
+        Self::with_transfer_mode(TransferMode::Passive)
+    }
+}
+
+impl Esp8266Driver<4> {
+    /// Create a driver using `TransferMode::Active`, with a receive buffer for each socket.
+    pub fn active() -> Self {
+        Self::with_transfer_mode(TransferMode::Active)
+    }
+}
+
+impl Esp8266Driver<1> {
+    /// Create a driver using `TransferMode::Passthrough`, with a receive buffer for its single
+    /// connection.
+    pub fn passthrough() -> Self {
+        Self::with_transfer_mode(TransferMode::Passthrough)
+    }
+}
+
+impl<const LINKS: usize> Esp8266Driver<LINKS> {
+    fn with_transfer_mode(mode: TransferMode) -> Self {
         Self {
             initialized: Initialized::new(),
+            command_timeout: COMMAND_TIMEOUT,
+            network_timeout: NETWORK_TIMEOUT,
+            mode,
+            receive_storage: ReceiveStorage::new(),
             command_channel: Channel::new(),
             response_channel: Channel::new(),
             notification_channel: Channel::new(),
         }
     }
 
+    /// Set the time to wait for the response to a command, after which the modem is considered
+    /// unresponsive and is reset.
+    pub fn command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = timeout;
+        self
+    }
+
+    /// Set the time to wait for the response to commands that wait on the network: joining and
+    /// scanning for access points, opening connections and resolving host names.
+    pub fn network_timeout(mut self, timeout: Duration) -> Self {
+        self.network_timeout = timeout;
+        self
+    }
+
     pub fn initialize<'a, UART, ENABLE, RESET>(
         &'a mut self,
         uart: UART,
@@ -134,8 +379,28 @@ impl Esp8266Driver {
         let (rp, rc) = self.response_channel.split();
         let (np, nc) = self.notification_channel.split();
 
-        let modem = Esp8266Modem::new(&self.initialized, uart, enable, reset, cc, rp, np);
-        let controller = Esp8266Controller::new(&self.initialized, cp, rc, nc);
+        let modem = Esp8266Modem::new(
+            &self.initialized,
+            uart,
+            enable,
+            reset,
+            self.command_timeout,
+            self.mode,
+            self.receive_storage.buffers(),
+            cc,
+            rp,
+            np,
+        );
+        let controller = Esp8266Controller::new(
+            &self.initialized,
+            self.command_timeout,
+            self.network_timeout,
+            self.mode,
+            self.receive_storage.buffers(),
+            cp,
+            rc,
+            nc,
+        );
 
         (controller, modem)
     }
@@ -152,6 +417,9 @@ where
         uart: UART,
         enable: ENABLE,
         reset: RESET,
+        command_timeout: Duration,
+        mode: TransferMode,
+        buffers: ReceiveBuffers<'a>,
         command_consumer: ChannelReceiver<'a, CommandBuffer, U2>,
         response_producer: ChannelSender<'a, AtResponse, U2>,
         notification_producer: ChannelSender<'a, AtResponse, U2>,
@@ -161,7 +429,16 @@ where
             uart,
             enable,
             reset,
+            command_timeout,
+            mode,
+            buffers,
             parse_buffer: Buffer::new(),
+            connecting: None,
+            udp: [false; 4],
+            frame: None,
+            overflow: false,
+            entering_passthrough: false,
+            passthrough: false,
             command_consumer,
             response_producer,
             notification_producer,
@@ -169,43 +446,75 @@ where
     }
 
     async fn initialize(&mut self) -> Result<(), DriverError> {
-        let mut buffer: [u8; 1024] = [0; 1024];
-        let mut pos = 0;
-
-        const READY: [u8; 7] = *b"ready\r\n";
-
         info!("Initializing ESP8266");
 
         self.enable.set_high().ok().unwrap();
         self.reset.set_high().ok().unwrap();
 
-        let mut rx_buf = [0; 1];
-        loop {
-            let result = uart_read(&mut self.uart, &mut rx_buf[..]).await;
+        self.wait_for(b"ready\r\n", READY_TIMEOUT).await?;
+        self.disable_echo().await?;
+        trace!("Echo disabled");
+        let dialect = self.query_dialect().await?;
+        self.initialized.set_dialect(dialect);
+        trace!("Firmware uses dialect {:?}", dialect);
+        if dialect == Dialect::V2 {
+            self.disable_store().await?;
+            trace!("Configuration storage disabled");
+        }
+        self.enable_mux().await?;
+        trace!("Mux enabled");
+        self.set_recv_mode().await?;
+        trace!("Recv mode configured");
+        if self.mode == TransferMode::Passthrough {
+            self.set_transfer_mode().await?;
+            trace!("Passthrough mode configured");
+        }
+        self.set_mode().await?;
+        info!("ESP8266 initialized");
+        Ok(())
+    }
+
+    /// Initialize the modem, resetting it when it does not respond, which is also the case when
+    /// it was already running and does not announce that it is ready.
+    async fn start(&mut self) -> Result<(), DriverError> {
+        let mut result = self.initialize().await;
+        for _ in 0..RESET_ATTEMPTS {
             match result {
-                Ok(c) => {
-                    if c > 0 {
-                        buffer[pos] = rx_buf[0];
-                        pos += 1;
-                        if pos >= READY.len() && buffer[pos - READY.len()..pos] == READY {
-                            self.disable_echo().await?;
-                            trace!("Echo disabled");
-                            self.enable_mux().await?;
-                            trace!("Mux enabled");
-                            self.set_recv_mode().await?;
-                            trace!("Recv mode configured");
-                            self.set_mode().await?;
-                            info!("ESP8266 initialized");
-                            return Ok(());
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("Error initializing ESP8266 modem: {:?}", e);
-                    return Err(DriverError::UnableToInitialize);
-                }
+                Ok(()) | Err(DriverError::UnsupportedFirmware) => break,
+                Err(e) => warn!("Error initializing ESP8266: {:?}", e),
             }
+            self.hard_reset().await;
+            result = self.initialize().await;
+        }
+        result
+    }
+
+    /// Reset the modem using its pins, discarding the state of its connections.
+    async fn hard_reset(&mut self) {
+        warn!("Resetting ESP8266");
+        self.enable.set_low().ok().unwrap();
+        self.reset.set_low().ok().unwrap();
+        Timer::after(RESET_PULSE).await;
+
+        self.parse_buffer.clear();
+        self.frame = None;
+        self.connecting = None;
+        self.entering_passthrough = false;
+        self.passthrough = false;
+        self.udp = [false; 4];
+        for link_id in 0..self.udp.len() {
+            self.buffers.clear(link_id);
         }
+        // Commands sent before the reset would be answered as if they were sent after it
+        while self.command_consumer.try_receive().is_ok() {}
+    }
+
+    /// Reset and initialize the modem again after it stopped responding.
+    async fn recover(&mut self) {
+        self.initialized.reset_started();
+        self.hard_reset().await;
+        let result = self.start().await;
+        self.initialized.signal(result);
     }
 
     async fn disable_echo(&mut self) -> Result<(), DriverError> {
@@ -219,7 +528,23 @@ where
     }
 
     async fn enable_mux(&mut self) -> Result<(), DriverError> {
-        uart_write(&mut self.uart, b"AT+CIPMUX=1\r\n")
+        // Passthrough mode is only available for a single connection
+        let command = match self.mode {
+            TransferMode::Passthrough => &b"AT+CIPMUX=0\r\n"[..],
+            _ => &b"AT+CIPMUX=1\r\n"[..],
+        };
+        uart_write(&mut self.uart, command)
+            .await
+            .map_err(|_| DriverError::UnableToInitialize)?;
+        Ok(self
+            .wait_for_ok()
+            .await
+            .map_err(|_| DriverError::UnableToInitialize)?)
+    }
+
+    /// Keep configuration changes out of flash, like the `_CUR` commands of the 1.x firmware.
+    async fn disable_store(&mut self) -> Result<(), DriverError> {
+        uart_write(&mut self.uart, b"AT+SYSSTORE=0\r\n")
             .await
             .map_err(|_| DriverError::UnableToInitialize)?;
         Ok(self
@@ -229,7 +554,21 @@ where
     }
 
     async fn set_recv_mode(&mut self) -> Result<(), DriverError> {
-        uart_write(&mut self.uart, b"AT+CIPRECVMODE=1\r\n")
+        let command = match self.mode {
+            TransferMode::Passive => &b"AT+CIPRECVMODE=1\r\n"[..],
+            _ => &b"AT+CIPRECVMODE=0\r\n"[..],
+        };
+        uart_write(&mut self.uart, command)
+            .await
+            .map_err(|_| DriverError::UnableToInitialize)?;
+        Ok(self
+            .wait_for_ok()
+            .await
+            .map_err(|_| DriverError::UnableToInitialize)?)
+    }
+
+    async fn set_transfer_mode(&mut self) -> Result<(), DriverError> {
+        uart_write(&mut self.uart, b"AT+CIPMODE=1\r\n")
             .await
             .map_err(|_| DriverError::UnableToInitialize)?;
         Ok(self
@@ -238,8 +577,39 @@ where
             .map_err(|_| DriverError::UnableToInitialize)?)
     }
 
+    /// Query the firmware version, which determines the dialect of the commands sent to the
+    /// modem.
+    async fn query_dialect(&mut self) -> Result<Dialect, DriverError> {
+        uart_write(&mut self.uart, b"AT+GMR\r\n")
+            .await
+            .map_err(|_| DriverError::UnableToInitialize)?;
+        let mut buf = [0; 512];
+        let len = self.read_response(&mut buf).await?;
+        let response = &buf[..len];
+        let start = response
+            .windows(11)
+            .position(|w| w == b"AT version:")
+            .unwrap_or(0);
+        match parser::firmware_info(&response[start..]) {
+            Ok((_, AtResponse::FirmwareInfo(info))) => {
+                info!("ESP8266 firmware {:?}", info);
+                if info.version() < MIN_FIRMWARE_VERSION {
+                    error!("AT firmware {} is not supported", info.version());
+                    return Err(DriverError::UnsupportedFirmware);
+                }
+                Ok(info.dialect())
+            }
+            _ => {
+                warn!("Unknown firmware version, assuming AT firmware 1.x");
+                Ok(Dialect::V1)
+            }
+        }
+    }
+
     async fn set_mode(&mut self) -> Result<(), DriverError> {
-        uart_write(&mut self.uart, b"AT+CWMODE_CUR=1\r\n")
+        let mut command = Command::SetMode(WiFiMode::Station).as_bytes(self.initialized.dialect());
+        command.push_str("\r\n").unwrap();
+        uart_write(&mut self.uart, command.as_bytes())
             .await
             .map_err(|_| DriverError::UnableToInitialize)?;
         Ok(self
@@ -249,42 +619,105 @@ where
     }
 
     async fn wait_for_ok(&mut self) -> Result<(), DriverError> {
-        let mut buf: [u8; 64] = [0; 64];
-        let mut pos = 0;
+        let timeout = self.command_timeout;
+        self.wait_for(b"OK\r\n", timeout).await
+    }
 
-        loop {
-            uart_read(&mut self.uart, &mut buf[pos..pos + 1])
-                .await
-                .map_err(|_| DriverError::ReadError)?;
-            pos += 1;
-            if buf[0..pos].ends_with(b"OK\r\n") {
-                return Ok(());
-            } else if buf[0..pos].ends_with(b"ERROR\r\n") {
-                return Err(DriverError::UnableToInitialize);
+    /// Read a response ending with `OK`, failing when an error is received instead, the response
+    /// does not fit in `buf` or is not received in time.
+    async fn read_response(&mut self, buf: &mut [u8]) -> Result<usize, DriverError> {
+        let uart = &mut self.uart;
+        let result = with_timeout(self.command_timeout, async move {
+            let mut pos = 0;
+            while pos < buf.len() {
+                pos += uart_read(uart, &mut buf[pos..pos + 1])
+                    .await
+                    .map_err(|_| DriverError::ReadError)?;
+                if buf[..pos].ends_with(b"OK\r\n") {
+                    return Ok(pos);
+                } else if buf[..pos].ends_with(b"ERROR\r\n") {
+                    return Err(DriverError::UnableToInitialize);
+                }
             }
-        }
+            Err(DriverError::UnableToInitialize)
+        })
+        .await;
+        result.unwrap_or(Err(DriverError::Timeout))
+    }
+
+    /// Read from the modem until `expected` is received, failing when an error is received
+    /// instead or nothing is received in time.
+    async fn wait_for(&mut self, expected: &[u8], timeout: Duration) -> Result<(), DriverError> {
+        let uart = &mut self.uart;
+        let result = with_timeout(timeout, async move {
+            // Only the end of the input is of interest, as it may start with garbage
+            let mut window = [0; 8];
+            let mut rx_buf = [0; 1];
+            loop {
+                let len = uart_read(uart, &mut rx_buf[..])
+                    .await
+                    .map_err(|_| DriverError::ReadError)?;
+                if len == 0 {
+                    continue;
+                }
+                window.rotate_left(1);
+                window[window.len() - 1] = rx_buf[0];
+                if window.ends_with(expected) {
+                    return Ok(());
+                } else if window.ends_with(b"ERROR\r\n") {
+                    return Err(DriverError::UnableToInitialize);
+                }
+            }
+        })
+        .await;
+        result.unwrap_or(Err(DriverError::Timeout))
     }
 
     /// Run the processing loop until an error is encountered
     pub async fn run(&mut self) -> ! {
         // Result<(), DriverError> where Self: 'a {
-        let result = self.initialize().await;
+        let result = self.start().await;
         self.initialized.signal(result);
         loop {
-            let mut buf = [0; 1];
+            let mut buf = [0; 64];
             let (cmd, input) = {
                 let command_fut = self.command_consumer.receive();
                 let uart_fut = uart_read(&mut self.uart, &mut buf[..]);
                 pin_mut!(uart_fut);
+                let initialized = self.initialized;
+                let reset_fut = poll_fn(|cx| initialized.reset.poll_wait(cx));
 
-                match select(command_fut, uart_fut).await {
-                    Either::Left((s, _)) => (Some(s), None),
-                    Either::Right((r, _)) => (None, Some(r)),
+                match select(select(command_fut, uart_fut), reset_fut).await {
+                    Either::Left((Either::Left((s, _)), _)) => (Some(s), None),
+                    Either::Left((Either::Right((r, _)), _)) => (None, Some(r)),
+                    // Requested by the controller when the modem does not respond
+                    Either::Right(_) => (None, None),
                 }
             };
+            if cmd.is_none() && input.is_none() {
+                self.recover().await;
+                continue;
+            }
             // We got command to write, write it
-            if let Some((len, buf)) = cmd {
-                if let Err(e) = uart_write(&mut self.uart, &buf[0..len]).await {
+            if let Some((len, buf, escape)) = cmd {
+                let command = &buf[..len];
+                if self.passthrough {
+                    // Everything is data until the escape sequence
+                    if escape {
+                        self.passthrough = false;
+                    }
+                } else {
+                    // A CONNECT for the link received before the command completes belongs to
+                    // the command, any other CONNECT is an incoming connection to the server.
+                    if let Some((link_id, udp)) = connection_start(command) {
+                        self.connecting = Some(link_id);
+                        if let Some(u) = self.udp.get_mut(link_id) {
+                            *u = udp;
+                        }
+                    }
+                    self.entering_passthrough = command == b"AT+CIPSEND\r\n";
+                }
+                if let Err(e) = uart_write(&mut self.uart, command).await {
                     error!("Error writing command to uart: {:?}", e);
                 }
             }
@@ -294,10 +727,12 @@ where
                 match input {
                     Ok(len) => {
                         for b in &buf[..len] {
-                            self.parse_buffer.write(*b).unwrap();
-                        }
-                        if let Err(e) = self.digest().await {
-                            error!("Error digesting modem input: {:?}", e);
+                            if let Err(e) = self.ingest(*b).await {
+                                error!("Error digesting modem input: {:?}", e);
+                                self.initialized.request_reset();
+                                self.recover().await;
+                                break;
+                            }
                         }
                     }
                     Err(e) => {
@@ -308,6 +743,66 @@ where
         }
     }
 
+    /// Process a byte received from the modem, which is either data for a socket or part of a
+    /// response.
+    async fn ingest(&mut self, octet: u8) -> Result<(), DriverError> {
+        if self.passthrough {
+            self.receive(0, octet);
+            return Ok(());
+        }
+
+        if let Some((link_id, remaining)) = self.frame {
+            self.receive(link_id, octet);
+            self.frame = if remaining > 1 {
+                Some((link_id, remaining - 1))
+            } else {
+                None
+            };
+            return Ok(());
+        }
+
+        // The prompt for passthrough data is not followed by a space, so it cannot be told apart
+        // from data by the parser.
+        if self.entering_passthrough && octet == b'>' && self.parse_buffer.is_blank() {
+            trace!("--> ReadyForData");
+            self.parse_buffer.clear();
+            self.entering_passthrough = false;
+            self.passthrough = true;
+            self.response_producer.send(AtResponse::ReadyForData).await;
+            return Ok(());
+        }
+
+        if self.parse_buffer.write(octet).is_err() {
+            warn!("Unable to parse modem input");
+            return Err(DriverError::ReadError);
+        }
+
+        // Frames for TCP connections are received straight into the socket buffers, datagrams
+        // are parsed to keep them apart.
+        if self.mode == TransferMode::Active {
+            if let Some((link_id, len)) = self.parse_buffer.frame_header() {
+                if !self.udp.get(link_id).copied().unwrap_or(true) {
+                    self.parse_buffer.clear();
+                    if len > 0 {
+                        self.frame = Some((link_id, len));
+                    }
+                    return Ok(());
+                }
+            }
+        }
+
+        self.digest().await
+    }
+
+    fn receive(&mut self, link_id: usize, octet: u8) {
+        if self.buffers.write(link_id, octet).is_ok() {
+            self.overflow = false;
+        } else if !self.overflow {
+            warn!("Receive buffer full, dropping data for link {}", link_id);
+            self.overflow = true;
+        }
+    }
+
     async fn digest(&mut self) -> Result<(), DriverError> {
         let result = self.parse_buffer.parse();
 
@@ -315,11 +810,25 @@ where
             if !matches!(response, AtResponse::None) {
                 trace!("--> {:?}", response);
             }
+            if let AtResponse::Connect(link_id) = response {
+                // Data left over from a previous connection on the link
+                self.buffers.clear(link_id);
+                if self.connecting != Some(link_id) {
+                    if let Some(u) = self.udp.get_mut(link_id) {
+                        *u = false;
+                    }
+                }
+            }
             match response {
                 AtResponse::None => {}
-                AtResponse::Ok
-                | AtResponse::Error
-                | AtResponse::FirmwareInfo(..)
+                AtResponse::Connect(link_id) if self.connecting != Some(link_id) => {
+                    self.notification_producer.send(response).await;
+                }
+                AtResponse::Ok | AtResponse::Error | AtResponse::DnsFail => {
+                    self.connecting = None;
+                    self.response_producer.send(response).await;
+                }
+                AtResponse::FirmwareInfo(..)
                 | AtResponse::Connect(..)
                 | AtResponse::ReadyForData
                 | AtResponse::ReceivedDataToSend(..)
@@ -328,23 +837,42 @@ where
                 | AtResponse::SendFail
                 | AtResponse::WifiConnectionFailure(..)
                 | AtResponse::IpAddress(..)
+                | AtResponse::MacAddress(..)
                 | AtResponse::Resolvers(..)
-                | AtResponse::DnsFail
                 | AtResponse::UnlinkFail
+                | AtResponse::AccessPoint(..)
+                | AtResponse::JoinedAp(..)
+                | AtResponse::Station(..)
                 | AtResponse::IpAddresses(..) => {
                     self.response_producer.send(response).await;
                 }
                 AtResponse::Closed(..) | AtResponse::DataAvailable { .. } => {
                     self.notification_producer.send(response).await;
                 }
+                AtResponse::DataFrame(..) => {
+                    // Datagrams may be dropped, but the modem must not block waiting for
+                    // the controller to pick them up.
+                    if self.notification_producer.try_send(response).is_err() {
+                        warn!("Notification queue full, dropping incoming datagram");
+                    }
+                }
                 AtResponse::WifiConnected => {
                     debug!("wifi connected");
                 }
-                AtResponse::WifiDisconnect => {
-                    debug!("wifi disconnect");
+                AtResponse::WifiDisconnect | AtResponse::GotIp => {
+                    debug!("wifi link state: {:?}", response);
+                    if self.notification_producer.try_send(response).is_err() {
+                        warn!("Notification queue full, dropping link state change");
+                    }
+                }
+                AtResponse::StationConnected(mac) => {
+                    debug!("station connected: {:x?}", mac);
+                }
+                AtResponse::StationDisconnected(mac) => {
+                    debug!("station disconnected: {:x?}", mac);
                 }
-                AtResponse::GotIp => {
-                    debug!("wifi got ip");
+                AtResponse::StationIp(station) => {
+                    debug!("station assigned ip: {:?}", station);
                 }
             }
         }
@@ -355,13 +883,34 @@ where
 impl<'a> Esp8266Controller<'a> {
     pub fn new(
         initialized: &'a Initialized,
+        command_timeout: Duration,
+        network_timeout: Duration,
+        mode: TransferMode,
+        buffers: ReceiveBuffers<'a>,
         command_producer: ChannelSender<'a, CommandBuffer, U2>,
         response_consumer: ChannelReceiver<'a, AtResponse, U2>,
         notification_consumer: ChannelReceiver<'a, AtResponse, U2>,
     ) -> Self {
         Self {
             initialized,
+            generation: Cell::new(initialized.generation()),
+            command_timeout,
+            network_timeout,
+            mode,
+            buffers,
             socket_pool: SocketPool::new(),
+            timeouts: [None; 4],
+            passthrough: Cell::new(None),
+            ip_config: IpConfig::Dhcp,
+            hostname: None,
+            resolvers: None,
+            sntp: None,
+            access_point: None,
+            access_point_lost: Cell::new(false),
+            incoming: Queue::new(),
+            disconnected: false,
+            pending: Cell::new(None),
+            closing: Cell::new(0),
             command_producer,
             response_consumer,
             notification_consumer,
@@ -372,7 +921,74 @@ impl<'a> Esp8266Controller<'a> {
         trace!("Sending command");
         self.initialized.wait().await?;
         trace!("Confirmed initialized");
-        let mut bytes = command.as_bytes();
+        self.check_reset();
+        // Complete the exchange of a caller that stopped waiting for it, discarding its responses
+        while let Some(exchange) = self.pending.get() {
+            let result = match exchange {
+                Exchange::Data | Exchange::Passthrough => {
+                    // The modem is waiting for data that will not be written
+                    warn!("Data transfer abandoned, resetting ESP8266");
+                    self.initialized.request_reset();
+                    Err(DriverError::Timeout)
+                }
+                _ => self.receive(self.command_timeout).await,
+            };
+            match result {
+                Ok(response) => trace!("Discarding stale response {:?}", response),
+                Err(_) => {
+                    self.initialized.wait().await?;
+                    self.check_reset();
+                }
+            }
+        }
+
+        // Close the sockets that were dropped
+        for link_id in 0..4 {
+            if self.closing.get() & 1 << link_id != 0 {
+                if let Err(e) = self.exchange(Command::CloseConnection(link_id)).await {
+                    warn!("Error closing socket {}: {:?}", link_id, e);
+                }
+                self.closing.set(self.closing.get() & !(1 << link_id));
+            }
+        }
+
+        if self.access_point_lost.replace(false) {
+            if let Err(e) = self.restart_access_point().await {
+                warn!("Error restarting access point: {:?}", e);
+            }
+        }
+
+        self.exchange(command).await
+    }
+
+    /// Start the access point again after the modem was reset.
+    async fn restart_access_point(&self) -> Result<(), DriverError> {
+        if let Some((ssid, password, channel)) = &self.access_point {
+            self.exchange_ok(Command::SetMode(WiFiMode::SoftAccessPointAndStation))
+                .await?;
+            self.exchange_ok(Command::SetAccessPoint {
+                ssid: ssid.as_str(),
+                password: password.as_str(),
+                channel: *channel,
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn exchange_ok<'c>(&self, command: Command<'c>) -> Result<(), DriverError> {
+        match self.exchange(command).await? {
+            AtResponse::Ok => Ok(()),
+            r => {
+                warn!("Unexpected response: {:?}", r);
+                Err(DriverError::UnableToInitialize)
+            }
+        }
+    }
+
+    /// Write a command and wait for its first response.
+    async fn exchange<'c>(&self, command: Command<'c>) -> Result<AtResponse, DriverError> {
+        let mut bytes = command.as_bytes(self.initialized.dialect());
         trace!(
             "writing command {}",
             core::str::from_utf8(bytes.as_bytes()).unwrap()
@@ -380,13 +996,60 @@ impl<'a> Esp8266Controller<'a> {
 
         bytes.push_str("\r\n").unwrap();
         let bs = bytes.as_bytes();
-        let mut data = [0; 256];
+        let mut data = [0; COMMAND_LEN];
         data[0..bs.len()].copy_from_slice(&bs[0..bs.len()]);
-        self.command_producer.send((bs.len(), data)).await;
-        Ok(self.response_consumer.receive().await)
+        self.command_producer.send((bs.len(), data, false)).await;
+        // Pending until completed, in case the caller stops waiting for it
+        self.pending.set(Some(Exchange::new(&command)));
+        self.receive(self.timeout(&command)).await
+    }
+
+    /// Wait for a response, having the modem reset when it does not respond in time.
+    async fn receive(&self, timeout: Duration) -> Result<AtResponse, DriverError> {
+        match with_timeout(timeout, self.response_consumer.receive()).await {
+            Ok(response) => {
+                if let Some(exchange) = self.pending.get() {
+                    self.pending.set(exchange.after(&response));
+                }
+                Ok(response)
+            }
+            Err(_) => {
+                // Unless the modem was already reset while waiting
+                if self.initialized.generation() == self.generation.get() {
+                    warn!("Timeout waiting for response, resetting ESP8266");
+                    self.initialized.request_reset();
+                }
+                Err(DriverError::Timeout)
+            }
+        }
+    }
+
+    /// Forget about connections and responses from before the modem was reset.
+    fn check_reset(&self) {
+        let generation = self.initialized.generation();
+        if generation != self.generation.get() {
+            self.generation.set(generation);
+            self.pending.set(None);
+            self.closing.set(0);
+            while self.response_consumer.try_receive().is_ok() {}
+            while self.notification_consumer.try_receive().is_ok() {}
+            self.socket_pool.reset();
+            self.passthrough.set(None);
+            self.access_point_lost.set(self.access_point.is_some());
+        }
+    }
+
+    fn timeout(&self, command: &Command) -> Duration {
+        match command {
+            Command::JoinAp { .. }
+            | Command::ListAccessPoints
+            | Command::StartConnection(..)
+            | Command::StartSingleConnection(..)
+            | Command::GetHostByName { .. } => self.network_timeout,
+            _ => self.command_timeout,
+        }
     }
 
-    /*
     async fn set_wifi_mode(&self, mode: WiFiMode) -> Result<(), ()> {
         let command = Command::SetMode(mode);
         match self.send(command).await {
@@ -394,7 +1057,68 @@ impl<'a> Esp8266Controller<'a> {
             _ => Err(()),
         }
     }
-    */
+
+    /// Set how the station gets its IP address when joining a network.
+    pub fn set_ip_config(&mut self, config: IpConfig) {
+        self.ip_config = config;
+    }
+
+    /// Set the hostname announced when joining a network, which is at most 32 bytes long.
+    pub fn set_hostname(&mut self, hostname: &str) -> Result<(), ()> {
+        let mut name = String::new();
+        name.push_str(hostname)?;
+        self.hostname.replace(name);
+        Ok(())
+    }
+
+    /// Set the DNS servers used after joining a network, or use the ones provided by DHCP.
+    pub fn set_dns_resolvers(&mut self, resolvers: Option<ResolverAddresses>) {
+        self.resolvers = resolvers;
+    }
+
+    /// Synchronize the time of the modem using an SNTP server, at most 64 bytes long, and a
+    /// timezone offset in hours.
+    pub fn set_sntp(&mut self, timezone: i8, server: &str) -> Result<(), ()> {
+        let mut name = String::new();
+        name.push_str(server)?;
+        self.sntp.replace((timezone, name));
+        Ok(())
+    }
+
+    /// Apply the network configuration, which the modem forgets when it is reset.
+    async fn configure_network(&self) -> Result<(), JoinError> {
+        if let Some(hostname) = &self.hostname {
+            self.configure(Command::SetHostname(hostname.as_str()))
+                .await?;
+        }
+        match self.ip_config {
+            IpConfig::Dhcp => self.configure(Command::SetDhcp(true)).await?,
+            IpConfig::Static(addresses) => self.configure(Command::SetIpAddress(addresses)).await?,
+        }
+        match self.resolvers {
+            Some(resolvers) => self.configure(Command::SetDnsResolvers(resolvers)).await?,
+            None => self.configure(Command::ClearDnsResolvers).await?,
+        }
+        if let Some((timezone, server)) = &self.sntp {
+            self.configure(Command::SetSntpConfig {
+                timezone: *timezone,
+                server: server.as_str(),
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn configure<'c>(&self, command: Command<'c>) -> Result<(), JoinError> {
+        match self.send(command).await {
+            Ok(AtResponse::Ok) => Ok(()),
+            Err(DriverError::Timeout) => Err(JoinError::Timeout),
+            r => {
+                warn!("Error configuring network: {:?}", r);
+                Err(JoinError::Unknown)
+            }
+        }
+    }
 
     async fn join_wep(&self, ssid: &str, password: &str) -> Result<IpAddress, JoinError> {
         let command = Command::JoinAp { ssid, password };
@@ -404,6 +1128,7 @@ impl<'a> Esp8266Controller<'a> {
                 warn!("Error connecting to wifi: {:?}", reason);
                 Err(JoinError::Unknown)
             }
+            Err(DriverError::Timeout) => Err(JoinError::Timeout),
             _ => Err(JoinError::UnableToAssociate),
         }
     }
@@ -418,109 +1143,553 @@ impl<'a> Esp8266Controller<'a> {
         Err(())
     }
 
-    fn process_notifications(&mut self) {
-        while let Ok(response) = self.notification_consumer.try_receive() {
-            match response {
-                AtResponse::DataAvailable { .. } => {
-                    //  shared.socket_pool // [link_id].available += len;
-                }
-                AtResponse::Connect(_) => {}
-                AtResponse::Closed(link_id) => {
-                    self.socket_pool.close(link_id as u8);
+    async fn start_connection(
+        &self,
+        handle: u8,
+        connection_type: ConnectionType,
+        dst: SocketAddress,
+    ) -> Result<(), DriverError> {
+        let command = Command::StartConnection(handle as usize, connection_type, dst);
+        match self.send(command).await {
+            Ok(AtResponse::Connect(..)) => match self.receive(self.command_timeout).await? {
+                AtResponse::Ok => {
+                    // Also reopens a socket closed when the link went down
+                    self.socket_pool.connected(handle);
+                    Ok(())
                 }
-                _ => { /* ignore */ }
-            }
+                _ => Err(DriverError::UnableToOpen),
+            },
+            Err(DriverError::Timeout) => Err(DriverError::Timeout),
+            _ => Err(DriverError::UnableToOpen),
         }
     }
-}
 
-impl<'a> WifiSupplicant for Esp8266Controller<'a> {
-    #[rustfmt::skip]
-    type JoinFuture<'m> where 'a: 'm = impl Future<Output = Result<IpAddress, JoinError>> + 'm;
-    fn join<'m>(&'m mut self, join_info: Join<'m>) -> Self::JoinFuture<'m> {
-        async move {
-            match join_info {
-                Join::Open => Err(JoinError::Unknown),
-                Join::Wpa { ssid, password } => self.join_wep(ssid, password).await,
-            }
+    /// Register a connection accepted by the server, to be returned by `accept`.
+    fn queue_incoming(&mut self, link_id: usize) {
+        self.socket_pool.connected(link_id as u8);
+        if self.incoming.enqueue(link_id as u8).is_err() {
+            warn!("Too many pending connections, dropping link {}", link_id);
         }
     }
-}
-
-impl<'a> TcpStack for Esp8266Controller<'a> {
-    type SocketHandle = u8;
-
-    #[rustfmt::skip]
-    type OpenFuture<'m> where 'a: 'm = impl Future<Output = Self::SocketHandle> + 'm;
-    fn open<'m>(&'m mut self) -> Self::OpenFuture<'m> {
-        async move { self.socket_pool.open().await }
-    }
 
-    #[rustfmt::skip]
-    type ConnectFuture<'m> where 'a: 'm = impl Future<Output = Result<(), TcpError>> + 'm;
-    fn connect<'m>(
-        &'m mut self,
-        handle: Self::SocketHandle,
-        _: IpProtocol,
+    /// Start a connection in passthrough mode, closing a previous one if it was left open.
+    async fn start_passthrough(
+        &mut self,
+        handle: u8,
         dst: SocketAddress,
-    ) -> Self::ConnectFuture<'m> {
-        async move {
-            let command = Command::StartConnection(handle as usize, ConnectionType::TCP, dst);
-            if let Ok(AtResponse::Connect(..)) = self.send(command).await {
-                Ok(())
-            } else {
-                Err(TcpError::ConnectError)
+    ) -> Result<(), DriverError> {
+        if let Some(previous) = self.passthrough.get() {
+            if !self.socket_pool.is_closed(previous) {
+                return Err(DriverError::NoAvailableSockets);
             }
+            self.stop_passthrough().await;
         }
-    }
 
-    #[rustfmt::skip]
-    type WriteFuture<'m> where 'a: 'm = impl Future<Output = Result<usize, TcpError>> + 'm;
-    fn write<'m>(&'m mut self, handle: Self::SocketHandle, buf: &'m [u8]) -> Self::WriteFuture<'m> {
-        async move {
-            self.process_notifications();
-            if self.socket_pool.is_closed(handle) {
-                return Err(TcpError::SocketClosed);
+        let command = Command::StartSingleConnection(ConnectionType::TCP, dst);
+        match self.send(command).await {
+            Ok(AtResponse::Connect(..)) => match self.receive(self.command_timeout).await? {
+                AtResponse::Ok => {}
+                _ => return Err(DriverError::UnableToOpen),
+            },
+            Err(DriverError::Timeout) => return Err(DriverError::Timeout),
+            _ => return Err(DriverError::UnableToOpen),
+        }
+
+        let ready = match self.send(Command::StartPassthrough).await {
+            Ok(AtResponse::Ok) => matches!(
+                self.receive(self.command_timeout).await?,
+                AtResponse::ReadyForData
+            ),
+            Err(DriverError::Timeout) => return Err(DriverError::Timeout),
+            _ => false,
+        };
+        if !ready {
+            if let Err(e) = self.send(Command::CloseSingleConnection).await {
+                warn!("Error closing connection: {:?}", e);
             }
-            let command = Command::Send {
-                link_id: handle as usize,
-                len: buf.len(),
-            };
+            return Err(DriverError::UnableToOpen);
+        }
+
+        self.socket_pool.connected(handle);
+        self.passthrough.set(Some(handle));
+        Ok(())
+    }
+
+    /// Leave passthrough mode and close the connection.
+    async fn stop_passthrough(&mut self) {
+        Timer::after(ESCAPE_GUARD).await;
+        let mut data = [0; COMMAND_LEN];
+        data[..3].copy_from_slice(b"+++");
+        self.command_producer.send((3, data, true)).await;
+        self.passthrough.set(None);
+        Timer::after(PASSTHROUGH_EXIT).await;
+
+        if let Err(e) = self.send(Command::CloseSingleConnection).await {
+            warn!("Error closing connection: {:?}", e);
+        }
+    }
+
+    /// Write data, using as many `AT+CIPSEND` commands as needed. No further command is sent once
+    /// the deadline has passed.
+    async fn send_data(
+        &mut self,
+        handle: u8,
+        buf: &[u8],
+        deadline: Option<Instant>,
+    ) -> Result<usize, DriverError> {
+        let mut sent = 0;
+        for chunk in buf.chunks(MAX_SEND_LEN) {
+            if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+                if sent == 0 {
+                    return Err(DriverError::Timeout);
+                }
+                break;
+            }
+            match self.send_chunk(handle, chunk).await {
+                Ok(len) => {
+                    sent += len;
+                    if len < chunk.len() {
+                        break;
+                    }
+                }
+                Err(e) if sent == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(sent)
+    }
+
+    async fn send_chunk(&mut self, handle: u8, buf: &[u8]) -> Result<usize, DriverError> {
+        if buf.len() > MAX_SEND_LEN {
+            return Err(DriverError::WriteError);
+        }
+        let command = Command::Send {
+            link_id: handle as usize,
+            len: buf.len(),
+        };
 
-            let result = match self.send(command).await {
-                Ok(AtResponse::Ok) => {
-                    match self.response_consumer.receive().await {
-                        AtResponse::ReadyForData => {
-                            let mut data = [0; 256];
-                            data[0..buf.len()].copy_from_slice(&buf[0..buf.len()]);
-                            self.command_producer.send((buf.len(), data)).await;
-                            let mut data_sent: Option<usize> = None;
-                            loop {
-                                match self.response_consumer.receive().await {
-                                    AtResponse::ReceivedDataToSend(len) => {
-                                        data_sent.replace(len);
-                                    }
-                                    AtResponse::SendOk => break Ok(data_sent.unwrap_or_default()),
-                                    _ => {
-                                        break Err(TcpError::WriteError);
-                                        // unknown response
-                                    }
-                                }
+        match self.send(command).await {
+            Ok(AtResponse::Ok) => match self.receive(self.command_timeout).await? {
+                AtResponse::ReadyForData => {
+                    self.send_raw(buf).await;
+                    self.pending.set(Some(Exchange::Send));
+                    let mut data_sent: Option<usize> = None;
+                    loop {
+                        match self.receive(self.command_timeout).await? {
+                            AtResponse::ReceivedDataToSend(len) => {
+                                data_sent.replace(len);
+                            }
+                            AtResponse::SendOk => break Ok(data_sent.unwrap_or_default()),
+                            _ => {
+                                break Err(DriverError::WriteError);
+                                // unknown response
                             }
                         }
-                        r => {
-                            warn!("Unexpected response: {:?}", r);
-                            Err(TcpError::WriteError)
+                    }
+                }
+                r => {
+                    warn!("Unexpected response: {:?}", r);
+                    Err(DriverError::WriteError)
+                }
+            },
+            Ok(r) => {
+                warn!("Unexpected response: {:?}", r);
+                Err(DriverError::WriteError)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write data to the modem as is.
+    async fn send_raw(&self, buf: &[u8]) {
+        for chunk in buf.chunks(COMMAND_LEN) {
+            let mut data = [0; COMMAND_LEN];
+            data[..chunk.len()].copy_from_slice(chunk);
+            self.command_producer.send((chunk.len(), data, false)).await;
+        }
+    }
+
+    /// Deadline of an operation on a socket starting now.
+    fn deadline(&self, handle: u8) -> Option<Instant> {
+        self.timeouts[handle as usize].map(|timeout| Instant::now() + timeout)
+    }
+
+    /// Read the data received for a socket, returning 0 when there is none.
+    async fn read_available(&mut self, handle: u8, buf: &mut [u8]) -> Result<usize, TcpError> {
+        if self.mode != TransferMode::Passive {
+            self.process_notifications();
+            let link_id = match self.mode {
+                TransferMode::Passthrough => 0,
+                _ => handle as usize,
+            };
+            return match self.buffers.read(link_id, buf) {
+                0 if self.socket_pool.is_closed(handle) => Err(TcpError::SocketClosed),
+                len => Ok(len),
+            };
+        }
+
+        let mut rp = 0;
+        loop {
+            let result = async {
+                self.process_notifications();
+                // Data received before the remote end closed the connection can still be read
+                if self.socket_pool.is_closed(handle) && self.socket_pool.available(handle) == 0 {
+                    return Err(TcpError::SocketClosed);
+                }
+
+                let requested = core::cmp::min(buf.len() - rp, BUFFER_LEN);
+                let command = Command::Receive {
+                    link_id: handle as usize,
+                    len: requested,
+                };
+
+                match self.send(command).await {
+                    Ok(AtResponse::DataReceived(inbound, len)) => {
+                        for (i, b) in inbound[0..len].iter().enumerate() {
+                            buf[rp + i] = *b;
                         }
+                        self.socket_pool.consumed(handle, len, requested);
+                        Ok(len)
                     }
+                    Ok(AtResponse::Ok) => Ok(0),
+                    Err(DriverError::Timeout) => Err(TcpError::Timeout),
+                    _ => Err(TcpError::ReadError),
+                }
+            }
+            .await;
+
+            match result {
+                Ok(len) => {
+                    rp += len;
+                    if len == 0 || rp == buf.len() {
+                        return Ok(rp);
+                    }
+                }
+                Err(e) => {
+                    if rp == 0 {
+                        return Err(e);
+                    } else {
+                        return Ok(rp);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Data received for a socket and not yet read.
+    fn buffered(&self, handle: u8) -> usize {
+        match self.mode {
+            TransferMode::Passive => self.socket_pool.available(handle),
+            TransferMode::Active => self.buffers.available(handle as usize),
+            TransferMode::Passthrough => self.buffers.available(0),
+        }
+    }
+
+    fn process_notifications(&mut self) {
+        while let Ok(response) = self.notification_consumer.try_receive() {
+            self.process_notification(response);
+        }
+    }
+
+    fn process_notification(&mut self, response: AtResponse) {
+        match response {
+            AtResponse::DataAvailable { link_id, len } => {
+                self.socket_pool.received(link_id as u8, len);
+            }
+            AtResponse::Connect(link_id) => {
+                self.queue_incoming(link_id);
+            }
+            // Only reported when the passthrough connection is closed by the driver
+            AtResponse::Closed(_) if self.mode == TransferMode::Passthrough => {}
+            AtResponse::Closed(link_id) => {
+                self.socket_pool.close(link_id as u8);
+            }
+            AtResponse::DataFrame(link_id, data, len) => {
+                if self.buffers.write_datagram(link_id, &data[..len]).is_err() {
+                    warn!(
+                        "Receive buffer full, dropping datagram for link {}",
+                        link_id
+                    );
                 }
+            }
+            AtResponse::WifiDisconnect => {
+                self.disconnected = true;
+            }
+            AtResponse::GotIp => {
+                self.disconnected = false;
+            }
+            _ => { /* ignore */ }
+        }
+    }
+}
+
+impl<'a> WifiSupplicant for Esp8266Controller<'a> {
+    #[rustfmt::skip]
+    type JoinFuture<'m> where 'a: 'm = impl Future<Output = Result<IpAddress, JoinError>> + 'm;
+    fn join<'m>(&'m mut self, join_info: Join<'m>) -> Self::JoinFuture<'m> {
+        async move {
+            match join_info {
+                Join::Open => Err(JoinError::Unknown),
+                Join::Wpa { ssid, password } => {
+                    self.configure_network().await?;
+                    let ip = self.join_wep(ssid, password).await?;
+                    self.process_notifications();
+                    self.disconnected = false;
+                    Ok(ip)
+                }
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type ScanFuture<'m> where 'a: 'm = impl Future<Output = Result<usize, WifiError>> + 'm;
+    fn scan<'m>(&'m mut self, results: &'m mut [AccessPointInfo]) -> Self::ScanFuture<'m> {
+        async move {
+            let mut count = 0;
+            let mut response = self.send(Command::ListAccessPoints).await;
+            loop {
+                match response {
+                    Ok(AtResponse::AccessPoint(ap)) => {
+                        if count < results.len() {
+                            results[count] = ap;
+                            count += 1;
+                        }
+                    }
+                    Ok(AtResponse::Ok) => return Ok(count),
+                    Ok(AtResponse::Error) => return Err(WifiError::CommandFailed),
+                    Ok(r) => {
+                        warn!("Unexpected response: {:?}", r);
+                        return Err(WifiError::UnexpectedResponse);
+                    }
+                    Err(DriverError::Timeout) => return Err(WifiError::Timeout),
+                    Err(_) => return Err(WifiError::CommandFailed),
+                }
+                response = self.receive(self.command_timeout).await;
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type DisconnectFuture<'m> where 'a: 'm = impl Future<Output = Result<(), WifiError>> + 'm;
+    fn disconnect<'m>(&'m mut self) -> Self::DisconnectFuture<'m> {
+        async move {
+            match self.send(Command::QuitAp).await {
+                Ok(AtResponse::Ok) => Ok(()),
+                Err(DriverError::Timeout) => Err(WifiError::Timeout),
+                Ok(AtResponse::Error) | Err(_) => Err(WifiError::CommandFailed),
+                Ok(r) => {
+                    warn!("Unexpected response: {:?}", r);
+                    Err(WifiError::UnexpectedResponse)
+                }
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type StatusFuture<'m> where 'a: 'm = impl Future<Output = Result<Option<WifiStatus>, WifiError>> + 'm;
+    fn status<'m>(&'m mut self) -> Self::StatusFuture<'m> {
+        async move {
+            // Avoid querying the modem while it has reported that the link is down
+            self.process_notifications();
+            if self.disconnected {
+                return Ok(None);
+            }
+            match self.send(Command::QueryJoinedAp).await {
+                Ok(AtResponse::JoinedAp(Some(ap))) => Ok(Some(WifiStatus {
+                    ssid: ap.ssid,
+                    ip: self.get_ip_address().await.ok(),
+                    rssi: ap.rssi,
+                    channel: ap.channel,
+                })),
+                Ok(AtResponse::JoinedAp(None)) => Ok(None),
+                Err(DriverError::Timeout) => Err(WifiError::Timeout),
+                Ok(AtResponse::Error) | Err(_) => Err(WifiError::CommandFailed),
                 Ok(r) => {
                     warn!("Unexpected response: {:?}", r);
-                    Err(TcpError::WriteError)
+                    Err(WifiError::UnexpectedResponse)
+                }
+            }
+        }
+    }
+}
+
+impl<'a> ModemInfo for Esp8266Controller<'a> {
+    #[rustfmt::skip]
+    type InfoFuture<'m> where 'a: 'm = impl Future<Output = Result<ModemDetails, ModemError>> + 'm;
+    fn info<'m>(&'m mut self) -> Self::InfoFuture<'m> {
+        async move {
+            let info = match self.send(Command::QueryFirmwareInfo).await {
+                Ok(AtResponse::FirmwareInfo(info)) => info,
+                Err(DriverError::Timeout) => return Err(ModemError::Timeout),
+                Ok(AtResponse::Error) | Err(_) => return Err(ModemError::CommandFailed),
+                Ok(r) => {
+                    warn!("Unexpected response: {:?}", r);
+                    return Err(ModemError::UnexpectedResponse);
+                }
+            };
+            let mac = match self.send(Command::QueryMacAddress).await {
+                Ok(AtResponse::MacAddress(mac)) => Some(mac),
+                Err(DriverError::Timeout) => return Err(ModemError::Timeout),
+                _ => None,
+            };
+            Ok(ModemDetails {
+                at_version: info.version(),
+                sdk_version: info.sdk,
+                module: info.module,
+                mac,
+            })
+        }
+    }
+}
+
+impl<'a> AccessPoint for Esp8266Controller<'a> {
+    #[rustfmt::skip]
+    type StartFuture<'m> where 'a: 'm = impl Future<Output = Result<IpAddress, WifiError>> + 'm;
+    fn start<'m>(
+        &'m mut self,
+        ssid: &'m str,
+        password: &'m str,
+        channel: u8,
+    ) -> Self::StartFuture<'m> {
+        async move {
+            let mut ap_ssid: String<U32> = String::new();
+            ap_ssid.push_str(ssid).map_err(|_| WifiError::InvalidSsid)?;
+            if !password.is_empty() && password.len() < 8 {
+                return Err(WifiError::InvalidPassword);
+            }
+            let mut ap_password: String<U64> = String::new();
+            ap_password
+                .push_str(password)
+                .map_err(|_| WifiError::InvalidPassword)?;
+
+            // Keep the station enabled so that joining a network remains possible
+            self.set_wifi_mode(WiFiMode::SoftAccessPointAndStation)
+                .await
+                .map_err(|_| WifiError::CommandFailed)?;
+            let command = Command::SetAccessPoint {
+                ssid,
+                password,
+                channel,
+            };
+            match self.send(command).await {
+                Ok(AtResponse::Ok) => {}
+                Err(DriverError::Timeout) => return Err(WifiError::Timeout),
+                Ok(AtResponse::Error) | Err(_) => return Err(WifiError::CommandFailed),
+                Ok(r) => {
+                    warn!("Unexpected response: {:?}", r);
+                    return Err(WifiError::UnexpectedResponse);
+                }
+            }
+            self.access_point.replace((ap_ssid, ap_password, channel));
+            match self.send(Command::QueryAccessPointAddress).await {
+                Ok(AtResponse::IpAddresses(addresses)) => Ok(IpAddress::V4(addresses.ip)),
+                Err(DriverError::Timeout) => Err(WifiError::Timeout),
+                Ok(AtResponse::Error) | Err(_) => Err(WifiError::CommandFailed),
+                Ok(r) => {
+                    warn!("Unexpected response: {:?}", r);
+                    Err(WifiError::UnexpectedResponse)
+                }
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type StationsFuture<'m> where 'a: 'm = impl Future<Output = Result<usize, WifiError>> + 'm;
+    fn stations<'m>(&'m mut self, stations: &'m mut [StationInfo]) -> Self::StationsFuture<'m> {
+        async move {
+            let mut count = 0;
+            let mut response = self.send(Command::ListStations).await;
+            loop {
+                match response {
+                    Ok(AtResponse::Station(station)) => {
+                        if count < stations.len() {
+                            stations[count] = station;
+                            count += 1;
+                        }
+                    }
+                    Ok(AtResponse::Ok) => return Ok(count),
+                    Ok(AtResponse::Error) => return Err(WifiError::CommandFailed),
+                    Ok(r) => {
+                        warn!("Unexpected response: {:?}", r);
+                        return Err(WifiError::UnexpectedResponse);
+                    }
+                    Err(DriverError::Timeout) => return Err(WifiError::Timeout),
+                    Err(_) => return Err(WifiError::CommandFailed),
                 }
-                Err(_) => Err(TcpError::WriteError),
+                response = self.receive(self.command_timeout).await;
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type StopFuture<'m> where 'a: 'm = impl Future<Output = Result<(), WifiError>> + 'm;
+    fn stop<'m>(&'m mut self) -> Self::StopFuture<'m> {
+        async move {
+            self.access_point.take();
+            self.set_wifi_mode(WiFiMode::Station)
+                .await
+                .map_err(|_| WifiError::CommandFailed)
+        }
+    }
+}
+
+impl<'a> TcpStack for Esp8266Controller<'a> {
+    type SocketHandle = u8;
+
+    #[rustfmt::skip]
+    type OpenFuture<'m> where 'a: 'm = impl Future<Output = Self::SocketHandle> + 'm;
+    fn open<'m>(&'m mut self) -> Self::OpenFuture<'m> {
+        async move {
+            let handle = self.socket_pool.open().await;
+            self.timeouts[handle as usize] = None;
+            handle
+        }
+    }
+
+    #[rustfmt::skip]
+    type ConnectFuture<'m> where 'a: 'm = impl Future<Output = Result<(), TcpError>> + 'm;
+    fn connect<'m>(
+        &'m mut self,
+        handle: Self::SocketHandle,
+        _: IpProtocol,
+        dst: SocketAddress,
+    ) -> Self::ConnectFuture<'m> {
+        async move {
+            let result = if self.mode == TransferMode::Passthrough {
+                self.start_passthrough(handle, dst).await
+            } else {
+                self.start_connection(handle, ConnectionType::TCP, dst)
+                    .await
             };
-            result
+            result.map_err(|e| match e {
+                DriverError::Timeout => TcpError::Timeout,
+                _ => TcpError::ConnectError,
+            })
+        }
+    }
+
+    #[rustfmt::skip]
+    type WriteFuture<'m> where 'a: 'm = impl Future<Output = Result<usize, TcpError>> + 'm;
+    fn write<'m>(&'m mut self, handle: Self::SocketHandle, buf: &'m [u8]) -> Self::WriteFuture<'m> {
+        async move {
+            self.process_notifications();
+            if self.socket_pool.is_closed(handle) {
+                return Err(TcpError::SocketClosed);
+            }
+            if self.mode == TransferMode::Passthrough {
+                if self.passthrough.get() != Some(handle) {
+                    return Err(TcpError::WriteError);
+                }
+                self.send_raw(buf).await;
+                return Ok(buf.len());
+            }
+            let deadline = self.deadline(handle);
+            self.send_data(handle, buf, deadline)
+                .await
+                .map_err(|e| match e {
+                    DriverError::Timeout => TcpError::Timeout,
+                    _ => TcpError::WriteError,
+                })
         }
     }
 
@@ -532,47 +1701,217 @@ impl<'a> TcpStack for Esp8266Controller<'a> {
         buf: &'m mut [u8],
     ) -> Self::ReadFuture<'m> {
         async move {
-            let mut rp = 0;
+            let deadline = match self.deadline(handle) {
+                Some(deadline) if !buf.is_empty() => deadline,
+                _ => return self.read_available(handle, buf).await,
+            };
             loop {
-                let result = async {
+                match self.read_available(handle, buf).await? {
+                    0 => {}
+                    len => return Ok(len),
+                }
+                // Wait for the modem to report data before reading again
+                loop {
+                    if Instant::now() >= deadline {
+                        return Err(TcpError::Timeout);
+                    }
+                    Timer::after(READ_INTERVAL).await;
                     self.process_notifications();
-                    if self.socket_pool.is_closed(handle) {
-                        return Err(TcpError::SocketClosed);
+                    if self.buffered(handle) > 0 || self.socket_pool.is_closed(handle) {
+                        break;
                     }
+                }
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type CloseFuture<'m> where 'a: 'm = impl Future<Output = ()> + 'm;
+    fn close<'m>(&'m mut self, handle: Self::SocketHandle) -> Self::CloseFuture<'m> {
+        async move {
+            // Released before waiting for the modem, so that the socket is not leaked when the
+            // close is not awaited to completion.
+            self.socket_pool.close(handle);
+            self.timeouts[handle as usize] = None;
+            if self.mode == TransferMode::Passthrough {
+                if self.passthrough.get() == Some(handle) {
+                    self.stop_passthrough().await;
+                }
+                return;
+            }
+            let command = Command::CloseConnection(handle as usize);
+            if let Err(e) = self.send(command).await {
+                warn!("Error closing socket {}: {:?}", handle, e);
+            }
+        }
+    }
 
-                    let command = Command::Receive {
-                        link_id: handle as usize,
-                        len: core::cmp::min(buf.len() - rp, BUFFER_LEN),
-                    };
+    fn close_detached(&mut self, handle: Self::SocketHandle) {
+        self.socket_pool.close(handle);
+        self.timeouts[handle as usize] = None;
+        // A passthrough connection is closed when the next one is started
+        if self.mode != TransferMode::Passthrough {
+            self.closing.set(self.closing.get() | 1 << handle);
+        }
+    }
 
-                    match self.send(command).await {
-                        Ok(AtResponse::DataReceived(inbound, len)) => {
-                            for (i, b) in inbound[0..len].iter().enumerate() {
-                                buf[rp + i] = *b;
-                            }
-                            Ok(len)
-                        }
-                        Ok(AtResponse::Ok) => Ok(0),
-                        _ => Err(TcpError::ReadError),
-                    }
+    #[rustfmt::skip]
+    type PollReadableFuture<'m> where 'a: 'm = impl Future<Output = Result<Readiness, TcpError>> + 'm;
+    fn poll_readable<'m>(&'m mut self, handle: Self::SocketHandle) -> Self::PollReadableFuture<'m> {
+        async move {
+            self.process_notifications();
+            if self.buffered(handle) > 0 {
+                Ok(Readiness::Readable)
+            } else if self.socket_pool.is_half_closed(handle) {
+                Ok(Readiness::HalfClosed)
+            } else if self.socket_pool.is_closed(handle) {
+                Err(TcpError::SocketClosed)
+            } else {
+                Ok(Readiness::Pending)
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type AvailableFuture<'m> where 'a: 'm = impl Future<Output = Result<usize, TcpError>> + 'm;
+    fn available<'m>(&'m mut self, handle: Self::SocketHandle) -> Self::AvailableFuture<'m> {
+        async move {
+            self.process_notifications();
+            match self.buffered(handle) {
+                0 if self.socket_pool.is_closed(handle) => Err(TcpError::SocketClosed),
+                available => Ok(available),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type SetTimeoutFuture<'m> where 'a: 'm = impl Future<Output = Result<(), TcpError>> + 'm;
+    fn set_timeout<'m>(
+        &'m mut self,
+        handle: Self::SocketHandle,
+        timeout: Option<Duration>,
+    ) -> Self::SetTimeoutFuture<'m> {
+        async move {
+            match self.timeouts.get_mut(handle as usize) {
+                Some(slot) => {
+                    *slot = timeout;
+                    Ok(())
                 }
-                .await;
+                None => Err(TcpError::SocketClosed),
+            }
+        }
+    }
+}
 
-                match result {
-                    Ok(len) => {
-                        rp += len;
-                        if len == 0 || rp == buf.len() {
-                            return Ok(rp);
-                        }
-                    }
-                    Err(e) => {
-                        if rp == 0 {
-                            return Err(e);
-                        } else {
-                            return Ok(rp);
-                        }
-                    }
+impl<'a> TcpServer for Esp8266Controller<'a> {
+    #[rustfmt::skip]
+    type ListenFuture<'m> where 'a: 'm = impl Future<Output = Result<(), TcpError>> + 'm;
+    fn listen<'m>(&'m mut self, port: u16) -> Self::ListenFuture<'m> {
+        async move {
+            if self.mode == TransferMode::Passthrough {
+                return Err(TcpError::ListenError);
+            }
+            match self.send(Command::StartServer(port)).await {
+                Ok(AtResponse::Ok) => Ok(()),
+                _ => Err(TcpError::ListenError),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type AcceptFuture<'m> where 'a: 'm = impl Future<Output = Result<Self::SocketHandle, TcpError>> + 'm;
+    fn accept<'m>(&'m mut self) -> Self::AcceptFuture<'m> {
+        async move {
+            loop {
+                self.process_notifications();
+                if let Some(link_id) = self.incoming.dequeue() {
+                    return Ok(link_id);
                 }
+                // Notifications for other sockets are kept while waiting
+                let response = self.notification_consumer.receive().await;
+                self.process_notification(response);
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type UnlistenFuture<'m> where 'a: 'm = impl Future<Output = Result<(), TcpError>> + 'm;
+    fn unlisten<'m>(&'m mut self) -> Self::UnlistenFuture<'m> {
+        async move {
+            match self.send(Command::StopServer).await {
+                Ok(AtResponse::Ok) => Ok(()),
+                _ => Err(TcpError::ListenError),
+            }
+        }
+    }
+}
+
+// The trait is deliberately not imported, so that `self.send(..)` keeps resolving to the
+// inherent method that sends AT commands.
+impl<'a> udp::UdpStack for Esp8266Controller<'a> {
+    type SocketHandle = u8;
+
+    #[rustfmt::skip]
+    type OpenFuture<'m> where 'a: 'm = impl Future<Output = Self::SocketHandle> + 'm;
+    fn open<'m>(&'m mut self) -> Self::OpenFuture<'m> {
+        async move { self.socket_pool.open().await }
+    }
+
+    #[rustfmt::skip]
+    type ConnectFuture<'m> where 'a: 'm = impl Future<Output = Result<(), UdpError>> + 'm;
+    fn connect<'m>(
+        &'m mut self,
+        handle: Self::SocketHandle,
+        dst: SocketAddress,
+    ) -> Self::ConnectFuture<'m> {
+        async move {
+            if self.mode == TransferMode::Passthrough {
+                return Err(UdpError::ConnectError);
+            }
+            self.start_connection(handle, ConnectionType::UDP, dst)
+                .await
+                .map_err(|e| match e {
+                    DriverError::Timeout => UdpError::Timeout,
+                    _ => UdpError::ConnectError,
+                })
+        }
+    }
+
+    #[rustfmt::skip]
+    type SendFuture<'m> where 'a: 'm = impl Future<Output = Result<usize, UdpError>> + 'm;
+    fn send<'m>(&'m mut self, handle: Self::SocketHandle, buf: &'m [u8]) -> Self::SendFuture<'m> {
+        async move {
+            self.process_notifications();
+            if self.socket_pool.is_closed(handle) {
+                return Err(UdpError::SocketClosed);
+            }
+            // A datagram must not be split
+            self.send_chunk(handle, buf).await.map_err(|e| match e {
+                DriverError::Timeout => UdpError::Timeout,
+                _ => UdpError::SendError,
+            })
+        }
+    }
+
+    #[rustfmt::skip]
+    type RecvFuture<'m> where 'a: 'm = impl Future<Output = Result<usize, UdpError>> + 'm;
+    fn recv<'m>(
+        &'m mut self,
+        handle: Self::SocketHandle,
+        buf: &'m mut [u8],
+    ) -> Self::RecvFuture<'m> {
+        async move {
+            loop {
+                self.process_notifications();
+                // Datagrams received before the socket was closed can still be read
+                if let Some(len) = self.buffers.read_datagram(handle as usize, buf) {
+                    return Ok(len);
+                }
+                if self.socket_pool.is_closed(handle) {
+                    return Err(UdpError::SocketClosed);
+                }
+                let response = self.notification_consumer.receive().await;
+                self.process_notification(response);
             }
         }
     }
@@ -581,17 +1920,28 @@ impl<'a> TcpStack for Esp8266Controller<'a> {
     type CloseFuture<'m> where 'a: 'm = impl Future<Output = ()> + 'm;
     fn close<'m>(&'m mut self, handle: Self::SocketHandle) -> Self::CloseFuture<'m> {
         async move {
+            // Released before waiting for the modem, so that the socket is not leaked when the
+            // close is not awaited to completion.
+            self.socket_pool.close(handle);
             let command = Command::CloseConnection(handle as usize);
-            match self.send(command).await {
-                Ok(AtResponse::Ok) | Ok(AtResponse::UnlinkFail) => {
-                    self.socket_pool.close(handle);
-                }
-                _ => {}
+            if let Err(e) = self.send(command).await {
+                warn!("Error closing socket {}: {:?}", handle, e);
             }
         }
     }
 }
 
+/// The link id of a command starting a connection, and whether it is a UDP connection.
+fn connection_start(command: &[u8]) -> Option<(usize, bool)> {
+    let args = command.strip_prefix(b"AT+CIPSTART=")?;
+    let link_id = match args.first() {
+        Some(d) if d.is_ascii_digit() => (d - b'0') as usize,
+        _ => 0,
+    };
+    let udp = args.windows(5).any(|w| w == b"\"UDP\"");
+    Some((link_id, udp))
+}
+
 async fn uart_read<UART>(uart: &mut UART, rx_buf: &mut [u8]) -> Result<usize, embassy::io::Error>
 where
     UART: AsyncBufRead + AsyncBufReadExt + 'static,
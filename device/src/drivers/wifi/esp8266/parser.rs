@@ -1,6 +1,6 @@
 use nom::alt;
 use nom::char;
-use nom::character::streaming::digit1;
+use nom::character::streaming::{digit1, hex_digit1};
 use nom::do_parse;
 use nom::named;
 use nom::opt;
@@ -10,11 +10,18 @@ use nom::take_until;
 use nom::tuple;
 use nom::IResult;
 
-use crate::traits::ip::{IpAddress, IpAddressV4};
+use crate::traits::{
+    ip::{IpAddress, IpAddressV4},
+    wifi::{AccessPointInfo, StationInfo},
+};
+use heapless::{consts::U32, String};
 
 use super::{
     num::{atoi_u8, atoi_usize},
-    protocol::{FirmwareInfo, IpAddresses, ResolverAddresses, Response, WifiConnectionFailure},
+    protocol::{
+        security, FirmwareInfo, IpAddresses, JoinedAp, ResolverAddresses, Response,
+        WifiConnectionFailure,
+    },
     BUFFER_LEN,
 };
 
@@ -23,6 +30,69 @@ fn parse_u8(input: &[u8]) -> IResult<&[u8], u8> {
     IResult::Ok((input, atoi_u8(digits).unwrap()))
 }
 
+fn parse_i8(input: &[u8]) -> IResult<&[u8], i8> {
+    let (input, negative) = match input.first() {
+        Some(b'-') => (&input[1..], true),
+        _ => (input, false),
+    };
+    let (input, value) = parse_u8(input)?;
+    let value = if negative {
+        -(value as i16)
+    } else {
+        value as i16
+    };
+    IResult::Ok((input, value as i8))
+}
+
+fn text(data: &[u8]) -> String<U32> {
+    let mut text = String::new();
+    for c in core::str::from_utf8(data).unwrap_or("").chars() {
+        if text.push(c).is_err() {
+            break;
+        }
+    }
+    text
+}
+
+/// The value of a line in the output of `AT+GMR`, without the details in parentheses.
+fn firmware_field(details: &[u8], name: &[u8]) -> Option<String<U32>> {
+    let start = details.windows(name.len()).position(|w| w == name)? + name.len();
+    let value = &details[start..];
+    let end = value
+        .iter()
+        .position(|b| *b == b'\r' || *b == b'(')
+        .unwrap_or(value.len());
+    Some(text(&value[..end]))
+}
+
+/// The module the firmware was built for, reported as `Bin version(WROOM 02):1.7.4` in 1.x and
+/// as `Bin version:2.1.0(WROOM-32)` in 2.x.
+fn firmware_module(details: &[u8]) -> Option<String<U32>> {
+    let start = details.windows(11).position(|w| w == b"Bin version")?;
+    let line = &details[start..];
+    let line = &line[..line.iter().position(|b| *b == b'\r').unwrap_or(line.len())];
+    let open = line.iter().position(|b| *b == b'(')? + 1;
+    let close = open + line[open..].iter().position(|b| *b == b')')?;
+    Some(text(&line[open..close]))
+}
+
+fn parse_mac(input: &[u8]) -> IResult<&[u8], [u8; 6]> {
+    let mut mac = [0; 6];
+    let mut input = input;
+    for (i, octet) in mac.iter_mut().enumerate() {
+        if i > 0 {
+            let (i, _) = char!(input, ':')?;
+            input = i;
+        }
+        let (i, digits) = hex_digit1(input)?;
+        *octet = digits.iter().fold(0, |acc, d| {
+            acc << 4 | (*d as char).to_digit(16).unwrap() as u8
+        });
+        input = i;
+    }
+    IResult::Ok((input, mac))
+}
+
 fn parse_usize(input: &[u8]) -> IResult<&[u8], usize> {
     let (input, digits) = digit1(input)?;
     let num = atoi_usize(digits).unwrap();
@@ -150,10 +220,146 @@ named!(
         patch: parse_u8 >>
         tag!(".") >>
         build: parse_u8 >>
-        take_until!("OK") >>
+        details: take_until!("OK") >>
+        ok >>
+        (
+            Response::FirmwareInfo(FirmwareInfo{
+                major,
+                minor,
+                patch,
+                build,
+                sdk: firmware_field(details, b"SDK version:"),
+                module: firmware_module(details),
+            })
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub access_point<Response>,
+    do_parse!(
+        opt!(crlf) >>
+        tag!("+CWLAP:(") >>
+        ecn: parse_u8 >>
+        tag!(",\"") >>
+        name: take_until!("\"") >>
+        tag!("\",") >>
+        rssi: parse_i8 >>
+        tag!(",\"") >>
+        take_until!("\"") >>
+        tag!("\",") >>
+        channel: parse_u8 >>
+        take_until!(")") >>
+        char!(')') >>
+        crlf >>
+        (
+            Response::AccessPoint(AccessPointInfo {
+                ssid: text(name),
+                rssi,
+                channel,
+                security: security(ecn),
+            })
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub joined_ap<Response>,
+    do_parse!(
+        tag!("+CWJAP") >>
+        opt!(tag!("_CUR")) >>
+        tag!(":\"") >>
+        name: take_until!("\"") >>
+        tag!("\",\"") >>
+        take_until!("\"") >>
+        tag!("\",") >>
+        channel: parse_u8 >>
+        char!(',') >>
+        rssi: parse_i8 >>
+        // Followed by connection settings in ESP-AT 2.x
+        take_until!("\r\n") >>
+        crlf >>
+        ok >>
+        (
+            Response::JoinedAp(Some(JoinedAp {
+                ssid: text(name),
+                channel,
+                rssi,
+            }))
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub no_ap<Response>,
+    do_parse!(
+        tag!("No AP") >>
+        crlf >>
         ok >>
         (
-            Response::FirmwareInfo(FirmwareInfo{major, minor, patch, build})
+            Response::JoinedAp(None)
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub station<Response>,
+    do_parse!(
+        opt!(crlf) >>
+        ip: ip_addr >>
+        char!(',') >>
+        mac: parse_mac >>
+        crlf >>
+        (
+            Response::Station(StationInfo { ip: IpAddress::V4(ip), mac })
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub station_connected<Response>,
+    do_parse!(
+        tag!("+STA_CONNECTED:\"") >>
+        mac: parse_mac >>
+        char!('"') >>
+        crlf >>
+        (
+            Response::StationConnected(mac)
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub station_disconnected<Response>,
+    do_parse!(
+        tag!("+STA_DISCONNECTED:\"") >>
+        mac: parse_mac >>
+        char!('"') >>
+        crlf >>
+        (
+            Response::StationDisconnected(mac)
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub station_ip<Response>,
+    do_parse!(
+        tag!("+DIST_STA_IP:\"") >>
+        mac: parse_mac >>
+        tag!("\",\"") >>
+        ip: ip_addr >>
+        char!('"') >>
+        crlf >>
+        (
+            Response::StationIp(StationInfo { ip: IpAddress::V4(ip), mac })
         )
     )
 );
@@ -179,19 +385,51 @@ named!(
 named!(
     pub ip_addresses<Response>,
     do_parse!(
-        tag!("+CIPSTA_CUR:ip:\"") >>
+        tag!("+CIPSTA") >> opt!(tag!("_CUR")) >> tag!(":ip:\"") >>
         ip: ip_addr >>
         tag!("\"") >>
         crlf >>
-        tag!("+CIPSTA_CUR:gateway:\"") >>
+        tag!("+CIPSTA") >> opt!(tag!("_CUR")) >> tag!(":gateway:\"") >>
         gateway: ip_addr >>
         tag!("\"") >>
         crlf >>
-        tag!("+CIPSTA_CUR:netmask:\"") >>
+        tag!("+CIPSTA") >> opt!(tag!("_CUR")) >> tag!(":netmask:\"") >>
         netmask: ip_addr >>
         tag!("\"") >>
         crlf >>
+        // ESP-AT 2.x may also report IPv6 addresses
+        take_until!("OK\r\n") >>
+        ok >>
+        (
+            Response::IpAddresses(
+                IpAddresses {
+                    ip,
+                    gateway,
+                    netmask,
+                }
+            )
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub ap_ip_addresses<Response>,
+    do_parse!(
+        tag!("+CIPAP") >> opt!(tag!("_CUR")) >> tag!(":ip:\"") >>
+        ip: ip_addr >>
+        tag!("\"") >>
+        crlf >>
+        tag!("+CIPAP") >> opt!(tag!("_CUR")) >> tag!(":gateway:\"") >>
+        gateway: ip_addr >>
+        tag!("\"") >>
+        crlf >>
+        tag!("+CIPAP") >> opt!(tag!("_CUR")) >> tag!(":netmask:\"") >>
+        netmask: ip_addr >>
+        tag!("\"") >>
         crlf >>
+        // ESP-AT 2.x may also report IPv6 addresses
+        take_until!("OK\r\n") >>
         ok >>
         (
             Response::IpAddresses(
@@ -212,13 +450,24 @@ named!(
         link_id: parse_u8 >>
         tag!(",CONNECT") >>
         crlf >>
-        ok >>
         (
             Response::Connect(link_id as usize)
         )
     )
 );
 
+#[rustfmt::skip]
+named!(
+    pub single_connect<Response>,
+    do_parse!(
+        tag!("CONNECT") >>
+        crlf >>
+        (
+            Response::Connect(0)
+        )
+    )
+);
+
 named!(
     pub ready_for_data<Response>,
     do_parse!(
@@ -282,6 +531,25 @@ named!(
     )
 );
 
+named!(
+    pub data_frame<Response>,
+    do_parse!(
+        opt!( crlf ) >>
+        tag!( "+IPD,") >>
+        link_id: parse_usize >>
+        char!(',') >>
+        len: parse_usize >>
+        char!(':') >>
+        data: take!(len) >>
+        ( {
+            let mut buf = [0; BUFFER_LEN];
+            let len = core::cmp::min(len, BUFFER_LEN);
+            buf[..len].copy_from_slice(&data[..len]);
+            Response::DataFrame(link_id, buf, len)
+        } )
+    )
+);
+
 named!(
     pub closed<Response>,
     do_parse!(
@@ -295,14 +563,47 @@ named!(
     )
 );
 
+named!(
+    pub single_closed<Response>,
+    do_parse!(
+        opt!(crlf) >>
+        tag!("CLOSED") >>
+        crlf >>
+        (
+            Response::Closed(0)
+        )
+    )
+);
+
+named!(
+    pub frame_header<(usize, usize)>,
+    do_parse!(
+        opt!( crlf ) >>
+        tag!( "+IPD,") >>
+        link_id: parse_usize >>
+        char!(',') >>
+        len: parse_usize >>
+        char!(':') >>
+        (
+            (link_id, len)
+        )
+    )
+);
+
 named!(
     pub data_received<Response>,
     do_parse!(
         opt!(tag!("\r")) >>
         opt!(tag!("\n")) >>
-        tag!("+CIPRECVDATA,") >>
-        len: parse_usize >>
-        char!(':') >>
+        len: alt!(
+            do_parse!(
+                tag!("+CIPRECVDATA,") >> len: parse_usize >> char!(':') >> (len)
+            )
+            // ESP-AT 2.x
+            | do_parse!(
+                tag!("+CIPRECVDATA:") >> len: parse_usize >> char!(',') >> (len)
+            )
+        ) >>
         data: take!(len) >>
         crlf >>
         ok >>
@@ -318,6 +619,23 @@ named!(
     )
 );
 
+#[rustfmt::skip]
+named!(
+    pub mac_address<Response>,
+    do_parse!(
+        tag!("+CIPSTAMAC") >>
+        opt!(tag!("_CUR")) >>
+        tag!(":\"") >>
+        mac: parse_mac >>
+        char!('"') >>
+        crlf >>
+        ok >>
+        (
+            Response::MacAddress(mac)
+        )
+    )
+);
+
 named!(
     pub dns_resolvers<Response>,
     do_parse!(
@@ -348,7 +666,9 @@ named!(
     pub dns_lookup<Response>,
     do_parse!(
         tag!("+CIPDOMAIN:") >>
+        opt!(char!('"')) >>
         ip_addr: ip_addr >>
+        opt!(char!('"')) >>
         crlf >>
         ok >>
         (
@@ -392,14 +712,26 @@ named!(
         | wifi_disconnect
         | wifi_connection_failure
         | got_ip
+        | access_point
+        | joined_ap
+        | no_ap
+        | station_connected
+        | station_disconnected
+        | station_ip
         | ip_addresses
+        | ap_ip_addresses
+        | mac_address
         | connect
+        | single_connect
         | closed
+        | single_closed
+        | station
         | ready_for_data
         | received_data_to_send
         | send_ok
         | send_fail
         | data_available
+        | data_frame
         | data_received
         | dns_resolvers
         | dns_lookup
@@ -407,3 +739,139 @@ named!(
         | unlink_fail
     )
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::wifi::esp8266::protocol::Dialect;
+    use crate::traits::wifi::Security;
+    use arrayvec::ArrayString;
+    use core::fmt::Write;
+
+    #[test]
+    fn test_access_point() {
+        let input = b"+CWLAP:(3,\"drogue\",-61,\"a0:b1:c2:d3:e4:f5\",11,-4,0,4,4,7,0)\r\n";
+        match parse(input) {
+            Ok((remainder, Response::AccessPoint(ap))) => {
+                assert!(remainder.is_empty());
+                assert_eq!("drogue", ap.ssid.as_str());
+                assert_eq!(-61, ap.rssi);
+                assert_eq!(11, ap.channel);
+                assert_eq!(Security::Wpa2Psk, ap.security);
+            }
+            r => panic!("Unexpected response: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_joined_ap() {
+        let input = b"+CWJAP_CUR:\"drogue\",\"a0:b1:c2:d3:e4:f5\",6,-55\r\n\r\nOK\r\n";
+        match parse(input) {
+            Ok((_, Response::JoinedAp(Some(ap)))) => {
+                assert_eq!("drogue", ap.ssid.as_str());
+                assert_eq!(6, ap.channel);
+                assert_eq!(-55, ap.rssi);
+            }
+            r => panic!("Unexpected response: {:?}", r),
+        }
+
+        assert!(matches!(
+            parse(b"No AP\r\n\r\nOK\r\n"),
+            Ok((_, Response::JoinedAp(None)))
+        ));
+    }
+
+    #[test]
+    fn test_v2_responses() {
+        let input = b"+CWJAP:\"drogue\",\"a0:b1:c2:d3:e4:f5\",6,-55,0,1,3,0,1\r\n\r\nOK\r\n";
+        assert!(matches!(
+            parse(input),
+            Ok((_, Response::JoinedAp(Some(ap)))) if ap.channel == 6 && ap.rssi == -55
+        ));
+
+        let input = b"+CIPSTA:ip:\"192.168.1.10\"\r\n+CIPSTA:gateway:\"192.168.1.1\"\r\n\
+            +CIPSTA:netmask:\"255.255.255.0\"\r\n+CIPSTA:ip6ll:\"fe80::1\"\r\n\r\nOK\r\n";
+        match parse(input) {
+            Ok((remainder, Response::IpAddresses(addresses))) => {
+                assert!(remainder.is_empty());
+                assert_eq!([192, 168, 1, 10], addresses.ip.octets());
+                assert_eq!([255, 255, 255, 0], addresses.netmask.octets());
+            }
+            r => panic!("Unexpected response: {:?}", r),
+        }
+
+        match parse(b"+CIPRECVDATA:5,hello\r\nOK\r\n") {
+            Ok((_, Response::DataReceived(data, len))) => assert_eq!(b"hello", &data[..len]),
+            r => panic!("Unexpected response: {:?}", r),
+        }
+
+        match parse(b"AT version:2.1.0.0(883f7f2 - Jul 24 2020 11:50:07)\r\nOK\r\n") {
+            Ok((_, Response::FirmwareInfo(info))) => assert_eq!(Dialect::V2, info.dialect()),
+            r => panic!("Unexpected response: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_firmware_info() {
+        let input = b"AT version:1.7.4.0(May 11 2020 19:13:04)\r\n\
+            SDK version:3.0.4(9532ceb)\r\ncompile time:May 27 2020 10:12:17\r\n\
+            Bin version(Wroom 02):1.7.4\r\nOK\r\n";
+        match parse(input) {
+            Ok((_, Response::FirmwareInfo(info))) => {
+                assert_eq!((1, 7, 4, 0), (info.major, info.minor, info.patch, info.build));
+                assert_eq!(Some("3.0.4"), info.sdk.as_deref());
+                assert_eq!(Some("Wroom 02"), info.module.as_deref());
+            }
+            r => panic!("Unexpected response: {:?}", r),
+        }
+
+        let input = b"AT version:2.1.0.0(883f7f2 - Jul 24 2020 11:50:07)\r\n\
+            SDK version:v4.0.1-193-ge7ac221\r\ncompile time(0ad6331):Jul 28 2020 02:47:21\r\n\
+            Bin version:2.1.0(WROOM-32)\r\n\r\nOK\r\n";
+        match parse(input) {
+            Ok((_, Response::FirmwareInfo(info))) => {
+                assert_eq!(Some("v4.0.1-193-ge7ac221"), info.sdk.as_deref());
+                assert_eq!(Some("WROOM-32"), info.module.as_deref());
+            }
+            r => panic!("Unexpected response: {:?}", r),
+        }
+
+        match parse(b"+CIPSTAMAC_CUR:\"18:fe:34:a1:b2:c3\"\r\n\r\nOK\r\n") {
+            Ok((_, Response::MacAddress(mac))) => {
+                assert_eq!([0x18, 0xfe, 0x34, 0xa1, 0xb2, 0xc3], mac)
+            }
+            r => panic!("Unexpected response: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_frame_header() {
+        assert!(matches!(
+            frame_header(b"\r\n+IPD,2,1460:"),
+            Ok((remainder, (2, 1460))) if remainder.is_empty()
+        ));
+        assert!(frame_header(b"+IPD,2,14").is_err());
+    }
+
+    #[test]
+    fn test_single_connection() {
+        assert!(matches!(
+            parse(b"CONNECT\r\n"),
+            Ok((_, Response::Connect(0)))
+        ));
+        assert!(matches!(parse(b"CLOSED\r\n"), Ok((_, Response::Closed(0)))));
+    }
+
+    #[test]
+    fn test_station() {
+        match parse(b"192.168.4.2,5c:cf:7f:0a:1b:2c\r\n") {
+            Ok((_, Response::Station(station))) => {
+                assert_eq!([0x5c, 0xcf, 0x7f, 0x0a, 0x1b, 0x2c], station.mac);
+                let mut ip = ArrayString::<16>::new();
+                write!(&mut ip, "{}", station.ip).expect("Can't write");
+                assert_eq!("192.168.4.2", &ip);
+            }
+            r => panic!("Unexpected response: {:?}", r),
+        }
+    }
+}
@@ -1,10 +1,18 @@
 use super::BUFFER_LEN;
-use crate::traits::ip::{IpAddress, IpAddressV4, SocketAddress};
+use crate::traits::{
+    ip::{IpAddress, IpAddressV4, SocketAddress},
+    modem::Version,
+    wifi::{AccessPointInfo, Security, StationInfo},
+};
 use core::fmt;
 use core::fmt::{Debug, Write};
-use heapless::{consts::U256, String};
+use heapless::{
+    consts::{U256, U32},
+    String,
+};
 
-#[derive(Debug)]
+/// DNS servers used to resolve host names.
+#[derive(Debug, Clone, Copy)]
 pub struct ResolverAddresses {
     pub resolver1: IpAddressV4,
     pub resolver2: Option<IpAddressV4>,
@@ -28,40 +36,155 @@ pub enum WiFiMode {
     SoftAccessPointAndStation,
 }
 
+/// Dialect of AT commands understood by the firmware of the board.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dialect {
+    /// The NonOS AT firmware 1.x of ESP8266 modules, using the `_CUR` commands.
+    V1,
+    /// The ESP-AT 2.x firmware of ESP32 and ESP32-C3 modules, and of recent ESP8266 modules.
+    V2,
+}
+
+impl Dialect {
+    /// Suffix of commands whose settings are not saved to flash.
+    fn current(&self) -> &'static str {
+        match self {
+            Dialect::V1 => "_CUR",
+            Dialect::V2 => "",
+        }
+    }
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Dialect::V1
+    }
+}
+
 /// Commands to be sent to the ESP board.
 #[derive(Debug)]
 pub enum Command<'a> {
     QueryFirmwareInfo,
     SetMode(WiFiMode),
     JoinAp { ssid: &'a str, password: &'a str },
+    QueryJoinedAp,
+    QuitAp,
+    ListAccessPoints,
+    SetAccessPoint { ssid: &'a str, password: &'a str, channel: u8 },
+    QueryAccessPointAddress,
+    ListStations,
     QueryIpAddress,
+    QueryMacAddress,
+    /// Set a static IP address for the station, which disables DHCP.
+    SetIpAddress(IpAddresses),
+    /// Enable or disable DHCP for the station.
+    SetDhcp(bool),
+    SetHostname(&'a str),
     StartConnection(usize, ConnectionType, SocketAddress),
     CloseConnection(usize),
+    /// Start the only connection, when multiple connections are disabled.
+    StartSingleConnection(ConnectionType, SocketAddress),
+    CloseSingleConnection,
+    /// Start sending data in passthrough mode, until `+++` is sent.
+    StartPassthrough,
+    StartServer(u16),
+    StopServer,
     Send { link_id: usize, len: usize },
     Receive { link_id: usize, len: usize },
     QueryDnsResolvers,
     SetDnsResolvers(ResolverAddresses),
+    /// Use the DNS servers provided by DHCP.
+    ClearDnsResolvers,
     GetHostByName { hostname: &'a str },
+    /// Enable SNTP with a server and a timezone offset in hours.
+    SetSntpConfig { timezone: i8, server: &'a str },
 }
 
 impl<'a> Command<'a> {
-    pub fn as_bytes(&self) -> String<U256> {
+    pub fn as_bytes(&self, dialect: Dialect) -> String<U256> {
+        let cur = dialect.current();
         match self {
             Command::QueryFirmwareInfo => String::from("AT+GMR"),
-            Command::QueryIpAddress => String::from("AT+CIPSTA_CUR?"),
-            Command::SetMode(mode) => match mode {
-                WiFiMode::Station => String::from("AT+CWMODE_CUR=1"),
-                WiFiMode::SoftAccessPoint => String::from("AT+CWMODE_CUR=2"),
-                WiFiMode::SoftAccessPointAndStation => String::from("AT+CWMODE_CUR=3"),
-            },
+            Command::QueryIpAddress => {
+                let mut s = String::new();
+                write!(s, "AT+CIPSTA{}?", cur).unwrap();
+                s
+            }
+            Command::QueryMacAddress => {
+                let mut s = String::new();
+                write!(s, "AT+CIPSTAMAC{}?", cur).unwrap();
+                s
+            }
+            Command::SetIpAddress(addresses) => {
+                let mut s = String::new();
+                write!(
+                    s,
+                    "AT+CIPSTA{}=\"{}\",\"{}\",\"{}\"",
+                    cur, addresses.ip, addresses.gateway, addresses.netmask
+                )
+                .unwrap();
+                s
+            }
+            Command::SetDhcp(enabled) => {
+                let mut s = String::new();
+                // The order of the mode, which is station, and the state changed in 2.x
+                match dialect {
+                    Dialect::V1 => write!(s, "AT+CWDHCP_CUR=1,{}", *enabled as u8).unwrap(),
+                    Dialect::V2 => write!(s, "AT+CWDHCP={},1", *enabled as u8).unwrap(),
+                }
+                s
+            }
+            Command::SetHostname(hostname) => {
+                let mut s = String::from("AT+CWHOSTNAME=");
+                write!(s, "\"{}\"", hostname).unwrap();
+                s
+            }
+            Command::SetMode(mode) => {
+                let mode = match mode {
+                    WiFiMode::Station => 1,
+                    WiFiMode::SoftAccessPoint => 2,
+                    WiFiMode::SoftAccessPointAndStation => 3,
+                };
+                let mut s = String::new();
+                write!(s, "AT+CWMODE{}={}", cur, mode).unwrap();
+                s
+            }
             Command::JoinAp { ssid, password } => {
-                let mut s = String::from("AT+CWJAP_CUR=\"");
+                let mut s = String::new();
+                write!(s, "AT+CWJAP{}=\"", cur).unwrap();
                 s.push_str(ssid).unwrap();
                 s.push_str("\",\"").unwrap();
                 s.push_str(password).unwrap();
                 s.push_str("\"").unwrap();
                 s
             }
+            Command::QueryJoinedAp => {
+                let mut s = String::new();
+                write!(s, "AT+CWJAP{}?", cur).unwrap();
+                s
+            }
+            Command::QuitAp => String::from("AT+CWQAP"),
+            Command::ListAccessPoints => String::from("AT+CWLAP"),
+            Command::SetAccessPoint {
+                ssid,
+                password,
+                channel,
+            } => {
+                let mut s = String::new();
+                write!(s, "AT+CWSAP{}=\"", cur).unwrap();
+                s.push_str(ssid).unwrap();
+                s.push_str("\",\"").unwrap();
+                s.push_str(password).unwrap();
+                let ecn = if password.is_empty() { 0 } else { 3 };
+                write!(s, "\",{},{}", channel, ecn).unwrap();
+                s
+            }
+            Command::QueryAccessPointAddress => {
+                let mut s = String::new();
+                write!(s, "AT+CIPAP{}?", cur).unwrap();
+                s
+            }
+            Command::ListStations => String::from("AT+CWLIF"),
             Command::StartConnection(link_id, connection_type, socket_addr) => {
                 let mut s = String::from("AT+CIPSTART=");
                 write!(s, "{},", link_id).unwrap();
@@ -81,11 +204,36 @@ impl<'a> Command<'a> {
                 }
                 s as String<U256>
             }
+            Command::StartSingleConnection(connection_type, socket_addr) => {
+                let mut s = String::from("AT+CIPSTART=");
+                match connection_type {
+                    ConnectionType::TCP => {
+                        write!(s, "\"TCP\"").unwrap();
+                    }
+                    ConnectionType::UDP => {
+                        write!(s, "\"UDP\"").unwrap();
+                    }
+                }
+                match socket_addr.ip() {
+                    IpAddress::V4(ip) => {
+                        write!(s, ",\"{}\",{}", ip, socket_addr.port()).unwrap();
+                    }
+                }
+                s
+            }
+            Command::CloseSingleConnection => String::from("AT+CIPCLOSE"),
+            Command::StartPassthrough => String::from("AT+CIPSEND"),
             Command::CloseConnection(link_id) => {
                 let mut s = String::from("AT+CIPCLOSE=");
                 write!(s, "{}", link_id).unwrap();
                 s
             }
+            Command::StartServer(port) => {
+                let mut s = String::from("AT+CIPSERVER=1,");
+                write!(s, "{}", port).unwrap();
+                s
+            }
+            Command::StopServer => String::from("AT+CIPSERVER=0"),
             Command::Send { link_id, len } => {
                 let mut s = String::from("AT+CIPSEND=");
                 write!(s, "{},{}", link_id, len).unwrap();
@@ -96,20 +244,34 @@ impl<'a> Command<'a> {
                 write!(s, "{},{}", link_id, len).unwrap();
                 s
             }
-            Command::QueryDnsResolvers => String::from("AT+CIPDNS_CUR?"),
+            Command::QueryDnsResolvers => {
+                let mut s = String::new();
+                write!(s, "AT+CIPDNS{}?", cur).unwrap();
+                s
+            }
             Command::SetDnsResolvers(addr) => {
-                let mut s = String::from("AT+CIPDNS_CUR=1,");
-                write!(s, "\"{}\"", addr.resolver1).unwrap();
+                let mut s = String::new();
+                write!(s, "AT+CIPDNS{}=1,\"{}\"", cur, addr.resolver1).unwrap();
                 if let Some(resolver2) = addr.resolver2 {
                     write!(s, ",\"{}\"", resolver2).unwrap()
                 }
                 s
             }
+            Command::ClearDnsResolvers => {
+                let mut s = String::new();
+                write!(s, "AT+CIPDNS{}=0", cur).unwrap();
+                s
+            }
             Command::GetHostByName { hostname } => {
                 let mut s = String::from("AT+CIPDOMAIN=");
                 write!(s, "\"{}\"", hostname).unwrap();
                 s
             }
+            Command::SetSntpConfig { timezone, server } => {
+                let mut s = String::from("AT+CIPSNTPCFG=");
+                write!(s, "1,{},\"{}\"", timezone, server).unwrap();
+                s
+            }
         }
     }
 }
@@ -127,15 +289,24 @@ pub enum Response {
     SendFail,
     DataAvailable { link_id: usize, len: usize },
     DataReceived([u8; BUFFER_LEN], usize),
+    /// A datagram delivered in active receive mode: link id, data and length.
+    DataFrame(usize, [u8; BUFFER_LEN], usize),
     WifiConnected,
     WifiConnectionFailure(WifiConnectionFailure),
     WifiDisconnect,
     GotIp,
+    AccessPoint(AccessPointInfo),
+    JoinedAp(Option<JoinedAp>),
+    Station(StationInfo),
+    StationConnected([u8; 6]),
+    StationDisconnected([u8; 6]),
+    StationIp(StationInfo),
     IpAddresses(IpAddresses),
     Connect(usize),
     Closed(usize),
     Resolvers(ResolverAddresses),
     IpAddress(IpAddress),
+    MacAddress([u8; 6]),
     DnsFail,
     UnlinkFail,
 }
@@ -160,16 +331,30 @@ impl Debug for Response {
                 .finish(),
             //Response::DataReceived(d, l) => dump_data("DataReceived", d, *l, f),
             Response::DataReceived(_, _) => f.write_str("DataReceived"),
+            Response::DataFrame(link_id, _, len) => f
+                .debug_tuple("DataFrame")
+                .field(link_id)
+                .field(len)
+                .finish(),
             Response::WifiConnected => f.write_str("WifiConnected"),
             Response::WifiConnectionFailure(v) => {
                 f.debug_tuple("WifiConnectionFailure").field(v).finish()
             }
             Response::WifiDisconnect => f.write_str("WifiDisconnect"),
             Response::GotIp => f.write_str("GotIp"),
+            Response::AccessPoint(v) => f.debug_tuple("AccessPoint").field(v).finish(),
+            Response::JoinedAp(v) => f.debug_tuple("JoinedAp").field(v).finish(),
+            Response::Station(v) => f.debug_tuple("Station").field(v).finish(),
+            Response::StationConnected(v) => f.debug_tuple("StationConnected").field(v).finish(),
+            Response::StationDisconnected(v) => {
+                f.debug_tuple("StationDisconnected").field(v).finish()
+            }
+            Response::StationIp(v) => f.debug_tuple("StationIp").field(v).finish(),
             Response::IpAddresses(v) => f.debug_tuple("IpAddresses").field(v).finish(),
             Response::Connect(v) => f.debug_tuple("Connect").field(v).finish(),
             Response::Closed(v) => f.debug_tuple("Closed").field(v).finish(),
             Response::IpAddress(v) => f.debug_tuple("IpAddress").field(v).finish(),
+            Response::MacAddress(v) => f.debug_tuple("MacAddress").field(v).finish(),
             Response::Resolvers(v) => f.debug_tuple("Resolvers").field(v).finish(),
             Response::DnsFail => f.write_str("DNS Fail"),
             Response::UnlinkFail => f.write_str("UnlinkFail"),
@@ -178,13 +363,36 @@ impl Debug for Response {
 }
 
 /// IP addresses for the board, including its own address, netmask and gateway.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct IpAddresses {
     pub ip: IpAddressV4,
     pub gateway: IpAddressV4,
     pub netmask: IpAddressV4,
 }
 
+/// The access point the station is currently joined to.
+#[derive(Debug)]
+pub struct JoinedAp {
+    pub ssid: String<U32>,
+    pub channel: u8,
+    pub rssi: i8,
+}
+
+/// Map the encryption method reported when listing access points.
+pub fn security(ecn: u8) -> Security {
+    match ecn {
+        0 => Security::Open,
+        1 => Security::Wep,
+        2 => Security::WpaPsk,
+        3 => Security::Wpa2Psk,
+        4 => Security::WpaWpa2Psk,
+        5 => Security::Wpa2Enterprise,
+        6 => Security::Wpa3Psk,
+        7 => Security::Wpa2Wpa3Psk,
+        _ => Security::Unknown,
+    }
+}
+
 /// Version information for the ESP board.
 #[derive(Debug)]
 pub struct FirmwareInfo {
@@ -192,6 +400,23 @@ pub struct FirmwareInfo {
     pub minor: u8,
     pub patch: u8,
     pub build: u8,
+    pub sdk: Option<String<U32>>,
+    pub module: Option<String<U32>>,
+}
+
+impl FirmwareInfo {
+    pub fn version(&self) -> Version {
+        Version::new(self.major, self.minor, self.patch, self.build)
+    }
+
+    /// The dialect of the AT firmware, which is versioned independently of the SDK.
+    pub fn dialect(&self) -> Dialect {
+        if self.major >= 2 {
+            Dialect::V2
+        } else {
+            Dialect::V1
+        }
+    }
 }
 
 /// Reasons for Wifi access-point join failures.
@@ -266,6 +491,77 @@ mod test {
         assert_eq!(&buf, "Connect(1)");
     }
 
+    #[test]
+    fn test_network_configuration_commands() {
+        let addresses = IpAddresses {
+            ip: IpAddressV4::new(192, 168, 1, 10),
+            gateway: IpAddressV4::new(192, 168, 1, 1),
+            netmask: IpAddressV4::new(255, 255, 255, 0),
+        };
+        assert_eq!(
+            "AT+CIPSTA_CUR=\"192.168.1.10\",\"192.168.1.1\",\"255.255.255.0\"",
+            Command::SetIpAddress(addresses)
+                .as_bytes(Dialect::V1)
+                .as_str()
+        );
+        assert_eq!(
+            "AT+CWDHCP_CUR=1,1",
+            Command::SetDhcp(true).as_bytes(Dialect::V1).as_str()
+        );
+        assert_eq!(
+            "AT+CWHOSTNAME=\"drogue\"",
+            Command::SetHostname("drogue")
+                .as_bytes(Dialect::V1)
+                .as_str()
+        );
+        let resolvers = ResolverAddresses {
+            resolver1: IpAddressV4::new(1, 1, 1, 1),
+            resolver2: Some(IpAddressV4::new(8, 8, 8, 8)),
+        };
+        assert_eq!(
+            "AT+CIPDNS_CUR=1,\"1.1.1.1\",\"8.8.8.8\"",
+            Command::SetDnsResolvers(resolvers)
+                .as_bytes(Dialect::V1)
+                .as_str()
+        );
+    }
+
+    #[test]
+    fn test_v2_dialect() {
+        assert_eq!(
+            "AT+CWMODE=1",
+            Command::SetMode(WiFiMode::Station)
+                .as_bytes(Dialect::V2)
+                .as_str()
+        );
+        assert_eq!(
+            "AT+CWJAP=\"drogue\",\"secret\"",
+            Command::JoinAp {
+                ssid: "drogue",
+                password: "secret"
+            }
+            .as_bytes(Dialect::V2)
+            .as_str()
+        );
+        assert_eq!(
+            "AT+CIPSTA?",
+            Command::QueryIpAddress.as_bytes(Dialect::V2).as_str()
+        );
+        assert_eq!(
+            "AT+CWDHCP=0,1",
+            Command::SetDhcp(false).as_bytes(Dialect::V2).as_str()
+        );
+        assert_eq!(
+            "AT+CIPSNTPCFG=1,-5,\"pool.ntp.org\"",
+            Command::SetSntpConfig {
+                timezone: -5,
+                server: "pool.ntp.org"
+            }
+            .as_bytes(Dialect::V2)
+            .as_str()
+        );
+    }
+
     fn test_debug_data() {
         let mut buf = ArrayString::<256>::new();
         let data = b"FOO\0BAR";
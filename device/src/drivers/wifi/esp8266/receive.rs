@@ -0,0 +1,199 @@
+use core::cell::RefCell;
+use heapless::{
+    consts::{U1024, U2048},
+    spsc::Queue,
+};
+
+type Buffer = RefCell<Queue<u8, U2048>>;
+
+/// Datagrams received on a link, each preceded by its length.
+type Datagrams = RefCell<Queue<u8, U1024>>;
+
+/// Storage for the buffers of data received in active or passthrough mode, one per link. Passive
+/// mode needs none, passthrough mode one, and active mode one per socket. Datagrams are pushed by
+/// the modem in every mode, and are kept apart for each of the four links.
+pub struct ReceiveStorage<const LINKS: usize> {
+    buffers: [Buffer; LINKS],
+    datagrams: [Datagrams; 4],
+}
+
+impl<const LINKS: usize> ReceiveStorage<LINKS> {
+    pub fn new() -> Self {
+        Self {
+            buffers: [(); LINKS].map(|_| RefCell::new(Queue::new())),
+            datagrams: [(); 4].map(|_| RefCell::new(Queue::new())),
+        }
+    }
+
+    pub(crate) fn buffers(&self) -> ReceiveBuffers<'_> {
+        ReceiveBuffers {
+            buffers: &self.buffers,
+            datagrams: &self.datagrams,
+        }
+    }
+}
+
+/// Per-link buffers shared by the modem, which fills them from `+IPD` frames or passthrough data,
+/// and the controller, which reads them.
+#[derive(Clone, Copy)]
+pub struct ReceiveBuffers<'a> {
+    buffers: &'a [Buffer],
+    datagrams: &'a [Datagrams],
+}
+
+impl<'a> ReceiveBuffers<'a> {
+    /// Buffer a received byte, failing when the buffer is full.
+    pub(crate) fn write(&self, link_id: usize, octet: u8) -> Result<(), u8> {
+        match self.buffers.get(link_id) {
+            Some(buffer) => buffer.borrow_mut().enqueue(octet),
+            None => Err(octet),
+        }
+    }
+
+    pub(crate) fn read(&self, link_id: usize, buf: &mut [u8]) -> usize {
+        let mut buffer = match self.buffers.get(link_id) {
+            Some(buffer) => buffer.borrow_mut(),
+            None => return 0,
+        };
+        let mut len = 0;
+        while len < buf.len() {
+            match buffer.dequeue() {
+                Some(b) => {
+                    buf[len] = b;
+                    len += 1;
+                }
+                None => break,
+            }
+        }
+        len
+    }
+
+    pub(crate) fn available(&self, link_id: usize) -> usize {
+        self.buffers
+            .get(link_id)
+            .map(|buffer| buffer.borrow().len())
+            .unwrap_or(0)
+    }
+
+    /// Buffer a received datagram, failing when there is no room left for all of it.
+    pub(crate) fn write_datagram(&self, link_id: usize, data: &[u8]) -> Result<(), ()> {
+        let mut datagrams = match self.datagrams.get(link_id) {
+            Some(datagrams) => datagrams.borrow_mut(),
+            None => return Err(()),
+        };
+        if datagrams.capacity() - datagrams.len() < 2 + data.len() {
+            return Err(());
+        }
+        let len = (data.len() as u16).to_le_bytes();
+        for b in len.iter().chain(data) {
+            datagrams.enqueue(*b).map_err(|_| ())?;
+        }
+        Ok(())
+    }
+
+    /// Read the oldest datagram received on a link, truncated to the length of `buf`.
+    pub(crate) fn read_datagram(&self, link_id: usize, buf: &mut [u8]) -> Option<usize> {
+        let mut datagrams = self.datagrams.get(link_id)?.borrow_mut();
+        let len = u16::from_le_bytes([datagrams.dequeue()?, datagrams.dequeue()?]) as usize;
+        for i in 0..len {
+            let b = datagrams.dequeue()?;
+            if let Some(slot) = buf.get_mut(i) {
+                *slot = b;
+            }
+        }
+        Some(core::cmp::min(len, buf.len()))
+    }
+
+    /// Discard data left over from a previous connection.
+    pub(crate) fn clear(&self, link_id: usize) {
+        if let Some(buffer) = self.buffers.get(link_id) {
+            let mut buffer = buffer.borrow_mut();
+            while buffer.dequeue().is_some() {}
+        }
+        if let Some(datagrams) = self.datagrams.get(link_id) {
+            let mut datagrams = datagrams.borrow_mut();
+            while datagrams.dequeue().is_some() {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_buffered_data() {
+        let storage = ReceiveStorage::<4>::new();
+        let buffers = storage.buffers();
+        for b in b"hello" {
+            buffers.write(1, *b).unwrap();
+        }
+        assert_eq!(0, buffers.available(0));
+        assert_eq!(5, buffers.available(1));
+
+        let mut buf = [0; 3];
+        assert_eq!(3, buffers.read(1, &mut buf));
+        assert_eq!(b"hel", &buf);
+        assert_eq!(2, buffers.read(1, &mut buf));
+        assert_eq!(b"lo", &buf[..2]);
+        assert_eq!(0, buffers.read(1, &mut buf));
+    }
+
+    #[test]
+    fn full_buffer() {
+        let storage = ReceiveStorage::<1>::new();
+        let buffers = storage.buffers();
+        let mut written = 0;
+        while buffers.write(0, 0).is_ok() {
+            written += 1;
+        }
+        assert_eq!(2048, written);
+        buffers.clear(0);
+        assert_eq!(0, buffers.available(0));
+    }
+
+    #[test]
+    fn read_buffered_datagrams() {
+        let storage = ReceiveStorage::<0>::new();
+        let buffers = storage.buffers();
+        buffers.write_datagram(2, b"hello").unwrap();
+        buffers.write_datagram(2, b"").unwrap();
+        buffers.write_datagram(2, b"world").unwrap();
+        assert_eq!(0, buffers.available(2));
+        assert_eq!(None, buffers.read_datagram(1, &mut [0; 8]));
+
+        let mut buf = [0; 8];
+        assert_eq!(Some(5), buffers.read_datagram(2, &mut buf));
+        assert_eq!(b"hello", &buf[..5]);
+        assert_eq!(Some(0), buffers.read_datagram(2, &mut buf));
+        // Datagrams are truncated, and the rest discarded
+        assert_eq!(Some(3), buffers.read_datagram(2, &mut buf[..3]));
+        assert_eq!(b"wor", &buf[..3]);
+        assert_eq!(None, buffers.read_datagram(2, &mut buf));
+    }
+
+    #[test]
+    fn full_datagram_buffer() {
+        let storage = ReceiveStorage::<0>::new();
+        let buffers = storage.buffers();
+        let datagram = [0; 508];
+        assert!(buffers.write_datagram(0, &datagram).is_ok());
+        assert!(buffers.write_datagram(0, &datagram).is_ok());
+        // A datagram is kept whole or not at all
+        assert!(buffers.write_datagram(0, &[0; 3]).is_err());
+        assert!(buffers.write_datagram(0, &[0; 2]).is_ok());
+        assert!(buffers.write_datagram(4, b"").is_err());
+
+        buffers.clear(0);
+        assert_eq!(None, buffers.read_datagram(0, &mut [0; 4]));
+    }
+
+    #[test]
+    fn missing_buffer() {
+        let storage = ReceiveStorage::<0>::new();
+        let buffers = storage.buffers();
+        assert_eq!(Err(1), buffers.write(0, 1));
+        assert_eq!(0, buffers.available(0));
+        assert_eq!(0, buffers.read(0, &mut [0; 4]));
+    }
+}
@@ -21,6 +21,7 @@ impl Default for SocketState {
 
 pub(crate) struct SocketPool {
     sockets: RefCell<[SocketState; 4]>,
+    available: RefCell<[usize; 4]>,
     waiters: RefCell<Queue<Waker, U8>>,
 }
 
@@ -28,6 +29,7 @@ impl SocketPool {
     pub(crate) fn new() -> Self {
         Self {
             sockets: Default::default(),
+            available: Default::default(),
             waiters: RefCell::new(Queue::new()),
         }
     }
@@ -52,6 +54,47 @@ impl SocketPool {
         }
     }
 
+    /// Mark a socket as connected, either by `connect` or by the remote end.
+    pub(crate) fn connected<'a>(&'a self, socket: u8) {
+        let mut sockets = self.sockets.borrow_mut();
+        sockets[socket as usize] = SocketState::Connected;
+        self.available.borrow_mut()[socket as usize] = 0;
+    }
+
+    /// Record data received by the modem and not yet read.
+    pub(crate) fn received<'a>(&'a self, socket: u8, len: usize) {
+        self.available.borrow_mut()[socket as usize] += len;
+    }
+
+    /// Record data read from the modem. Reading less than requested means all data was read.
+    pub(crate) fn consumed<'a>(&'a self, socket: u8, len: usize, requested: usize) {
+        let mut available = self.available.borrow_mut();
+        let index = socket as usize;
+        available[index] = if len < requested {
+            0
+        } else {
+            available[index].saturating_sub(len)
+        };
+    }
+
+    pub(crate) fn available<'a>(&'a self, socket: u8) -> usize {
+        self.available.borrow()[socket as usize]
+    }
+
+    /// Mark all connections as closed by the remote end, as they were lost when the modem reset.
+    pub(crate) fn reset<'a>(&'a self) {
+        for socket in self.sockets.borrow_mut().iter_mut() {
+            if *socket == SocketState::Open || *socket == SocketState::Connected {
+                *socket = SocketState::HalfClosed;
+            }
+        }
+        *self.available.borrow_mut() = Default::default();
+    }
+
+    pub(crate) fn is_half_closed<'a>(&'a self, socket: u8) -> bool {
+        self.sockets.borrow()[socket as usize] == SocketState::HalfClosed
+    }
+
     pub(crate) fn is_closed<'a>(&'a self, socket: u8) -> bool {
         let sockets = self.sockets.borrow();
         let index = socket as usize;
@@ -68,6 +111,7 @@ impl SocketPool {
 
         if let Some((index, _)) = available {
             sockets[index] = SocketState::Open;
+            self.available.borrow_mut()[index] = 0;
             Poll::Ready(index as u8)
         } else {
             if !waiting {
@@ -121,4 +165,38 @@ mod tests {
             assert_eq!(expected, actual);
         }
     }
+
+    #[test]
+    fn accepted_socket_not_reused() {
+        let pool = SocketPool::new();
+        pool.connected(0);
+        assert!(!pool.is_closed(0));
+        assert_eq!(1, block_on(pool.open()));
+    }
+
+    #[test]
+    fn available_data() {
+        let pool = SocketPool::new();
+        let socket = block_on(pool.open());
+        pool.received(socket, 10);
+        pool.received(socket, 5);
+        assert_eq!(15, pool.available(socket));
+        pool.consumed(socket, 8, 8);
+        assert_eq!(7, pool.available(socket));
+        pool.consumed(socket, 4, 8);
+        assert_eq!(0, pool.available(socket));
+    }
+
+    #[test]
+    fn reset_closes_connections() {
+        let pool = SocketPool::new();
+        let opened = block_on(pool.open());
+        pool.connected(2);
+        pool.received(2, 10);
+        pool.reset();
+        assert!(pool.is_half_closed(opened));
+        assert!(pool.is_half_closed(2));
+        assert!(!pool.is_half_closed(1));
+        assert_eq!(0, pool.available(2));
+    }
 }
@@ -10,6 +10,7 @@
 #![feature(generic_associated_types)]
 #![feature(associated_type_defaults)]
 #![feature(type_alias_impl_trait)]
+#![feature(array_map)]
 //! An async, no-alloc actor framework for embedded devices.
 //!
 //! See [the book](https://book.drogue.io/drogue-device/dev/index.html) for more about the architecture, how to write device drivers, and running some examples.
@@ -98,6 +99,8 @@ pub mod traits;
 
 pub mod drivers;
 
+pub mod clients;
+
 #[doc(hidden)]
 pub use drogue_device_macros::{self as drogue, log_stack};
 pub use embassy::*;
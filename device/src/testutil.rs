@@ -1,4 +1,5 @@
 use crate::actors::button::{ButtonEvent, FromButtonEvent};
+use crate::actors::connection::{FromLinkEvent, LinkEvent};
 use crate::kernel::{
     actor::{Actor, ActorContext, ActorSpawner},
     device::DeviceContext,
@@ -74,6 +75,15 @@ impl FromButtonEvent<TestMessage> for TestHandler {
     }
 }
 
+impl FromLinkEvent<TestMessage> for TestHandler {
+    fn from(event: LinkEvent) -> Option<TestMessage> {
+        match event {
+            LinkEvent::Down => Some(TestMessage(0)),
+            LinkEvent::Up => Some(TestMessage(1)),
+        }
+    }
+}
+
 /// A dummy actor that does nothing
 #[derive(Default)]
 pub struct DummyActor {}
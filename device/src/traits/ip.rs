@@ -11,6 +11,13 @@ impl IpAddress {
     }
 }
 
+/// The unspecified address, `0.0.0.0`.
+impl Default for IpAddress {
+    fn default() -> Self {
+        Self::new_v4(0, 0, 0, 0)
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct IpAddressV4(u8, u8, u8, u8);
 
@@ -28,6 +35,10 @@ impl IpAddressV4 {
     pub fn new(a: u8, b: u8, c: u8, d: u8) -> Self {
         IpAddressV4(a, b, c, d)
     }
+
+    pub fn octets(&self) -> [u8; 4] {
+        [self.0, self.1, self.2, self.3]
+    }
 }
 
 impl Debug for IpAddressV4 {
@@ -42,7 +53,7 @@ impl Display for IpAddressV4 {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct SocketAddress {
     ip: IpAddress,
     port: u16,
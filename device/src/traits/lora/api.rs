@@ -1,5 +1,6 @@
 use super::types::*;
 use core::future::Future;
+use embassy::time::{Duration, Instant};
 
 /// API for accessing LoRa modules
 pub trait LoraDriver {
@@ -9,11 +10,12 @@ pub trait LoraDriver {
     /// Configure the LoRa module with the provided config.
     fn configure<'a>(&'a mut self, config: &'a LoraConfig) -> Self::ConfigureFuture<'a>;
 
-    /*
-    type ResetFuture: Future<Output = Result<(), LoraError>>;
-    /// Reset the LoRa module.
-    fn reset(&mut self, mode: ResetMode) -> Self::ResetFuture;
-    */
+    type ResetFuture<'a>: Future<Output = Result<(), LoraError>>
+    where
+        Self: 'a;
+    /// Reset the LoRa module. Any LoRaWAN session is discarded, and the module must be joined
+    /// again before sending.
+    fn reset<'a>(&'a mut self, mode: ResetMode) -> Self::ResetFuture<'a>;
 
     type JoinFuture<'a>: Future<Output = Result<(), LoraError>>
     where
@@ -27,11 +29,12 @@ pub trait LoraDriver {
     /// Send data on a specific port with a given quality of service.
     fn send<'a>(&'a mut self, qos: QoS, port: Port, data: &'a [u8]) -> Self::SendFuture<'a>;
 
-    type SendRecvFuture<'a>: Future<Output = Result<usize, LoraError>>
+    type SendRecvFuture<'a>: Future<Output = Result<(Port, usize), LoraError>>
     where
         Self: 'a;
     /// Send data on a specific port with a given quality of service. If the LoRa module receives
-    /// any data as part of the confirmation, write it into the provided buffer and return the size of the data read.
+    /// any data as part of the confirmation, write it into the provided buffer and return the
+    /// port and size of the data read. The size is 0 if no data was received.
     fn send_recv<'a>(
         &'a mut self,
         qos: QoS,
@@ -39,6 +42,54 @@ pub trait LoraDriver {
         data: &'a [u8],
         rx: &'a mut [u8],
     ) -> Self::SendRecvFuture<'a>;
+
+    type ReceiveFuture<'a>: Future<Output = Result<Downlink, LoraError>>
+    where
+        Self: 'a;
+    /// Wait for a downlink sent by the network outside of the receive windows of an uplink.
+    /// This requires the device to be configured as a Class C device. Dropping the future before
+    /// it completes must not lose a downlink, so that waiting can be interleaved with uplinks.
+    fn receive<'a>(&'a mut self) -> Self::ReceiveFuture<'a>;
+
+    type LinkStatusFuture<'a>: Future<Output = Result<LinkStatus, LoraError>>
+    where
+        Self: 'a;
+    /// Report the quality of the link and statistics of the uplinks and downlinks so far.
+    fn link_status<'a>(&'a mut self) -> Self::LinkStatusFuture<'a>;
+
+    type NextSendAllowedFuture<'a>: Future<Output = Instant>
+    where
+        Self: 'a;
+    /// When the duty-cycle limits of the region allow the next uplink. An instant in the past
+    /// means that sending is allowed right away.
+    fn next_send_allowed<'a>(&'a mut self) -> Self::NextSendAllowedFuture<'a>;
+}
+
+/// API for raw LoRa links between devices, without a LoRaWAN network.
+pub trait LoraRadio {
+    type ConfigureRadioFuture<'a>: Future<Output = Result<(), LoraError>>
+    where
+        Self: 'a;
+    /// Set the frequency, modulation and TX power used for transmitting and receiving.
+    fn configure_radio<'a>(&'a mut self, config: &'a RadioConfig)
+        -> Self::ConfigureRadioFuture<'a>;
+
+    type TransmitFuture<'a>: Future<Output = Result<(), LoraError>>
+    where
+        Self: 'a;
+    /// Transmit a packet of up to 255 bytes, completing once it has been sent.
+    fn transmit<'a>(&'a mut self, data: &'a [u8]) -> Self::TransmitFuture<'a>;
+
+    type ReceivePacketFuture<'a>: Future<Output = Result<RadioPacket, LoraError>>
+    where
+        Self: 'a;
+    /// Wait for a packet and write it into the provided buffer. Fails with `RecvTimeout` if no
+    /// packet is received within the timeout.
+    fn receive_packet<'a>(
+        &'a mut self,
+        rx: &'a mut [u8],
+        timeout: Duration,
+    ) -> Self::ReceivePacketFuture<'a>;
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -53,5 +104,9 @@ pub enum LoraError {
     NotInitialized,
     NotImplemented,
     UnsupportedRegion,
+    UnsupportedFirmware,
+    ConfigError,
+    UnsupportedOption,
+    DutyCycleExceeded,
     OtherError,
 }
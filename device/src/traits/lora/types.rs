@@ -1,3 +1,5 @@
+use heapless::{consts::U256, Vec};
+
 #[derive(Debug, Clone, Copy)]
 pub enum QoS {
     Unconfirmed,
@@ -6,8 +8,12 @@ pub enum QoS {
 
 #[derive(Debug, Clone, Copy)]
 pub enum ResetMode {
+    /// Restart the module, keeping its current configuration.
     Restart,
+    /// Restart the module and reload its configuration from persistent storage.
     Reload,
+    /// Reset the module using its reset pin.
+    Hardware,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -16,6 +22,15 @@ pub enum ConnectMode {
     ABP,
 }
 
+/// LoRaWAN device class, deciding when the device listens for downlinks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceClass {
+    /// Receive only in the windows following an uplink.
+    A,
+    /// Keep receiving on the RX2 parameters between uplinks.
+    C,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u8)]
 pub enum LoraMode {
@@ -23,6 +38,36 @@ pub enum LoraMode {
     P2P = 1,
 }
 
+/// What to do with an uplink that would exceed the duty-cycle limits of the region.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DutyCyclePolicy {
+    /// Fail with `LoraError::DutyCycleExceeded`.
+    Reject,
+    /// Wait until sending is allowed.
+    Wait,
+}
+
+/// LoRaWAN data rate. The spreading factor and bandwidth of each data rate depend on the region.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataRate {
+    DR0,
+    DR1,
+    DR2,
+    DR3,
+    DR4,
+    DR5,
+    DR6,
+    DR7,
+    DR8,
+    DR9,
+    DR10,
+    DR11,
+    DR12,
+    DR13,
+    DR14,
+    DR15,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum LoraRegion {
     EU868,
@@ -36,6 +81,117 @@ pub enum LoraRegion {
 }
 
 pub type Port = u8;
+
+/// Link quality and statistics of a LoRaWAN link.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkStatus {
+    /// Whether the device has joined the network, or has an ABP session.
+    pub joined: bool,
+    pub data_rate: Option<DataRate>,
+    /// RSSI in dBm of the last received downlink.
+    pub rssi: Option<i16>,
+    /// SNR in dB of the last received downlink.
+    pub snr: Option<i8>,
+    pub tx_ok: u32,
+    pub tx_err: u32,
+    pub rx_ok: u32,
+    /// Number of confirmed uplinks that were not acknowledged.
+    pub rx_timeout: u32,
+    pub rx_err: u32,
+}
+
+/// LoRa spreading factor of a point-to-point link.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpreadingFactor {
+    SF7 = 7,
+    SF8 = 8,
+    SF9 = 9,
+    SF10 = 10,
+    SF11 = 11,
+    SF12 = 12,
+}
+
+/// LoRa bandwidth of a point-to-point link.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bandwidth {
+    KHz125,
+    KHz250,
+    KHz500,
+}
+
+/// LoRa coding rate of a point-to-point link.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CodingRate {
+    CR4_5,
+    CR4_6,
+    CR4_7,
+    CR4_8,
+}
+
+/// Radio settings of a point-to-point link. Both ends must use the same settings.
+#[derive(Debug, Clone, Copy)]
+pub struct RadioConfig {
+    /// Frequency in Hz.
+    pub frequency: u32,
+    pub spreading_factor: SpreadingFactor,
+    pub bandwidth: Bandwidth,
+    pub coding_rate: CodingRate,
+    /// TX power in dBm.
+    pub tx_power: i8,
+}
+
+impl RadioConfig {
+    pub fn new(frequency: u32) -> Self {
+        Self {
+            frequency,
+            spreading_factor: SpreadingFactor::SF7,
+            bandwidth: Bandwidth::KHz125,
+            coding_rate: CodingRate::CR4_5,
+            tx_power: 14,
+        }
+    }
+
+    pub fn spreading_factor(mut self, spreading_factor: SpreadingFactor) -> Self {
+        self.spreading_factor = spreading_factor;
+        self
+    }
+
+    pub fn bandwidth(mut self, bandwidth: Bandwidth) -> Self {
+        self.bandwidth = bandwidth;
+        self
+    }
+
+    pub fn coding_rate(mut self, coding_rate: CodingRate) -> Self {
+        self.coding_rate = coding_rate;
+        self
+    }
+
+    pub fn tx_power(mut self, tx_power: i8) -> Self {
+        self.tx_power = tx_power;
+        self
+    }
+}
+
+/// A packet received on a point-to-point link.
+#[derive(Debug, Clone, Copy)]
+pub struct RadioPacket {
+    /// Length of the packet, written to the start of the receive buffer.
+    pub len: usize,
+    pub rssi: i16,
+    pub snr: i8,
+}
+
+/// A downlink received from the network.
+#[derive(Debug, Clone)]
+pub struct Downlink {
+    pub port: Port,
+    pub data: Vec<u8, U256>,
+    /// Signal strength in dBm, when reported by the module.
+    pub rssi: Option<i16>,
+    /// Signal to noise ratio in dB, when reported by the module.
+    pub snr: Option<i8>,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct DevAddr([u8; 4]);
 #[derive(Debug, Clone, Copy)]
@@ -51,10 +207,34 @@ pub struct AppsKey([u8; 16]);
 pub struct LoraConfig {
     pub region: Option<LoraRegion>,
     pub lora_mode: Option<LoraMode>,
+    pub device_class: Option<DeviceClass>,
     pub device_address: Option<DevAddr>,
     pub device_eui: Option<EUI>,
     pub app_eui: Option<EUI>,
     pub app_key: Option<AppKey>,
+    /// Network session key, used with ABP activation.
+    pub nwks_key: Option<NwksKey>,
+    /// Application session key, used with ABP activation.
+    pub apps_key: Option<AppsKey>,
+    /// Initial uplink frame counter of an ABP session.
+    pub fcnt_up: Option<u32>,
+    /// Initial downlink frame counter of an ABP session.
+    pub fcnt_down: Option<u32>,
+    pub data_rate: Option<DataRate>,
+    /// Adaptive data rate, letting the network adjust the data rate and TX power.
+    pub adr: Option<bool>,
+    /// TX power in dBm.
+    pub tx_power: Option<i8>,
+    /// Number of retransmissions of a confirmed uplink that is not acknowledged. The SX127x
+    /// driver does not retransmit, and only accepts 0.
+    pub retries: Option<u8>,
+    /// Delay of the first receive window in milliseconds. The second window opens a second later.
+    pub rx_delay1: Option<u32>,
+    /// Sub-band of eight 125 kHz uplink channels to use in the US915 and AU915 regions, from 1
+    /// to 8. Gateways often listen on a single sub-band, such as sub-band 2 used by TTN.
+    pub sub_band: Option<u8>,
+    /// Handling of uplinks exceeding the duty-cycle limits. Such uplinks are rejected by default.
+    pub duty_cycle: Option<DutyCyclePolicy>,
 }
 
 impl LoraConfig {
@@ -62,10 +242,22 @@ impl LoraConfig {
         Self {
             region: None,
             lora_mode: None,
+            device_class: None,
             device_address: None,
             device_eui: None,
             app_eui: None,
             app_key: None,
+            nwks_key: None,
+            apps_key: None,
+            fcnt_up: None,
+            fcnt_down: None,
+            data_rate: None,
+            adr: None,
+            tx_power: None,
+            retries: None,
+            rx_delay1: None,
+            sub_band: None,
+            duty_cycle: None,
         }
     }
 
@@ -79,6 +271,11 @@ impl LoraConfig {
         self
     }
 
+    pub fn device_class(mut self, device_class: DeviceClass) -> Self {
+        self.device_class.replace(device_class);
+        self
+    }
+
     pub fn device_address(mut self, device_address: &DevAddr) -> Self {
         self.device_address.replace(device_address.clone());
         self
@@ -98,6 +295,61 @@ impl LoraConfig {
         self.app_key.replace(app_key.clone());
         self
     }
+
+    pub fn nwks_key(mut self, nwks_key: &NwksKey) -> Self {
+        self.nwks_key.replace(nwks_key.clone());
+        self
+    }
+
+    pub fn apps_key(mut self, apps_key: &AppsKey) -> Self {
+        self.apps_key.replace(apps_key.clone());
+        self
+    }
+
+    pub fn fcnt_up(mut self, fcnt_up: u32) -> Self {
+        self.fcnt_up.replace(fcnt_up);
+        self
+    }
+
+    pub fn fcnt_down(mut self, fcnt_down: u32) -> Self {
+        self.fcnt_down.replace(fcnt_down);
+        self
+    }
+
+    pub fn data_rate(mut self, data_rate: DataRate) -> Self {
+        self.data_rate.replace(data_rate);
+        self
+    }
+
+    pub fn adr(mut self, adr: bool) -> Self {
+        self.adr.replace(adr);
+        self
+    }
+
+    pub fn tx_power(mut self, tx_power: i8) -> Self {
+        self.tx_power.replace(tx_power);
+        self
+    }
+
+    pub fn retries(mut self, retries: u8) -> Self {
+        self.retries.replace(retries);
+        self
+    }
+
+    pub fn rx_delay1(mut self, rx_delay1: u32) -> Self {
+        self.rx_delay1.replace(rx_delay1);
+        self
+    }
+
+    pub fn sub_band(mut self, sub_band: u8) -> Self {
+        self.sub_band.replace(sub_band);
+        self
+    }
+
+    pub fn duty_cycle(mut self, duty_cycle: DutyCyclePolicy) -> Self {
+        self.duty_cycle.replace(duty_cycle);
+        self
+    }
 }
 
 impl EUI {
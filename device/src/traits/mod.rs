@@ -1,6 +1,9 @@
 pub mod ip;
 pub mod lora;
+pub mod modem;
+pub mod storage;
 pub mod tcp;
+pub mod udp;
 pub mod wifi;
 
 pub use embassy::traits::*;
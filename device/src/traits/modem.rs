@@ -0,0 +1,60 @@
+use core::fmt::{Display, Formatter};
+use core::future::Future;
+use heapless::{consts::U32, String};
+
+/// Version of a modem firmware, in the `major.minor.patch.build` form reported by AT firmwares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+    pub build: u8,
+}
+
+impl Version {
+    pub const fn new(major: u8, minor: u8, patch: u8, build: u8) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+            build,
+        }
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}.{}",
+            self.major, self.minor, self.patch, self.build
+        )
+    }
+}
+
+/// Identification of a modem and its firmware.
+#[derive(Debug, Clone)]
+pub struct ModemDetails {
+    /// Version of the AT command firmware.
+    pub at_version: Version,
+    /// Version of the SDK the firmware is built on, as reported by the modem.
+    pub sdk_version: Option<String<U32>>,
+    /// Name of the hardware module, as reported by the modem.
+    pub module: Option<String<U32>>,
+    pub mac: Option<[u8; 6]>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ModemError {
+    CommandFailed,
+    UnexpectedResponse,
+    Timeout,
+}
+
+/// Modems reporting what they are and which firmware they run.
+pub trait ModemInfo {
+    type InfoFuture<'m>: Future<Output = Result<ModemDetails, ModemError>>
+    where
+        Self: 'm;
+    fn info<'m>(&'m mut self) -> Self::InfoFuture<'m>;
+}
@@ -0,0 +1,23 @@
+use core::future::Future;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageError {
+    ReadError,
+    WriteError,
+    OutOfBounds,
+}
+
+/// Persistent storage, such as a flash or EEPROM region, addressed by byte offset.
+pub trait Storage {
+    type ReadFuture<'m>: Future<Output = Result<(), StorageError>>
+    where
+        Self: 'm;
+    /// Read `buf.len()` bytes starting at `offset`.
+    fn read<'m>(&'m mut self, offset: u32, buf: &'m mut [u8]) -> Self::ReadFuture<'m>;
+
+    type WriteFuture<'m>: Future<Output = Result<(), StorageError>>
+    where
+        Self: 'm;
+    /// Write `data` starting at `offset`, erasing as needed.
+    fn write<'m>(&'m mut self, offset: u32, data: &'m [u8]) -> Self::WriteFuture<'m>;
+}
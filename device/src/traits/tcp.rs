@@ -1,13 +1,35 @@
 use super::ip::{IpProtocol, SocketAddress};
 use core::future::Future;
+use core::marker::{PhantomData, PhantomPinned};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use embassy::io::{self, AsyncBufRead, AsyncWrite};
+use embassy::time::{Duration, Instant, Timer};
 
-#[derive(Debug)]
+/// Interval between reads while waiting for data on a `TcpSocket`.
+const READ_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy)]
 pub enum TcpError {
     ConnectError,
     ReadError,
     WriteError,
     CloseError,
     SocketClosed,
+    ListenError,
+    Timeout,
+}
+
+/// Whether data can be read from a socket without waiting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Readiness {
+    /// No data has been received yet.
+    Pending,
+    /// Data has been received and can be read.
+    Readable,
+    /// The remote end has closed the connection. Data received before may still be read, after
+    /// which reads fail with `SocketClosed`. Writing is no longer possible.
+    HalfClosed,
 }
 
 pub trait TcpStack {
@@ -46,4 +68,318 @@ pub trait TcpStack {
     where
         Self: 'm;
     fn close<'m>(&'m mut self, handle: Self::SocketHandle) -> Self::CloseFuture<'m>;
+
+    /// Start closing a socket without waiting for it, such as when a socket is dropped. The stack
+    /// completes the close in the background.
+    fn close_detached(&mut self, handle: Self::SocketHandle);
+
+    type PollReadableFuture<'m>: Future<Output = Result<Readiness, TcpError>>
+    where
+        Self: 'm;
+    /// Check whether a read would return data or report the connection closed, without waiting
+    /// for data to arrive.
+    fn poll_readable<'m>(&'m mut self, handle: Self::SocketHandle) -> Self::PollReadableFuture<'m>;
+
+    type AvailableFuture<'m>: Future<Output = Result<usize, TcpError>>
+    where
+        Self: 'm;
+    /// Number of bytes received and not yet read.
+    fn available<'m>(&'m mut self, handle: Self::SocketHandle) -> Self::AvailableFuture<'m>;
+
+    type SetTimeoutFuture<'m>: Future<Output = Result<(), TcpError>>
+    where
+        Self: 'm;
+    /// Fail reads and writes on the socket with `TcpError::Timeout` when they do not complete
+    /// within `timeout`. With a timeout, reads wait for data to arrive instead of returning 0.
+    /// Stacks check the deadline between exchanges with the network device, so an operation is
+    /// never abandoned halfway. Sockets have no timeout when opened.
+    fn set_timeout<'m>(
+        &'m mut self,
+        handle: Self::SocketHandle,
+        timeout: Option<Duration>,
+    ) -> Self::SetTimeoutFuture<'m>;
+}
+
+/// A TCP stack able to accept incoming connections.
+pub trait TcpServer: TcpStack {
+    type ListenFuture<'m>: Future<Output = Result<(), TcpError>>
+    where
+        Self: 'm;
+    /// Start listening for incoming connections on the given port.
+    fn listen<'m>(&'m mut self, port: u16) -> Self::ListenFuture<'m>;
+
+    type AcceptFuture<'m>: Future<Output = Result<Self::SocketHandle, TcpError>>
+    where
+        Self: 'm;
+    /// Wait for an incoming connection. The returned socket is used with the `TcpStack` read,
+    /// write and close operations.
+    fn accept<'m>(&'m mut self) -> Self::AcceptFuture<'m>;
+
+    type UnlistenFuture<'m>: Future<Output = Result<(), TcpError>>
+    where
+        Self: 'm;
+    /// Stop accepting incoming connections. Connections already accepted are left open.
+    fn unlisten<'m>(&'m mut self) -> Self::UnlistenFuture<'m>;
+}
+
+enum SocketState<'a, S: TcpStack + 'a> {
+    Idle,
+    Reading(S::ReadFuture<'a>),
+    Writing(S::WriteFuture<'a>),
+    Waiting(Timer),
+}
+
+/// A connected socket, implementing `AsyncBufRead` and `AsyncWrite` on top of a `TcpStack`.
+///
+/// The socket borrows the stack for its lifetime, together with buffers for received data and for
+/// data being written. It is closed when dropped, without waiting for the stack to complete the
+/// close; use `close` to wait for it.
+///
+/// Operations in progress are kept inside the socket, so it must be pinned before use.
+pub struct TcpSocket<'a, S: TcpStack + 'a> {
+    // Declared first so that it is dropped first, as operations borrow the stack and buffers
+    state: SocketState<'a, S>,
+    stack: *mut S,
+    handle: S::SocketHandle,
+    rx: *mut [u8],
+    rx_pos: usize,
+    rx_len: usize,
+    tx: *mut [u8],
+    open: bool,
+    eof: bool,
+    read_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    _borrow: PhantomData<(&'a mut S, &'a mut [u8])>,
+    _pinned: PhantomPinned,
+}
+
+impl<'a, S: TcpStack + 'a> TcpSocket<'a, S> {
+    /// Take ownership of an open socket.
+    pub fn new(
+        stack: &'a mut S,
+        handle: S::SocketHandle,
+        rx: &'a mut [u8],
+        tx: &'a mut [u8],
+    ) -> Self {
+        Self {
+            state: SocketState::Idle,
+            stack,
+            handle,
+            rx,
+            rx_pos: 0,
+            rx_len: 0,
+            tx,
+            open: true,
+            eof: false,
+            read_timeout: None,
+            deadline: None,
+            _borrow: PhantomData,
+            _pinned: PhantomPinned,
+        }
+    }
+
+    /// Open a socket and connect it to `dst`.
+    pub async fn connect(
+        stack: &'a mut S,
+        proto: IpProtocol,
+        dst: SocketAddress,
+        rx: &'a mut [u8],
+        tx: &'a mut [u8],
+    ) -> Result<TcpSocket<'a, S>, TcpError> {
+        let handle = stack.open().await;
+        if let Err(e) = stack.connect(handle, proto, dst).await {
+            stack.close(handle).await;
+            return Err(e);
+        }
+        Ok(Self::new(stack, handle, rx, tx))
+    }
+
+    pub fn handle(&self) -> S::SocketHandle {
+        self.handle
+    }
+
+    /// Fail reads with `TimedOut` when no data is received within `timeout`. Reads wait for data
+    /// indefinitely by default.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Close the socket, cancelling any operation in progress.
+    pub async fn close(self: Pin<&mut Self>) {
+        // Safety: nothing is moved out of the socket
+        let this = unsafe { self.get_unchecked_mut() };
+        this.state = SocketState::Idle;
+        if this.open {
+            this.open = false;
+            this.stack().close(this.handle).await;
+        }
+    }
+
+    /// The stack, for starting a new operation.
+    fn stack(&mut self) -> &'a mut S {
+        // Safety: only called when no other operation is in progress
+        unsafe { &mut *self.stack }
+    }
+
+    fn start_read(&mut self) {
+        let handle = self.handle;
+        // Safety: the receive buffer is not accessed until the read has completed
+        let rx = unsafe { &mut *self.rx };
+        self.state = SocketState::Reading(self.stack().read(handle, rx));
+    }
+
+    fn start_write(&mut self, buf: &[u8]) -> usize {
+        // Safety: the transmit buffer is not accessed until the write has completed
+        let tx = unsafe { &mut *self.tx };
+        let len = core::cmp::min(buf.len(), tx.len());
+        tx[..len].copy_from_slice(&buf[..len]);
+        let tx: &'a [u8] = tx;
+        let handle = self.handle;
+        self.state = SocketState::Writing(self.stack().write(handle, &tx[..len]));
+        len
+    }
+
+    /// Poll a read in progress. Stacks return 0 while no data has been received, in which case
+    /// the next read is delayed.
+    fn poll_read(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), TcpError>> {
+        let result = match &mut self.state {
+            // Safety: the socket is pinned, so the future is never moved
+            SocketState::Reading(read) => match unsafe { Pin::new_unchecked(read) }.poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(result) => result,
+            },
+            _ => return Poll::Ready(Ok(())),
+        };
+        self.state = SocketState::Idle;
+        match result {
+            Ok(0) => {
+                if self.deadline.is_none() {
+                    self.deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+                }
+                self.state = SocketState::Waiting(Timer::after(READ_BACKOFF));
+                Poll::Ready(Ok(()))
+            }
+            Ok(len) => {
+                self.deadline = None;
+                self.rx_pos = 0;
+                self.rx_len = len;
+                Poll::Ready(Ok(()))
+            }
+            Err(TcpError::SocketClosed) => {
+                self.eof = true;
+                Poll::Ready(Ok(()))
+            }
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Poll a write in progress.
+    fn poll_written(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize, TcpError>> {
+        let result = match &mut self.state {
+            // Safety: the socket is pinned, so the future is never moved
+            SocketState::Writing(write) => match unsafe { Pin::new_unchecked(write) }.poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(result) => result,
+            },
+            _ => Ok(0),
+        };
+        self.state = SocketState::Idle;
+        Poll::Ready(result)
+    }
+}
+
+impl<'a, S: TcpStack + 'a> Drop for TcpSocket<'a, S> {
+    fn drop(&mut self) {
+        self.state = SocketState::Idle;
+        if self.open {
+            let handle = self.handle;
+            self.stack().close_detached(handle);
+        }
+    }
+}
+
+fn io_error(e: TcpError) -> io::Error {
+    match e {
+        TcpError::SocketClosed => io::Error::ConnectionReset,
+        TcpError::Timeout => io::Error::TimedOut,
+        _ => io::Error::Other,
+    }
+}
+
+impl<'a, S: TcpStack + 'a> AsyncBufRead for TcpSocket<'a, S> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        // Safety: nothing is moved out of the socket
+        let this = unsafe { self.get_unchecked_mut() };
+        loop {
+            if this.rx_pos < this.rx_len {
+                // Safety: no read is in progress while there is unconsumed data
+                let rx = unsafe { &*this.rx };
+                return Poll::Ready(Ok(&rx[this.rx_pos..this.rx_len]));
+            }
+            if this.eof || !this.open {
+                return Poll::Ready(Ok(&[]));
+            }
+
+            match &mut this.state {
+                SocketState::Idle => this.start_read(),
+                SocketState::Reading(_) => {
+                    if let Err(e) = futures::ready!(this.poll_read(cx)) {
+                        return Poll::Ready(Err(io_error(e)));
+                    }
+                }
+                SocketState::Writing(_) => {
+                    // Left behind by a cancelled write, whose outcome is no longer of interest
+                    let _ = futures::ready!(this.poll_written(cx));
+                }
+                SocketState::Waiting(timer) => {
+                    futures::ready!(Pin::new(timer).poll(cx));
+                    this.state = SocketState::Idle;
+                    if matches!(this.deadline, Some(deadline) if Instant::now() >= deadline) {
+                        this.deadline = None;
+                        return Poll::Ready(Err(io::Error::TimedOut));
+                    }
+                }
+            }
+        }
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        // Safety: nothing is moved out of the socket
+        let this = unsafe { self.get_unchecked_mut() };
+        this.rx_pos = core::cmp::min(this.rx_pos + amt, this.rx_len);
+    }
+}
+
+impl<'a, S: TcpStack + 'a> AsyncWrite for TcpSocket<'a, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // Safety: nothing is moved out of the socket
+        let this = unsafe { self.get_unchecked_mut() };
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        loop {
+            match &mut this.state {
+                SocketState::Idle => {
+                    if !this.open {
+                        return Poll::Ready(Err(io::Error::NotConnected));
+                    }
+                    this.start_write(buf);
+                }
+                SocketState::Writing(_) => {
+                    return this.poll_written(cx).map_err(io_error);
+                }
+                SocketState::Reading(_) => {
+                    // Completed rather than cancelled, so that received data is not lost. Errors
+                    // are left to the next write.
+                    let _ = futures::ready!(this.poll_read(cx));
+                    this.state = SocketState::Idle;
+                }
+                SocketState::Waiting(_) => this.state = SocketState::Idle,
+            }
+        }
+    }
 }
@@ -0,0 +1,52 @@
+use super::ip::SocketAddress;
+use core::future::Future;
+
+#[derive(Debug, Clone, Copy)]
+pub enum UdpError {
+    ConnectError,
+    SendError,
+    RecvError,
+    CloseError,
+    SocketClosed,
+    Timeout,
+}
+
+pub trait UdpStack {
+    type SocketHandle: Copy;
+
+    type OpenFuture<'m>: Future<Output = Self::SocketHandle>
+    where
+        Self: 'm;
+    fn open<'m>(&'m mut self) -> Self::OpenFuture<'m>;
+
+    type ConnectFuture<'m>: Future<Output = Result<(), UdpError>>
+    where
+        Self: 'm;
+    /// Set the remote peer that datagrams are sent to and received from.
+    fn connect<'m>(
+        &'m mut self,
+        handle: Self::SocketHandle,
+        dst: SocketAddress,
+    ) -> Self::ConnectFuture<'m>;
+
+    type SendFuture<'m>: Future<Output = Result<usize, UdpError>>
+    where
+        Self: 'm;
+    /// Send a single datagram.
+    fn send<'m>(&'m mut self, handle: Self::SocketHandle, buf: &'m [u8]) -> Self::SendFuture<'m>;
+
+    type RecvFuture<'m>: Future<Output = Result<usize, UdpError>>
+    where
+        Self: 'm;
+    /// Wait for a single datagram. Datagrams larger than the buffer are truncated.
+    fn recv<'m>(
+        &'m mut self,
+        handle: Self::SocketHandle,
+        buf: &'m mut [u8],
+    ) -> Self::RecvFuture<'m>;
+
+    type CloseFuture<'m>: Future<Output = ()>
+    where
+        Self: 'm;
+    fn close<'m>(&'m mut self, handle: Self::SocketHandle) -> Self::CloseFuture<'m>;
+}
@@ -1,7 +1,8 @@
 use super::ip::IpAddress;
 use core::future::Future;
+use heapless::{consts::U32, String};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Join<'a> {
     Open,
     Wpa { ssid: &'a str, password: &'a str },
@@ -13,6 +14,69 @@ pub enum JoinError {
     InvalidSsid,
     InvalidPassword,
     UnableToAssociate,
+    Timeout,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum WifiError {
+    CommandFailed,
+    UnexpectedResponse,
+    Timeout,
+    InvalidSsid,
+    InvalidPassword,
+}
+
+/// Security protocol used by an access point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Security {
+    Open,
+    Wep,
+    WpaPsk,
+    Wpa2Psk,
+    WpaWpa2Psk,
+    Wpa2Enterprise,
+    Wpa3Psk,
+    Wpa2Wpa3Psk,
+    Unknown,
+}
+
+/// An access point found when scanning.
+#[derive(Debug, Clone)]
+pub struct AccessPointInfo {
+    pub ssid: String<U32>,
+    /// Signal strength in dBm.
+    pub rssi: i8,
+    pub channel: u8,
+    pub security: Security,
+}
+
+impl Default for AccessPointInfo {
+    fn default() -> Self {
+        Self {
+            ssid: String::new(),
+            rssi: i8::MIN,
+            channel: 0,
+            security: Security::Unknown,
+        }
+    }
+}
+
+/// The access point the station is currently associated with.
+#[derive(Debug, Clone)]
+pub struct WifiStatus {
+    pub ssid: String<U32>,
+    /// The address assigned to the station, if any.
+    pub ip: Option<IpAddress>,
+    /// Signal strength in dBm.
+    pub rssi: i8,
+    pub channel: u8,
+}
+
+/// A station connected to the soft access point.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StationInfo {
+    pub ip: IpAddress,
+    pub mac: [u8; 6],
 }
 
 pub trait WifiSupplicant {
@@ -20,4 +84,50 @@ pub trait WifiSupplicant {
     where
         Self: 'm;
     fn join<'m>(&'m mut self, join: Join<'m>) -> Self::JoinFuture<'m>;
+
+    type ScanFuture<'m>: Future<Output = Result<usize, WifiError>>
+    where
+        Self: 'm;
+    /// Scan for access points, storing at most `results.len()` entries. Returns the number of
+    /// entries stored.
+    fn scan<'m>(&'m mut self, results: &'m mut [AccessPointInfo]) -> Self::ScanFuture<'m>;
+
+    type DisconnectFuture<'m>: Future<Output = Result<(), WifiError>>
+    where
+        Self: 'm;
+    fn disconnect<'m>(&'m mut self) -> Self::DisconnectFuture<'m>;
+
+    type StatusFuture<'m>: Future<Output = Result<Option<WifiStatus>, WifiError>>
+    where
+        Self: 'm;
+    /// Query the current association. Returns `None` when not associated with an access point.
+    fn status<'m>(&'m mut self) -> Self::StatusFuture<'m>;
+}
+
+/// Soft access point, allowing stations to connect to the device directly.
+pub trait AccessPoint {
+    type StartFuture<'m>: Future<Output = Result<IpAddress, WifiError>>
+    where
+        Self: 'm;
+    /// Start an access point, returning the address of the device on the access point network.
+    /// An empty password starts an open access point, otherwise WPA2 requires a password of 8
+    /// to 64 bytes.
+    fn start<'m>(
+        &'m mut self,
+        ssid: &'m str,
+        password: &'m str,
+        channel: u8,
+    ) -> Self::StartFuture<'m>;
+
+    type StationsFuture<'m>: Future<Output = Result<usize, WifiError>>
+    where
+        Self: 'm;
+    /// List the connected stations, storing at most `stations.len()` entries. Returns the
+    /// number of entries stored.
+    fn stations<'m>(&'m mut self, stations: &'m mut [StationInfo]) -> Self::StationsFuture<'m>;
+
+    type StopFuture<'m>: Future<Output = Result<(), WifiError>>
+    where
+        Self: 'm;
+    fn stop<'m>(&'m mut self) -> Self::StopFuture<'m>;
 }
@@ -0,0 +1,225 @@
+#![macro_use]
+#![allow(incomplete_features)]
+#![feature(min_type_alias_impl_trait)]
+#![feature(impl_trait_in_bindings)]
+#![feature(generic_associated_types)]
+#![feature(type_alias_impl_trait)]
+
+#[cfg(feature = "std")]
+mod common;
+
+#[cfg(feature = "std")]
+mod tests {
+    extern crate std;
+    use super::common::*;
+    use core::future::Future;
+    use drogue_device::{
+        clients::coap::{message::*, *},
+        testutil::*,
+        traits::{ip::*, udp::*},
+        *,
+    };
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    /// A server stand-in serving `resource` on GET and storing POSTed payloads in `uploaded`,
+    /// using 64 byte blocks.
+    struct MockServer {
+        ignore: usize,
+        requests: Rc<RefCell<usize>>,
+        /// Message ID and token of each request.
+        received: Rc<RefCell<Vec<(u16, Vec<u8>)>>>,
+        resource: Vec<u8>,
+        uploaded: Rc<RefCell<Vec<u8>>>,
+        rx: VecDeque<Vec<u8>>,
+    }
+
+    impl MockServer {
+        fn new(resource: &[u8]) -> Self {
+            Self {
+                ignore: 0,
+                requests: Rc::new(RefCell::new(0)),
+                received: Rc::new(RefCell::new(Vec::new())),
+                resource: resource.to_vec(),
+                uploaded: Rc::new(RefCell::new(Vec::new())),
+                rx: VecDeque::new(),
+            }
+        }
+
+        fn handle(&mut self, data: &[u8]) {
+            *self.requests.borrow_mut() += 1;
+            if self.ignore > 0 {
+                self.ignore -= 1;
+                return;
+            }
+
+            let request = Message::parse(data).unwrap();
+            self.received
+                .borrow_mut()
+                .push((request.message_id, request.token.to_vec()));
+            let mut buf = [0; 256];
+            let len = match request.code {
+                1 => {
+                    let num = request.block2().map(|b| b.num).unwrap_or(0);
+                    let start = num as usize * 64;
+                    let end = core::cmp::min(start + 64, self.resource.len());
+                    let more = end < self.resource.len();
+                    let mut builder = MessageBuilder::new(
+                        &mut buf,
+                        MessageType::Acknowledgement,
+                        CONTENT,
+                        request.message_id,
+                        request.token,
+                    )
+                    .unwrap();
+                    if more || num > 0 {
+                        builder
+                            .uint_option(BLOCK2, Block::new(num, more, 2).value())
+                            .unwrap();
+                    }
+                    builder.payload(&self.resource[start..end]).unwrap();
+                    builder.len()
+                }
+                _ => {
+                    self.uploaded
+                        .borrow_mut()
+                        .extend_from_slice(request.payload);
+                    let block1 = request.block1();
+                    let more = block1.map(|b| b.more).unwrap_or(false);
+                    let code = if more { CONTINUE } else { CHANGED };
+                    let mut builder = MessageBuilder::new(
+                        &mut buf,
+                        MessageType::Acknowledgement,
+                        code,
+                        request.message_id,
+                        request.token,
+                    )
+                    .unwrap();
+                    if let Some(block) = block1 {
+                        builder.uint_option(BLOCK1, block.value()).unwrap();
+                    }
+                    builder.len()
+                }
+            };
+            self.rx.push_back(buf[..len].to_vec());
+        }
+    }
+
+    impl UdpStack for MockServer {
+        type SocketHandle = u8;
+
+        type OpenFuture<'m> = impl Future<Output = Self::SocketHandle> + 'm;
+        fn open<'m>(&'m mut self) -> Self::OpenFuture<'m> {
+            async move { 0 }
+        }
+
+        type ConnectFuture<'m> = impl Future<Output = Result<(), UdpError>> + 'm;
+        fn connect<'m>(
+            &'m mut self,
+            _: Self::SocketHandle,
+            _: SocketAddress,
+        ) -> Self::ConnectFuture<'m> {
+            async move { Ok(()) }
+        }
+
+        type SendFuture<'m> = impl Future<Output = Result<usize, UdpError>> + 'm;
+        fn send<'m>(&'m mut self, _: Self::SocketHandle, buf: &'m [u8]) -> Self::SendFuture<'m> {
+            async move {
+                self.handle(buf);
+                Ok(buf.len())
+            }
+        }
+
+        type RecvFuture<'m> = impl Future<Output = Result<usize, UdpError>> + 'm;
+        fn recv<'m>(
+            &'m mut self,
+            _: Self::SocketHandle,
+            buf: &'m mut [u8],
+        ) -> Self::RecvFuture<'m> {
+            async move {
+                match self.rx.pop_front() {
+                    Some(datagram) => {
+                        buf[..datagram.len()].copy_from_slice(&datagram);
+                        Ok(datagram.len())
+                    }
+                    None => core::future::pending().await,
+                }
+            }
+        }
+
+        type CloseFuture<'m> = impl Future<Output = ()> + 'm;
+        fn close<'m>(&'m mut self, _: Self::SocketHandle) -> Self::CloseFuture<'m> {
+            async move {}
+        }
+    }
+
+    fn server() -> SocketAddress {
+        SocketAddress::new(IpAddress::new_v4(127, 0, 0, 1), 5683)
+    }
+
+    #[drogue::test]
+    async fn test_retransmit(mut context: TestContext<DummyDevice>) {
+        mount_dummy(&mut context);
+
+        let mut stack = MockServer::new(b"world");
+        stack.ignore = 1;
+        let requests = stack.requests.clone();
+        let mut client = CoapClient::new(stack, server(), || 1);
+
+        let mut rx = [0; 64];
+        let response = client.get("/hello", &mut rx).await.unwrap();
+        assert_eq!(CONTENT, response.code);
+        assert_eq!(b"world", &rx[..response.len]);
+        assert_eq!(2, *requests.borrow());
+    }
+
+    #[drogue::test]
+    async fn test_block2_get(mut context: TestContext<DummyDevice>) {
+        mount_dummy(&mut context);
+
+        let resource: Vec<u8> = (0..200).collect();
+        let mut client = CoapClient::new(MockServer::new(&resource), server(), || 1);
+
+        let mut rx = [0; 256];
+        let response = client.get("/large", &mut rx).await.unwrap();
+        assert!(response.is_success());
+        assert_eq!(&resource[..], &rx[..response.len]);
+    }
+
+    #[drogue::test]
+    async fn test_block1_post(mut context: TestContext<DummyDevice>) {
+        mount_dummy(&mut context);
+
+        let stack = MockServer::new(&[]);
+        let uploaded = stack.uploaded.clone();
+        let requests = stack.requests.clone();
+        let mut client = CoapClient::new(stack, server(), || 1);
+
+        let payload: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let mut rx = [0; 16];
+        let response = client.post("/upload", &payload, &mut rx).await.unwrap();
+        assert_eq!(CHANGED, response.code);
+        assert_eq!(0, response.len);
+        assert_eq!(payload, *uploaded.borrow());
+        assert_eq!(3, *requests.borrow());
+    }
+
+    #[drogue::test]
+    async fn test_seeded_ids(mut context: TestContext<DummyDevice>) {
+        mount_dummy(&mut context);
+
+        let mut received = Vec::new();
+        for get_random in [(|| 1) as fn() -> u32, || 0x8765_4321].iter() {
+            let stack = MockServer::new(b"world");
+            let requests = stack.received.clone();
+            let mut client = CoapClient::new(stack, server(), *get_random);
+            let mut rx = [0; 64];
+            client.get("/hello", &mut rx).await.unwrap();
+            received.push(requests.borrow()[0].clone());
+        }
+        assert_ne!(received[0].0, received[1].0);
+        assert_ne!(received[0].1, received[1].1);
+    }
+}
@@ -0,0 +1,16 @@
+//! Fixtures shared by the integration tests.
+
+use drogue_device::{testutil::*, *};
+
+/// A device with a single actor doing nothing, for tests that only need the executor running.
+pub struct DummyDevice {
+    dummy: ActorContext<'static, DummyActor>,
+}
+
+/// Configure and mount a `DummyDevice`.
+pub fn mount_dummy(context: &mut TestContext<DummyDevice>) {
+    context.configure(DummyDevice {
+        dummy: ActorContext::new(DummyActor::new()),
+    });
+    context.mount(|device, spawner| device.dummy.mount((), spawner));
+}
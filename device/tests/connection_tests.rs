@@ -0,0 +1,265 @@
+#![macro_use]
+#![allow(incomplete_features)]
+#![feature(min_type_alias_impl_trait)]
+#![feature(impl_trait_in_bindings)]
+#![feature(generic_associated_types)]
+#![feature(type_alias_impl_trait)]
+
+#[cfg(feature = "std")]
+mod tests {
+    extern crate std;
+    use core::cell::RefCell;
+    use core::future::Future;
+    use drogue_device::{
+        actors::connection::*,
+        testutil::*,
+        traits::{ip::*, tcp::*, wifi::*},
+        *,
+    };
+    use heapless::String;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    #[derive(Default)]
+    struct LinkState {
+        join_failures: usize,
+        joined: bool,
+        /// Number of sockets opened, each getting a new handle.
+        opened: u8,
+        connects: Vec<u8>,
+        writes: Vec<u8>,
+        closes: Vec<u8>,
+        /// Sockets closed by the remote end.
+        closed: Vec<u8>,
+    }
+
+    /// A driver whose link state is controlled by the test.
+    struct MockWifi {
+        state: &'static RefCell<LinkState>,
+    }
+
+    impl WifiSupplicant for MockWifi {
+        type JoinFuture<'m> = impl Future<Output = Result<IpAddress, JoinError>> + 'm;
+        fn join<'m>(&'m mut self, _: Join<'m>) -> Self::JoinFuture<'m> {
+            async move {
+                let mut state = self.state.borrow_mut();
+                if state.join_failures > 0 {
+                    state.join_failures -= 1;
+                    return Err(JoinError::UnableToAssociate);
+                }
+                state.joined = true;
+                Ok(IpAddress::new_v4(192, 168, 1, 2))
+            }
+        }
+
+        type ScanFuture<'m> = impl Future<Output = Result<usize, WifiError>> + 'm;
+        fn scan<'m>(&'m mut self, _: &'m mut [AccessPointInfo]) -> Self::ScanFuture<'m> {
+            async move { Ok(0) }
+        }
+
+        type DisconnectFuture<'m> = impl Future<Output = Result<(), WifiError>> + 'm;
+        fn disconnect<'m>(&'m mut self) -> Self::DisconnectFuture<'m> {
+            async move {
+                self.state.borrow_mut().joined = false;
+                Ok(())
+            }
+        }
+
+        type StatusFuture<'m> = impl Future<Output = Result<Option<WifiStatus>, WifiError>> + 'm;
+        fn status<'m>(&'m mut self) -> Self::StatusFuture<'m> {
+            async move {
+                if !self.state.borrow().joined {
+                    return Ok(None);
+                }
+                Ok(Some(WifiStatus {
+                    ssid: String::from("test"),
+                    ip: None,
+                    rssi: -50,
+                    channel: 1,
+                }))
+            }
+        }
+    }
+
+    impl TcpStack for MockWifi {
+        type SocketHandle = u8;
+
+        type OpenFuture<'m> = impl Future<Output = Self::SocketHandle> + 'm;
+        fn open<'m>(&'m mut self) -> Self::OpenFuture<'m> {
+            async move {
+                let mut state = self.state.borrow_mut();
+                state.opened += 1;
+                state.opened - 1
+            }
+        }
+
+        type ConnectFuture<'m> = impl Future<Output = Result<(), TcpError>> + 'm;
+        fn connect<'m>(
+            &'m mut self,
+            handle: Self::SocketHandle,
+            _: IpProtocol,
+            _: SocketAddress,
+        ) -> Self::ConnectFuture<'m> {
+            async move {
+                self.state.borrow_mut().connects.push(handle);
+                Ok(())
+            }
+        }
+
+        type WriteFuture<'m> = impl Future<Output = Result<usize, TcpError>> + 'm;
+        fn write<'m>(
+            &'m mut self,
+            handle: Self::SocketHandle,
+            buf: &'m [u8],
+        ) -> Self::WriteFuture<'m> {
+            async move {
+                self.state.borrow_mut().writes.push(handle);
+                Ok(buf.len())
+            }
+        }
+
+        type ReadFuture<'m> = impl Future<Output = Result<usize, TcpError>> + 'm;
+        fn read<'m>(&'m mut self, _: Self::SocketHandle, _: &'m mut [u8]) -> Self::ReadFuture<'m> {
+            async move { Ok(0) }
+        }
+
+        type CloseFuture<'m> = impl Future<Output = ()> + 'm;
+        fn close<'m>(&'m mut self, handle: Self::SocketHandle) -> Self::CloseFuture<'m> {
+            async move {
+                self.state.borrow_mut().closes.push(handle);
+            }
+        }
+
+        fn close_detached(&mut self, _: Self::SocketHandle) {}
+
+        type PollReadableFuture<'m> = impl Future<Output = Result<Readiness, TcpError>> + 'm;
+        fn poll_readable<'m>(
+            &'m mut self,
+            handle: Self::SocketHandle,
+        ) -> Self::PollReadableFuture<'m> {
+            async move {
+                if self.state.borrow().closed.contains(&handle) {
+                    Ok(Readiness::HalfClosed)
+                } else {
+                    Ok(Readiness::Pending)
+                }
+            }
+        }
+
+        type AvailableFuture<'m> = impl Future<Output = Result<usize, TcpError>> + 'm;
+        fn available<'m>(&'m mut self, _: Self::SocketHandle) -> Self::AvailableFuture<'m> {
+            async move { Ok(0) }
+        }
+
+        type SetTimeoutFuture<'m> = impl Future<Output = Result<(), TcpError>> + 'm;
+        fn set_timeout<'m>(
+            &'m mut self,
+            _: Self::SocketHandle,
+            _: Option<embassy::time::Duration>,
+        ) -> Self::SetTimeoutFuture<'m> {
+            async move { Ok(()) }
+        }
+    }
+
+    struct ConnectionDevice {
+        handler: ActorContext<'static, TestHandler>,
+        manager: ActorContext<'static, ConnectionManager<'static, MockWifi, TestHandler>>,
+    }
+
+    #[drogue::test]
+    async fn test_rejoin_and_reconnect(mut context: TestContext<ConnectionDevice>) {
+        let events = context.signal();
+        let state: &'static RefCell<LinkState> = Box::leak(Box::new(RefCell::new(LinkState {
+            join_failures: 1,
+            ..Default::default()
+        })));
+        context.configure(ConnectionDevice {
+            handler: ActorContext::new(TestHandler::new(events)),
+            manager: ActorContext::new(ConnectionManager::new(Join::Wpa {
+                ssid: "ssid",
+                password: "password",
+            })),
+        });
+        let mut manager = context.mount(|device, spawner| {
+            let handler = device.handler.mount((), spawner);
+            device.manager.mount((MockWifi { state }, handler), spawner)
+        });
+
+        // The first attempt fails, the next is made after backing off
+        while !state.borrow().joined {
+            manager.request(ConnectionRequest::Check).unwrap().await;
+            time::Timer::after(time::Duration::from_millis(100)).await;
+        }
+        events.wait_signaled().await;
+        assert_eq!(1, events.message().unwrap().0);
+
+        let socket = manager.open().await;
+        let server = SocketAddress::new(IpAddress::new_v4(192, 168, 1, 1), 1883);
+        manager
+            .connect(socket, IpProtocol::Tcp, server)
+            .await
+            .unwrap();
+        assert_eq!(1, state.borrow().connects.len());
+
+        // Losing the link is reported, and the connection is re-established after rejoining
+        {
+            let mut state = state.borrow_mut();
+            state.joined = false;
+            state.join_failures = 1;
+        }
+        manager.request(ConnectionRequest::Check).unwrap().await;
+        events.wait_signaled().await;
+        assert_eq!(0, events.message().unwrap().0);
+
+        while !state.borrow().joined {
+            manager.request(ConnectionRequest::Check).unwrap().await;
+            time::Timer::after(time::Duration::from_millis(100)).await;
+        }
+        events.wait_signaled().await;
+        assert_eq!(1, events.message().unwrap().0);
+        assert!(state.borrow().joined);
+        // The connection is re-established on a fresh socket
+        assert_eq!(&[socket, socket + 1], &state.borrow().connects[..]);
+    }
+
+    #[drogue::test]
+    async fn test_reconnect_closed_socket(mut context: TestContext<ConnectionDevice>) {
+        let events = context.signal();
+        let state: &'static RefCell<LinkState> =
+            Box::leak(Box::new(RefCell::new(LinkState::default())));
+        context.configure(ConnectionDevice {
+            handler: ActorContext::new(TestHandler::new(events)),
+            manager: ActorContext::new(ConnectionManager::new(Join::Wpa {
+                ssid: "ssid",
+                password: "password",
+            })),
+        });
+        let mut manager = context.mount(|device, spawner| {
+            let handler = device.handler.mount((), spawner);
+            device.manager.mount((MockWifi { state }, handler), spawner)
+        });
+        events.wait_signaled().await;
+        assert_eq!(1, events.message().unwrap().0);
+
+        let socket = manager.open().await;
+        let server = SocketAddress::new(IpAddress::new_v4(192, 168, 1, 1), 1883);
+        manager
+            .connect(socket, IpProtocol::Tcp, server)
+            .await
+            .unwrap();
+
+        // A connection closed while the link is up is re-established on a fresh socket, which is
+        // used for the handle of the owner
+        manager.request(ConnectionRequest::Check).unwrap().await;
+        assert_eq!(&[socket], &state.borrow().connects[..]);
+        state.borrow_mut().closed.push(socket);
+        manager.request(ConnectionRequest::Check).unwrap().await;
+        assert_eq!(&[socket, socket + 1], &state.borrow().connects[..]);
+        manager.write(socket, b"data").await.unwrap();
+        assert_eq!(&[socket + 1], &state.borrow().writes[..]);
+
+        // Closing the handle also closes the socket carrying the connection
+        manager.close(socket).await;
+        assert_eq!(&[socket + 1, socket], &state.borrow().closes[..]);
+    }
+}
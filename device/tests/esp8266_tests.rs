@@ -0,0 +1,616 @@
+#![macro_use]
+#![allow(incomplete_features)]
+#![feature(min_type_alias_impl_trait)]
+#![feature(impl_trait_in_bindings)]
+#![feature(generic_associated_types)]
+#![feature(type_alias_impl_trait)]
+
+#[cfg(all(feature = "std", feature = "wifi+esp8266"))]
+mod tests {
+    extern crate std;
+    use core::cell::{Cell, RefCell, UnsafeCell};
+    use core::pin::Pin;
+    use core::task::{Context, Poll, Waker};
+    use drogue_device::{
+        drivers::wifi::esp8266::*,
+        io::{AsyncBufRead, AsyncWrite},
+        testutil::*,
+        traits::{ip::*, tcp::*, wifi::*},
+        *,
+    };
+    use embedded_hal::digital::v2::OutputPin;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    /// Size of the `+IPD` frames sent by the modem.
+    const FRAME_LEN: usize = 1460;
+
+    /// Traffic on the UART, used to compare transfer modes.
+    #[derive(Default)]
+    struct Traffic {
+        commands: Cell<usize>,
+        bytes: Cell<usize>,
+    }
+
+    impl Traffic {
+        fn clear(&self) {
+            self.commands.set(0);
+            self.bytes.set(0);
+        }
+
+        /// Payload bytes moved per second, on a 115200 baud UART with 10 bits per byte, and
+        /// assuming the modem takes 2 ms to process a command.
+        fn throughput(&self, payload: usize) -> usize {
+            let duration_ms = self.bytes.get() * 10 * 1000 / 115_200 + self.commands.get() * 2;
+            payload * 1000 / duration_ms
+        }
+    }
+
+    enum RemoteEvent {
+        Connect(usize),
+        Data(usize, Vec<u8>),
+    }
+
+    /// Clients of the server on the modem, whose events are reported by the modem as they happen.
+    #[derive(Default)]
+    struct Remote {
+        events: RefCell<Vec<RemoteEvent>>,
+        waker: RefCell<Option<Waker>>,
+    }
+
+    impl Remote {
+        fn connect(&self, link_id: usize) {
+            self.push(RemoteEvent::Connect(link_id));
+        }
+
+        fn send(&self, link_id: usize, data: &[u8]) {
+            self.push(RemoteEvent::Data(link_id, data.to_vec()));
+        }
+
+        fn push(&self, event: RemoteEvent) {
+            self.events.borrow_mut().push(event);
+            if let Some(waker) = self.waker.borrow_mut().take() {
+                waker.wake();
+            }
+        }
+    }
+
+    enum Input {
+        Command,
+        Data { link_id: usize, remaining: usize },
+        Passthrough,
+    }
+
+    /// A UART connected to an ESP8266 whose connections all go to an echo server.
+    struct MockUart {
+        rx: Vec<u8>,
+        rx_pos: usize,
+        waker: Option<Waker>,
+        input: Input,
+        line: Vec<u8>,
+        sent: Vec<u8>,
+        active: bool,
+        single: bool,
+        pending: [Vec<u8>; 4],
+        traffic: &'static Traffic,
+        /// Prefix of a command the modem hangs on, once.
+        hang_on: Option<&'static str>,
+        hung: bool,
+        resets: &'static Cell<usize>,
+        resets_seen: usize,
+        remote: &'static Remote,
+        /// Link of a client connecting to the server while the next connection is opened.
+        connect_on_start: Option<usize>,
+        /// Prefix of commands to count, and their count.
+        counted: Option<(&'static str, &'static Cell<usize>)>,
+    }
+
+    impl MockUart {
+        fn new(traffic: &'static Traffic) -> Self {
+            Self {
+                rx: b"ready\r\n".to_vec(),
+                rx_pos: 0,
+                waker: None,
+                input: Input::Command,
+                line: Vec::new(),
+                sent: Vec::new(),
+                active: false,
+                single: false,
+                pending: Default::default(),
+                traffic,
+                hang_on: None,
+                hung: false,
+                resets: Box::leak(Box::new(Cell::new(0))),
+                resets_seen: 0,
+                remote: Box::leak(Box::new(Remote::default())),
+                connect_on_start: None,
+                counted: None,
+            }
+        }
+
+        fn count(mut self, command: &'static str, count: &'static Cell<usize>) -> Self {
+            self.counted.replace((command, count));
+            self
+        }
+
+        fn remote(mut self, remote: &'static Remote) -> Self {
+            self.remote = remote;
+            self
+        }
+
+        fn connect_on_start(mut self, link_id: usize) -> Self {
+            self.connect_on_start.replace(link_id);
+            self
+        }
+
+        fn hang_on(mut self, command: &'static str, resets: &'static Cell<usize>) -> Self {
+            self.hang_on.replace(command);
+            self.resets = resets;
+            self
+        }
+
+        /// Start again after the reset pin was pulled low.
+        fn reboot(&mut self) {
+            self.resets_seen = self.resets.get();
+            self.rx.clear();
+            self.rx_pos = 0;
+            self.input = Input::Command;
+            self.line.clear();
+            self.active = false;
+            self.single = false;
+            self.hung = false;
+            self.respond(b"ready\r\n");
+        }
+
+        fn respond(&mut self, data: &[u8]) {
+            self.traffic
+                .bytes
+                .set(self.traffic.bytes.get() + data.len());
+            self.rx.extend_from_slice(data);
+            if let Some(waker) = self.waker.take() {
+                waker.wake();
+            }
+        }
+
+        fn command(&mut self) {
+            let line = core::mem::take(&mut self.line);
+            let line = std::str::from_utf8(&line[..line.len() - 2]).unwrap();
+            self.traffic.commands.set(self.traffic.commands.get() + 1);
+            if let Some((command, count)) = self.counted {
+                if line.starts_with(command) {
+                    count.set(count.get() + 1);
+                }
+            }
+            if self.hung {
+                return;
+            }
+            if let Some(command) = self.hang_on {
+                if line.starts_with(command) {
+                    self.hang_on.take();
+                    self.hung = true;
+                    return;
+                }
+            }
+            let args: Vec<usize> = line
+                .split(|c| c == '=' || c == ',')
+                .skip(1)
+                .filter_map(|a| a.parse().ok())
+                .collect();
+
+            if line == "AT+GMR" {
+                self.respond(
+                    b"AT version:1.7.4.0(May 11 2020 19:13:04)\r\n\
+                      SDK version:3.0.4(9532ceb)\r\n\r\nOK\r\n",
+                );
+            } else if line == "AT+CIPAP_CUR?" {
+                self.respond(
+                    b"+CIPAP_CUR:ip:\"192.168.4.1\"\r\n\
+                      +CIPAP_CUR:gateway:\"192.168.4.1\"\r\n\
+                      +CIPAP_CUR:netmask:\"255.255.255.0\"\r\n\r\nOK\r\n",
+                );
+            } else if line == "AT+CIPMUX=0" {
+                self.single = true;
+                self.respond(b"OK\r\n");
+            } else if line.starts_with("AT+CIPRECVMODE=") {
+                self.active = line.ends_with('0');
+                self.respond(b"OK\r\n");
+            } else if line.starts_with("AT+CIPSTART=") {
+                if let Some(link_id) = self.connect_on_start.take() {
+                    self.respond(std::format!("{},CONNECT\r\n", link_id).as_bytes());
+                }
+                if self.single {
+                    self.respond(b"CONNECT\r\n\r\nOK\r\n");
+                } else {
+                    self.respond(std::format!("{},CONNECT\r\n\r\nOK\r\n", args[0]).as_bytes());
+                }
+            } else if line == "AT+CIPSEND" {
+                self.input = Input::Passthrough;
+                self.respond(b"\r\nOK\r\n\r\n>");
+            } else if line.starts_with("AT+CIPSEND=") {
+                self.input = Input::Data {
+                    link_id: args[0],
+                    remaining: args[1],
+                };
+                self.respond(b"\r\nOK\r\n> ");
+            } else if line.starts_with("AT+CIPRECVDATA=") {
+                let pending = &mut self.pending[args[0]];
+                let len = core::cmp::min(args[1], pending.len());
+                let data: Vec<u8> = pending.drain(..len).collect();
+                if len > 0 {
+                    self.respond(std::format!("+CIPRECVDATA,{}:", len).as_bytes());
+                    self.respond(&data);
+                }
+                self.respond(b"\r\nOK\r\n");
+            } else if line == "AT+CIPCLOSE" {
+                self.respond(b"CLOSED\r\n\r\nOK\r\n");
+            } else if line.starts_with("AT+CIPCLOSE=") {
+                self.respond(std::format!("{},CLOSED\r\n\r\nOK\r\n", args[0]).as_bytes());
+            } else {
+                self.respond(b"OK\r\n");
+            }
+        }
+
+        /// Acknowledge data sent using `AT+CIPSEND`, and echo it back.
+        fn echo(&mut self, link_id: usize) {
+            let sent = core::mem::take(&mut self.sent);
+            self.respond(
+                std::format!("\r\nRecv {} bytes\r\n\r\nSEND OK\r\n", sent.len()).as_bytes(),
+            );
+            self.deliver(link_id, &sent);
+        }
+
+        /// Report data received on a link.
+        fn deliver(&mut self, link_id: usize, sent: &[u8]) {
+            if self.active {
+                for frame in sent.chunks(FRAME_LEN) {
+                    self.respond(std::format!("\r\n+IPD,{},{}:", link_id, frame.len()).as_bytes());
+                    self.respond(frame);
+                }
+            } else {
+                self.pending[link_id].extend_from_slice(sent);
+                self.respond(std::format!("\r\n+IPD,{},{}\r\n", link_id, sent.len()).as_bytes());
+            }
+        }
+    }
+
+    impl AsyncBufRead for MockUart {
+        fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+            let this = self.get_mut();
+            if this.resets.get() > this.resets_seen {
+                this.reboot();
+            }
+            let events = core::mem::take(&mut *this.remote.events.borrow_mut());
+            for event in events {
+                match event {
+                    RemoteEvent::Connect(link_id) => {
+                        this.respond(std::format!("{},CONNECT\r\n", link_id).as_bytes())
+                    }
+                    RemoteEvent::Data(link_id, data) => this.deliver(link_id, &data),
+                }
+            }
+            if this.rx_pos < this.rx.len() {
+                Poll::Ready(Ok(&this.rx[this.rx_pos..]))
+            } else {
+                this.rx.clear();
+                this.rx_pos = 0;
+                this.waker.replace(cx.waker().clone());
+                this.remote.waker.replace(Some(cx.waker().clone()));
+                Poll::Pending
+            }
+        }
+
+        fn consume(self: Pin<&mut Self>, amt: usize) {
+            self.get_mut().rx_pos += amt;
+        }
+    }
+
+    impl AsyncWrite for MockUart {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            this.traffic.bytes.set(this.traffic.bytes.get() + buf.len());
+            match this.input {
+                Input::Passthrough if buf == b"+++" => this.input = Input::Command,
+                Input::Passthrough => this.respond(buf),
+                _ => {
+                    for b in buf {
+                        match &mut this.input {
+                            Input::Data { link_id, remaining } => {
+                                this.sent.push(*b);
+                                *remaining -= 1;
+                                if *remaining == 0 {
+                                    let link_id = *link_id;
+                                    this.input = Input::Command;
+                                    this.echo(link_id);
+                                }
+                            }
+                            _ => {
+                                this.line.push(*b);
+                                if this.line.ends_with(b"\r\n") {
+                                    this.command();
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            Poll::Ready(Ok(buf.len()))
+        }
+    }
+
+    pub struct DummyPin {}
+    impl OutputPin for DummyPin {
+        type Error = ();
+        fn set_low(&mut self) -> Result<(), ()> {
+            Ok(())
+        }
+        fn set_high(&mut self) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    /// Counts the times the modem is reset.
+    pub struct ResetPin {
+        resets: &'static Cell<usize>,
+    }
+    impl OutputPin for ResetPin {
+        type Error = ();
+        fn set_low(&mut self) -> Result<(), ()> {
+            self.resets.set(self.resets.get() + 1);
+            Ok(())
+        }
+        fn set_high(&mut self) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    type Modem = Esp8266ModemActor<'static, MockUart, DummyPin, DummyPin>;
+
+    struct EspDevice {
+        passive: UnsafeCell<Esp8266Driver>,
+        active: UnsafeCell<Esp8266Driver<4>>,
+        passthrough: UnsafeCell<Esp8266Driver<1>>,
+        modems: [ActorContext<'static, Modem>; 3],
+    }
+
+    /// Send data through an echo connection in blocks, reading each block back before sending
+    /// the next one.
+    async fn echo(controller: &mut Esp8266Controller<'static>, data: &[u8]) {
+        let socket = controller.open().await;
+        controller
+            .connect(
+                socket,
+                IpProtocol::Tcp,
+                SocketAddress::new(IpAddress::new_v4(192, 168, 1, 2), 7),
+            )
+            .await
+            .unwrap();
+
+        let mut rx = [0; 1024];
+        for block in data.chunks(rx.len()) {
+            let mut written = 0;
+            while written < block.len() {
+                written += controller.write(socket, &block[written..]).await.unwrap();
+            }
+            let mut pos = 0;
+            while pos < block.len() {
+                let len = controller
+                    .read(socket, &mut rx[pos..block.len()])
+                    .await
+                    .unwrap();
+                if len == 0 {
+                    time::Timer::after(time::Duration::from_millis(1)).await;
+                }
+                pos += len;
+            }
+            assert_eq!(block, &rx[..block.len()]);
+        }
+        controller.close(socket).await;
+    }
+
+    #[drogue::test]
+    async fn transfer_mode_throughput(mut context: TestContext<EspDevice>) {
+        context.configure(EspDevice {
+            passive: UnsafeCell::new(Esp8266Driver::new()),
+            active: UnsafeCell::new(Esp8266Driver::active()),
+            passthrough: UnsafeCell::new(Esp8266Driver::passthrough()),
+            modems: [
+                ActorContext::new(Esp8266ModemActor::new()),
+                ActorContext::new(Esp8266ModemActor::new()),
+                ActorContext::new(Esp8266ModemActor::new()),
+            ],
+        });
+
+        let traffic: Vec<&'static Traffic> = (0..3)
+            .map(|_| &*Box::leak(Box::new(Traffic::default())))
+            .collect();
+        let uarts: Vec<MockUart> = traffic.iter().map(|t| MockUart::new(*t)).collect();
+        let controllers: Vec<Esp8266Controller<'static>> = context.mount(|device, spawner| {
+            let mut uarts = uarts.into_iter();
+            let mut controllers = Vec::new();
+            let (controller, modem) = unsafe { &mut *device.passive.get() }.initialize(
+                uarts.next().unwrap(),
+                DummyPin {},
+                DummyPin {},
+            );
+            device.modems[0].mount(modem, spawner);
+            controllers.push(controller);
+            let (controller, modem) = unsafe { &mut *device.active.get() }.initialize(
+                uarts.next().unwrap(),
+                DummyPin {},
+                DummyPin {},
+            );
+            device.modems[1].mount(modem, spawner);
+            controllers.push(controller);
+            let (controller, modem) = unsafe { &mut *device.passthrough.get() }.initialize(
+                uarts.next().unwrap(),
+                DummyPin {},
+                DummyPin {},
+            );
+            device.modems[2].mount(modem, spawner);
+            controllers.push(controller);
+            controllers
+        });
+
+        let data: Vec<u8> = (0..16 * 1024).map(|i| i as u8).collect();
+        let mut commands = Vec::new();
+        let mut throughput = Vec::new();
+        for (mut controller, traffic) in controllers.into_iter().zip(traffic.iter()) {
+            // Only the transfer itself is measured
+            traffic.clear();
+            echo(&mut controller, &data).await;
+            commands.push(traffic.commands.get());
+            // Data is moved in both directions
+            throughput.push(traffic.throughput(2 * data.len()));
+        }
+
+        // Passive mode needs a command for every write and every read, active mode only for
+        // writes, and passthrough mode only to enter and leave passthrough
+        assert!(commands[1] < commands[0]);
+        assert!(commands[2] < commands[1]);
+        assert!(throughput[1] > throughput[0]);
+        assert!(throughput[2] > throughput[1]);
+    }
+
+    struct ResetDevice {
+        driver: UnsafeCell<Esp8266Driver>,
+        modem: ActorContext<'static, Esp8266ModemActor<'static, MockUart, DummyPin, ResetPin>>,
+    }
+
+    #[drogue::test]
+    async fn reset_unresponsive_modem(mut context: TestContext<ResetDevice>) {
+        context.configure(ResetDevice {
+            driver: UnsafeCell::new(
+                Esp8266Driver::new()
+                    .command_timeout(time::Duration::from_millis(100))
+                    .network_timeout(time::Duration::from_millis(100)),
+            ),
+            modem: ActorContext::new(Esp8266ModemActor::new()),
+        });
+
+        let resets: &'static Cell<usize> = Box::leak(Box::new(Cell::new(0)));
+        let traffic = Box::leak(Box::new(Traffic::default()));
+        let uart = MockUart::new(traffic).hang_on("AT+CIPSTART", resets);
+        let mut controller = context.mount(|device, spawner| {
+            let (controller, modem) = unsafe { &mut *device.driver.get() }.initialize(
+                uart,
+                DummyPin {},
+                ResetPin { resets },
+            );
+            device.modem.mount(modem, spawner);
+            controller
+        });
+
+        let dst = SocketAddress::new(IpAddress::new_v4(192, 168, 1, 2), 7);
+        let socket = controller.open().await;
+        assert!(matches!(
+            controller.connect(socket, IpProtocol::Tcp, dst).await,
+            Err(TcpError::Timeout)
+        ));
+        controller.close(socket).await;
+
+        // The modem is reset and initialized again before the next command
+        let data: Vec<u8> = (0..2048).map(|i| i as u8).collect();
+        echo(&mut controller, &data).await;
+        assert_eq!(1, resets.get());
+    }
+    struct ServerDevice {
+        driver: UnsafeCell<Esp8266Driver>,
+        modem: ActorContext<'static, Modem>,
+    }
+
+    #[drogue::test]
+    async fn accept_incoming_connections(mut context: TestContext<ServerDevice>) {
+        context.configure(ServerDevice {
+            driver: UnsafeCell::new(Esp8266Driver::new()),
+            modem: ActorContext::new(Esp8266ModemActor::new()),
+        });
+
+        let remote: &'static Remote = Box::leak(Box::new(Remote::default()));
+        let uart = MockUart::new(Box::leak(Box::new(Traffic::default())))
+            .remote(remote)
+            .connect_on_start(3);
+        let mut controller = context.mount(|device, spawner| {
+            let (controller, modem) =
+                unsafe { &mut *device.driver.get() }.initialize(uart, DummyPin {}, DummyPin {});
+            device.modem.mount(modem, spawner);
+            controller
+        });
+
+        controller.listen(80).await.unwrap();
+
+        // A client connecting while a connection is opened does not complete the open
+        let socket = controller.open().await;
+        controller
+            .connect(
+                socket,
+                IpProtocol::Tcp,
+                SocketAddress::new(IpAddress::new_v4(192, 168, 1, 2), 7),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(controller.accept().await, Ok(3)));
+
+        // Data received for other sockets while waiting for a client is kept
+        let (accepted, _) = futures::future::join(controller.accept(), async {
+            time::Timer::after(time::Duration::from_millis(10)).await;
+            remote.send(socket as usize, b"hello");
+            remote.connect(2);
+        })
+        .await;
+        assert!(matches!(accepted, Ok(2)));
+        assert!(matches!(controller.available(socket).await, Ok(5)));
+
+        let mut buf = [0; 8];
+        assert!(matches!(controller.read(socket, &mut buf).await, Ok(5)));
+        assert_eq!(b"hello", &buf[..5]);
+    }
+    #[drogue::test]
+    async fn restart_access_point_after_reset(mut context: TestContext<ResetDevice>) {
+        context.configure(ResetDevice {
+            driver: UnsafeCell::new(
+                Esp8266Driver::new()
+                    .command_timeout(time::Duration::from_millis(100))
+                    .network_timeout(time::Duration::from_millis(100)),
+            ),
+            modem: ActorContext::new(Esp8266ModemActor::new()),
+        });
+
+        let resets: &'static Cell<usize> = Box::leak(Box::new(Cell::new(0)));
+        let access_points: &'static Cell<usize> = Box::leak(Box::new(Cell::new(0)));
+        let uart = MockUart::new(Box::leak(Box::new(Traffic::default())))
+            .hang_on("AT+CIPSTART", resets)
+            .count("AT+CWSAP_CUR=", access_points);
+        let mut controller = context.mount(|device, spawner| {
+            let (controller, modem) = unsafe { &mut *device.driver.get() }.initialize(
+                uart,
+                DummyPin {},
+                ResetPin { resets },
+            );
+            device.modem.mount(modem, spawner);
+            controller
+        });
+
+        assert!(matches!(
+            controller.start("drogue", "secret", 6).await,
+            Err(WifiError::InvalidPassword)
+        ));
+        assert_eq!(0, access_points.get());
+        controller.start("drogue", "password", 6).await.unwrap();
+        assert_eq!(1, access_points.get());
+
+        // The modem is reset, and the access point started again before the next command
+        let dst = SocketAddress::new(IpAddress::new_v4(192, 168, 4, 2), 7);
+        let socket = controller.open().await;
+        assert!(matches!(
+            controller.connect(socket, IpProtocol::Tcp, dst).await,
+            Err(TcpError::Timeout)
+        ));
+        controller.close(socket).await;
+        assert_eq!(1, resets.get());
+        assert_eq!(2, access_points.get());
+    }
+}
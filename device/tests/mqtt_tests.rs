@@ -0,0 +1,251 @@
+#![macro_use]
+#![allow(incomplete_features)]
+#![feature(min_type_alias_impl_trait)]
+#![feature(impl_trait_in_bindings)]
+#![feature(generic_associated_types)]
+#![feature(type_alias_impl_trait)]
+
+#[cfg(feature = "std")]
+mod common;
+
+#[cfg(feature = "std")]
+mod tests {
+    extern crate std;
+    use super::common::*;
+    use core::future::Future;
+    use core::pin::Pin;
+    use drogue_device::{
+        actors::mqtt::*,
+        clients::mqtt::*,
+        testutil::*,
+        traits::{ip::*, tcp::*},
+        *,
+    };
+    use std::vec::Vec;
+
+    /// A broker stand-in that acknowledges every request and publishes a retained
+    /// message containing the byte 42 on every subscribed topic.
+    struct MockBroker {
+        rx: Vec<u8>,
+        oversized: usize,
+    }
+
+    impl MockBroker {
+        fn new() -> Self {
+            Self {
+                rx: Vec::new(),
+                oversized: 0,
+            }
+        }
+
+        /// Publish a message with a payload of the given length before the retained message.
+        fn oversized(mut self, len: usize) -> Self {
+            self.oversized = len;
+            self
+        }
+
+        fn handle(&mut self, packet: &[u8]) {
+            match packet[0] & 0xF0 {
+                0x10 => self.rx.extend_from_slice(&[0x20, 0x02, 0x00, 0x00]),
+                0x30 => {
+                    if packet[0] & 0x06 != 0 {
+                        let topic_len = (packet[2] as usize) << 8 | packet[3] as usize;
+                        let id = &packet[4 + topic_len..6 + topic_len];
+                        self.rx.extend_from_slice(&[0x40, 0x02, id[0], id[1]]);
+                    }
+                }
+                0x80 => {
+                    self.rx
+                        .extend_from_slice(&[0x90, 0x03, packet[2], packet[3], 0x01]);
+                    let topic_len = (packet[4] as usize) << 8 | packet[5] as usize;
+                    let topic = &packet[6..6 + topic_len];
+                    if self.oversized > 0 {
+                        let remaining = 2 + topic_len + self.oversized;
+                        self.rx.push(0x30);
+                        self.rx.push((remaining % 128) as u8 | 0x80);
+                        self.rx.push((remaining / 128) as u8);
+                        self.rx.extend_from_slice(&packet[4..6]);
+                        self.rx.extend_from_slice(topic);
+                        self.rx.resize(self.rx.len() + self.oversized, 0);
+                    }
+                    self.rx.push(0x31);
+                    self.rx.push((2 + topic_len + 1) as u8);
+                    self.rx.extend_from_slice(&packet[4..6]);
+                    self.rx.extend_from_slice(topic);
+                    self.rx.push(42);
+                }
+                0xC0 => self.rx.extend_from_slice(&[0xD0, 0x00]),
+                _ => {}
+            }
+        }
+    }
+
+    impl TcpStack for MockBroker {
+        type SocketHandle = u8;
+
+        type OpenFuture<'m> = impl Future<Output = Self::SocketHandle> + 'm;
+        fn open<'m>(&'m mut self) -> Self::OpenFuture<'m> {
+            async move { 0 }
+        }
+
+        type ConnectFuture<'m> = impl Future<Output = Result<(), TcpError>> + 'm;
+        fn connect<'m>(
+            &'m mut self,
+            _: Self::SocketHandle,
+            _: IpProtocol,
+            _: SocketAddress,
+        ) -> Self::ConnectFuture<'m> {
+            async move { Ok(()) }
+        }
+
+        type WriteFuture<'m> = impl Future<Output = Result<usize, TcpError>> + 'm;
+        fn write<'m>(&'m mut self, _: Self::SocketHandle, buf: &'m [u8]) -> Self::WriteFuture<'m> {
+            async move {
+                self.handle(buf);
+                Ok(buf.len())
+            }
+        }
+
+        type ReadFuture<'m> = impl Future<Output = Result<usize, TcpError>> + 'm;
+        fn read<'m>(
+            &'m mut self,
+            _: Self::SocketHandle,
+            buf: &'m mut [u8],
+        ) -> Self::ReadFuture<'m> {
+            async move {
+                let len = core::cmp::min(buf.len(), self.rx.len());
+                buf[..len].copy_from_slice(&self.rx[..len]);
+                self.rx.drain(..len);
+                Ok(len)
+            }
+        }
+
+        type CloseFuture<'m> = impl Future<Output = ()> + 'm;
+        fn close<'m>(&'m mut self, _: Self::SocketHandle) -> Self::CloseFuture<'m> {
+            async move {}
+        }
+
+        fn close_detached(&mut self, _: Self::SocketHandle) {}
+
+        type PollReadableFuture<'m> = impl Future<Output = Result<Readiness, TcpError>> + 'm;
+        fn poll_readable<'m>(&'m mut self, _: Self::SocketHandle) -> Self::PollReadableFuture<'m> {
+            async move {
+                if self.rx.is_empty() {
+                    Ok(Readiness::Pending)
+                } else {
+                    Ok(Readiness::Readable)
+                }
+            }
+        }
+
+        type AvailableFuture<'m> = impl Future<Output = Result<usize, TcpError>> + 'm;
+        fn available<'m>(&'m mut self, _: Self::SocketHandle) -> Self::AvailableFuture<'m> {
+            async move { Ok(self.rx.len()) }
+        }
+
+        type SetTimeoutFuture<'m> = impl Future<Output = Result<(), TcpError>> + 'm;
+        fn set_timeout<'m>(
+            &'m mut self,
+            _: Self::SocketHandle,
+            _: Option<embassy::time::Duration>,
+        ) -> Self::SetTimeoutFuture<'m> {
+            async move { Ok(()) }
+        }
+    }
+
+    struct Inbox {
+        notified: &'static TestSignal,
+    }
+
+    impl Actor for Inbox {
+        type Message<'m> = TestMessage;
+        type OnStartFuture<'m> = ImmediateFuture;
+        type OnMessageFuture<'m> = ImmediateFuture;
+
+        fn on_start(self: Pin<&'_ mut Self>) -> Self::OnStartFuture<'_> {
+            ImmediateFuture::new()
+        }
+
+        fn on_message<'m>(
+            self: Pin<&'m mut Self>,
+            message: Self::Message<'m>,
+        ) -> Self::OnMessageFuture<'m> {
+            self.notified.signal(message);
+            ImmediateFuture::new()
+        }
+    }
+
+    impl FromMqttPublication<TestMessage> for Inbox {
+        fn from(publication: Publication) -> Option<TestMessage> {
+            publication
+                .payload()
+                .first()
+                .map(|b| TestMessage(*b as u32))
+        }
+    }
+
+    struct MqttDevice {
+        inbox: ActorContext<'static, Inbox>,
+        mqtt: ActorContext<'static, Mqtt<'static, MockBroker, Inbox>>,
+    }
+
+    #[drogue::test]
+    async fn test_subscribe_publish(mut context: TestContext<MqttDevice>) {
+        let notified = context.signal();
+        let broker = SocketAddress::new(IpAddress::new_v4(127, 0, 0, 1), 1883);
+        context.configure(MqttDevice {
+            inbox: ActorContext::new(Inbox { notified }),
+            mqtt: ActorContext::new(Mqtt::new(
+                MqttConfig::new("drogue", broker).credentials("user", "secret"),
+            )),
+        });
+
+        let mqtt = context.mount(|device, spawner| {
+            let inbox = device.inbox.mount((), spawner);
+            device.mqtt.mount((MockBroker::new(), inbox), spawner)
+        });
+
+        mqtt.request(MqttRequest::Subscribe {
+            topic: "commands",
+            qos: QoS::AtLeastOnce,
+        })
+        .unwrap()
+        .await
+        .unwrap();
+
+        mqtt.request(MqttRequest::Publish {
+            topic: "telemetry",
+            payload: b"22.5",
+            qos: QoS::AtLeastOnce,
+        })
+        .unwrap()
+        .await
+        .unwrap();
+
+        mqtt.request(MqttRequest::Poll).unwrap().await.unwrap();
+        notified.wait_signaled().await;
+        assert_eq!(42, notified.message().unwrap().0);
+    }
+
+    #[drogue::test]
+    async fn test_oversized_publication(mut context: TestContext<DummyDevice>) {
+        mount_dummy(&mut context);
+
+        let broker = SocketAddress::new(IpAddress::new_v4(127, 0, 0, 1), 1883);
+        let mut client = MqttClient::new(
+            MockBroker::new().oversized(300),
+            MqttConfig::new("drogue", broker).keep_alive(0),
+        );
+        client.subscribe("commands", QoS::AtMostOnce).await.unwrap();
+
+        // The publication does not fit, and is skipped without losing the next one
+        assert!(matches!(
+            client.poll().await,
+            Err(MqttError::BufferTooSmall)
+        ));
+        let publication = client.poll().await.unwrap().unwrap();
+        assert_eq!("commands", publication.topic());
+        assert_eq!(&[42], publication.payload());
+        assert!(client.is_connected());
+    }
+}
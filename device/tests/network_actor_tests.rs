@@ -0,0 +1,74 @@
+#![macro_use]
+#![allow(incomplete_features)]
+#![feature(min_type_alias_impl_trait)]
+#![feature(impl_trait_in_bindings)]
+#![feature(generic_associated_types)]
+#![feature(type_alias_impl_trait)]
+
+#[cfg(feature = "std")]
+mod tests {
+    extern crate std;
+    use drogue_device::{
+        actors::net::*,
+        drivers::net::std::StdNetwork,
+        testutil::*,
+        traits::{ip::*, tcp::*},
+        *,
+    };
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    struct NetworkDevice {
+        network: ActorContext<'static, NetworkActor<StdNetwork>>,
+    }
+
+    async fn echo<S: TcpStack>(stack: &mut S, socket: S::SocketHandle, data: &[u8; 4]) {
+        assert_eq!(4, stack.write(socket, data).await.unwrap());
+        let mut rx = [0; 4];
+        let mut pos = 0;
+        while pos < rx.len() {
+            let len = stack.read(socket, &mut rx[pos..]).await.unwrap();
+            if len == 0 {
+                time::Timer::after(time::Duration::from_millis(10)).await;
+            }
+            pos += len;
+        }
+        assert_eq!(data, &rx);
+    }
+
+    #[drogue::test]
+    async fn test_shared_stack(mut context: TestContext<NetworkDevice>) {
+        context.configure(NetworkDevice {
+            network: ActorContext::new(NetworkActor::new()),
+        });
+        let mut first =
+            context.mount(|device, spawner| device.network.mount(StdNetwork::new(), spawner));
+        let mut second = first;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                thread::spawn(move || {
+                    let mut buf = [0; 4];
+                    stream.read_exact(&mut buf).unwrap();
+                    stream.write_all(&buf).unwrap();
+                });
+            }
+        });
+        let server = SocketAddress::new(IpAddress::new_v4(127, 0, 0, 1), port);
+
+        let a = first.open().await;
+        first.connect(a, IpProtocol::Tcp, server).await.unwrap();
+        let b = second.open().await;
+        second.connect(b, IpProtocol::Tcp, server).await.unwrap();
+        assert_ne!(a, b);
+
+        echo(&mut second, b, b"PONG").await;
+        echo(&mut first, a, b"PING").await;
+        first.close(a).await;
+        second.close(b).await;
+    }
+}
@@ -0,0 +1,145 @@
+#![macro_use]
+#![allow(incomplete_features)]
+#![feature(min_type_alias_impl_trait)]
+#![feature(impl_trait_in_bindings)]
+#![feature(generic_associated_types)]
+#![feature(type_alias_impl_trait)]
+
+#[cfg(feature = "std")]
+mod common;
+
+#[cfg(feature = "std")]
+mod tests {
+    extern crate std;
+    use super::common::*;
+    use drogue_device::{
+        drivers::net::std::StdNetwork,
+        testutil::*,
+        traits::{ip::*, tcp::*, udp::UdpStack, wifi::*},
+        *,
+    };
+    use embassy::time;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, UdpSocket};
+    use std::thread;
+
+    fn localhost(port: u16) -> SocketAddress {
+        SocketAddress::new(IpAddress::new_v4(127, 0, 0, 1), port)
+    }
+
+    #[drogue::test]
+    async fn test_tcp_echo(mut context: TestContext<DummyDevice>) {
+        mount_dummy(&mut context);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(&buf).unwrap();
+        });
+
+        let mut network = StdNetwork::new();
+        network
+            .join(Join::Wpa {
+                ssid: "ssid",
+                password: "password",
+            })
+            .await
+            .unwrap();
+
+        let socket = TcpStack::open(&mut network).await;
+        TcpStack::connect(&mut network, socket, IpProtocol::Tcp, localhost(port))
+            .await
+            .unwrap();
+        assert_eq!(4, network.write(socket, b"PING").await.unwrap());
+
+        let mut rx = [0; 4];
+        let mut pos = 0;
+        while pos < rx.len() {
+            let len = network.read(socket, &mut rx[pos..]).await.unwrap();
+            if len == 0 {
+                time::Timer::after(time::Duration::from_millis(10)).await;
+            }
+            pos += len;
+        }
+        assert_eq!(b"PING", &rx);
+        TcpStack::close(&mut network, socket).await;
+    }
+
+    #[drogue::test]
+    async fn test_tcp_read_timeout(mut context: TestContext<DummyDevice>) {
+        mount_dummy(&mut context);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (close_tx, close_rx) = std::sync::mpsc::channel::<()>();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            // Stay silent until told to close
+            close_rx.recv().unwrap();
+            drop(stream);
+        });
+
+        let mut network = StdNetwork::new();
+        let socket = TcpStack::open(&mut network).await;
+        TcpStack::connect(&mut network, socket, IpProtocol::Tcp, localhost(port))
+            .await
+            .unwrap();
+
+        let mut rx = [0; 4];
+        assert_eq!(
+            0,
+            TcpStack::read(&mut network, socket, &mut rx).await.unwrap()
+        );
+        network
+            .set_timeout(socket, Some(time::Duration::from_millis(200)))
+            .await
+            .unwrap();
+        assert!(matches!(
+            TcpStack::read(&mut network, socket, &mut rx).await,
+            Err(TcpError::Timeout)
+        ));
+        assert_eq!(
+            Readiness::Pending,
+            network.poll_readable(socket).await.unwrap()
+        );
+        assert_eq!(0, network.available(socket).await.unwrap());
+
+        close_tx.send(()).unwrap();
+        while network.poll_readable(socket).await.unwrap() == Readiness::Pending {
+            time::Timer::after(time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            Readiness::HalfClosed,
+            network.poll_readable(socket).await.unwrap()
+        );
+        TcpStack::close(&mut network, socket).await;
+    }
+
+    #[drogue::test]
+    async fn test_udp_echo(mut context: TestContext<DummyDevice>) {
+        mount_dummy(&mut context);
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let mut buf = [0; 16];
+            let (len, peer) = server.recv_from(&mut buf).unwrap();
+            server.send_to(&buf[..len], peer).unwrap();
+        });
+
+        let mut network = StdNetwork::new();
+        let socket = UdpStack::open(&mut network).await;
+        UdpStack::connect(&mut network, socket, localhost(port))
+            .await
+            .unwrap();
+        network.send(socket, b"hello").await.unwrap();
+
+        let mut rx = [0; 16];
+        let len = network.recv(socket, &mut rx).await.unwrap();
+        assert_eq!(b"hello", &rx[..len]);
+        UdpStack::close(&mut network, socket).await;
+    }
+}
@@ -0,0 +1,195 @@
+#![macro_use]
+#![allow(incomplete_features)]
+#![feature(min_type_alias_impl_trait)]
+#![feature(impl_trait_in_bindings)]
+#![feature(generic_associated_types)]
+#![feature(type_alias_impl_trait)]
+
+#[cfg(feature = "std")]
+mod common;
+
+#[cfg(feature = "std")]
+mod tests {
+    extern crate std;
+    use super::common::*;
+    use core::future::Future;
+    use drogue_device::{
+        testutil::*,
+        traits::{ip::*, tcp::*},
+        *,
+    };
+    use embassy::io::{AsyncBufRead, AsyncWrite};
+    use futures::{future::poll_fn, pin_mut};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    /// A stack echoing written data, returning no data on the first read and writing at most
+    /// 4 bytes at a time.
+    struct EchoStack {
+        data: Vec<u8>,
+        reads: usize,
+        closed: Rc<RefCell<bool>>,
+    }
+
+    impl TcpStack for EchoStack {
+        type SocketHandle = u8;
+
+        type OpenFuture<'m> = impl Future<Output = Self::SocketHandle> + 'm;
+        fn open<'m>(&'m mut self) -> Self::OpenFuture<'m> {
+            async move { 0 }
+        }
+
+        type ConnectFuture<'m> = impl Future<Output = Result<(), TcpError>> + 'm;
+        fn connect<'m>(
+            &'m mut self,
+            _: Self::SocketHandle,
+            _: IpProtocol,
+            _: SocketAddress,
+        ) -> Self::ConnectFuture<'m> {
+            async move { Ok(()) }
+        }
+
+        type WriteFuture<'m> = impl Future<Output = Result<usize, TcpError>> + 'm;
+        fn write<'m>(&'m mut self, _: Self::SocketHandle, buf: &'m [u8]) -> Self::WriteFuture<'m> {
+            async move {
+                let len = core::cmp::min(4, buf.len());
+                self.data.extend_from_slice(&buf[..len]);
+                Ok(len)
+            }
+        }
+
+        type ReadFuture<'m> = impl Future<Output = Result<usize, TcpError>> + 'm;
+        fn read<'m>(
+            &'m mut self,
+            _: Self::SocketHandle,
+            buf: &'m mut [u8],
+        ) -> Self::ReadFuture<'m> {
+            async move {
+                self.reads += 1;
+                if self.reads == 1 {
+                    return Ok(0);
+                }
+                if self.data.is_empty() {
+                    return Err(TcpError::SocketClosed);
+                }
+                let len = core::cmp::min(buf.len(), self.data.len());
+                buf[..len].copy_from_slice(&self.data[..len]);
+                self.data.drain(..len);
+                Ok(len)
+            }
+        }
+
+        type CloseFuture<'m> = impl Future<Output = ()> + 'm;
+        fn close<'m>(&'m mut self, _: Self::SocketHandle) -> Self::CloseFuture<'m> {
+            async move {
+                *self.closed.borrow_mut() = true;
+            }
+        }
+
+        fn close_detached(&mut self, _: Self::SocketHandle) {
+            *self.closed.borrow_mut() = true;
+        }
+
+        type PollReadableFuture<'m> = impl Future<Output = Result<Readiness, TcpError>> + 'm;
+        fn poll_readable<'m>(&'m mut self, _: Self::SocketHandle) -> Self::PollReadableFuture<'m> {
+            async move {
+                if self.data.is_empty() {
+                    Ok(Readiness::HalfClosed)
+                } else {
+                    Ok(Readiness::Readable)
+                }
+            }
+        }
+
+        type AvailableFuture<'m> = impl Future<Output = Result<usize, TcpError>> + 'm;
+        fn available<'m>(&'m mut self, _: Self::SocketHandle) -> Self::AvailableFuture<'m> {
+            async move { Ok(self.data.len()) }
+        }
+
+        type SetTimeoutFuture<'m> = impl Future<Output = Result<(), TcpError>> + 'm;
+        fn set_timeout<'m>(
+            &'m mut self,
+            _: Self::SocketHandle,
+            _: Option<embassy::time::Duration>,
+        ) -> Self::SetTimeoutFuture<'m> {
+            async move { Ok(()) }
+        }
+    }
+
+    #[drogue::test]
+    async fn test_socket_echo(mut context: TestContext<DummyDevice>) {
+        mount_dummy(&mut context);
+
+        let closed = Rc::new(RefCell::new(false));
+        let mut stack = EchoStack {
+            data: Vec::new(),
+            reads: 0,
+            closed: closed.clone(),
+        };
+        let mut rx = [0; 8];
+        let mut tx = [0; 8];
+        {
+            let socket = TcpSocket::connect(
+                &mut stack,
+                IpProtocol::Tcp,
+                SocketAddress::new(IpAddress::new_v4(127, 0, 0, 1), 80),
+                &mut rx,
+                &mut tx,
+            )
+            .await
+            .unwrap();
+            pin_mut!(socket);
+
+            let mut written = 0;
+            let data = b"hello world";
+            while written < data.len() {
+                written += poll_fn(|cx| socket.as_mut().poll_write(cx, &data[written..]))
+                    .await
+                    .unwrap();
+            }
+
+            let mut received = Vec::new();
+            loop {
+                let len = poll_fn(|cx| {
+                    socket.as_mut().poll_fill_buf(cx).map(|r| {
+                        r.map(|buf| {
+                            received.extend_from_slice(buf);
+                            buf.len()
+                        })
+                    })
+                })
+                .await
+                .unwrap();
+                if len == 0 {
+                    break;
+                }
+                socket.as_mut().consume(len);
+            }
+            assert_eq!(&data[..], &received[..]);
+            assert!(!*closed.borrow());
+        }
+        assert!(*closed.borrow());
+    }
+
+    #[drogue::test]
+    async fn test_socket_close(mut context: TestContext<DummyDevice>) {
+        mount_dummy(&mut context);
+
+        let closed = Rc::new(RefCell::new(false));
+        let mut stack = EchoStack {
+            data: Vec::new(),
+            reads: 0,
+            closed: closed.clone(),
+        };
+        let mut rx = [0; 8];
+        let mut tx = [0; 8];
+        let socket = TcpSocket::new(&mut stack, 0, &mut rx, &mut tx);
+        pin_mut!(socket);
+        socket.as_mut().close().await;
+        assert!(*closed.borrow());
+
+        let result = poll_fn(|cx| socket.as_mut().poll_write(cx, b"x")).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,37 @@
+#![macro_use]
+#![allow(incomplete_features)]
+#![feature(generic_associated_types)]
+#![feature(min_type_alias_impl_trait)]
+#![feature(impl_trait_in_bindings)]
+#![feature(type_alias_impl_trait)]
+#![feature(concat_idents)]
+
+use drogue_device::{drivers::net::std::StdNetwork, traits::ip::*, *};
+use wifi_app::*;
+
+const HOST: IpAddress = IpAddress::new_v4(127, 0, 0, 1);
+const PORT: u16 = 12345;
+
+pub struct MyDevice {
+    app: ActorContext<'static, App<StdNetwork>>,
+}
+
+#[drogue::main]
+async fn main(context: DeviceContext<MyDevice>) {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .format_timestamp_nanos()
+        .init();
+
+    // The network of the host is used, so there is no access point to join
+    context.configure(MyDevice {
+        app: ActorContext::new(App::new("", "", HOST, PORT)),
+    });
+
+    let app = context.mount(|device, spawner| device.app.mount(StdNetwork::new(), spawner));
+
+    loop {
+        app.request(Command::Send).unwrap().await;
+        time::Timer::after(time::Duration::from_secs(10)).await;
+    }
+}
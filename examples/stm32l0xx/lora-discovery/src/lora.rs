@@ -83,7 +83,7 @@ where
                             log::error!("Error sending message: {:?}", e);
                             LoraResult::Err(e)
                         }
-                        Ok(len) => LoraResult::OkSent(len),
+                        Ok((_, len)) => LoraResult::OkSent(len),
                     }
                 }
             }